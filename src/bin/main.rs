@@ -1,17 +1,39 @@
-use std::{env, process};
+use std::{
+    env,
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
 use getopts::Options;
 
+pub use print_queue::lock;
+pub use print_queue::manager;
+pub use print_queue::notify;
 pub use print_queue::queue;
+pub use print_queue::rtm;
+pub use print_queue::slack_bot;
+pub use print_queue::storage;
 pub use print_queue::user;
+pub use print_queue::web;
 use user::create_uid_username_mapping;
 
+/// How often to check whether someone has been at the front of a queue for too long.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Display usage information. Used for handling the "-h" or "--help" flags if passed, or if the Slack
 /// API key was not given, as that is a _required_ command line argument.
 fn usage(program: &str, opts: Options) {
     let desc = format!(
         "Queue \u{2014} a Slack bot to keep track of who is using a 3D \
-	printer\nUsage:\n\t{} (-k api-KEY | --key API-KEY>) [-f FILE | --file FILE] [-h | --help]",
+	printer\nUsage:\n\t{} (-k api-KEY | --key API-KEY>) [-d DIR | --dir DIR | --db FILE] [-q NAME | --queue NAME] \
+	[-f FILE | --file FILE] [--recover MODE] [-a USER-ID | --admin USER-ID]... [--front-limit DURATION [--front-grace DURATION] [--on-timeout ACTION]] [--notify-top N] \
+	[--force-unlock] [-h | --help]",
         program
     );
     print!("{}", opts.usage(&desc));
@@ -26,19 +48,79 @@ fn main() -> Result<(), slack::error::Error> {
 
     let mut opts = Options::new();
     opts.optopt("k", "key", "Slack bot API key", "API-KEY");
+    opts.optopt(
+        "d",
+        "dir",
+        "directory holding the backup file of each queue (default: queues); will be created if it does not exist",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "db",
+        "SQLite database to keep every queue in, instead of a directory of backup files",
+        "FILE",
+    );
+    opts.optopt(
+        "q",
+        "queue",
+        "name of the queue to use when a command does not name one (default: printer)",
+        "NAME",
+    );
     opts.optopt(
         "f",
         "file",
-        "name of the backup file to use; will be created if empty",
+        "name of the backup file to use for the default queue; will be created if empty",
         "FILE",
     );
+    opts.optopt(
+        "",
+        "recover",
+        "what to do with a queue whose backup has problems in it: strict (default; refuse to start), \
+        skip (leave out the bad lines), or start-empty (set the backup aside and start over)",
+        "MODE",
+    );
+    opts.optmulti(
+        "a",
+        "admin",
+        "Slack ID of a user who may rearrange the queues; may be given more than once",
+        "USER-ID",
+    );
+    opts.optopt(
+        "",
+        "front-limit",
+        "how long someone can be at the front of a queue without starting before they are warned, e.g. 1d or 2h30m",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "front-grace",
+        "how long after the warning someone has before they are skipped (default: 1h)",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "on-timeout",
+        "what to do with someone who is skipped: move-back (default) or remove",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "notify-top",
+        "how many of the first places in line people are notified about moving into (default: 3)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "force-unlock",
+        "break the lock on the queues left behind by an instance of Queue that is no longer running",
+    );
     opts.optflag("h", "", "show a one-line usage summary");
     opts.optflag("", "help", "display this help message and exit");
 
     let matches = match opts.parse(args) {
         Ok(m) => m,
         Err(f) => {
-            eprintln!("{}", f.to_string());
+            eprintln!("{}", f);
             usage(&program, opts);
             process::exit(-3);
         }
@@ -76,9 +158,200 @@ fn main() -> Result<(), slack::error::Error> {
         println!("Number of members: {:?}", users.len());
     }
 
-    let mut queue = match matches.opt_str("f") {
-        Some(file) => queue::Queue::from_file(&users, file),
-        None => queue::Queue::new(&users),
+    let default_queue = matches
+        .opt_str("queue")
+        .unwrap_or_else(|| String::from(manager::DEFAULT_QUEUE));
+    if !manager::is_valid_queue_name(&default_queue) {
+        eprintln!(
+            "\'{}\' cannot be used as the name of a queue",
+            default_queue
+        );
+        process::exit(-4);
+    }
+    let backend = match backend(&matches) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&program, opts);
+            process::exit(-3);
+        }
+    };
+
+    let recovery = match matches.opt_str("recover").as_deref() {
+        None | Some("strict") => queue::Recovery::Strict,
+        Some("skip") => queue::Recovery::SkipInvalid,
+        Some("start-empty") => queue::Recovery::StartEmpty,
+        Some(other) => {
+            eprintln!("\'{}\' is not a valid --recover mode", other);
+            usage(&program, opts);
+            process::exit(-3);
+        }
+    };
+
+    // The locks are held until the bot exits
+    let force_unlock = matches.opt_present("force-unlock");
+    if force_unlock {
+        if let Some(path) = backend.lock_path() {
+            break_lock(&path);
+        }
+    }
+    let _backend_lock = take_lock(backend.lock());
+    let _file_lock = matches.opt_str("f").map(|file| {
+        let path = storage::state_file_lock_path(&file);
+        if force_unlock {
+            break_lock(&path);
+        }
+        take_lock(lock::Lock::acquire(path).map(Some))
+    });
+
+    let (mut queues, reports) = match manager::QueueManager::load(backend, &default_queue, recovery)
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Use --recover skip or --recover start-empty to start anyway");
+            process::exit(-5);
+        }
+    };
+    for (name, report) in reports {
+        print_load_report(&name, &report);
+    }
+    if let Some(file) = matches.opt_str("f") {
+        let loaded = storage::open_state_file(&file)
+            .and_then(|storage| manager::load_queue(&default_queue, Box::new(storage), recovery));
+        match loaded {
+            Ok((_, report)) if recovery == queue::Recovery::Strict && !report.is_clean() => {
+                eprintln!("{}: {}", file, report.problems[0]);
+                eprintln!("Use --recover skip or --recover start-empty to start anyway");
+                process::exit(-5);
+            }
+            Ok((queue, report)) => {
+                print_load_report(&default_queue, &report);
+                queues.insert(&default_queue, queue);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                process::exit(-5);
+            }
+        }
+    }
+
+    let front_timeout = match front_timeout(&matches) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&program, opts);
+            process::exit(-6);
+        }
     };
-    slack::RtmClient::login_and_run(&api_key, &mut queue)
+    queues.set_front_timeout(front_timeout);
+    let notify_top = match matches.opt_get_default("notify-top", notify::DEFAULT_NOTIFY_TOP) {
+        Ok(top) => top,
+        Err(e) => {
+            eprintln!("Invalid --notify-top: {}", e);
+            process::exit(-3);
+        }
+    };
+    queues.set_notify_top(notify_top);
+    queues.set_admins(
+        matches
+            .opt_strs("admin")
+            .iter()
+            .map(|id| user::UserID::new(id)),
+    );
+    let mut bot = slack_bot::SlackBot::new(queues, &users);
+    bot.set_notifier(Box::new(web::WebClient::new(&api_key)));
+
+    let client = slack::RtmClient::login(&api_key)?;
+    let sender = client.sender().clone();
+    let bot = Mutex::new(bot);
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        if front_timeout.is_some() {
+            scope.spawn(|| rtm::watch_front_timeouts(&bot, &sender, TIMEOUT_CHECK_INTERVAL, &stop));
+        }
+        let result = client.run(&mut rtm::SharedSlackBot(&bot));
+        stop.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Tell whoever started the bot about the problems that were found while loading the queue called
+/// `name`, and what was done about them.
+fn print_load_report(name: &str, report: &queue::LoadReport) {
+    if let Some(backup) = &report.restored {
+        eprintln!(
+            "queue {}: the saved state was damaged, so it was restored from {}",
+            name, backup
+        );
+    }
+    let verb = match report.set_aside {
+        Some(_) => "found",
+        None => "skipped",
+    };
+    for problem in &report.problems {
+        eprintln!("queue {}: {} {}", name, verb, problem);
+    }
+    if let Some(aside) = &report.set_aside {
+        eprintln!(
+            "queue {}: starting empty; the old backup was moved to {}",
+            name, aside
+        );
+    }
+}
+
+/// Break the lock at `path` for `--force-unlock`, saying whose it was.
+fn break_lock(path: &Path) {
+    match lock::force_unlock(path) {
+        Ok(Some(holder)) => eprintln!("Broke the lock on {} held by {}", path.display(), holder),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Could not break the lock on {}: {}", path.display(), e);
+            process::exit(-7);
+        }
+    }
+}
+
+/// Exit if a lock could not be taken, e.g. because another instance of Queue is using the same
+/// queues.
+fn take_lock(result: Result<Option<lock::Lock>, lock::LockError>) -> Option<lock::Lock> {
+    match result {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{}", e);
+            if let lock::LockError::Held { .. } = e {
+                eprintln!(
+                    "Is another instance of Queue running? If not, start again with --force-unlock"
+                );
+            }
+            process::exit(-7);
+        }
+    }
+}
+
+/// Pick where the queues are saved out of the `--dir` and `--db` options. The queues are kept in a
+/// directory called `queues` unless either is given.
+fn backend(matches: &getopts::Matches) -> Result<storage::Backend, String> {
+    match (matches.opt_str("dir"), matches.opt_str("db")) {
+        (Some(_), Some(_)) => Err(String::from("--dir and --db cannot be used together")),
+        #[cfg(feature = "sqlite")]
+        (None, Some(db)) => Ok(storage::Backend::Sqlite(db.into())),
+        #[cfg(not(feature = "sqlite"))]
+        (None, Some(_)) => Err(String::from(
+            "--db needs Queue to be built with the sqlite feature",
+        )),
+        (dir, None) => Ok(storage::Backend::TextFiles(
+            dir.unwrap_or_else(|| String::from("queues")).into(),
+        )),
+    }
+}
+
+/// Build the front-of-line time limit out of the `--front-limit`, `--front-grace`, and `--on-timeout`
+/// options. There is no limit unless `--front-limit` is given.
+fn front_timeout(matches: &getopts::Matches) -> Result<Option<queue::FrontTimeout>, String> {
+    queue::FrontTimeout::from_options(
+        matches.opt_str("front-limit").as_deref(),
+        matches.opt_str("front-grace").as_deref(),
+        matches.opt_str("on-timeout").as_deref(),
+    )
 }
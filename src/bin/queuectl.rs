@@ -0,0 +1,848 @@
+//! Inspect and fix the saved state of a queue while the bot is not running, e.g. to take someone out
+//! of line who left without saying so.
+//!
+//! `queuectl` works on the same queues as `slack_main` and `verify` (`--dir`, `--db`, or a single
+//! state file with `--file`), loads them the same way, and takes the same lock on them, so it
+//! refuses to run while the bot is using them. Changes are journaled like any other, with no one in
+//! particular as the actor.
+//!
+//! Exit codes: -2 if the members of the workspace cannot be looked up, -3 for bad arguments, -4 for a
+//! queue that does not exist, -5 for a queue that cannot be loaded or has problems in it, -6 for a
+//! command that could not be done, and -7 if the queue is locked.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
+
+use getopts::{Matches, Options};
+
+use print_queue::export::{self, Format};
+use print_queue::fsck::{self, Repair};
+use print_queue::lock::{self, Lock, LockError};
+use print_queue::manager::{self, POLICY_KEY};
+use print_queue::notify::DELIVERY_KEY;
+use print_queue::policy;
+use print_queue::queue::{
+    AddResult, Entry, ImportResult, LoadReport, Queue, RearrangeResult, Recovery, RemoveResult,
+    TURNS_KEY,
+};
+use print_queue::state_file;
+use print_queue::storage::{self, Backend, Record, Storage, TextFileStorage};
+#[cfg(feature = "http-api")]
+use print_queue::user::create_uid_username_mapping;
+use print_queue::user::{SlackMap, UserID};
+
+/// Display usage information. Used for handling the "-h" or "--help" flags if passed, or if no
+/// command was given.
+fn usage(program: &str, opts: Options) {
+    let desc = format!(
+        "queuectl \u{2014} inspect and edit the saved queues of Queue while it is not running\nUsage:\n\t{} \
+		[-d DIR | --dir DIR | --db FILE | -f FILE | --file FILE] [-q NAME | --queue NAME] [--recover MODE] \
+		[--force-unlock] [-k API-KEY | --key API-KEY] [--format FORMAT] COMMAND [ARGS...]\n\nCommands:\n\
+		\tlist                    show who is in line\n\
+		\tadd USER-ID             add someone to the back of the line, if the queue's rules allow it\n\
+		\tremove USER-ID          take someone out of line\n\
+		\tmove USER-ID POSITION   move someone to a place in line, counting from 0\n\
+		\tvalidate                check the saved queue for problems, without fixing them\n\
+		\tfsck [REPAIR...]        find everything wrong with the saved queue, and repair it with any of\n\
+		\t                        renumber (close gaps and give duplicate positions their own),\n\
+		\t                        drop (leave out anyone unreadable, unknown, or breaking the rules),\n\
+		\t                        and relax (let people who break the rules stay in line)\n\
+		\tclear                   take everyone out of line\n\
+		\texport                  write the queue to standard output, as a state file unless --format\n\
+		\t                        says otherwise\n\
+		\thistory                 write everyone who has left the line to standard output, as CSV\n\
+		\t                        unless --format says otherwise\n\
+		\timport CSV-FILE         add the people in a CSV file (e.g. one written by export) to the\n\
+		\t                        back of the line, if the queue's rules allow all of them\n\
+		\tdiff OLD-FILE NEW-FILE  show how two state files (e.g. a file and one of its backups) differ",
+        program
+    );
+    print!("{}", opts.usage(&desc));
+}
+
+fn main() {
+    // Exiting from `run` would leave the lock behind
+    if let Err(code) = run() {
+        process::exit(code);
+    }
+}
+
+/// Run the command that was given, returning the code to exit with if it fails.
+fn run() -> Result<(), i32> {
+    let mut args = env::args();
+    let program = args
+        .next()
+        .expect("Program name was not passed to command line arguments");
+
+    let mut opts = Options::new();
+    opts.optopt(
+        "d",
+        "dir",
+        "directory holding the backup file of each queue (default: queues)",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "db",
+        "SQLite database to keep every queue in, instead of a directory of backup files",
+        "FILE",
+    );
+    opts.optopt(
+        "f",
+        "file",
+        "a single state file to work on, instead of a queue in a directory or database",
+        "FILE",
+    );
+    opts.optopt(
+        "q",
+        "queue",
+        "name of the queue to work on (default: printer)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "recover",
+        "what to do with a queue whose backup has problems in it: strict (default; refuse to change \
+        it), skip (leave out the bad lines), or start-empty (set the backup aside and start over)",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "force-unlock",
+        "break the lock on the queues left behind by an instance of Queue that is no longer running",
+    );
+    #[cfg(feature = "http-api")]
+    opts.optopt(
+        "k",
+        "key",
+        "Slack API token, to check that everyone in line is a member of the workspace (fsck and \
+        import) and to look up their real names (export and history)",
+        "API-KEY",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "what export and history write: state (a state file; the default for export), csv (the \
+        default for history), or json",
+        "FORMAT",
+    );
+    opts.optflag("h", "help", "display this help message and exit");
+
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f);
+            usage(&program, opts);
+            return Err(-3);
+        }
+    };
+    if matches.opt_present("help") || matches.free.is_empty() {
+        usage(&program, opts);
+        return Ok(());
+    }
+
+    let command = matches.free[0].as_str();
+    let args = &matches.free[1..];
+    let arity = match command {
+        "list" | "validate" | "clear" | "export" | "history" => 0,
+        "add" | "remove" | "import" => 1,
+        "move" | "diff" => 2,
+        // Any number of repairs
+        "fsck" => args.len(),
+        other => {
+            eprintln!("\'{}\' is not a queuectl command", other);
+            usage(&program, opts);
+            return Err(-3);
+        }
+    };
+    if args.len() != arity {
+        eprintln!(
+            "{} takes {} argument{}",
+            command,
+            arity,
+            if arity == 1 { "" } else { "s" }
+        );
+        usage(&program, opts);
+        return Err(-3);
+    }
+
+    // Comparing two files does not touch any queue
+    if command == "diff" {
+        return diff(&args[0], &args[1]);
+    }
+
+    // Checking a queue does not load it, since that stops at the first problem
+    if command == "fsck" {
+        let repairs: Vec<Repair> = match args.iter().map(|arg| arg.parse()).collect() {
+            Ok(repairs) => repairs,
+            Err(e) => {
+                eprintln!("{}", e);
+                usage(&program, opts);
+                return Err(-3);
+            }
+        };
+        let mut opened = open(&matches).inspect_err(|e| {
+            if *e == -3 {
+                usage(&program, opts);
+            }
+        })?;
+        let users = members(&matches)?;
+        return check(&mut opened, users.as_ref(), &repairs);
+    }
+
+    // None for a state file
+    let format = match (command, matches.opt_str("format").as_deref()) {
+        ("export", None) | (_, Some("state")) => None,
+        (_, None) => Some(Format::Csv),
+        (_, Some(format)) => match format.parse() {
+            Ok(format) => Some(format),
+            Err(e) => {
+                eprintln!("{}", e);
+                usage(&program, opts);
+                return Err(-3);
+            }
+        },
+    };
+    if command == "history" && format.is_none() {
+        eprintln!("The history cannot be written as a state file");
+        return Err(-3);
+    }
+
+    let recovery = match (command, matches.opt_str("recover").as_deref()) {
+        // Problems are only ever reported when validating, never fixed
+        ("validate", _) | (_, None) | (_, Some("strict")) => Recovery::Strict,
+        (_, Some("skip")) => Recovery::SkipInvalid,
+        (_, Some("start-empty")) => Recovery::StartEmpty,
+        (_, Some(other)) => {
+            eprintln!("\'{}\' is not a valid --recover mode", other);
+            usage(&program, opts);
+            return Err(-3);
+        }
+    };
+    let Opened {
+        name,
+        storage,
+        lock: _lock,
+        ..
+    } = open(&matches).inspect_err(|e| {
+        if *e == -3 {
+            usage(&program, opts);
+        }
+    })?;
+    let (mut queue, report) = match manager::load_queue(&name, storage, recovery) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(-5);
+        }
+    };
+    print_load_report(&name, &report);
+    if recovery == Recovery::Strict && !report.is_clean() {
+        if command != "validate" {
+            eprintln!("Use --recover skip or --recover start-empty to change it anyway");
+        }
+        return Err(-5);
+    }
+
+    match command {
+        "list" => {
+            list(&queue);
+            Ok(())
+        }
+        "add" => add(&mut queue, UserID::new(&args[0])),
+        "remove" => remove(&mut queue, UserID::new(&args[0])),
+        "move" => {
+            let to = match args[1].parse() {
+                Ok(to) => to,
+                Err(e) => {
+                    eprintln!("Invalid position \'{}\': {}", args[1], e);
+                    return Err(-3);
+                }
+            };
+            move_user(&mut queue, &UserID::new(&args[0]), to)
+        }
+        "validate" => {
+            println!(
+                "queue {}: no problems; {} in line",
+                name,
+                people(queue.len())
+            );
+            Ok(())
+        }
+        "clear" => clear(&mut queue),
+        "export" => match format {
+            Some(format) => {
+                let users = members(&matches)?;
+                print!("{}", export::queue(queue.iter(), users.as_ref(), format));
+                Ok(())
+            }
+            None => export(&mut queue),
+        },
+        "history" => {
+            let users = members(&matches)?;
+            history(&mut queue, users.as_ref(), format.unwrap_or(Format::Csv))
+        }
+        "import" => {
+            let users = members(&matches)?;
+            import(&mut queue, &args[0], users.as_ref())
+        }
+        _ => unreachable!("Commands were checked above"),
+    }
+}
+
+/// A queue that was opened.
+struct Opened {
+    /// The name of the queue
+    name: String,
+    /// Where it is saved
+    storage: Box<dyn Storage>,
+    /// The lock on it, which is held until this is dropped
+    lock: Option<Lock>,
+    /// The state file it is saved in, if everything about it is saved there, i.e. there are no
+    /// journaled changes on top of it
+    state_file: Option<PathBuf>,
+}
+
+/// Open the storage of the queue that was picked out of the `--dir`, `--db`, `--file`, and
+/// `--queue` options, after taking the lock on it.
+fn open(matches: &Matches) -> Result<Opened, i32> {
+    let force_unlock = matches.opt_present("force-unlock");
+    if let Some(file) = matches.opt_str("file") {
+        if matches.opt_present("dir") || matches.opt_present("db") {
+            eprintln!("--file cannot be used together with --dir or --db");
+            return Err(-3);
+        }
+        if !Path::new(&file).exists() {
+            eprintln!("There is no state file called {}", file);
+            return Err(-4);
+        }
+        let path = storage::state_file_lock_path(&file);
+        if force_unlock {
+            break_lock(&path)?;
+        }
+        let lock = take_lock(Lock::acquire(path).map(Some))?;
+        let storage = storage::open_state_file(&file).map_err(|e| {
+            eprintln!("{}: {}", file, e);
+            -5
+        })?;
+        let journaled = has_events(&storage::state_file_journal(&file));
+        return Ok(Opened {
+            state_file: Some(PathBuf::from(&file)).filter(|_| !journaled),
+            name: file,
+            storage: Box::new(storage),
+            lock,
+        });
+    }
+
+    let backend = match (matches.opt_str("dir"), matches.opt_str("db")) {
+        (Some(_), Some(_)) => {
+            eprintln!("--dir and --db cannot be used together");
+            return Err(-3);
+        }
+        #[cfg(feature = "sqlite")]
+        (None, Some(db)) => Backend::Sqlite(db.into()),
+        #[cfg(not(feature = "sqlite"))]
+        (None, Some(_)) => {
+            eprintln!("--db needs Queue to be built with the sqlite feature");
+            return Err(-3);
+        }
+        (dir, None) => Backend::TextFiles(dir.unwrap_or_else(|| String::from("queues")).into()),
+    };
+    let name = matches
+        .opt_str("queue")
+        .unwrap_or_else(|| String::from(manager::DEFAULT_QUEUE));
+    if !manager::is_valid_queue_name(&name) {
+        eprintln!("\'{}\' cannot be used as the name of a queue", name);
+        return Err(-4);
+    }
+    if force_unlock {
+        if let Some(path) = backend.lock_path() {
+            break_lock(&path)?;
+        }
+    }
+    let lock = take_lock(backend.lock())?;
+    match backend.queue_names() {
+        Ok(names) if names.contains(&name) => {}
+        Ok(_) => {
+            eprintln!("There is no queue called {}", name);
+            return Err(-4);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(-5);
+        }
+    }
+    let storage = backend.open(&name).map_err(|e| {
+        eprintln!("queue {}: {}", name, e);
+        -5
+    })?;
+    let journaled = backend
+        .journal(&name)
+        .is_some_and(|journal| has_events(&journal));
+    let state_file = backend.state_file(&name).filter(|_| !journaled);
+    Ok(Opened {
+        name,
+        storage,
+        lock,
+        state_file,
+    })
+}
+
+/// Whether the journal at `path` has changes in it that are not in the snapshot yet.
+fn has_events(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0)
+}
+
+/// Break the lock at `path` for `--force-unlock`, saying whose it was.
+fn break_lock(path: &Path) -> Result<(), i32> {
+    match lock::force_unlock(path) {
+        Ok(Some(holder)) => eprintln!("Broke the lock on {} held by {}", path.display(), holder),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Could not break the lock on {}: {}", path.display(), e);
+            return Err(-7);
+        }
+    }
+    Ok(())
+}
+
+/// Explain why a lock could not be taken, e.g. because the bot is using the same queues.
+fn take_lock(result: Result<Option<Lock>, LockError>) -> Result<Option<Lock>, i32> {
+    match result {
+        Ok(lock) => Ok(lock),
+        Err(e) => {
+            eprintln!("{}", e);
+            if let LockError::Held { .. } = e {
+                eprintln!(
+                    "Stop the bot first. If it is not running, try again with --force-unlock"
+                );
+            }
+            Err(-7)
+        }
+    }
+}
+
+/// Look up the members of the workspace with the `--key` option, if it was given.
+#[cfg(feature = "http-api")]
+fn members(matches: &Matches) -> Result<Option<SlackMap>, i32> {
+    match matches.opt_str("key") {
+        Some(api_key) => match create_uid_username_mapping(&api_key) {
+            Ok(users) => Ok(Some(users)),
+            Err(e) => {
+                eprintln!("{}", e);
+                Err(-2)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+/// Without the Web API, the members of the workspace cannot be looked up.
+#[cfg(not(feature = "http-api"))]
+fn members(_matches: &Matches) -> Result<Option<SlackMap>, i32> {
+    Ok(None)
+}
+
+/// Say what was found while loading the queue called `name`, and what was done about it.
+fn print_load_report(name: &str, report: &LoadReport) {
+    if let Some(backup) = &report.restored {
+        eprintln!(
+            "queue {}: the saved state was damaged, so it was restored from {}",
+            name, backup
+        );
+    }
+    for problem in &report.problems {
+        eprintln!("queue {}: {}", name, problem);
+    }
+    if let Some(aside) = &report.set_aside {
+        eprintln!(
+            "queue {}: starting empty; the old backup was moved to {}",
+            name, aside
+        );
+    }
+}
+
+/// "1 person" or "3 people".
+fn people(count: usize) -> String {
+    match count {
+        1 => String::from("1 person"),
+        _ => format!("{} people", count),
+    }
+}
+
+/// One line describing `entry`, which is at `position`.
+fn describe(position: usize, entry: &Entry, now: SystemTime) -> String {
+    let mut line = format!(
+        "{}\t{}\t{}\t{}",
+        position,
+        entry.user,
+        entry.state(),
+        policy::format_duration(entry.waited(now))
+    );
+    if let Some(name) = &entry.job.name {
+        line.push('\t');
+        line.push_str(name);
+    }
+    line
+}
+
+/// Handle the list command.
+fn list(queue: &Queue) {
+    let now = SystemTime::now();
+    for (position, entry) in queue.iter().enumerate() {
+        println!("{}", describe(position, entry, now));
+    }
+}
+
+/// Handle the add command.
+fn add(queue: &mut Queue, user: UserID) -> Result<(), i32> {
+    match queue.add_user(user) {
+        (user, AddResult::UserSuccessfullyAdded) => {
+            println!("Added {} at position {}", user, queue.len() - 1);
+            Ok(())
+        }
+        (user, AddResult::UserNotAdded(rejection)) => {
+            eprintln!("{} was not added: {}", user, rejection);
+            Err(-6)
+        }
+        (user, AddResult::UserUnsuccessfullyAdded(e)) => {
+            eprintln!(
+                "{} was added, but the queue could not be saved: {}",
+                user, e
+            );
+            Err(-6)
+        }
+    }
+}
+
+/// Handle the remove command.
+fn remove(queue: &mut Queue, user: UserID) -> Result<(), i32> {
+    match queue.remove_user(user) {
+        (user, RemoveResult::UserSuccessfullyRemoved(position)) => {
+            println!("Removed {} from position {}", user, position);
+            Ok(())
+        }
+        (user, RemoveResult::NonExistentUser) => {
+            eprintln!("{} is not in line", user);
+            Err(-6)
+        }
+        (user, RemoveResult::UserUnsuccessfullyRemoved(e)) => {
+            eprintln!(
+                "{} was removed, but the queue could not be saved: {}",
+                user, e
+            );
+            Err(-6)
+        }
+    }
+}
+
+/// Handle the move command.
+fn move_user(queue: &mut Queue, user: &UserID, to: usize) -> Result<(), i32> {
+    match queue.move_user(user, to) {
+        RearrangeResult::Rearranged => {
+            println!("Moved {} to position {}", user, to);
+            Ok(())
+        }
+        RearrangeResult::UserNotInQueue => {
+            eprintln!("{} is not in line", user);
+            Err(-6)
+        }
+        RearrangeResult::PositionOutOfRange(to) => {
+            eprintln!(
+                "There is no position {}; there are {} in line",
+                to,
+                people(queue.len())
+            );
+            Err(-6)
+        }
+        RearrangeResult::UnsuccessfullyRearranged(e) => {
+            eprintln!(
+                "{} was moved, but the queue could not be saved: {}",
+                user, e
+            );
+            Err(-6)
+        }
+    }
+}
+
+/// Handle the fsck command. A queue kept in a state file with nothing journaled on top of it is
+/// checked line by line, so that every issue can be pointed to; otherwise, the queue is checked as
+/// it loads. Nobody is unknown unless the members of the workspace are `users`.
+fn check(opened: &mut Opened, users: Option<&SlackMap>, repairs: &[Repair]) -> Result<(), i32> {
+    let scanned = match opened.state_file.clone() {
+        Some(path) => {
+            let scanned = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| storage::scan_state(&contents).map_err(|e| e.to_string()))
+                .and_then(|slots| {
+                    // Loading the state file through the journal would start the journal, so it is
+                    // checked and repaired on its own
+                    let storage = TextFileStorage::open(&path).map_err(|e| e.to_string())?;
+                    opened.storage = Box::new(storage);
+                    Ok(slots)
+                });
+            match scanned {
+                Ok(slots) => Some(slots),
+                Err(e) => {
+                    eprintln!("{}: {}", path.display(), e);
+                    return Err(-5);
+                }
+            }
+        }
+        None => None,
+    };
+    let name = &opened.name;
+    let storage = opened.storage.as_mut();
+    // Loading also gets the storage ready to save the repairs
+    let loaded = storage.load().and_then(|records| {
+        let policy = manager::load_policy(name, storage)?;
+        let exempt = fsck::exemptions(storage)?;
+        Ok((records, policy, exempt))
+    });
+    let (records, policy, exempt) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("queue {}: {}", name, e);
+            return Err(-5);
+        }
+    };
+    let slots = scanned.unwrap_or_else(|| fsck::slots(records));
+
+    let check = fsck::check(slots, &*policy, users, &exempt, repairs);
+    for finding in &check.findings {
+        match finding.repair {
+            Some(repair) => println!("queue {}: {} (fixed by {})", name, finding.issue, repair),
+            None => println!("queue {}: {}", name, finding.issue),
+        }
+    }
+    if check.is_clean() {
+        println!(
+            "queue {}: no problems; {} in line",
+            name,
+            people(check.entries.len())
+        );
+        return Ok(());
+    }
+    if !check.is_repaired() {
+        eprintln!(
+            "Not every problem can be fixed by the repairs that were given (renumber, drop, or relax); \
+            nothing was changed"
+        );
+        return Err(-5);
+    }
+    if let Err(e) = fsck::repair(storage, &check) {
+        eprintln!("queue {}: the repairs could not be saved: {}", name, e);
+        return Err(-6);
+    }
+    println!(
+        "queue {}: repaired; {} in line",
+        name,
+        people(check.entries.len())
+    );
+    Ok(())
+}
+
+/// Handle the clear command.
+fn clear(queue: &mut Queue) -> Result<(), i32> {
+    let (removed, written) = queue.clear();
+    for user in &removed {
+        println!("Removed {}", user);
+    }
+    if let Err(e) = written {
+        eprintln!("The queue was cleared, but could not be saved: {}", e);
+        return Err(-6);
+    }
+    Ok(())
+}
+
+/// Handle the export command. Besides the people in line, the queue's settings are exported, so
+/// the output can be used as the `--file` of `slack_main`.
+fn export(queue: &mut Queue) -> Result<(), i32> {
+    let mut metadata = BTreeMap::new();
+    for key in &[POLICY_KEY, DELIVERY_KEY, TURNS_KEY] {
+        match queue.metadata(key) {
+            Ok(Some(value)) => {
+                metadata.insert(key.to_string(), value);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Could not read the {} of the queue: {}", key, e);
+                return Err(-5);
+            }
+        }
+    }
+    print!("{}", state_file::format(queue.iter(), &metadata));
+    Ok(())
+}
+
+/// Handle the history command.
+fn history(queue: &mut Queue, users: Option<&SlackMap>, format: Format) -> Result<(), i32> {
+    match queue.history() {
+        Ok(history) => {
+            print!("{}", export::history(&history, users, format));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Could not read the history of the queue: {}", e);
+            Err(-5)
+        }
+    }
+}
+
+/// Handle the import command. Nothing is imported if any row of the file at `path` has a problem,
+/// including someone who is not a member of the workspace, if its members are `users`.
+fn import(queue: &mut Queue, path: &str, users: Option<&SlackMap>) -> Result<(), i32> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Err(-5);
+        }
+    };
+    let mut lines = Vec::new();
+    let mut entries = Vec::new();
+    let mut problems = 0;
+    for record in export::read_csv(&contents) {
+        match record {
+            Ok(Record { line, entry }) => {
+                if users.is_some_and(|users| !users.contains_key(&entry.user)) {
+                    eprintln!(
+                        "{}: line {}: user {} is not a member of the workspace",
+                        path, line, entry.user
+                    );
+                    problems += 1;
+                }
+                lines.push(line);
+                entries.push(entry);
+            }
+            Err(problem) => {
+                eprintln!("{}: {}", path, problem);
+                problems += 1;
+            }
+        }
+    }
+    if problems > 0 {
+        eprintln!("Nothing was imported");
+        return Err(-5);
+    }
+
+    let count = entries.len();
+    match queue.import(entries) {
+        ImportResult::Imported => {
+            println!(
+                "Imported {}; {} in line",
+                people(count),
+                people(queue.len())
+            );
+            Ok(())
+        }
+        ImportResult::NotImported {
+            index,
+            user,
+            rejection,
+        } => {
+            eprintln!(
+                "{}: line {}: {} would not be let in: {}",
+                path, lines[index], user, rejection
+            );
+            eprintln!("Nothing was imported");
+            Err(-6)
+        }
+        ImportResult::UnsuccessfullyImported(e) => {
+            eprintln!(
+                "{} were imported, but the queue could not be saved: {}",
+                people(count),
+                e
+            );
+            Err(-6)
+        }
+    }
+}
+
+/// Read the entries of the state file at `path` for the diff command. Entries that do not make
+/// sense are reported and left out.
+fn read_entries(path: &str) -> Result<Vec<Entry>, i32> {
+    let records = match storage::read_state_file(path) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return Err(-5);
+        }
+    };
+    let mut entries = Vec::with_capacity(records.len());
+    for record in records {
+        match record {
+            Ok(Record { entry, .. }) => entries.push(entry),
+            Err(problem) => eprintln!("{}: skipped {}", path, problem),
+        }
+    }
+    Ok(entries)
+}
+
+/// The places in `positions`, from the front of the line to the back.
+fn sorted<K>(positions: &HashMap<K, usize>) -> Vec<(&K, &usize)> {
+    let mut sorted = positions.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(_, position)| **position);
+    sorted
+}
+
+/// Where each place in `entries` is, keyed by who it is and which of their places in line it is
+/// (0 for their first place, 1 for the one after that, and so on).
+fn positions(entries: &[Entry]) -> HashMap<(&UserID, usize), usize> {
+    let mut seen = HashMap::new();
+    let mut positions = HashMap::with_capacity(entries.len());
+    for (position, entry) in entries.iter().enumerate() {
+        let nth = seen.entry(&entry.user).or_insert(0);
+        positions.insert((&entry.user, *nth), position);
+        *nth += 1;
+    }
+    positions
+}
+
+/// Handle the diff command. Places in line are matched up by who they belong to rather than by
+/// when they joined, since legacy files may not say, so someone who is in line more than once is
+/// followed through each of their places in order.
+fn diff(old: &str, new: &str) -> Result<(), i32> {
+    let (old, new) = (read_entries(old)?, read_entries(new)?);
+    let (old_positions, new_positions) = (positions(&old), positions(&new));
+
+    for (key, &position) in sorted(&old_positions) {
+        if !new_positions.contains_key(key) {
+            println!("- {} (was at position {})", key.0, position);
+        }
+    }
+    for (key, &position) in sorted(&new_positions) {
+        let entry = &new[position];
+        let from = match old_positions.get(key) {
+            Some(&from) => from,
+            None => {
+                println!("+ {} (now at position {})", entry.user, position);
+                continue;
+            }
+        };
+        let was = &old[from];
+        if from != position {
+            println!(
+                "~ {} moved from position {} to {}",
+                entry.user, from, position
+            );
+        }
+        if was.state() != entry.state() {
+            println!(
+                "~ {} went from {} to {}",
+                entry.user,
+                was.state(),
+                entry.state()
+            );
+        }
+        if was.job != entry.job {
+            println!("~ {} changed their job", entry.user);
+        }
+    }
+    Ok(())
+}
@@ -1,42 +1,549 @@
-//! A "script" used to validate the slack events api. Since Queue is (not yet) running on a permanent
-//! server, this needs to be run every time before working on Queue in order to validate the URL that
-//! ngrok randomly generates each session.
+//! Queue as a server for the Slack Events API, as opposed to `slack_main`, which connects to the
+//! deprecated real time messaging API.
+//!
+//! Slack POSTs events to `/slack/events`. The URL challenge Slack sends when the endpoint is first
+//! configured is answered, and `app_mention` and `message.channels` events are handed to the bot,
+//! whose responses are posted back with `chat.postMessage`. Slash commands are POSTed to
+//! `/slack/commands`, and Queue's response is the reply to the request. Clicks on the buttons of a
+//! queue message are POSTed to `/slack/interactivity`, and the message is redrawn in place through
+//! the `response_url` Slack sends with them. Requests to any of these endpoints whose signature does
+//! not match the app's signing secret are turned away with a 401 before they are even parsed.
+//!
+//! With `--front-limit`, people who stay at the front of a line for too long are warned and then
+//! skipped, just like with `slack_main`, and what was done is posted to Queue's channel.
+//!
+//! Since Queue is (not yet) running on a permanent server, the URL that ngrok randomly generates each
+//! session needs to be configured as the app's request URL every time before working on Queue.
 //!
 //! Run ngrok with: `ngrok http PORT` (see PORT below)
 
-// use std::net::SocketAddr;
-use std::{io, net::Ipv4Addr};
+use std::{
+    env, io,
+    net::Ipv4Addr,
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use getopts::Options;
 
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use serde::Deserialize;
+use print_queue::events::{
+    CommandReply, EventsApi, Interaction, Payload, Reply, SigningSecret, SlashCommand,
+};
+use print_queue::lock::{self, LockError};
+use print_queue::manager::{self, QueueManager};
+use print_queue::notify::{self, Notification};
+use print_queue::queue::{FrontTimeout, Recovery};
+use print_queue::slack_bot::{SlackBot, INSPIRATIONAL_QUOTE};
+use print_queue::storage::Backend;
+use print_queue::user::{self, create_uid_username_mapping_from};
+use print_queue::web::{Message, WebClient, SLACK_API};
 
-/// The IP address we are connecting to
-// const IP_ADDR: [u8; 4] = [213u8, 108, 105, 162];
-/// Which port number the host is bound to
+/// Which port number the host is bound to, unless another one is given with `--port`
 const PORT: u16 = 3152;
 
-#[derive(Deserialize, Debug)]
-struct Payload {
-    token: String,
-    challenge: String,
-    r#type: String,
+/// The environment variable the signing secret is read from if `--signing-secret` is not given
+const SIGNING_SECRET_VAR: &str = "SLACK_SIGNING_SECRET";
+
+/// How often to check whether someone has been at the front of a queue for too long.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Everything the request handler needs, shared between the server's worker threads.
+struct Server {
+    /// Applies commands to the queues
+    bot: Mutex<SlackBot<'static>>,
+    /// Turns events into responses
+    events: Mutex<EventsApi>,
+    /// Posts the responses to Slack
+    slack: WebClient,
+    /// Checks that requests really come from Slack
+    secret: SigningSecret,
+}
+
+impl Server {
+    /// Run `f` on the queues, returning what it returns along with the notifications it caused. The
+    /// queues are unlocked again before anything is sent to Slack.
+    fn with<T>(
+        &self,
+        f: impl FnOnce(&mut EventsApi, &mut SlackBot<'static>) -> T,
+    ) -> (T, Vec<Notification>) {
+        let mut bot = self.bot.lock().unwrap_or_else(|e| e.into_inner());
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let result = f(&mut events, &mut bot);
+        (result, bot.queues_mut().take_notifications())
+    }
+
+    /// Apply `payload` to the queues, posting any response to Slack.
+    fn handle(&self, payload: Payload) -> Reply {
+        let (reply, notifications) = self.with(|events, bot| events.handle(bot, payload));
+        if let Reply::Post(message) = &reply {
+            if let Err(e) = self.slack.post_message(message) {
+                eprintln!("Could not post to {}: {}", message.channel, e);
+            }
+        }
+        notify::deliver(&self.slack, &notifications);
+        reply
+    }
+
+    /// Run a slash command on the queues.
+    fn command(&self, command: SlashCommand) -> CommandReply {
+        let (reply, notifications) = self.with(|events, bot| events.command(bot, command));
+        notify::deliver(&self.slack, &notifications);
+        reply
+    }
+
+    /// Apply the buttons clicked in `interaction` to the queues, redrawing the message that was
+    /// clicked.
+    fn interact(&self, interaction: Interaction) {
+        let (reply, notifications) = self.with(|events, bot| events.interact(bot, interaction));
+        notify::deliver(&self.slack, &notifications);
+        if let Some(reply) = reply {
+            for followup in &reply.followups {
+                if let Err(e) = self.slack.respond(&reply.response_url, followup) {
+                    eprintln!("Could not respond to an interaction: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Every `every`, check for people who have been at the front of a queue for too long and post
+    /// what was done about it to Queue's channel, until `stop` is set. This runs on its own thread
+    /// alongside the server.
+    fn watch_front_timeouts(&self, every: Duration, stop: &AtomicBool) {
+        loop {
+            // Sleep in small steps so that we notice when we are told to stop
+            let started = Instant::now();
+            while started.elapsed() < every {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                thread::sleep(Duration::from_secs(1).min(every));
+            }
+            let (notices, notifications) = self.with(|_, bot| {
+                // Nobody would see the notices without Queue's channel, so wait until it is known
+                let channel = bot.channel_id()?.to_string();
+                Some((channel, bot.check_front_timeouts(SystemTime::now())))
+            });
+            if let Some((channel, notices)) = notices {
+                for text in notices {
+                    let message = Message {
+                        channel: channel.clone(),
+                        text,
+                        blocks: None,
+                    };
+                    if let Err(e) = self.slack.post_message(&message) {
+                        eprintln!("Could not post to {}: {}", channel, e);
+                    }
+                }
+            }
+            notify::deliver(&self.slack, &notifications);
+        }
+    }
 }
 
-#[actix_rt::main]
-async fn main() -> io::Result<()> {
+/// Display usage information. Used for handling the "-h" or "--help" flags if passed, or if the Slack
+/// API key was not given, as that is a _required_ command line argument.
+fn usage(program: &str, opts: Options) {
+    let desc = format!(
+        "Queue \u{2014} a Slack bot to keep track of who is using a 3D printer, served over the \
+		Events API\nUsage:\n\t{} (-k API-KEY | --key API-KEY) [-s SECRET | --signing-secret SECRET] [-p PORT | --port PORT] \
+		[-d DIR | --dir DIR | --db FILE] [-q NAME | --queue NAME] [--recover MODE] \
+		[-a USER-ID | --admin USER-ID]... [--front-limit DURATION [--front-grace DURATION] [--on-timeout ACTION]] \
+		[--notify-top N] [--slack-api URL] [--force-unlock] [-h | --help]",
+        program
+    );
+    print!("{}", opts.usage(&desc));
+}
+
+fn main() -> io::Result<()> {
+    let mut args = env::args();
+    let program = args
+        .next()
+        .expect("Program name was not passed to command line arguments");
+
+    let mut opts = Options::new();
+    opts.optopt("k", "key", "Slack bot API key", "API-KEY");
+    opts.optopt(
+        "s",
+        "signing-secret",
+        "signing secret of the Slack app, used to check that requests come from Slack (default: the \
+        SLACK_SIGNING_SECRET environment variable)",
+        "SECRET",
+    );
+    opts.optopt(
+        "p",
+        "port",
+        "port to listen for events on (default: 3152)",
+        "PORT",
+    );
+    opts.optopt(
+        "d",
+        "dir",
+        "directory holding the backup file of each queue (default: queues); will be created if it does not exist",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "db",
+        "SQLite database to keep every queue in, instead of a directory of backup files",
+        "FILE",
+    );
+    opts.optopt(
+        "q",
+        "queue",
+        "name of the queue to use when a command does not name one (default: printer)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "recover",
+        "what to do with a queue whose backup has problems in it: strict (default), skip, or start-empty",
+        "MODE",
+    );
+    opts.optmulti(
+        "a",
+        "admin",
+        "Slack ID of a user who may rearrange the queues; may be given more than once",
+        "USER-ID",
+    );
+    opts.optopt(
+        "",
+        "front-limit",
+        "how long someone can be at the front of a queue without starting before they are warned, e.g. 1d or 2h30m",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "front-grace",
+        "how long after the warning someone has before they are skipped (default: 1h)",
+        "DURATION",
+    );
+    opts.optopt(
+        "",
+        "on-timeout",
+        "what to do with someone who is skipped: move-back (default) or remove",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "notify-top",
+        "how many of the first places in line people are notified about moving into (default: 3)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "slack-api",
+        "where the Slack Web API lives, e.g. a mock Slack for testing (default: https://slack.com/api)",
+        "URL",
+    );
+    opts.optflag(
+        "",
+        "force-unlock",
+        "break the lock on the queues left behind by an instance of Queue that is no longer running",
+    );
+    opts.optflag("h", "help", "display this help message and exit");
+
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("{}", f);
+            usage(&program, opts);
+            process::exit(-3);
+        }
+    };
+    if matches.opt_present("help") {
+        usage(&program, opts);
+        return Ok(());
+    }
+
+    let api_key = match matches.opt_str("key") {
+        Some(api_key) => api_key,
+        None => {
+            eprintln!("Required option \'key\' missing");
+            usage(&program, opts);
+            process::exit(-1);
+        }
+    };
+    let secret = match matches
+        .opt_str("signing-secret")
+        .or_else(|| env::var(SIGNING_SECRET_VAR).ok())
+    {
+        Some(secret) => SigningSecret::new(&secret),
+        None => {
+            eprintln!(
+                "Required option \'signing-secret\' missing, and {} is not set",
+                SIGNING_SECRET_VAR
+            );
+            usage(&program, opts);
+            process::exit(-1);
+        }
+    };
+    let port = match matches.opt_get_default("port", PORT) {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("Invalid port: {}", e);
+            process::exit(-3);
+        }
+    };
+    let backend = match (matches.opt_str("dir"), matches.opt_str("db")) {
+        (Some(_), Some(_)) => {
+            eprintln!("--dir and --db cannot be used together");
+            process::exit(-3);
+        }
+        #[cfg(feature = "sqlite")]
+        (None, Some(db)) => Backend::Sqlite(db.into()),
+        #[cfg(not(feature = "sqlite"))]
+        (None, Some(_)) => {
+            eprintln!("--db needs Queue to be built with the sqlite feature");
+            process::exit(-3);
+        }
+        (dir, None) => Backend::TextFiles(dir.unwrap_or_else(|| String::from("queues")).into()),
+    };
+    let recovery = match matches.opt_str("recover").as_deref() {
+        None | Some("strict") => Recovery::Strict,
+        Some("skip") => Recovery::SkipInvalid,
+        Some("start-empty") => Recovery::StartEmpty,
+        Some(other) => {
+            eprintln!("\'{}\' is not a valid --recover mode", other);
+            process::exit(-3);
+        }
+    };
+    if matches.opt_present("force-unlock") {
+        if let Some(path) = backend.lock_path() {
+            break_lock(&path);
+        }
+    }
+    // The lock is held until the server exits
+    let _lock = match backend.lock() {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{}", e);
+            if let LockError::Held { .. } = e {
+                eprintln!(
+                    "Is another instance of Queue running? If not, start again with --force-unlock"
+                );
+            }
+            process::exit(-7);
+        }
+    };
+    let default_queue = matches
+        .opt_str("queue")
+        .unwrap_or_else(|| String::from(manager::DEFAULT_QUEUE));
+    if !manager::is_valid_queue_name(&default_queue) {
+        eprintln!(
+            "\'{}\' cannot be used as the name of a queue",
+            default_queue
+        );
+        process::exit(-4);
+    }
+    let slack_api = matches
+        .opt_str("slack-api")
+        .unwrap_or_else(|| String::from(SLACK_API));
+    let slack = WebClient::with_base_url(&api_key, &slack_api);
+
+    let users = match create_uid_username_mapping_from(&slack_api, &api_key) {
+        Ok(u) => u,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(-2);
+        }
+    };
+    // The bot borrows the users for as long as the server runs, i.e. forever
+    let users = Box::leak(Box::new(users));
+
+    let (mut queues, reports) = match QueueManager::load(backend, &default_queue, recovery) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Use --recover skip or --recover start-empty to start anyway");
+            process::exit(-5);
+        }
+    };
+    for (name, report) in reports {
+        for problem in &report.problems {
+            eprintln!("queue {}: {}", name, problem);
+        }
+    }
+    let front_timeout = match FrontTimeout::from_options(
+        matches.opt_str("front-limit").as_deref(),
+        matches.opt_str("front-grace").as_deref(),
+        matches.opt_str("on-timeout").as_deref(),
+    ) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            eprintln!("{}", e);
+            usage(&program, opts);
+            process::exit(-6);
+        }
+    };
+    queues.set_front_timeout(front_timeout);
+    let notify_top = match matches.opt_get_default("notify-top", notify::DEFAULT_NOTIFY_TOP) {
+        Ok(top) => top,
+        Err(e) => {
+            eprintln!("Invalid --notify-top: {}", e);
+            process::exit(-3);
+        }
+    };
+    queues.set_notify_top(notify_top);
+    queues.set_admins(
+        matches
+            .opt_strs("admin")
+            .iter()
+            .map(|id| user::UserID::new(id)),
+    );
+    let mut bot = SlackBot::new(queues, users);
+    match slack.channels() {
+        Ok(channels) => bot.set_channels(channels),
+        Err(e) => {
+            eprintln!("Could not list the channels in the workspace: {}", e);
+            process::exit(-2);
+        }
+    }
+
+    println!("{}", INSPIRATIONAL_QUOTE);
+    let server = web::Data::new(Server {
+        bot: Mutex::new(bot),
+        events: Mutex::new(EventsApi::new()),
+        slack,
+        secret,
+    });
+
     // Which public url to connect to
     // In the future, it should be alchemi.dev:3152 (or something else)
-    // let socket_addr = SocketAddr::from((IP_ADDR, PORT));
     // but right now it is localhost:3152
-    let socket_addr = (Ipv4Addr::LOCALHOST, PORT);
+    let socket_addr = (Ipv4Addr::LOCALHOST, port);
     // Let's set up a web server!
-    HttpServer::new(|| App::new().route("/slack/events", web::post().to(post_handler)))
-        .bind(socket_addr)?
-        .run()
-        .await
+    let app_data = server.clone();
+    let http_server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_data.clone())
+            .route("/slack/events", web::post().to(post_handler))
+            .route("/slack/commands", web::post().to(command_handler))
+            .route(
+                "/slack/interactivity",
+                web::post().to(interactivity_handler),
+            )
+    })
+    .bind(socket_addr)?
+    .run();
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        if front_timeout.is_some() {
+            scope.spawn(|| server.watch_front_timeouts(TIMEOUT_CHECK_INTERVAL, &stop));
+        }
+        let result = actix_rt::System::new().block_on(http_server);
+        stop.store(true, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Break the lock at `path` for `--force-unlock`, saying whose it was.
+fn break_lock(path: &Path) {
+    match lock::force_unlock(path) {
+        Ok(Some(holder)) => eprintln!("Broke the lock on {} held by {}", path.display(), holder),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Could not break the lock on {}: {}", path.display(), e);
+            process::exit(-7);
+        }
+    }
+}
+
+/// Does `request` really come from Slack?
+fn verify(server: &Server, request: &HttpRequest, body: &[u8]) -> bool {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    server
+        .secret
+        .verify(
+            header("X-Slack-Request-Timestamp"),
+            header("X-Slack-Signature"),
+            body,
+            SystemTime::now(),
+        )
+        .map_err(|e| eprintln!("Rejected a request to {}: {}", request.path(), e))
+        .is_ok()
+}
+
+/// Handle one event from Slack, once its signature has been checked. Handling may post to Slack,
+/// which blocks, so it is done on a thread set aside for blocking work.
+async fn post_handler(
+    server: web::Data<Server>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !verify(&server, &request, &body) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let payload = match serde_json::from_slice::<Payload>(&body) {
+        Ok(payload) => payload,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    match web::block(move || server.handle(payload)).await {
+        Ok(Reply::Challenge(challenge)) => HttpResponse::Ok().body(challenge),
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            eprintln!("Could not handle an event: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
-async fn post_handler(web::Json(response): web::Json<Payload>) -> impl Responder {
-    println!("{:?}", response);
-    HttpResponse::Ok().body(response.challenge)
+/// Handle one slash command from Slack, once its signature has been checked, replying with what
+/// Queue has to say about it.
+async fn command_handler(
+    server: web::Data<Server>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !verify(&server, &request, &body) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let command = match SlashCommand::from_form(&body) {
+        Ok(command) => command,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    match web::block(move || server.command(command)).await {
+        Ok(reply) => HttpResponse::Ok().json(reply),
+        Err(e) => {
+            eprintln!("Could not handle a command: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Handle one click on the buttons of a queue message, once its signature has been checked. The
+/// message is redrawn through its `response_url`, so the request itself is answered with nothing.
+async fn interactivity_handler(
+    server: web::Data<Server>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    if !verify(&server, &request, &body) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let interaction = match Interaction::from_form(&body) {
+        Ok(interaction) => interaction,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    match web::block(move || server.interact(interaction)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            eprintln!("Could not handle an interaction: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
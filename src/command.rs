@@ -0,0 +1,1056 @@
+//! The commands people give Queue, and how they are parsed from what they post.
+//!
+//! A command is a message that mentions Queue (the mention can be anywhere in the message), e.g.
+//! `@Queue add prusa 3 --note "PLA benchy"`. The first word after the mention is the name of the
+//! command (or one of its aliases, e.g. `join` for `add`), and the rest are its arguments: queue
+//! names, counts, positions in line, and mentions of other people. Words can be quoted to keep their
+//! spaces, and options (e.g. `--note`) can appear anywhere after the command's name. See
+//! [`parse`](fn.parse.html).
+//!
+//! Commands that cannot be parsed are described by a [`ParseError`](enum.ParseError.html), which
+//! says what was wrong in a way that can be shown to whoever posted the command.
+
+use std::{collections::BTreeMap, fmt, iter, time::Duration};
+
+use crate::notify::Delivery;
+use crate::policy;
+use crate::queue::Job;
+use crate::slack_bot::{parse_mention, QUEUE_UID};
+use crate::user::UserID;
+
+/// The most times someone can add themselves to a queue with one `add` command.
+pub const MAX_COUNT: usize = 10;
+
+/// How far (in [edit distance](fn.edit_distance.html)) an unknown word may be from the name of a
+/// command for that command to be suggested instead.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Everything there is to know about one of the commands Queue understands.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Spec {
+    /// The name the command is known by
+    pub name: &'static str,
+    /// Other names that mean the same command
+    pub aliases: &'static [&'static str],
+    /// The arguments the command takes, following its name
+    pub usage: &'static str,
+    /// What the command does
+    pub description: &'static str,
+    /// The names of the options (without their leading dashes) the command takes
+    pub options: &'static [&'static str],
+}
+
+impl Spec {
+    /// The help message shown by `help` followed by the name of the command.
+    pub fn help(&self) -> String {
+        let mut help = format!("*{}*: {}", self.usage, self.description);
+        if !self.aliases.is_empty() {
+            let aliases = self
+                .aliases
+                .iter()
+                .map(|alias| format!("`{}`", alias))
+                .collect::<Vec<_>>();
+            help.push_str(&format!("\nYou can also say {}.", aliases.join(" or ")));
+        }
+        help
+    }
+}
+
+/// The options that describe a [`Job`](../queue/struct.Job.html). `note` is another name for `name`.
+const JOB_OPTIONS: &[&str] = &["name", "note", "material", "color", "time", "printer"];
+
+/// Every command Queue understands, in the order they are listed in the help message.
+pub const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "add",
+        aliases: &["join"],
+        usage: "add [queue] [count] [--name TEXT] [--material TEXT] [--color TEXT] \
+			[--time DURATION] [--printer TEXT]",
+        description: "Add yourself to the queue, or `count` times in a row if you have several \
+			things to print (as long as the queue's rules allow it). The options say what you are \
+			waiting to print, e.g. `--name \"PLA benchy\" --material PLA --color red --printer prusa`; \
+			`--note` works as well as `--name`. The time (e.g. `--time 2h30m`) says how long your turn \
+			will take, which helps everyone behind you know how long they will wait.",
+        options: JOB_OPTIONS,
+    },
+    Spec {
+        name: "edit",
+        aliases: &[],
+        usage: "edit <position> [queue] [--name TEXT] [--material TEXT] [--color TEXT] \
+			[--time DURATION] [--printer TEXT]",
+        description:
+            "Change what the job in a place in line is, e.g. `@Queue edit 2 --material PETG \
+			--color black`. Leave a value empty (e.g. `--color \"\"`) to take it off the job. You can \
+			edit your own jobs, and Queue admins can edit anyone's.",
+        options: JOB_OPTIONS,
+    },
+    Spec {
+        name: "done",
+        aliases: &["leave"],
+        usage: "done [queue]",
+        description:
+            "Leave the queue. If you are in line more than once, the place closest to the \
+			front is the one you leave.",
+        options: &[],
+    },
+    Spec {
+        name: "start",
+        aliases: &[],
+        usage: "start [queue]",
+        description: "Say that you have started printing, once you are at the front of the line.",
+        options: &[],
+    },
+    Spec {
+        name: "finish",
+        aliases: &["finished"],
+        usage: "finish [queue]",
+        description: "Say that your print is done, which takes you out of the queue and lets the \
+			next person know it is their turn.",
+        options: &[],
+    },
+    Spec {
+        name: "failed",
+        aliases: &["fail"],
+        usage: "failed [queue]",
+        description: "Say that your print did not work out, which takes you out of the queue like \
+			`finish` does.",
+        options: &[],
+    },
+    Spec {
+        name: "retry",
+        aliases: &[],
+        usage: "retry [queue]",
+        description: "Say that your print did not work out and you want to try again right away. \
+			You stay at the front of the line for another go.",
+        options: &[],
+    },
+    Spec {
+        name: "show",
+        aliases: &["list"],
+        usage: "show [queue|all]",
+        description: "See who is in the queue and in what place, or who is in every queue.",
+        options: &[],
+    },
+    Spec {
+        name: "create",
+        aliases: &[],
+        usage: "create <queue> [rules]",
+        description: "Create a new, empty queue, e.g. `@Queue create prusa \
+			max-per-user=2,cooldown=30m`. The rules are `no-back-to-back`, `max-per-user=N`, \
+			`max-total=N`, and `cooldown=DURATION`.",
+        options: &[],
+    },
+    Spec {
+        name: "notify",
+        aliases: &[],
+        usage: "notify <channel|dm|both> [queue]",
+        description: "Choose how you are told that your turn is coming up: with a ping in this \
+			channel when you get to the front of the line, with a direct message when you get to the \
+			front or close to it, or both.",
+        options: &[],
+    },
+    Spec {
+        name: "help",
+        aliases: &[],
+        usage: "help [command]",
+        description: "Show what Queue can do, or how to use one command.",
+        options: &[],
+    },
+    Spec {
+        name: "move",
+        aliases: &[],
+        usage: "move @user <position> [queue]",
+        description: "Move someone to a different place in line. Only Queue admins can do this.",
+        options: &[],
+    },
+    Spec {
+        name: "swap",
+        aliases: &[],
+        usage: "swap <position> <position> [queue]",
+        description: "Swap the people in two places in line. Only Queue admins can do this.",
+        options: &[],
+    },
+    Spec {
+        name: "kick",
+        aliases: &[],
+        usage: "kick @user [queue]",
+        description: "Take someone out of the queue. Only Queue admins can do this.",
+        options: &[],
+    },
+    Spec {
+        name: "clear",
+        aliases: &[],
+        usage: "clear [queue]",
+        description: "Take everyone out of the queue. Only Queue admins can do this.",
+        options: &[],
+    },
+];
+
+/// Look up the command called `word`, which may be one of its aliases. Commands are
+/// case-insensitive.
+pub fn find(word: &str) -> Option<&'static Spec> {
+    let word = word.to_lowercase();
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == word || spec.aliases.contains(&word.as_str()))
+}
+
+/// A command, with its arguments. Queues are given by name, and `None` stands for the default queue.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// Add whoever posted the command to a queue `count` times, each time for the same job
+    Add {
+        queue: Option<String>,
+        count: usize,
+        job: Job,
+    },
+    /// Change the job at a position in a queue
+    Edit {
+        position: usize,
+        queue: Option<String>,
+        changes: JobChanges,
+    },
+    /// Take whoever posted the command out of a queue once
+    Done { queue: Option<String> },
+    /// Whoever posted the command started printing at the front of a queue
+    Start { queue: Option<String> },
+    /// The print of whoever posted the command at the front of a queue is done
+    Finish { queue: Option<String> },
+    /// The print of whoever posted the command at the front of a queue did not work out. If they
+    /// want to `retry`, they stay at the front of the line.
+    Failed { queue: Option<String>, retry: bool },
+    /// Show the people in a queue
+    Show { queue: Option<String> },
+    /// Show the people in every queue
+    ShowAll,
+    /// Create a queue, with the spec of its rules if it was given one
+    Create {
+        queue: String,
+        rules: Option<String>,
+    },
+    /// Set how whoever posted the command is notified about a queue
+    Notify {
+        delivery: Delivery,
+        queue: Option<String>,
+    },
+    /// Show the help message, or the help for the command with the given name
+    Help { command: Option<&'static str> },
+    /// Move someone to a position in a queue
+    Move {
+        user: UserID,
+        to: usize,
+        queue: Option<String>,
+    },
+    /// Swap the people at two positions in a queue
+    Swap {
+        a: usize,
+        b: usize,
+        queue: Option<String>,
+    },
+    /// Take someone out of a queue
+    Kick { user: UserID, queue: Option<String> },
+    /// Take everyone out of a queue
+    Clear { queue: Option<String> },
+}
+
+impl Command {
+    /// The name of the command, as it is listed in the help message.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Add { .. } => "add",
+            Command::Edit { .. } => "edit",
+            Command::Done { .. } => "done",
+            Command::Start { .. } => "start",
+            Command::Finish { .. } => "finish",
+            Command::Failed { retry: false, .. } => "failed",
+            Command::Failed { retry: true, .. } => "retry",
+            Command::Show { .. } | Command::ShowAll => "show",
+            Command::Create { .. } => "create",
+            Command::Notify { .. } => "notify",
+            Command::Help { .. } => "help",
+            Command::Move { .. } => "move",
+            Command::Swap { .. } => "swap",
+            Command::Kick { .. } => "kick",
+            Command::Clear { .. } => "clear",
+        }
+    }
+
+    /// Whether only Queue admins may use the command, since it changes other people's places in
+    /// line.
+    pub fn is_admin_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Move { .. }
+                | Command::Swap { .. }
+                | Command::Kick { .. }
+                | Command::Clear { .. }
+        )
+    }
+
+    /// Whether the command changes a queue, so that everyone should see what happened.
+    pub fn changes_queue(&self) -> bool {
+        matches!(
+            self,
+            Command::Add { .. }
+                | Command::Edit { .. }
+                | Command::Done { .. }
+                | Command::Start { .. }
+                | Command::Finish { .. }
+                | Command::Failed { .. }
+                | Command::Create { .. }
+        ) || self.is_admin_only()
+    }
+}
+
+/// Why a command could not be parsed. Each error is worded so that it can be shown to whoever posted
+/// the command, e.g. after "Sorry @user, ".
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// Queue was mentioned, but nothing else was said
+    NoCommand,
+    /// The first word is not the name of a command. The closest command is suggested, if any is
+    /// close enough.
+    UnknownCommand {
+        word: String,
+        suggestion: Option<&'static str>,
+    },
+    /// A quote was opened but never closed
+    UnterminatedQuote,
+    /// The command does not take an option with this name
+    UnknownOption {
+        command: &'static str,
+        option: String,
+    },
+    /// The option was given without a value
+    MissingOptionValue {
+        command: &'static str,
+        option: String,
+    },
+    /// A required argument was left out
+    MissingArgument {
+        command: &'static str,
+        argument: &'static str,
+    },
+    /// There was more after the last argument the command takes
+    UnexpectedArgument {
+        command: &'static str,
+        argument: String,
+    },
+    /// An argument does not make sense, for the given reason
+    InvalidArgument {
+        command: &'static str,
+        argument: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let usage = |command: &str| {
+            find(command)
+                .map(|spec| format!(" Usage: `@Queue {}`", spec.usage))
+                .unwrap_or_default()
+        };
+        match self {
+            ParseError::NoCommand => write!(
+                f,
+                "you mentioned me without saying what to do. Try `@Queue help`."
+            ),
+            ParseError::UnknownCommand {
+                word,
+                suggestion: Some(suggestion),
+            } => write!(
+                f,
+                "I do not know the command `{}`. Did you mean `{}`? Try `@Queue help` to see every \
+				command.",
+                word, suggestion
+            ),
+            ParseError::UnknownCommand {
+                word,
+                suggestion: None,
+            } => write!(
+                f,
+                "I do not know the command `{}`. Try `@Queue help` to see every command.",
+                word
+            ),
+            ParseError::UnterminatedQuote => {
+                write!(f, "a quote in your message was never closed.")
+            }
+            ParseError::UnknownOption { command, option } => write!(
+                f,
+                "`{}` does not take the option `--{}`.{}",
+                command,
+                option,
+                usage(command)
+            ),
+            ParseError::MissingOptionValue { command, option } => write!(
+                f,
+                "the option `--{}` needs a value.{}",
+                option,
+                usage(command)
+            ),
+            ParseError::MissingArgument { command, argument } => {
+                write!(f, "`{}` needs {}.{}", command, argument, usage(command))
+            }
+            ParseError::UnexpectedArgument { command, argument } => write!(
+                f,
+                "I did not expect `{}` after the rest of the `{}` command.{}",
+                argument,
+                command,
+                usage(command)
+            ),
+            ParseError::InvalidArgument {
+                command,
+                argument,
+                reason,
+            } => write!(
+                f,
+                "`{}` does not work there: {}.{}",
+                argument,
+                reason,
+                usage(command)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse the `body` of a message that mentions Queue into the command it gives.
+///
+/// The mention of Queue can be anywhere in the message: the command is whatever follows the first
+/// mention, or whatever comes before it if nothing follows. Command names, queue names, and the
+/// other keywords (e.g. `all` and `dm`) are case-insensitive, but mentions and notes are kept as
+/// they are.
+pub fn parse(body: &str) -> Result<Command, ParseError> {
+    let mut words = words(&command_text(body))?.into_iter();
+    let first = words.next().ok_or(ParseError::NoCommand)?;
+    let spec = find(&first.text).ok_or_else(|| unknown_command(&first.text))?;
+    let (args, mut options) = split_options(spec, words)?;
+    let mut args = Args {
+        command: spec.name,
+        args: args.into_iter(),
+    };
+
+    let command = match spec.name {
+        "add" => {
+            let (mut queue, mut count) = (None, None);
+            while let Some(arg) = args.next() {
+                match arg.parse::<usize>() {
+                    Ok(n) if count.is_none() => count = Some(args.times(&arg, n)?),
+                    _ if queue.is_none() => queue = Some(arg.to_lowercase()),
+                    _ => return Err(args.unexpected(arg)),
+                }
+            }
+            let mut job = Job::default();
+            args.job_changes(&mut options)?.apply(&mut job);
+            Command::Add {
+                queue,
+                count: count.unwrap_or(1),
+                job,
+            }
+        }
+        "edit" => {
+            let position = args.position()?;
+            let queue = args.queue();
+            let changes = args.job_changes(&mut options)?;
+            if changes.is_empty() {
+                return Err(ParseError::MissingArgument {
+                    command: spec.name,
+                    argument: "something to change, like `--material PETG`",
+                });
+            }
+            Command::Edit {
+                position,
+                queue,
+                changes,
+            }
+        }
+        "done" => Command::Done {
+            queue: args.queue(),
+        },
+        "start" => Command::Start {
+            queue: args.queue(),
+        },
+        "finish" => Command::Finish {
+            queue: args.queue(),
+        },
+        "failed" | "retry" => Command::Failed {
+            queue: args.queue(),
+            retry: spec.name == "retry",
+        },
+        "show" => match args.queue() {
+            Some(all) if all == "all" => Command::ShowAll,
+            queue => Command::Show { queue },
+        },
+        "create" => {
+            let queue = args.required("the name of the queue")?.to_lowercase();
+            let rules = args.rest().to_lowercase();
+            Command::Create {
+                queue,
+                rules: Some(rules).filter(|rules| !rules.is_empty()),
+            }
+        }
+        "notify" => {
+            let how = args.required("`channel`, `dm`, or `both`")?;
+            let delivery = how
+                .to_lowercase()
+                .parse::<Delivery>()
+                .map_err(|reason| args.invalid(&how, reason))?;
+            Command::Notify {
+                delivery,
+                queue: args.queue(),
+            }
+        }
+        "help" => match args.next() {
+            Some(topic) => Command::Help {
+                command: Some(find(&topic).ok_or_else(|| unknown_command(&topic))?.name),
+            },
+            None => Command::Help { command: None },
+        },
+        "move" => Command::Move {
+            user: args.user()?,
+            to: args.position()?,
+            queue: args.queue(),
+        },
+        "swap" => Command::Swap {
+            a: args.position()?,
+            b: args.position()?,
+            queue: args.queue(),
+        },
+        "kick" => Command::Kick {
+            user: args.user()?,
+            queue: args.queue(),
+        },
+        _ => Command::Clear {
+            queue: args.queue(),
+        },
+    };
+    args.finish()?;
+    Ok(command)
+}
+
+/// Changes to some parts of a [`Job`](../queue/struct.Job.html). A part that is `None` is left as
+/// it is, and a part that is `Some(None)` is taken off the job.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct JobChanges {
+    pub name: Option<Option<String>>,
+    pub material: Option<Option<String>>,
+    pub color: Option<Option<String>>,
+    pub time: Option<Option<Duration>>,
+    pub printer: Option<Option<String>>,
+}
+
+impl JobChanges {
+    /// Whether nothing would be changed.
+    pub fn is_empty(&self) -> bool {
+        *self == JobChanges::default()
+    }
+
+    /// Make the changes to `job`.
+    pub fn apply(&self, job: &mut Job) {
+        fn change<T: Clone>(part: &mut Option<T>, change: &Option<Option<T>>) {
+            if let Some(value) = change {
+                *part = value.clone();
+            }
+        }
+        change(&mut job.name, &self.name);
+        change(&mut job.material, &self.material);
+        change(&mut job.color, &self.color);
+        change(&mut job.time, &self.time);
+        change(&mut job.printer, &self.printer);
+    }
+}
+
+/// The help message for the command called `name` (or one of its aliases), if there is such a
+/// command.
+pub fn help(name: &str) -> Option<String> {
+    find(name).map(Spec::help)
+}
+
+/// The arguments of a command that are left to be parsed.
+struct Args {
+    /// The name of the command the arguments are for
+    command: &'static str,
+    /// The arguments, without any options
+    args: std::vec::IntoIter<String>,
+}
+
+impl Args {
+    /// The next argument, if there is one.
+    fn next(&mut self) -> Option<String> {
+        self.args.next()
+    }
+
+    /// Every argument that is left, separated by spaces.
+    fn rest(&mut self) -> String {
+        self.args.by_ref().collect::<Vec<_>>().join(" ")
+    }
+
+    /// The next argument, which is called `argument` in the error if it is missing.
+    fn required(&mut self, argument: &'static str) -> Result<String, ParseError> {
+        self.next().ok_or(ParseError::MissingArgument {
+            command: self.command,
+            argument,
+        })
+    }
+
+    /// The name of a queue, if there is another argument.
+    fn queue(&mut self) -> Option<String> {
+        self.next().map(|name| name.to_lowercase())
+    }
+
+    /// The next argument, which must be a position in line.
+    fn position(&mut self) -> Result<usize, ParseError> {
+        let arg = self.required("a position in line")?;
+        arg.parse()
+            .map_err(|_| self.invalid(&arg, "positions in line are numbers, starting from 0"))
+    }
+
+    /// The next argument, which must be a mention of someone.
+    fn user(&mut self) -> Result<UserID, ParseError> {
+        let arg = self.required("a mention of someone, like @user")?;
+        parse_mention(&arg).ok_or_else(|| self.invalid(&arg, "that is not a mention of someone"))
+    }
+
+    /// The changes to a job that the [`JOB_OPTIONS`](constant.JOB_OPTIONS.html) in `options` make.
+    /// An option with an empty value (or `-`) takes that part off the job.
+    fn job_changes(
+        &self,
+        options: &mut BTreeMap<&'static str, String>,
+    ) -> Result<JobChanges, ParseError> {
+        let mut text = |option| {
+            options.remove(option).map(|value: String| {
+                let value = value.trim();
+                Some(value.to_string()).filter(|value| !value.is_empty() && value != "-")
+            })
+        };
+        let name = text("name").or_else(|| text("note"));
+        let (material, color, printer) = (text("material"), text("color"), text("printer"));
+        let time = match text("time") {
+            Some(Some(time)) => Some(Some(policy::parse_duration(&time).ok_or_else(|| {
+                self.invalid(&time, "durations look like `30m`, `2h`, or `1h30m`")
+            })?)),
+            cleared => cleared.map(|_| None),
+        };
+        Ok(JobChanges {
+            name,
+            material,
+            color,
+            time,
+            printer,
+        })
+    }
+
+    /// Check that `n`, given as the argument `arg`, is a number of times someone can be added.
+    fn times(&self, arg: &str, n: usize) -> Result<usize, ParseError> {
+        match n {
+            0 => Err(self.invalid(arg, "you have to join at least once")),
+            n if n > MAX_COUNT => Err(self.invalid(
+                arg,
+                format!("you can join at most {} times at once", MAX_COUNT),
+            )),
+            n => Ok(n),
+        }
+    }
+
+    /// The error for an argument that does not make sense for `reason`.
+    fn invalid<R: Into<String>>(&self, arg: &str, reason: R) -> ParseError {
+        ParseError::InvalidArgument {
+            command: self.command,
+            argument: arg.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// The error for an argument that should not be there.
+    fn unexpected(&self, argument: String) -> ParseError {
+        ParseError::UnexpectedArgument {
+            command: self.command,
+            argument,
+        }
+    }
+
+    /// Check that every argument was parsed.
+    fn finish(mut self) -> Result<(), ParseError> {
+        match self.next() {
+            Some(arg) => Err(self.unexpected(arg)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One word of a command.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Word {
+    /// The word, without the quotes around it
+    text: String,
+    /// Whether the word was quoted, in which case it is never an option
+    quoted: bool,
+}
+
+/// The part of `body` that gives a command: whatever follows the first mention of Queue, or if
+/// nothing does (e.g. `add @Queue`), whatever comes before it. Other mentions of Queue are left
+/// out, as is the punctuation around the mention (e.g. in `Hey @Queue, add`).
+fn command_text(body: &str) -> String {
+    // The mention is ASCII, so lowercasing keeps every byte where it was
+    let lowercase = body.to_ascii_lowercase();
+    let mention = QUEUE_UID.to_ascii_lowercase();
+    let mut parts = Vec::new();
+    let mut rest = 0;
+    for (start, _) in lowercase.match_indices(&mention) {
+        parts.push(&body[rest..start]);
+        rest = start + mention.len();
+    }
+    parts.push(&body[rest..]);
+
+    let punctuation = |c: char| c.is_whitespace() || c == ',' || c == ':';
+    let after = parts[1..].join(" ");
+    let after = after.trim_start_matches(punctuation);
+    if after.trim().is_empty() {
+        parts[0].trim_end_matches(punctuation).to_string()
+    } else {
+        after.to_string()
+    }
+}
+
+/// The quotes that can close the quote `open`, if it is one. Slack (or rather, the keyboard of
+/// whoever posted the command) often turns straight quotes into curly ones.
+fn closing_quotes(open: char) -> Option<&'static [char]> {
+    match open {
+        '"' | '\u{201C}' | '\u{201D}' => Some(&['"', '\u{201C}', '\u{201D}']),
+        '\'' | '\u{2018}' => Some(&['\'', '\u{2018}', '\u{2019}']),
+        _ => None,
+    }
+}
+
+/// Split a command into its words. A quote goes on until it is closed, spaces and all. Double
+/// quotes can start anywhere in a word (e.g. `--note="PLA benchy"`), but single quotes only start a
+/// quote at the beginning of a word, so that apostrophes are left alone.
+fn words(body: &str) -> Result<Vec<Word>, ParseError> {
+    let mut words = Vec::new();
+    let mut chars = body.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let quoted = match chars.peek() {
+            Some(&c) => closing_quotes(c).is_some(),
+            None => return Ok(words),
+        };
+        let mut text = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            let closing = match closing_quotes(c) {
+                Some(closing) if text.is_empty() || closing.contains(&'"') => closing,
+                _ => {
+                    text.push(c);
+                    continue;
+                }
+            };
+            loop {
+                match chars.next() {
+                    Some(c) if closing.contains(&c) => break,
+                    Some(c) => text.push(c),
+                    None => return Err(ParseError::UnterminatedQuote),
+                }
+            }
+        }
+        words.push(Word { text, quoted });
+    }
+}
+
+/// The name of the option that `word` gives, if it is one. Options start with two dashes, which
+/// some keyboards turn into a long dash.
+fn option_name(word: &Word) -> Option<&str> {
+    if word.quoted {
+        None
+    } else {
+        word.text
+            .strip_prefix("--")
+            .or_else(|| word.text.strip_prefix('\u{2014}'))
+    }
+}
+
+/// Separate the options of the command described by `spec` from the rest of its arguments. An
+/// option's value either follows it after `=` (e.g. `--note=benchy`) or is the next word.
+fn split_options<I: Iterator<Item = Word>>(
+    spec: &Spec,
+    words: I,
+) -> Result<(Vec<String>, BTreeMap<&'static str, String>), ParseError> {
+    let mut args = Vec::new();
+    let mut options = BTreeMap::new();
+    let mut words = words.peekable();
+    while let Some(word) = words.next() {
+        let option = match option_name(&word) {
+            Some(option) => option,
+            None => {
+                args.push(word.text);
+                continue;
+            }
+        };
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name.to_lowercase(), Some(value.to_string())),
+            None => (option.to_lowercase(), None),
+        };
+        let name = match spec.options.iter().find(|known| **known == name) {
+            Some(known) => *known,
+            None => {
+                return Err(ParseError::UnknownOption {
+                    command: spec.name,
+                    option: name,
+                })
+            }
+        };
+        let value = value.or_else(|| {
+            words
+                .next_if(|next| option_name(next).is_none())
+                .map(|next| next.text)
+        });
+        match value {
+            Some(value) => options.insert(name, value),
+            None => {
+                return Err(ParseError::MissingOptionValue {
+                    command: spec.name,
+                    option: name.to_string(),
+                })
+            }
+        };
+    }
+    Ok((args, options))
+}
+
+/// The error for the unknown command `word`, suggesting the command (or alias) closest to it.
+fn unknown_command(word: &str) -> ParseError {
+    let word = word.to_lowercase();
+    let suggestion = COMMANDS
+        .iter()
+        .flat_map(|spec| iter::once(spec.name).chain(spec.aliases.iter().copied()))
+        .map(|name| (edit_distance(&word, name), name))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name);
+    ParseError::UnknownCommand { word, suggestion }
+}
+
+/// The number of characters that have to be inserted, removed, or replaced to turn `a` into `b`
+/// (their Levenshtein distance).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replaced = previous[j] + usize::from(ca != *cb);
+            current.push(replaced.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_are_ignored_anywhere() {
+        let add = Ok(Command::Add {
+            queue: None,
+            count: 1,
+            job: Job::default(),
+        });
+        assert_eq!(parse("<@U01A844Q2US> add"), add);
+        assert_eq!(parse("add <@U01A844Q2US>"), add);
+        assert_eq!(parse("Hey <@U01A844Q2US>, add"), add);
+        assert_eq!(parse("<@u01a844q2us>add"), add);
+        assert_eq!(parse("  ADD  "), add);
+        assert_eq!(parse("<@U01A844Q2US>"), Err(ParseError::NoCommand));
+    }
+
+    #[test]
+    fn arguments_and_options() {
+        assert_eq!(
+            parse("<@U01A844Q2US> add 3 --note \"PLA benchy\""),
+            Ok(Command::Add {
+                queue: None,
+                count: 3,
+                job: Job {
+                    name: Some(String::from("PLA benchy")),
+                    ..Job::default()
+                },
+            })
+        );
+        assert_eq!(
+            parse("join --note=\u{201C}big vase\u{201D} Prusa --time 1h30m"),
+            Ok(Command::Add {
+                queue: Some(String::from("prusa")),
+                count: 1,
+                job: Job {
+                    name: Some(String::from("big vase")),
+                    time: Some(Duration::from_secs(90 * 60)),
+                    ..Job::default()
+                },
+            })
+        );
+        assert_eq!(
+            parse("move <@UA8RXUPSP|joey> 2 prusa"),
+            Ok(Command::Move {
+                user: UserID::new("UA8RXUPSP"),
+                to: 2,
+                queue: Some(String::from("prusa")),
+            })
+        );
+        assert_eq!(
+            parse("create prusa MAX-TOTAL=3"),
+            Ok(Command::Create {
+                queue: String::from("prusa"),
+                rules: Some(String::from("max-total=3")),
+            })
+        );
+        assert_eq!(
+            parse("edit 2 prusa --material PETG --color \"\" --time=-"),
+            Ok(Command::Edit {
+                position: 2,
+                queue: Some(String::from("prusa")),
+                changes: JobChanges {
+                    material: Some(Some(String::from("PETG"))),
+                    color: Some(None),
+                    time: Some(None),
+                    ..JobChanges::default()
+                },
+            })
+        );
+        assert_eq!(parse("show all"), Ok(Command::ShowAll));
+        assert_eq!(
+            parse("notify DM"),
+            Ok(Command::Notify {
+                delivery: Delivery::DirectMessage,
+                queue: None,
+            })
+        );
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(
+            parse("leave prusa"),
+            Ok(Command::Done {
+                queue: Some(String::from("prusa")),
+            })
+        );
+        assert_eq!(parse("list"), Ok(Command::Show { queue: None }));
+        assert_eq!(parse("finished"), Ok(Command::Finish { queue: None }));
+        assert_eq!(
+            parse("retry prusa"),
+            Ok(Command::Failed {
+                queue: Some(String::from("prusa")),
+                retry: true,
+            })
+        );
+        assert_eq!(
+            parse("fail"),
+            Ok(Command::Failed {
+                queue: None,
+                retry: false,
+            })
+        );
+        assert_eq!(
+            parse("help join"),
+            Ok(Command::Help {
+                command: Some("add")
+            })
+        );
+        assert!(help("list").unwrap().starts_with("*show [queue|all]*"));
+        assert!(help("add").unwrap().ends_with("You can also say `join`."));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse("ad"),
+            Err(ParseError::UnknownCommand {
+                word: String::from("ad"),
+                suggestion: Some("add"),
+            })
+        );
+        assert_eq!(
+            parse("help shwo"),
+            Err(ParseError::UnknownCommand {
+                word: String::from("shwo"),
+                suggestion: Some("show"),
+            })
+        );
+        assert_eq!(
+            parse("printplease"),
+            Err(ParseError::UnknownCommand {
+                word: String::from("printplease"),
+                suggestion: None,
+            })
+        );
+        assert_eq!(
+            parse("add --colour red"),
+            Err(ParseError::UnknownOption {
+                command: "add",
+                option: String::from("colour"),
+            })
+        );
+        assert_eq!(
+            parse("add --note"),
+            Err(ParseError::MissingOptionValue {
+                command: "add",
+                option: String::from("note"),
+            })
+        );
+        assert_eq!(
+            parse("add --note \"PLA"),
+            Err(ParseError::UnterminatedQuote)
+        );
+        assert_eq!(
+            parse("swap 1"),
+            Err(ParseError::MissingArgument {
+                command: "swap",
+                argument: "a position in line",
+            })
+        );
+        assert_eq!(
+            parse("done prusa please"),
+            Err(ParseError::UnexpectedArgument {
+                command: "done",
+                argument: String::from("please"),
+            })
+        );
+        assert!(matches!(
+            parse("kick joey"),
+            Err(ParseError::InvalidArgument {
+                command: "kick",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("add --time soon"),
+            Err(ParseError::InvalidArgument { command: "add", .. })
+        ));
+        assert_eq!(
+            parse("edit 2"),
+            Err(ParseError::MissingArgument {
+                command: "edit",
+                argument: "something to change, like `--material PETG`",
+            })
+        );
+        assert!(matches!(
+            parse("add 0"),
+            Err(ParseError::InvalidArgument { command: "add", .. })
+        ));
+        assert_eq!(
+            parse("swap one 2").unwrap_err().to_string(),
+            "`one` does not work there: positions in line are numbers, starting from 0. Usage: \
+			`@Queue swap <position> <position> [queue]`"
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("add", "add"), 0);
+        assert_eq!(edit_distance("ad", "add"), 1);
+        assert_eq!(edit_distance("shwo", "show"), 2);
+        assert_eq!(edit_distance("", "kick"), 4);
+    }
+}
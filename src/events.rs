@@ -0,0 +1,935 @@
+//! Support for running Queue as a server for the Slack Events API, instead of connecting to the
+//! (deprecated) real time messaging API.
+//!
+//! Slack POSTs every event Queue is subscribed to as JSON to one URL. The [`Payload`](enum.Payload.html)
+//! of such a request is handed to [`EventsApi::handle`](struct.EventsApi.html#method.handle), which
+//! decides what to reply with, and any messages that should be posted in response are sent with a
+//! [`WebClient`](../web/struct.WebClient.html). See the `verify` binary for the server itself.
+//!
+//! Slash commands (e.g. `/queue add`) are POSTed to a second URL as a form. The
+//! [`SlashCommand`](struct.SlashCommand.html) is handed to [`EventsApi::command`](struct.EventsApi.html#method.command),
+//! and its [`CommandReply`](struct.CommandReply.html) is sent back as the response to the request.
+//!
+//! Queues are shown as Block Kit messages with buttons to join the queue, leave it, and refresh the
+//! message. Slack POSTs the clicks to a third URL as an [`Interaction`](enum.Interaction.html), which
+//! is handed to [`EventsApi::interact`](struct.EventsApi.html#method.interact); the message is then
+//! redrawn in place through the interaction's `response_url`.
+//!
+//! Anyone can POST to the endpoints, so before a request is handled its signature has to be checked
+//! with the app's [`SigningSecret`](struct.SigningSecret.html).
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::command;
+use crate::queue::{Job, Queue};
+use crate::slack_bot::{self, is_app_mention, SlackBot, CHANNEL};
+use crate::user::{SlackMap, UserID};
+use crate::web::Message;
+
+/// How many events to remember, so that the same message is not answered twice (once for its
+/// `message` event and once for its `app_mention` event, or because Slack retried sending it).
+const RECENT_EVENTS: usize = 100;
+
+/// How far the timestamp of a request may be from the current time before the request is turned
+/// away, in case it is an old request being replayed.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// The version of Slack's signing scheme that is supported.
+const SIGNATURE_VERSION: &str = "v0";
+
+/// The `action_id` of the button that adds whoever clicks it to the queue.
+pub const JOIN_ACTION: &str = "queue_join";
+
+/// The `action_id` of the button that removes whoever clicks it from the queue.
+pub const DONE_ACTION: &str = "queue_done";
+
+/// The `action_id` of the button that redraws the message with the queue as it is now.
+pub const REFRESH_ACTION: &str = "queue_refresh";
+
+/// Slack shows at most 50 blocks in a message, so only this many people in line get a row of their
+/// own, leaving room for the title, a note about everyone else, and the buttons.
+const MAX_ROWS: usize = 45;
+
+/// Why a request could not be verified as coming from Slack.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SignatureError {
+    /// The request does not have an `X-Slack-Request-Timestamp` or `X-Slack-Signature` header.
+    MissingHeaders,
+    /// The `X-Slack-Request-Timestamp` header is not a number of seconds since the Unix epoch.
+    BadTimestamp,
+    /// The request is older (or newer) than the [`REPLAY_WINDOW`](constant.REPLAY_WINDOW.html)
+    /// allows.
+    Stale,
+    /// The signature does not match the request.
+    BadSignature,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignatureError::MissingHeaders => "the request is not signed",
+            SignatureError::BadTimestamp => "the request timestamp is not a number",
+            SignatureError::Stale => "the request is too old",
+            SignatureError::BadSignature => "the request signature does not match",
+        })
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// The signing secret of the Slack app, which Slack signs every request to the Events API endpoint
+/// with. See <https://api.slack.com/authentication/verifying-requests-from-slack>.
+#[derive(Clone)]
+pub struct SigningSecret(Vec<u8>);
+
+impl SigningSecret {
+    /// The signing secret shown on the app's "Basic Information" page.
+    pub fn new(secret: &str) -> Self {
+        Self(secret.as_bytes().to_vec())
+    }
+
+    /// The MAC of a request with the given `timestamp` and `body`, ready to be finished.
+    fn mac(&self, timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC can take a key of any size");
+        mac.update(SIGNATURE_VERSION.as_bytes());
+        mac.update(b":");
+        mac.update(timestamp.as_bytes());
+        mac.update(b":");
+        mac.update(body);
+        mac
+    }
+
+    /// The `X-Slack-Signature` header Slack would send with a request with the given `timestamp`
+    /// and `body`.
+    pub fn sign(&self, timestamp: &str, body: &[u8]) -> String {
+        let digest = self.mac(timestamp, body).finalize().into_bytes();
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}={}", SIGNATURE_VERSION, hex)
+    }
+
+    /// Check that a request with the given `X-Slack-Request-Timestamp` and `X-Slack-Signature`
+    /// headers and `body` was sent by Slack within the [`REPLAY_WINDOW`](constant.REPLAY_WINDOW.html)
+    /// of `now`. The signature is compared in constant time.
+    pub fn verify(
+        &self,
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<(), SignatureError> {
+        let (timestamp, signature) = match (timestamp, signature) {
+            (Some(timestamp), Some(signature)) => (timestamp, signature),
+            _ => return Err(SignatureError::MissingHeaders),
+        };
+        let sent = timestamp
+            .trim()
+            .parse::<u64>()
+            .map(crate::storage::from_unix_secs)
+            .map_err(|_| SignatureError::BadTimestamp)?;
+        let age = now.duration_since(sent).unwrap_or_else(|e| e.duration());
+        if age > REPLAY_WINDOW {
+            return Err(SignatureError::Stale);
+        }
+
+        let expected = signature
+            .strip_prefix(SIGNATURE_VERSION)
+            .and_then(|rest| rest.strip_prefix('='))
+            .and_then(from_hex)
+            .ok_or(SignatureError::BadSignature)?;
+        self.mac(timestamp, body)
+            .verify_slice(&expected)
+            .map_err(|_| SignatureError::BadSignature)
+    }
+}
+
+impl fmt::Debug for SigningSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the secret out of logs
+        f.write_str("SigningSecret(..)")
+    }
+}
+
+/// Decode a string of hexadecimal digits, two per byte.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The body of a request that Slack sends to the Events API endpoint.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// Sent once, when the endpoint is first configured, to make sure it belongs to the app.
+    UrlVerification { challenge: String },
+    /// Something happened that the app is subscribed to.
+    EventCallback { event: Event },
+    /// Any other kind of request, which Queue does not care about.
+    #[serde(other)]
+    Other,
+}
+
+/// An event that Queue is subscribed to, i.e. an `app_mention` or a `message` in a public channel.
+/// Only the fields Queue uses are kept.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+pub struct Event {
+    /// What kind of event this is, e.g. `app_mention` or `message`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Who posted the message
+    pub user: Option<String>,
+    /// The content of the message
+    pub text: Option<String>,
+    /// The channel the message was posted in
+    pub channel: Option<String>,
+    /// The timestamp of the message, which identifies it within its channel
+    pub ts: Option<String>,
+    /// Set if the message was posted by a bot (including Queue itself)
+    pub bot_id: Option<String>,
+    /// Set for messages that are not just someone posting something, e.g. edits
+    pub subtype: Option<String>,
+}
+
+/// What to do in response to a [`Payload`](enum.Payload.html).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// Answer the request with this challenge, to prove the endpoint belongs to Queue.
+    Challenge(String),
+    /// Post this message to Slack.
+    Post(Message),
+    /// Nothing needs to be done besides acknowledging the request.
+    Nothing,
+}
+
+/// A slash command someone used, as Slack POSTs it. Only the fields Queue uses are kept.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize)]
+pub struct SlashCommand {
+    /// The command itself, e.g. `/queue`
+    pub command: String,
+    /// Everything after the command, e.g. `add prusa`
+    #[serde(default)]
+    pub text: String,
+    /// Who used the command
+    pub user_id: String,
+    /// The channel the command was used in
+    pub channel_id: String,
+}
+
+impl SlashCommand {
+    /// Decode the form-encoded body of a slash command request.
+    pub fn from_form(body: &[u8]) -> Result<Self, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_bytes(body)
+    }
+}
+
+/// Who gets to see the reply to a slash command.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseType {
+    /// Everyone in the channel, along with the command that was used.
+    InChannel,
+    /// Only the person who used the command.
+    Ephemeral,
+}
+
+/// The reply to a slash command, which is sent back as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommandReply {
+    /// Who gets to see the reply
+    pub response_type: ResponseType,
+    /// The reply itself, or what to show in notifications if there are `blocks`
+    pub text: String,
+    /// The Block Kit layout of the reply, if it is more than just `text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<Value>>,
+}
+
+/// What Slack POSTs to the interactivity URL when someone interacts with a message from Queue. Only
+/// the fields Queue uses are kept.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Interaction {
+    /// Someone clicked one of the buttons of a queue message.
+    BlockActions {
+        /// Who clicked
+        user: Actor,
+        /// The buttons that were clicked
+        actions: Vec<Action>,
+        /// Where to send messages that replace the clicked message, or follow it
+        response_url: Option<String>,
+    },
+    /// Any other kind of interaction, which Queue does not care about.
+    #[serde(other)]
+    Other,
+}
+
+impl Interaction {
+    /// Decode the form-encoded body of an interactivity request, whose `payload` field holds the
+    /// interaction as JSON.
+    pub fn from_form(body: &[u8]) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct Form {
+            payload: String,
+        }
+
+        let form: Form = serde_urlencoded::from_bytes(body).map_err(serde::de::Error::custom)?;
+        serde_json::from_str(&form.payload)
+    }
+}
+
+/// Someone who interacted with a message.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct Actor {
+    /// Their Slack ID
+    pub id: String,
+}
+
+/// A button that was clicked.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct Action {
+    /// Which button it was, e.g. [`JOIN_ACTION`](constant.JOIN_ACTION.html)
+    pub action_id: String,
+    /// The value of the button, i.e. the name of the queue it belongs to
+    pub value: Option<String>,
+}
+
+/// A message sent to the `response_url` of an interaction, which either replaces the message that
+/// was interacted with or is posted after it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Followup {
+    /// Whether this message replaces the one that was interacted with
+    pub replace_original: bool,
+    /// Who gets to see the message, if it does not replace the original
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_type: Option<ResponseType>,
+    /// The message itself, or what to show in notifications if there are `blocks`
+    pub text: String,
+    /// The Block Kit layout of the message, if it is more than just `text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<Value>>,
+}
+
+/// What to do in response to an [`Interaction`](enum.Interaction.html): send each of `followups`,
+/// in order, to `response_url`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InteractionReply {
+    /// Where to send the followups
+    pub response_url: String,
+    /// What to send
+    pub followups: Vec<Followup>,
+}
+
+/// Turns the events Slack sends into responses from a [`QueueManager`](../manager/struct.QueueManager.html).
+#[derive(Debug, Default)]
+pub struct EventsApi {
+    /// The channel and timestamp of the last few messages that were handled
+    recent: VecDeque<(String, String)>,
+}
+
+impl EventsApi {
+    /// Ready to handle events, none of which have been seen yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decide what to do about `payload`, applying any commands in it with `bot`.
+    ///
+    /// Like the real time messaging bot, Queue only answers messages that mention it, and only in
+    /// its own channel; elsewhere it points people to its channel. Messages posted by bots are
+    /// ignored, as is every message after the first one with the same channel and timestamp.
+    pub fn handle(&mut self, bot: &mut SlackBot, payload: Payload) -> Reply {
+        let event = match payload {
+            Payload::UrlVerification { challenge } => return Reply::Challenge(challenge),
+            Payload::EventCallback { event } => event,
+            Payload::Other => return Reply::Nothing,
+        };
+        if cfg!(debug_assertions) {
+            println!("Got event: {:?}", event);
+        }
+
+        let (user, text, channel) = match event {
+            Event {
+                user: Some(user),
+                text: Some(text),
+                channel: Some(channel),
+                bot_id: None,
+                subtype: None,
+                ref kind,
+                ..
+            } if kind == "app_mention" || kind == "message" => (user, text, channel),
+            _ => return Reply::Nothing,
+        };
+        if !is_app_mention(&text) {
+            return Reply::Nothing;
+        }
+        if let Some(ts) = event.ts {
+            let key = (channel.clone(), ts);
+            if self.recent.contains(&key) {
+                return Reply::Nothing;
+            }
+            if self.recent.len() == RECENT_EVENTS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(key);
+        }
+
+        let (text, blocks) = match bot.channel_id() {
+            Some(id) if id == channel => {
+                let blocks = bot
+                    .shown_queue(&text)
+                    .map(|(name, queue)| queue_blocks(bot.users(), name, queue));
+                (bot.determine_response(UserID(user), &text), blocks)
+            }
+            Some(id) => (
+                format!("Try invoking that same command in <#{}>!", id),
+                None,
+            ),
+            None => (
+                format!("Try invoking that same command in #{}!", CHANNEL),
+                None,
+            ),
+        };
+        Reply::Post(Message {
+            channel,
+            text,
+            blocks,
+        })
+    }
+
+    /// Run the slash `command` with `bot`, the same way as if it had been posted after a mention of
+    /// Queue. Commands that change a queue are answered in the channel; everything else is only
+    /// shown to the person who used the command. As with mentions, commands only work in Queue's own
+    /// channel.
+    pub fn command(&mut self, bot: &mut SlackBot, command: SlashCommand) -> CommandReply {
+        if cfg!(debug_assertions) {
+            println!("Got command: {:?}", command);
+        }
+        match bot.channel_id() {
+            Some(id) if id == command.channel_id => (),
+            other => {
+                return CommandReply {
+                    response_type: ResponseType::Ephemeral,
+                    text: match other {
+                        Some(id) => format!("Try invoking that same command in <#{}>!", id),
+                        None => format!("Try invoking that same command in #{}!", CHANNEL),
+                    },
+                    blocks: None,
+                }
+            }
+        }
+
+        // Commands that change a queue are answered for everyone in the channel to see. Everything
+        // else (e.g. `show`, `help`, and commands that could not be parsed) is only shown to whoever
+        // asked.
+        let changes_queue =
+            command::parse(&command.text).is_ok_and(|parsed| parsed.changes_queue());
+        let blocks = bot
+            .shown_queue(&command.text)
+            .map(|(name, queue)| queue_blocks(bot.users(), name, queue));
+        let text = match command.text.trim() {
+            "" => bot.determine_response(UserID(command.user_id), "help"),
+            text => bot.determine_response(UserID(command.user_id), text),
+        };
+        CommandReply {
+            response_type: if changes_queue {
+                ResponseType::InChannel
+            } else {
+                ResponseType::Ephemeral
+            },
+            text,
+            blocks,
+        }
+    }
+
+    /// Apply the buttons clicked in `interaction` to the queues of `bot`. "Join" adds whoever clicked it to the
+    /// queue the message shows and "I'm done" removes them, with what Queue has to say about that
+    /// posted in the channel; "Refresh" changes nothing. Either way, the message that was clicked is
+    /// redrawn to show the queue as it is now.
+    ///
+    /// Returns `None` if there is nothing to do, e.g. for interactions other than button clicks.
+    pub fn interact(
+        &mut self,
+        bot: &mut SlackBot,
+        interaction: Interaction,
+    ) -> Option<InteractionReply> {
+        if cfg!(debug_assertions) {
+            println!("Got interaction: {:?}", interaction);
+        }
+        let (user, actions, response_url) = match interaction {
+            Interaction::BlockActions {
+                user,
+                actions,
+                response_url: Some(response_url),
+            } => (UserID(user.id), actions, response_url),
+            _ => return None,
+        };
+
+        let users = bot.users();
+        let mut followups = Vec::new();
+        bot.queues_mut().set_actor(Some(&user));
+        for Action { action_id, value } in actions {
+            let name = match value {
+                Some(name) => name,
+                None => continue,
+            };
+            let queue = match bot.queues_mut().get_mut(&name) {
+                Some(queue) => queue,
+                None => {
+                    followups.push(Followup {
+                        replace_original: false,
+                        response_type: Some(ResponseType::Ephemeral),
+                        text: format!("There is no queue called *{}* anymore.", name),
+                        blocks: None,
+                    });
+                    continue;
+                }
+            };
+            let response = match action_id.as_str() {
+                JOIN_ACTION => Some(slack_bot::add(queue, user.clone(), 1, Job::default())),
+                DONE_ACTION => Some(slack_bot::done(queue, user.clone())),
+                REFRESH_ACTION => None,
+                _ => continue,
+            };
+            followups.push(Followup {
+                replace_original: true,
+                response_type: None,
+                text: format!("*{}*: {}", name, slack_bot::format_queue(users, queue)),
+                blocks: Some(queue_blocks(users, &name, queue)),
+            });
+            if let Some(text) = response {
+                followups.push(Followup {
+                    replace_original: false,
+                    response_type: Some(ResponseType::InChannel),
+                    text,
+                    blocks: None,
+                });
+            }
+        }
+        bot.queues_mut().set_actor(None);
+        bot.queues_mut().collect_notifications(Some(&user));
+        Some(InteractionReply {
+            response_url,
+            followups,
+        })
+    }
+}
+
+/// The Block Kit layout of a message showing the queue called `name`: one row per person in line,
+/// followed by the "Join", "I'm done", and "Refresh" buttons. The value of every button is the name
+/// of the queue, so that a click can be applied to the right queue. People are shown by their names
+/// in `users`.
+pub fn queue_blocks(users: &SlackMap, name: &str, queue: &Queue) -> Vec<Value> {
+    let mut blocks = vec![section(&format!("*{}*", name))];
+    if queue.is_empty() {
+        blocks.push(section("Nobody is in line!"));
+    }
+    let now = SystemTime::now();
+    let estimates = queue.estimated_waits(now);
+    for (idx, (entry, estimate)) in queue.iter().zip(estimates).enumerate().take(MAX_ROWS) {
+        let job = slack_bot::format_job(&entry.job)
+            .map(|job| format!(": {}", escape(&job)))
+            .unwrap_or_default();
+        let row = format!(
+            "{}. {}{} \u{2014} {}",
+            idx,
+            escape(&slack_bot::display_name(users, &entry.user)),
+            job,
+            slack_bot::wait_status(entry, estimate, now)
+        );
+        blocks.push(section(&row));
+    }
+    if queue.len() > MAX_ROWS {
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{
+                "type": "mrkdwn",
+                "text": format!("\u{2026}and {} more", queue.len() - MAX_ROWS),
+            }],
+        }));
+    }
+
+    let mut join = button(JOIN_ACTION, "Join", name);
+    join["style"] = json!("primary");
+    blocks.push(json!({
+        "type": "actions",
+        "elements": [
+            join,
+            button(DONE_ACTION, "I'm done", name),
+            button(REFRESH_ACTION, "Refresh", name),
+        ],
+    }));
+    blocks
+}
+
+/// A block of `mrkdwn` text.
+fn section(text: &str) -> Value {
+    json!({
+        "type": "section",
+        "text": {"type": "mrkdwn", "text": text},
+    })
+}
+
+/// A button labelled `label` that belongs to the queue called `queue`.
+fn button(action_id: &str, label: &str, queue: &str) -> Value {
+    json!({
+        "type": "button",
+        "action_id": action_id,
+        "text": {"type": "plain_text", "text": label},
+        "value": queue,
+    })
+}
+
+/// Escape the characters that Slack treats as markup in `mrkdwn` text, e.g. in someone's name.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::manager::{QueueManager, DEFAULT_QUEUE};
+    use crate::mock_slack::{self, Response};
+    use crate::storage::Backend;
+    use crate::web::{WebClient, SLACK_API};
+
+    const QUEUE_CHANNEL: &str = "C0123456789";
+
+    /// A bot for queues that are only kept in memory, running in `QUEUE_CHANNEL`.
+    fn bot(users: &SlackMap) -> SlackBot<'_> {
+        let queues = QueueManager::from_backend(Backend::Memory, DEFAULT_QUEUE).unwrap();
+        let mut bot = SlackBot::new(queues, users);
+        bot.set_channels(vec![(CHANNEL.to_string(), QUEUE_CHANNEL.to_string())]);
+        bot
+    }
+
+    fn mention(user: &str, text: &str, channel: &str, ts: &str) -> Payload {
+        Payload::EventCallback {
+            event: Event {
+                kind: String::from("app_mention"),
+                user: Some(user.to_string()),
+                text: Some(text.to_string()),
+                channel: Some(channel.to_string()),
+                ts: Some(ts.to_string()),
+                ..Event::default()
+            },
+        }
+    }
+
+    #[test]
+    fn payloads() {
+        let challenge: Payload = serde_json::from_str(
+            r#"{"token": "x", "challenge": "abc123", "type": "url_verification"}"#,
+        )
+        .unwrap();
+        let event: Payload = serde_json::from_str(
+            r#"{"type": "event_callback", "team_id": "T1", "event": {"type": "app_mention",
+            "user": "UA8RXUPSP", "text": "<@U01A844Q2US> add", "channel": "C1", "ts": "1.2"}}"#,
+        )
+        .unwrap();
+        let other: Payload = serde_json::from_str(r#"{"type": "app_rate_limited"}"#).unwrap();
+
+        assert_eq!(
+            challenge,
+            Payload::UrlVerification {
+                challenge: String::from("abc123")
+            }
+        );
+        assert_eq!(
+            event,
+            mention("UA8RXUPSP", "<@U01A844Q2US> add", "C1", "1.2")
+        );
+        assert_eq!(other, Payload::Other);
+    }
+
+    #[test]
+    fn signatures() {
+        // The example from Slack's documentation
+        let secret = SigningSecret::new("8f742231b10e8888abcd99yyyzzz85a5");
+        let body = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&\
+channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&\
+text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&\
+trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let timestamp = "1531420618";
+        let signature = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+        let now = crate::storage::from_unix_secs(1531420618 + 60);
+        let verify =
+            |timestamp, signature, now| secret.verify(Some(timestamp), Some(signature), body, now);
+
+        let tampered = signature.replace('a', "b");
+
+        assert_eq!(secret.sign(timestamp, body), signature);
+        assert_eq!(verify(timestamp, signature, now), Ok(()));
+        assert_eq!(
+            secret.verify(Some(timestamp), None, body, now),
+            Err(SignatureError::MissingHeaders)
+        );
+        assert_eq!(
+            verify("yesterday", signature, now),
+            Err(SignatureError::BadTimestamp)
+        );
+        assert_eq!(
+            verify(timestamp, signature, now + REPLAY_WINDOW),
+            Err(SignatureError::Stale)
+        );
+        assert_eq!(
+            verify(timestamp, &tampered, now),
+            Err(SignatureError::BadSignature)
+        );
+        assert_eq!(
+            verify(timestamp, "v0=not hex", now),
+            Err(SignatureError::BadSignature)
+        );
+        assert_eq!(
+            SigningSecret::new("someone else").verify(Some(timestamp), Some(signature), body, now),
+            Err(SignatureError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn events_are_answered_once() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let mut events = EventsApi::new();
+
+        let add = mention("UA8RXUPSP", "<@U01A844Q2US> add", QUEUE_CHANNEL, "1.1");
+        assert!(matches!(
+            events.handle(&mut bot, add.clone()),
+            Reply::Post(Message { ref channel, .. }) if channel == QUEUE_CHANNEL
+        ));
+        // The same message again, e.g. as a `message` event or a retry
+        assert_eq!(events.handle(&mut bot, add), Reply::Nothing);
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+
+        // Messages that do not mention Queue are not answered
+        let chatter = mention("UA8RXUPSP", "hello", QUEUE_CHANNEL, "1.2");
+        assert_eq!(events.handle(&mut bot, chatter), Reply::Nothing);
+
+        // Elsewhere, people are pointed to Queue's channel
+        let elsewhere = mention("UA8RXUPSP", "<@U01A844Q2US> add", "C999", "1.3");
+        assert_eq!(
+            events.handle(&mut bot, elsewhere),
+            Reply::Post(Message {
+                channel: String::from("C999"),
+                text: format!("Try invoking that same command in <#{}>!", QUEUE_CHANNEL),
+                blocks: None,
+            })
+        );
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn slash_commands() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let mut events = EventsApi::new();
+        let form = |text: &str, channel: &str| {
+            SlashCommand::from_form(
+                format!(
+                    "token=x&team_id=T1&channel_id={}&user_id=UA8RXUPSP&user_name=joey&\
+                    command=%2Fqueue&text={}&response_url=https%3A%2F%2Fhooks.slack.com%2Fx",
+                    channel, text
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+
+        let add = form("add+printer", QUEUE_CHANNEL);
+        assert_eq!(add.text, "add printer");
+        let reply = events.command(&mut bot, add);
+        assert_eq!(reply.response_type, ResponseType::InChannel);
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+
+        let reply = events.command(&mut bot, form("Show", QUEUE_CHANNEL));
+        assert_eq!(reply.response_type, ResponseType::Ephemeral);
+        assert!(reply.text.contains("UA8RXUPSP"), "{}", reply.text);
+
+        let reply = events.command(&mut bot, form("", QUEUE_CHANNEL));
+        assert_eq!(reply.text, crate::slack_bot::usage());
+
+        let reply = events.command(&mut bot, form("done", "C999"));
+        assert_eq!(reply.response_type, ResponseType::Ephemeral);
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap()["response_type"],
+            "ephemeral"
+        );
+    }
+
+    /// The form Slack POSTs when `user` clicks the button `action_id` of the queue called `queue`.
+    fn click(user: &str, action_id: &str, queue: &str) -> Interaction {
+        let payload = serde_json::json!({
+            "type": "block_actions",
+            "user": {"id": user, "username": "joey"},
+            "channel": {"id": QUEUE_CHANNEL},
+            "response_url": "https://hooks.slack.com/actions/T1/1/x",
+            "actions": [{"type": "button", "action_id": action_id, "value": queue}],
+        });
+        let form = serde_urlencoded::to_string([("payload", payload.to_string())]).unwrap();
+        Interaction::from_form(form.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn queues_are_shown_with_buttons() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let mut events = EventsApi::new();
+        events.handle(
+            &mut bot,
+            mention("UA8RXUPSP", "<@U01A844Q2US> add", QUEUE_CHANNEL, "1.1"),
+        );
+
+        let show = mention("UA8RXUPSP", "<@U01A844Q2US> show", QUEUE_CHANNEL, "1.2");
+        let blocks = match events.handle(&mut bot, show) {
+            Reply::Post(Message {
+                blocks: Some(blocks),
+                ..
+            }) => blocks,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(blocks[0]["text"]["text"], "*printer*");
+        assert!(blocks[1]["text"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("0. UA8RXUPSP \u{2014} at the front for"));
+        let buttons = blocks.last().unwrap()["elements"].as_array().unwrap();
+        let action_ids = buttons
+            .iter()
+            .map(|button| button["action_id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(action_ids, [JOIN_ACTION, DONE_ACTION, REFRESH_ACTION]);
+        assert!(buttons
+            .iter()
+            .all(|button| button["value"] == DEFAULT_QUEUE));
+
+        // Everything else is still plain text
+        let help = mention("UA8RXUPSP", "<@U01A844Q2US> help", QUEUE_CHANNEL, "1.3");
+        assert!(matches!(
+            events.handle(&mut bot, help),
+            Reply::Post(Message { blocks: None, .. })
+        ));
+
+        let reply = events.command(
+            &mut bot,
+            SlashCommand {
+                command: String::from("/queue"),
+                text: String::from("show printer"),
+                user_id: String::from("UA8RXUPSP"),
+                channel_id: String::from(QUEUE_CHANNEL),
+            },
+        );
+        assert_eq!(reply.blocks, Some(blocks));
+    }
+
+    #[test]
+    fn long_queues_are_cut_short() {
+        let hash_map = HashMap::new();
+        let mut queues = QueueManager::from_backend(Backend::Memory, DEFAULT_QUEUE).unwrap();
+        let queue = queues.get_mut(DEFAULT_QUEUE).unwrap();
+        for i in 0..MAX_ROWS + 5 {
+            queue.add_user(UserID(format!("U{}", i)));
+        }
+
+        let blocks = queue_blocks(&hash_map, DEFAULT_QUEUE, queue);
+
+        assert!(blocks.len() <= 50);
+        assert_eq!(
+            blocks[blocks.len() - 2]["elements"][0]["text"],
+            "\u{2026}and 5 more"
+        );
+    }
+
+    #[test]
+    fn buttons_change_the_queue() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let mut events = EventsApi::new();
+
+        let reply = events
+            .interact(&mut bot, click("UA8RXUPSP", JOIN_ACTION, DEFAULT_QUEUE))
+            .unwrap();
+        assert_eq!(reply.response_url, "https://hooks.slack.com/actions/T1/1/x");
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+        let (redrawn, said) = (&reply.followups[0], &reply.followups[1]);
+        assert!(redrawn.replace_original);
+        assert!(redrawn.blocks.as_ref().unwrap()[1]["text"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("0. UA8RXUPSP \u{2014}"));
+        assert!(!said.replace_original);
+        assert_eq!(said.response_type, Some(ResponseType::InChannel));
+        assert!(said.text.starts_with("Okay <@UA8RXUPSP>"), "{}", said.text);
+
+        let reply = events
+            .interact(&mut bot, click("UA8RXUPSP", REFRESH_ACTION, DEFAULT_QUEUE))
+            .unwrap();
+        assert_eq!(reply.followups.len(), 1);
+        assert!(reply.followups[0].replace_original);
+
+        let reply = events
+            .interact(&mut bot, click("UA8RXUPSP", DONE_ACTION, DEFAULT_QUEUE))
+            .unwrap();
+        assert!(bot.queues().get(DEFAULT_QUEUE).unwrap().is_empty());
+        assert_eq!(
+            reply.followups[0].blocks.as_ref().unwrap()[1]["text"]["text"],
+            "Nobody is in line!"
+        );
+
+        let reply = events
+            .interact(&mut bot, click("UA8RXUPSP", JOIN_ACTION, "ultimaker"))
+            .unwrap();
+        assert_eq!(
+            reply.followups[0].response_type,
+            Some(ResponseType::Ephemeral)
+        );
+        assert!(bot.queues().get(DEFAULT_QUEUE).unwrap().is_empty());
+
+        let other =
+            Interaction::from_form(b"payload=%7B%22type%22%3A%22view_submission%22%7D").unwrap();
+        assert_eq!(other, Interaction::Other);
+        assert!(events.interact(&mut bot, other).is_none());
+    }
+
+    #[test]
+    fn followups_are_sent_to_the_response_url() {
+        let (url, slack) = mock_slack::serve(vec![Response::ok("ok")]);
+        let client = WebClient::with_base_url("xoxb-test", SLACK_API);
+
+        client
+            .respond(
+                &format!("{}/actions/T1/1/x", url),
+                &Followup {
+                    replace_original: true,
+                    response_type: None,
+                    text: String::from("Nobody is in line!"),
+                    blocks: None,
+                },
+            )
+            .unwrap();
+
+        let requests = slack.join().unwrap();
+        assert_eq!(requests[0].line, "POST /api/actions/T1/1/x HTTP/1.1");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[0].body).unwrap(),
+            serde_json::json!({"replace_original": true, "text": "Nobody is in line!"})
+        );
+    }
+}
@@ -72,10 +72,8 @@ impl slack::EventHandler for MyHandler {
                     }
                     _ => ("", None),
                 };
-                if chan.is_some() {
-                    let _ = cli
-                        .sender()
-                        .send_message(chan.as_ref().unwrap(), response_text);
+                if let Some(chan) = chan {
+                    let _ = cli.sender().send_message(chan, response_text);
                 }
             }
             _ => println!("{:?}", event),
@@ -103,7 +101,7 @@ impl slack::EventHandler for MyHandler {
             .expect("botspam channel not found");
         let _ = cli
             .sender()
-            .send_message(&general_channel_id, "Hello world! (rtm)");
+            .send_message(general_channel_id, "Hello world! (rtm)");
         // Send a message over the real time api websocket
     }
 }
@@ -0,0 +1,537 @@
+//! Exporting a queue and its history as CSV or JSON, e.g. to keep track of who uses the printer in a
+//! spreadsheet, and reading a queue back from CSV, e.g. to move a queue that was kept on paper into
+//! Queue.
+//!
+//! Each place in line is a row (or a JSON object) with the columns in
+//! [`QUEUE_COLUMNS`](constant.QUEUE_COLUMNS.html), or [`HISTORY_COLUMNS`](constant.HISTORY_COLUMNS.html)
+//! for places that have left the line. Times are in seconds since the Unix epoch, as in state files,
+//! and positions count from 0. Whatever is not known about a place (e.g. the real name of someone
+//! whose name was not looked up) is left empty, or `null` in JSON.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use serde_json::{Map, Value};
+
+use crate::policy;
+use crate::queue::{Entry, Job, LoadError};
+use crate::state_file::{self, Slot};
+use crate::storage::{from_unix_secs, to_unix_secs, Record};
+use crate::user::{SlackMap, UserID};
+
+/// The columns of an exported queue, in order. `name` is the real name of the person in line, and
+/// `job`, `material`, `color`, `time` (in seconds), and `printer` are what they said about their job.
+pub const QUEUE_COLUMNS: [&str; 10] = [
+    "position", "user", "name", "joined", "state", "job", "material", "color", "time", "printer",
+];
+
+/// The columns of an exported history, in order. These are the same as the columns of a queue (see
+/// [`QUEUE_COLUMNS`](constant.QUEUE_COLUMNS.html)), except that places in the history have no
+/// position, and `left` is when they left the line.
+pub const HISTORY_COLUMNS: [&str; 10] = [
+    "user", "name", "joined", "left", "state", "job", "material", "color", "time", "printer",
+];
+
+/// What an export is written as.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Format {
+    /// Comma-separated values, with a header row naming the columns
+    Csv,
+    /// A JSON array with an object for each row
+    Json,
+}
+
+impl Format {
+    /// The name of this format, e.g. `csv`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown format `{}`; the formats are csv and json",
+                s
+            )),
+        }
+    }
+}
+
+/// Export the places in line in `entries`, from the front of the line to the back. Real names are
+/// taken from `users`, if they are known.
+pub fn queue<'a, I>(entries: I, users: Option<&SlackMap>, format: Format) -> String
+where
+    I: IntoIterator<Item = &'a Entry>,
+{
+    let rows = entries
+        .into_iter()
+        .enumerate()
+        .map(|(position, entry)| row(&QUEUE_COLUMNS, Some(position), entry, users));
+    write(&QUEUE_COLUMNS, rows, format)
+}
+
+/// Export the places in the history of a queue (see [`Queue::history`](../queue/struct.Queue.html#method.history)),
+/// oldest first. Real names are taken from `users`, if they are known.
+pub fn history(entries: &[Entry], users: Option<&SlackMap>, format: Format) -> String {
+    let rows = entries
+        .iter()
+        .map(|entry| row(&HISTORY_COLUMNS, None, entry, users));
+    write(&HISTORY_COLUMNS, rows, format)
+}
+
+/// The value of each of `columns` for `entry`, which is at `position` if it is still in line.
+fn row(
+    columns: &[&str],
+    position: Option<usize>,
+    entry: &Entry,
+    users: Option<&SlackMap>,
+) -> Vec<Value> {
+    let text = |text: &Option<String>| text.clone().map_or(Value::Null, Value::String);
+    columns
+        .iter()
+        .map(|column| match *column {
+            "position" => position.map_or(Value::Null, Value::from),
+            "user" => Value::from(entry.user.to_string()),
+            "name" => text(
+                &users
+                    .and_then(|users| users.get(&entry.user))
+                    .and_then(|(name, _)| name.clone()),
+            ),
+            "joined" => Value::from(to_unix_secs(entry.joined)),
+            "left" => entry
+                .transitions
+                .last()
+                .filter(|transition| transition.state.is_final())
+                .map_or(Value::Null, |transition| {
+                    Value::from(to_unix_secs(transition.at))
+                }),
+            "state" => Value::from(entry.state().as_str()),
+            "job" => text(&entry.job.name),
+            "material" => text(&entry.job.material),
+            "color" => text(&entry.job.color),
+            "time" => entry
+                .job
+                .time
+                .map_or(Value::Null, |time| Value::from(time.as_secs())),
+            "printer" => text(&entry.job.printer),
+            other => unreachable!("{} is not a column", other),
+        })
+        .collect()
+}
+
+/// Write `rows`, each of which has the values of `columns` in order, in `format`.
+fn write<I>(columns: &[&str], rows: I, format: Format) -> String
+where
+    I: Iterator<Item = Vec<Value>>,
+{
+    match format {
+        Format::Csv => {
+            let mut csv = columns.join(",");
+            csv.push('\n');
+            for row in rows {
+                let fields = row.iter().map(csv_field).collect::<Vec<_>>();
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            csv
+        }
+        Format::Json => {
+            let objects = rows
+                .map(|row| {
+                    let object = columns
+                        .iter()
+                        .map(|column| column.to_string())
+                        .zip(row)
+                        .collect::<Map<_, _>>();
+                    Value::Object(object)
+                })
+                .collect();
+            let mut json = serde_json::to_string_pretty(&Value::Array(objects))
+                .expect("JSON values can always be written");
+            json.push('\n');
+            json
+        }
+    }
+}
+
+/// Write `value` as a field of a CSV file, quoting it if it has to be. Text that a spreadsheet would
+/// take for a formula (e.g. a job called `=HYPERLINK(...)`) is written with a `'` in front of it,
+/// which [`read_csv`](fn.read_csv.html) takes back off (see [`is_escaped`](fn.is_escaped.html)).
+fn csv_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(text) if is_escaped(text) => format!("'{}", text),
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// Whether `text` is written to a CSV file with a `'` in front of it: text that a spreadsheet would
+/// take for a formula, and text that already starts with a `'`, so that reading it back only ever
+/// takes off the `'` that was added.
+fn is_escaped(text: &str) -> bool {
+    text.starts_with(['=', '+', '-', '@', '\''])
+}
+
+/// Split `contents` into the records of a CSV file, along with the line each starts on (counting
+/// from 1). A record with a quoted field that is never closed runs to the end of the file, and is
+/// an error.
+fn csv_records(contents: &str) -> Vec<(usize, Result<Vec<String>, String>)> {
+    let mut records = Vec::new();
+    let mut chars = contents.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut unclosed = false;
+        loop {
+            let c = match chars.next() {
+                Some(c) => c,
+                None => {
+                    unclosed = quoted;
+                    break;
+                }
+            };
+            if c == '\n' {
+                line += 1;
+            }
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') if field.is_empty() => quoted = true,
+                (false, ',') => fields.push(std::mem::take(&mut field)),
+                (false, '\r') if chars.peek() == Some(&'\n') => {}
+                (false, '\n') => break,
+                (false, c) => field.push(c),
+            }
+        }
+        fields.push(field);
+        let record = if unclosed {
+            Err(String::from("a quoted field is never closed"))
+        } else {
+            Ok(fields)
+        };
+        // Blank lines are not records
+        if record != Ok(vec![String::new()]) {
+            records.push((start, record));
+        }
+    }
+    records
+}
+
+/// Read the places in line in a CSV file, e.g. one written by [`queue`](fn.queue.html), in the
+/// same way as [`Storage::load`](../storage/trait.Storage.html#tymethod.load): the places that can
+/// be read come first, in order, followed by the rows that cannot, as problems.
+///
+/// The first row names the columns, and only the `user` column is needed. If there is a `position`
+/// column, places are put in order of their positions (and two places at the same position are a
+/// problem); otherwise they are in the order of the rows. Places that do not say when they joined
+/// joined just now. `time` is a number of seconds, or a duration like `1h30m`. Every other column,
+/// including `name` and `state`, is left out: imported places start out waiting.
+pub fn read_csv(contents: &str) -> Vec<Result<Record, LoadError>> {
+    let mut records = csv_records(contents).into_iter();
+    let columns = match records.next() {
+        Some((_, Ok(header))) => header
+            .iter()
+            .map(|column| column.trim().to_lowercase())
+            .collect::<Vec<_>>(),
+        Some((line, Err(reason))) => return vec![Err(LoadError::InvalidLine { line, reason })],
+        None => return Vec::new(),
+    };
+    if !columns.iter().any(|column| column == "user") {
+        return vec![Err(LoadError::InvalidLine {
+            line: 1,
+            reason: String::from("there is no user column"),
+        })];
+    }
+
+    let now = SystemTime::now();
+    let slots = records
+        .enumerate()
+        .map(|(row, (line, fields))| Slot {
+            line,
+            entry: fields.and_then(|fields| read_place(&columns, &fields, row, now)),
+        })
+        .collect();
+    state_file::place(slots)
+}
+
+/// Read the place in line in the row with the given `fields`, which is the `row`th (counting from
+/// 0) after the header, and its position.
+fn read_place(
+    columns: &[String],
+    fields: &[String],
+    row: usize,
+    now: SystemTime,
+) -> Result<(usize, Entry), String> {
+    if fields.len() > columns.len() {
+        return Err(format!(
+            "there are {} fields, but only {} columns",
+            fields.len(),
+            columns.len()
+        ));
+    }
+    let field = |name: &str| {
+        columns
+            .iter()
+            .position(|column| column == name)
+            .and_then(|i| fields.get(i))
+            .map(|field| field.trim())
+            .filter(|field| !field.is_empty())
+            .map(|field| match field.strip_prefix('\'') {
+                Some(escaped) if is_escaped(escaped) => escaped,
+                _ => field,
+            })
+    };
+    let text = |name: &str| field(name).map(String::from);
+    let number = |name: &str| {
+        field(name)
+            .map(|field| {
+                field
+                    .parse::<u64>()
+                    .map_err(|_| format!("the {} must be a whole number, not {:?}", name, field))
+            })
+            .transpose()
+    };
+
+    let user = field("user").ok_or_else(|| String::from("the user is missing"))?;
+    let position = number("position")?.map_or(row, |position| position as usize);
+    let joined = number("joined")?.map_or(now, from_unix_secs);
+    let time = field("time")
+        .map(|time| {
+            time.parse()
+                .map(Duration::from_secs)
+                .ok()
+                .or_else(|| policy::parse_duration(time))
+                .ok_or_else(|| format!("{:?} is not a length of time", time))
+        })
+        .transpose()?;
+    let job = Job {
+        name: text("job"),
+        material: text("material"),
+        color: text("color"),
+        time,
+        printer: text("printer"),
+    };
+    Ok((
+        position,
+        Entry {
+            job,
+            ..Entry::new(UserID::new(user), joined)
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{State, Transition};
+
+    fn entries() -> Vec<Entry> {
+        let joined = from_unix_secs(1_600_000_000);
+        let mut printing = Entry {
+            job: Job {
+                name: Some(String::from("benchy, but \"bigger\"")),
+                material: Some(String::from("PLA")),
+                time: Some(Duration::from_secs(90 * 60)),
+                ..Job::default()
+            },
+            ..Entry::new(UserID::new("UA8RXUPSP"), joined)
+        };
+        printing.transitions.push(Transition {
+            state: State::Printing,
+            at: joined,
+        });
+        let waiting = Entry {
+            job: Job {
+                name: Some(String::from("=1+1")),
+                ..Job::default()
+            },
+            ..Entry::new(UserID::new("UNB2LMZRP"), joined + Duration::from_secs(60))
+        };
+        vec![printing, waiting]
+    }
+
+    fn users() -> SlackMap {
+        let mut users = SlackMap::new();
+        users.insert(
+            UserID::new("UA8RXUPSP"),
+            (
+                Some(String::from("Ada Lovelace")),
+                Some(String::from("ada")),
+            ),
+        );
+        users
+    }
+
+    #[test]
+    fn queues_are_exported_as_csv() {
+        assert_eq!(
+            queue(&entries(), Some(&users()), Format::Csv),
+            "position,user,name,joined,state,job,material,color,time,printer\n\
+            0,UA8RXUPSP,Ada Lovelace,1600000000,printing,\"benchy, but \"\"bigger\"\"\",PLA,,5400,\n\
+            1,UNB2LMZRP,,1600000060,waiting,'=1+1,,,,\n"
+        );
+    }
+
+    #[test]
+    fn history_is_exported_as_json() {
+        let mut left = entries();
+        left[0].transitions.push(Transition {
+            state: State::Finished,
+            at: from_unix_secs(1_600_005_400),
+        });
+        let json = history(&left, None, Format::Json);
+        let rows: Vec<Map<String, Value>> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["user"], "UA8RXUPSP");
+        assert_eq!(rows[0]["state"], "finished");
+        assert_eq!(rows[0]["left"], 1_600_005_400);
+        assert_eq!(rows[0]["name"], Value::Null);
+        // Still in line
+        assert_eq!(rows[1]["left"], Value::Null);
+        assert!(!rows[0].contains_key("position"));
+    }
+
+    #[test]
+    fn exported_queues_can_be_read_back() {
+        let exported = queue(&entries(), Some(&users()), Format::Csv);
+        let read = read_csv(&exported)
+            .into_iter()
+            .map(|record| record.unwrap().entry)
+            .collect::<Vec<_>>();
+
+        assert_eq!(read.len(), 2);
+        for (read, entry) in read.iter().zip(entries()) {
+            assert_eq!(read.user, entry.user);
+            assert_eq!(read.joined, entry.joined);
+            assert_eq!(read.job, entry.job);
+            // Imported places start out waiting
+            assert_eq!(read.state(), State::Waiting);
+        }
+    }
+
+    #[test]
+    fn escaped_text_is_read_back_unchanged() {
+        let names = [
+            "=1+1",
+            "+44 20 7946 0000",
+            "-",
+            "@here",
+            "'=1+1",
+            "'tis",
+            "''",
+        ];
+        let entries = names
+            .iter()
+            .map(|name| Entry {
+                job: Job {
+                    name: Some(String::from(*name)),
+                    ..Job::default()
+                },
+                ..Entry::new(UserID::new("UA8RXUPSP"), from_unix_secs(1_600_000_000))
+            })
+            .collect::<Vec<_>>();
+        let exported = queue(&entries, None, Format::Csv);
+        assert!(exported.contains(",'=1+1,"), "{}", exported);
+        assert!(exported.contains(",''=1+1,"), "{}", exported);
+        assert!(exported.contains(",''tis,"), "{}", exported);
+
+        let read = read_csv(&exported)
+            .into_iter()
+            .map(|record| record.unwrap().entry.job.name.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(read, names);
+    }
+
+    #[test]
+    fn csv_files_are_checked() {
+        let csv = "User,Job,Time\r\n\
+            UA8RXUPSP,benchy,1h30m\r\n\
+            \r\n\
+            ,calibration cube,\r\n\
+            UNB2LMZRP,\"a\n\
+            multi-line job\",10\r\n\
+            UN480W9ND,vase,forever\r\n";
+        let records = read_csv(csv);
+
+        let read = records
+            .iter()
+            .filter_map(|record| record.as_ref().ok())
+            .map(|record| {
+                (
+                    record.line,
+                    record.entry.user.to_string(),
+                    record.entry.job.time,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            read,
+            [
+                (
+                    2,
+                    String::from("UA8RXUPSP"),
+                    Some(Duration::from_secs(5400))
+                ),
+                (5, String::from("UNB2LMZRP"), Some(Duration::from_secs(10))),
+            ]
+        );
+        let problems = records
+            .iter()
+            .filter_map(|record| match record {
+                Err(LoadError::InvalidLine { line, .. }) => Some(*line),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(problems, [4, 7]);
+
+        // Positions put places in order, and cannot be shared
+        let records = read_csv("position,user\n1,UA8RXUPSP\n0,UNB2LMZRP\n1,UN480W9ND\n");
+        assert_eq!(
+            records[0].as_ref().unwrap().entry.user,
+            UserID::new("UNB2LMZRP")
+        );
+        assert_eq!(
+            records[1].as_ref().unwrap().entry.user,
+            UserID::new("UA8RXUPSP")
+        );
+        assert!(records[2].is_err());
+
+        assert!(read_csv("job\nbenchy\n")[0].is_err());
+        assert!(read_csv("user\n\"UA8RXUPSP\n")
+            .iter()
+            .all(|record| record.is_err()));
+        assert!(read_csv("").is_empty());
+    }
+}
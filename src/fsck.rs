@@ -0,0 +1,487 @@
+//! Finding everything that keeps the saved state of a queue from loading cleanly, and repairing it.
+//!
+//! [`Queue::load`](../queue/struct.Queue.html#method.load) stops at the first problem (or, with
+//! [`Recovery::SkipInvalid`](../queue/enum.Recovery.html#variant.SkipInvalid), leaves out whatever
+//! has one). [`check`](fn.check.html) instead goes through the entries of a state file (see
+//! [`storage::scan_state`](../storage/fn.scan_state.html)) or of a queue as it was loaded (see
+//! [`slots`](fn.slots.html)) and reports every [`Issue`](enum.Issue.html) with them:
+//! * entries that cannot be read;
+//! * two entries at the same position;
+//! * positions that nobody is at, with someone behind them;
+//! * people who are not in the workspace, if its members are known;
+//! * people the queue's admission policy would not have let in.
+//!
+//! Each issue can be dealt with by one of the [`Repair`](enum.Repair.html)s that are asked for.
+//! If every issue is, [`repair`](fn.repair.html) saves the result, which for a state file means
+//! writing it atomically (see [`TextFileStorage`](../storage/struct.TextFileStorage.html)).
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt, io,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::journal::{Event, Operation};
+use crate::policy::{AdmissionPolicy, Rejection};
+use crate::queue::{self, Entry, LoadError, EXEMPT_KEY};
+use crate::state_file::Slot;
+use crate::storage::{to_unix_secs, Record, Storage};
+use crate::user::{SlackMap, UserID};
+
+/// Something that keeps the saved state of a queue from loading cleanly.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Issue {
+    /// An entry cannot be read, for the given reason. This variant contains the line it is on, if
+    /// that is known.
+    Unreadable { line: Option<usize>, reason: String },
+    /// An entry is at a position that an entry before it is already at.
+    DuplicatePosition { line: usize, position: usize },
+    /// An entry is at `position`, but nobody is at the positions from `expected` up to it.
+    Gap {
+        line: usize,
+        position: usize,
+        expected: usize,
+    },
+    /// Someone in line is not a member of the workspace.
+    UnknownUser { line: usize, user: UserID },
+    /// Someone in line would not have been let in by the queue's admission policy, for the given
+    /// reason.
+    BreaksRules {
+        line: usize,
+        user: UserID,
+        rejection: Rejection,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Unreadable {
+                line: Some(line),
+                reason,
+            } => write!(f, "line {}: {}", line, reason),
+            Issue::Unreadable { line: None, reason } => f.write_str(reason),
+            Issue::DuplicatePosition { line, position } => write!(
+                f,
+                "line {}: position {} is taken by someone on an earlier line",
+                line, position
+            ),
+            Issue::Gap {
+                line,
+                position,
+                expected,
+            } => write!(
+                f,
+                "line {}: position {} comes after an empty position {}",
+                line,
+                position,
+                position - 1
+            )
+            .and_then(|()| match position - expected {
+                1 => Ok(()),
+                _ => write!(f, " (positions {} to {} are empty)", expected, position - 1),
+            }),
+            Issue::UnknownUser { line, user } => write!(
+                f,
+                "line {}: user {} is not a member of the workspace",
+                line, user
+            ),
+            Issue::BreaksRules {
+                line,
+                user,
+                rejection,
+            } => write!(
+                f,
+                "line {}: user {} breaks the addition rules ({})",
+                line, user, rejection
+            ),
+        }
+    }
+}
+
+/// A way of dealing with [`Issue`](enum.Issue.html)s.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Repair {
+    /// Give everyone a position in the order they are in, keeping entries that share a position
+    /// with the one before them, and closing gaps.
+    Renumber,
+    /// Leave out entries that cannot be read, that share a position with the one before them, or
+    /// that belong to people who are not in the workspace or who break the rules.
+    Drop,
+    /// Keep people who break the rules in line, and let them in whenever the queue is loaded from
+    /// then on (see [`EXEMPT_KEY`](../queue/constant.EXEMPT_KEY.html)). This is meant for state
+    /// files written before the rules they break existed.
+    Relax,
+}
+
+impl Repair {
+    /// The name of this repair, e.g. `renumber`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Repair::Renumber => "renumber",
+            Repair::Drop => "drop",
+            Repair::Relax => "relax",
+        }
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Repair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "renumber" => Ok(Repair::Renumber),
+            "drop" => Ok(Repair::Drop),
+            "relax" => Ok(Repair::Relax),
+            _ => Err(format!(
+                "unknown repair `{}`; the repairs are renumber, drop, and relax",
+                s
+            )),
+        }
+    }
+}
+
+/// An [`Issue`](enum.Issue.html) that was found, along with the repair that deals with it, if one
+/// that does was asked for.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Finding {
+    /// What is wrong
+    pub issue: Issue,
+    /// What is done about it
+    pub repair: Option<Repair>,
+}
+
+/// What [`check`](fn.check.html) found.
+#[derive(Debug, Default)]
+pub struct Check {
+    /// Every issue, in the order they were found in
+    pub findings: Vec<Finding>,
+    /// The people in line once the repairs that were asked for are made, from the front of the
+    /// line to the back
+    pub entries: VecDeque<Entry>,
+    /// The places that were kept in line by [`Repair::Relax`](enum.Repair.html#variant.Relax), as
+    /// who they belong to and when (in seconds since the Unix epoch) they joined
+    pub exempt: HashSet<(UserID, u64)>,
+}
+
+impl Check {
+    /// Was nothing wrong?
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Is every issue dealt with by one of the repairs that were asked for?
+    pub fn is_repaired(&self) -> bool {
+        self.findings.iter().all(|finding| finding.repair.is_some())
+    }
+}
+
+/// Turn the records that a queue was loaded from (see [`Storage::load`](../storage/trait.Storage.html#tymethod.load))
+/// into slots for [`check`](fn.check.html). The records that were loaded are already in order, so
+/// each is at the position it is in among them; the ones that could not be loaded are unreadable.
+pub fn slots(records: Vec<Result<Record, LoadError>>) -> Vec<Slot> {
+    let mut position = 0;
+    records
+        .into_iter()
+        .map(|record| match record {
+            Ok(Record { line, entry }) => {
+                position += 1;
+                Slot {
+                    line,
+                    entry: Ok((position - 1, entry)),
+                }
+            }
+            Err(problem) => Slot {
+                line: 0,
+                entry: Err(problem.to_string()),
+            },
+        })
+        .collect()
+}
+
+/// Go through `slots` the way [`Queue::load`](../queue/struct.Queue.html#method.load) would,
+/// finding every issue with them and dealing with it using the `repairs` that are asked for. The
+/// members of the workspace are `users`, if they are known; otherwise nobody is unknown. The
+/// places in `exempt` are let in whether or not `policy` would let them in (see
+/// [`exemptions`](fn.exemptions.html)).
+///
+/// Issues that are not dealt with are left the way loading the queue would leave them: entries that
+/// are unreadable, at a taken position, or break the rules are left out, and everything else is
+/// kept.
+pub fn check(
+    slots: Vec<Slot>,
+    policy: &dyn AdmissionPolicy,
+    users: Option<&SlackMap>,
+    exempt: &HashSet<(UserID, u64)>,
+    repairs: &[Repair],
+) -> Check {
+    let now = SystemTime::now();
+    let chosen = |options: &[Repair]| {
+        options
+            .iter()
+            .copied()
+            .find(|repair| repairs.contains(repair))
+    };
+    let mut check = Check::default();
+
+    let mut placed = Vec::with_capacity(slots.len());
+    let mut findings = Vec::new();
+    for Slot { line, entry } in slots {
+        match entry {
+            Ok((position, entry)) => placed.push((position, line, entry)),
+            Err(reason) => findings.push(Finding {
+                issue: Issue::Unreadable {
+                    line: Some(line).filter(|line| *line > 0),
+                    reason,
+                },
+                repair: chosen(&[Repair::Drop]),
+            }),
+        }
+    }
+    // Entries at the same position stay in the order they were written in
+    placed.sort_by_key(|(position, _, _)| *position);
+
+    let mut last = None;
+    for (position, line, entry) in placed {
+        if last == Some(position) {
+            let repair = chosen(&[Repair::Renumber, Repair::Drop]);
+            findings.push(Finding {
+                issue: Issue::DuplicatePosition { line, position },
+                repair,
+            });
+            if repair != Some(Repair::Renumber) {
+                continue;
+            }
+        } else {
+            let expected = last.map_or(0, |last| last + 1);
+            if position > expected {
+                findings.push(Finding {
+                    issue: Issue::Gap {
+                        line,
+                        position,
+                        expected,
+                    },
+                    repair: chosen(&[Repair::Renumber]),
+                });
+            }
+            last = Some(position);
+        }
+
+        if users.is_some_and(|users| !users.contains_key(&entry.user)) {
+            let repair = chosen(&[Repair::Drop]);
+            findings.push(Finding {
+                issue: Issue::UnknownUser {
+                    line,
+                    user: entry.user.clone(),
+                },
+                repair,
+            });
+            if repair.is_some() {
+                continue;
+            }
+        }
+
+        let place = (entry.user.clone(), to_unix_secs(entry.joined));
+        if !exempt.contains(&place) {
+            if let Err(rejection) = policy.admit(&check.entries, &entry.user, now) {
+                let repair = chosen(&[Repair::Relax, Repair::Drop]);
+                findings.push(Finding {
+                    issue: Issue::BreaksRules {
+                        line,
+                        user: entry.user.clone(),
+                        rejection,
+                    },
+                    repair,
+                });
+                if repair != Some(Repair::Relax) {
+                    continue;
+                }
+                check.exempt.insert(place);
+            }
+        }
+        check.entries.push_back(entry);
+    }
+
+    check.findings = findings;
+    check
+}
+
+/// The places in line that the queue saved in `storage` lets in whether or not they break its
+/// rules. See [`EXEMPT_KEY`](../queue/constant.EXEMPT_KEY.html).
+pub fn exemptions(storage: &mut dyn Storage) -> io::Result<HashSet<(UserID, u64)>> {
+    Ok(storage
+        .metadata(EXEMPT_KEY)?
+        .as_deref()
+        .map(queue::parse_exempt)
+        .unwrap_or_default())
+}
+
+/// Save the result of `check` to `storage`, which the queue it checked was loaded from. The places
+/// kept in line by [`Repair::Relax`](enum.Repair.html#variant.Relax) are exempted first, so that
+/// the queue can always be loaded afterwards. Storage that keeps a journal writes the repair down as
+/// a recovery.
+///
+/// # Errors
+/// Besides errors saving to `storage`, an error of the kind `InvalidInput` is returned, and nothing
+/// is saved, if `check` has issues that none of the repairs it was asked for deals with.
+pub fn repair(storage: &mut dyn Storage, check: &Check) -> io::Result<()> {
+    if !check.is_repaired() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "some of the issues are not dealt with by the repairs that were asked for",
+        ));
+    }
+    if !check.exempt.is_empty() {
+        let mut exempt = exemptions(storage)?;
+        exempt.extend(check.exempt.iter().cloned());
+        storage.set_metadata(EXEMPT_KEY, &queue::format_exempt(&exempt))?;
+    }
+    let event = Event {
+        at: SystemTime::now(),
+        actor: None,
+        operation: Operation::Recover,
+    };
+    storage.save_event(&event, &check.entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy;
+    use crate::queue::{Queue, Recovery};
+    use crate::storage::{self, MemoryStorage};
+
+    /// A legacy state file with one of every issue: an unreadable line, a duplicate position, a gap,
+    /// someone who is not in the workspace, and someone back to back with themselves.
+    const BROKEN: &str = "0\tUA8RXUPSP\t1600000000\n\
+        1\tUNB2LMZRP\t1600000010\n\
+        one\tUN480W9ND\n\
+        1\tUN480W9ND\t1600000020\n\
+        3\tUA8RXUPSP\t1600000030\n\
+        4\tUA8RXUPSP\t1600000040\n\
+        5\tUSTRANGER\t1600000050\n";
+
+    fn users() -> SlackMap {
+        ["UA8RXUPSP", "UNB2LMZRP", "UN480W9ND"]
+            .iter()
+            .map(|id| (UserID::new(id), (None, None)))
+            .collect()
+    }
+
+    fn checked(repairs: &[Repair]) -> Check {
+        check(
+            storage::scan_state(BROKEN).unwrap(),
+            &*policy::from_spec("no-back-to-back").unwrap(),
+            Some(&users()),
+            &HashSet::new(),
+            repairs,
+        )
+    }
+
+    fn people(check: &Check) -> Vec<String> {
+        check
+            .entries
+            .iter()
+            .map(|entry| entry.user.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn every_issue_is_found() {
+        let check = checked(&[]);
+
+        assert!(!check.is_clean() && !check.is_repaired());
+        let issues = check
+            .findings
+            .iter()
+            .map(|finding| &finding.issue)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            issues[..],
+            [
+                Issue::Unreadable { line: Some(3), .. },
+                Issue::DuplicatePosition {
+                    line: 4,
+                    position: 1
+                },
+                Issue::Gap {
+                    line: 5,
+                    position: 3,
+                    expected: 2
+                },
+                Issue::BreaksRules { line: 6, .. },
+                Issue::UnknownUser { line: 7, .. },
+            ]
+        ));
+        // Left the way loading the queue would leave it
+        assert_eq!(
+            people(&check),
+            ["UA8RXUPSP", "UNB2LMZRP", "UA8RXUPSP", "USTRANGER"]
+        );
+        assert!(check
+            .findings
+            .iter()
+            .all(|finding| finding.repair.is_none()));
+    }
+
+    #[test]
+    fn repairs_deal_with_issues() {
+        // Unreadable entries can only be dropped
+        assert!(!checked(&[Repair::Renumber, Repair::Relax]).is_repaired());
+
+        let check = checked(&[Repair::Renumber, Repair::Drop]);
+        assert!(check.is_repaired());
+        assert_eq!(
+            people(&check),
+            ["UA8RXUPSP", "UNB2LMZRP", "UN480W9ND", "UA8RXUPSP"]
+        );
+        assert!(check.exempt.is_empty());
+
+        let check = checked(&[Repair::Drop, Repair::Relax]);
+        assert!(!check.is_repaired());
+        // Without renumbering, gaps are left
+        assert_eq!(
+            people(&check),
+            ["UA8RXUPSP", "UNB2LMZRP", "UA8RXUPSP", "UA8RXUPSP"]
+        );
+        assert_eq!(check.exempt.len(), 1);
+    }
+
+    #[test]
+    fn relaxed_places_are_let_in_when_loading() {
+        let repaired = checked(&[Repair::Renumber, Repair::Drop, Repair::Relax]);
+        assert!(repaired.is_repaired());
+        let storage = MemoryStorage::new();
+        repair(&mut storage.clone(), &repaired).unwrap();
+
+        let policy = policy::from_spec("no-back-to-back").unwrap();
+        let (queue, report) =
+            Queue::load(Box::new(storage.clone()), policy, Recovery::Strict).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(queue.len(), 5);
+
+        // Checking again finds nothing, since the exempted places are let in
+        let exempt = exemptions(&mut storage.clone()).unwrap();
+        let rechecked = check(
+            slots(storage.clone().load().unwrap()),
+            &*policy::from_spec("no-back-to-back").unwrap(),
+            Some(&users()),
+            &exempt,
+            &[],
+        );
+        assert!(rechecked.is_clean());
+
+        // Nothing is saved while issues are left
+        let unrepaired = checked(&[Repair::Renumber]);
+        let empty = MemoryStorage::new();
+        assert!(repair(&mut empty.clone(), &unrepaired).is_err());
+        assert!(empty.clone().load().unwrap().is_empty());
+    }
+}
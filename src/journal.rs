@@ -0,0 +1,1026 @@
+//! An append-only journal of every change made to a queue, so that who did what (and when) is never
+//! lost, and the queue can be rebuilt as it was at any point in time.
+//!
+//! Every time a queue changes, it describes the change as an [`Event`](struct.Event.html): who made
+//! it, when, and what they did (e.g. an admin moving someone to the front of the line). A
+//! [`JournalStorage`](struct.JournalStorage.html) appends the event to its journal along with the
+//! [`Change`](enum.Change.html)s it made to the entries, instead of rewriting every entry. Once in a
+//! while, the entries are saved as a snapshot and the journal is compacted: the events in it are
+//! moved to an archive that is never replayed, but can still be read. A queue is loaded by replaying
+//! the journal on top of the last snapshot.
+//!
+//! A queue in an SQLite database keeps its events in the database instead, next to its entries (see
+//! [`SqliteStorage`](../storage/struct.SqliteStorage.html)).
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::queue::{Entry, LoadError};
+use crate::storage::{self, from_unix_secs, read_if_exists, to_unix_secs, Record, Storage};
+use crate::user::UserID;
+
+/// The extension of the file that a [`JournalStorage`](struct.JournalStorage.html) for a queue in a
+/// state directory keeps its journal in, e.g. `queues/prusa.journal`.
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+/// The extension of the file that the events of a journal are moved to once they are part of a
+/// snapshot, next to the journal, e.g. `queues/prusa.archive`.
+pub const ARCHIVE_EXTENSION: &str = "archive";
+
+/// How many events are written to a journal between snapshots, unless told otherwise.
+pub const SNAPSHOT_EVERY: usize = 100;
+
+/// What was done to a queue. People are known by their IDs and places in line by their positions
+/// (counting from 0) at the time.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Operation {
+    /// Someone joined the back of the line
+    Add { user: UserID },
+    /// Someone left the line from the given position, without finishing a print
+    Remove { user: UserID, position: usize },
+    /// Someone was moved from one position to another
+    Move {
+        user: UserID,
+        from: usize,
+        to: usize,
+    },
+    /// The people in two positions were swapped
+    Swap { a: usize, b: usize },
+    /// The job in the given position was changed
+    Edit { position: usize },
+    /// Everyone was taken out of the line
+    Clear,
+    /// The person at the front of the line started printing
+    Start { user: UserID },
+    /// The person at the front of the line finished printing
+    Finish { user: UserID },
+    /// The print of the person at the front of the line failed, and they either left or are trying
+    /// again
+    Fail { user: UserID, retry: bool },
+    /// The person at the front of the line stayed there too long, and was dealt with
+    TimedOut { user: UserID },
+    /// The places in line that could not be loaded were left out
+    Recover,
+    /// The journal was started for a queue that was already saved, with whoever was in line then
+    Open,
+    /// This many places in line were brought in from somewhere else, e.g. a spreadsheet
+    Import { count: usize },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Add { user } => write!(f, "add {}", user),
+            Operation::Remove { user, position } => write!(f, "remove {} from {}", user, position),
+            Operation::Move { user, from, to } => {
+                write!(f, "move {} from {} to {}", user, from, to)
+            }
+            Operation::Swap { a, b } => write!(f, "swap {} {}", a, b),
+            Operation::Edit { position } => write!(f, "edit {}", position),
+            Operation::Clear => f.write_str("clear"),
+            Operation::Start { user } => write!(f, "start {}", user),
+            Operation::Finish { user } => write!(f, "finish {}", user),
+            Operation::Fail { user, retry: false } => write!(f, "fail {}", user),
+            Operation::Fail { user, retry: true } => write!(f, "retry {}", user),
+            Operation::TimedOut { user } => write!(f, "time-out {}", user),
+            Operation::Recover => f.write_str("recover"),
+            Operation::Open => f.write_str("open"),
+            Operation::Import { count } => write!(f, "import {}", count),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = String;
+
+    /// The inverse of the `Display` implementation, e.g. `move UA8RXUPSP from 2 to 0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{:?} is not an operation on a queue", s);
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let number = |word: &str| word.parse::<usize>().map_err(|_| invalid());
+        let user = UserID::new;
+        Ok(match words.as_slice() {
+            ["add", u] => Operation::Add { user: user(u) },
+            ["remove", u, "from", position] => Operation::Remove {
+                user: user(u),
+                position: number(position)?,
+            },
+            ["move", u, "from", from, "to", to] => Operation::Move {
+                user: user(u),
+                from: number(from)?,
+                to: number(to)?,
+            },
+            ["swap", a, b] => Operation::Swap {
+                a: number(a)?,
+                b: number(b)?,
+            },
+            ["edit", position] => Operation::Edit {
+                position: number(position)?,
+            },
+            ["clear"] => Operation::Clear,
+            ["start", u] => Operation::Start { user: user(u) },
+            ["finish", u] => Operation::Finish { user: user(u) },
+            ["fail", u] => Operation::Fail {
+                user: user(u),
+                retry: false,
+            },
+            ["retry", u] => Operation::Fail {
+                user: user(u),
+                retry: true,
+            },
+            ["time-out", u] => Operation::TimedOut { user: user(u) },
+            ["recover"] => Operation::Recover,
+            ["open"] => Operation::Open,
+            ["import", count] => Operation::Import {
+                count: number(count)?,
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// A change to a queue: what was done, by whom, and when.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Event {
+    /// When the change was made
+    pub at: SystemTime,
+    /// Who made the change, or `None` if Queue made it on its own (e.g. because someone timed out)
+    pub actor: Option<UserID>,
+    /// What was done
+    pub operation: Operation,
+}
+
+/// One step in turning the entries of a queue before an event into the entries after it.
+///
+/// The changes of an event say what the entries are afterwards rather than how they moved, so
+/// replaying an event that is already part of a snapshot leaves the entries as they were. That way,
+/// a snapshot that was saved right before a crash never has events applied to it twice.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    /// There are this many entries
+    Len(usize),
+    /// This is the entry in this position
+    Set(usize, Entry),
+}
+
+/// The changes that turn the entries `old` into `new`: how many entries there are, and every entry
+/// that is not what it was in the same position before.
+pub fn diff(old: &[Entry], new: &VecDeque<Entry>) -> Vec<Change> {
+    let mut changes = vec![Change::Len(new.len())];
+    changes.extend(
+        new.iter()
+            .enumerate()
+            .filter(|(position, entry)| old.get(*position) != Some(entry))
+            .map(|(position, entry)| Change::Set(position, entry.clone())),
+    );
+    changes
+}
+
+/// An event read back from a journal, along with what it changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoggedEvent {
+    /// The number of the event. Events are numbered from 1 in the order they were written, and the
+    /// numbers keep going across compactions.
+    pub seq: u64,
+    /// The event itself
+    pub event: Event,
+    /// What it changed
+    pub changes: Vec<Change>,
+}
+
+/// Make `changes` to `entries`. A position that the changes make room for but do not fill is left
+/// as `None`, which only happens if the events being replayed do not follow one another.
+pub(crate) fn replay(entries: &mut Vec<Option<Entry>>, changes: &[Change]) {
+    for change in changes {
+        match change {
+            Change::Len(len) => entries.resize(*len, None),
+            Change::Set(position, entry) => {
+                if *position >= entries.len() {
+                    entries.resize(position + 1, None);
+                }
+                entries[*position] = Some(entry.clone());
+            }
+        }
+    }
+}
+
+/// Write `logged` in the form it is kept in a journal: a line with its number, time, actor (or `-`),
+/// and operation, a line for each change, and a line that closes the event so that an event that
+/// was only partly written (e.g. because of a crash) can be told apart.
+fn format_event(logged: &LoggedEvent) -> String {
+    let LoggedEvent {
+        seq,
+        event,
+        changes,
+    } = logged;
+    let actor = event
+        .actor
+        .as_ref()
+        .map_or_else(|| String::from("-"), UserID::to_string);
+    format!(
+        "event {} {} {} {}\n{}end {}\n",
+        seq,
+        to_unix_secs(event.at),
+        actor,
+        event.operation,
+        format_changes(changes),
+        seq
+    )
+}
+
+/// Write `changes` a line each, the way they are written in a journal.
+pub(crate) fn format_changes(changes: &[Change]) -> String {
+    let mut text = String::new();
+    for change in changes {
+        match change {
+            Change::Len(len) => text.push_str(&format!("len {}\n", len)),
+            Change::Set(position, entry) => text.push_str(&format!(
+                "set {}\t{}\n",
+                position,
+                storage::format_entry(entry)
+            )),
+        }
+    }
+    text
+}
+
+/// Read back a change written by [`format_changes`](fn.format_changes.html), given the first word
+/// of its line and the rest of it.
+fn parse_change(keyword: &str, rest: &str, now: SystemTime) -> Result<Change, String> {
+    match keyword {
+        "len" => rest
+            .trim()
+            .parse()
+            .map(Change::Len)
+            .map_err(|_| format!("{:?} is not a number of entries", rest)),
+        "set" => {
+            let mut fields = rest.split_whitespace();
+            fields
+                .next()
+                .and_then(|position| position.parse().ok())
+                .ok_or_else(|| format!("{:?} does not start with a position", rest))
+                .and_then(|position| {
+                    storage::parse_entry(&mut fields, now).map(|entry| Change::Set(position, entry))
+                })
+        }
+        _ => Err(format!("{:?} is not a change", keyword)),
+    }
+}
+
+/// The inverse of [`format_changes`](fn.format_changes.html).
+#[cfg(feature = "sqlite")]
+pub(crate) fn parse_changes(text: &str) -> Result<Vec<Change>, String> {
+    let now = SystemTime::now();
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            parse_change(keyword, rest, now)
+        })
+        .collect()
+}
+
+/// What was read from a journal by [`parse_events`](fn.parse_events.html).
+#[derive(Debug, Default)]
+struct Parsed {
+    /// The events that were read back in full
+    events: Vec<LoggedEvent>,
+    /// Descriptions of whatever could not be understood
+    problems: Vec<String>,
+    /// Where the event that was being written when the journal ended begins, if it was cut off
+    /// (e.g. by a crash)
+    torn: Option<usize>,
+}
+
+/// Read the events written by [`format_event`](fn.format_event.html) in `text`. Events that were
+/// not closed are left out, along with anything that cannot be understood. An event that is cut off
+/// by the end of `text` is expected after a crash, so it is not a problem; it just never happened.
+fn parse_events(text: &str) -> Parsed {
+    let now = SystemTime::now();
+    let mut parsed = Parsed::default();
+    // The event being read, along with where it begins
+    let mut open: Option<(usize, LoggedEvent)> = None;
+    // Whether the rest of an event that could not be understood is being skipped
+    let mut skipping = false;
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let begins = offset;
+        offset += line.len();
+        if !line.ends_with('\n') {
+            // The last line was cut off while it was being written
+            parsed.torn = Some(open.map_or(begins, |(begins, _)| begins));
+            return parsed;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if keyword == "event" {
+            skipping = false;
+        } else if skipping {
+            continue;
+        }
+        let result = match (keyword, open.as_mut()) {
+            ("event", _) => {
+                if let Some((_, unclosed)) = open.take() {
+                    parsed
+                        .problems
+                        .push(format!("event {} was never finished", unclosed.seq));
+                }
+                parse_header(rest).map(|logged| open = Some((begins, logged)))
+            }
+            ("len", Some((_, logged))) | ("set", Some((_, logged))) => {
+                parse_change(keyword, rest, now).map(|change| logged.changes.push(change))
+            }
+            ("end", Some((_, logged))) if rest.trim() == logged.seq.to_string() => {
+                parsed.events.extend(open.take().map(|(_, logged)| logged));
+                Ok(())
+            }
+            _ => Err(format!("{:?} is not part of an event", line)),
+        };
+        if let Err(reason) = result {
+            parsed.problems.push(format!("line {}: {}", i + 1, reason));
+            open = None;
+            skipping = true;
+        }
+    }
+    parsed.torn = open.map(|(begins, _)| begins);
+    parsed
+}
+
+/// Parse the first line of an event, after the word `event`.
+fn parse_header(header: &str) -> Result<LoggedEvent, String> {
+    let mut fields = header.splitn(4, ' ');
+    let mut number = |what: &str| {
+        fields
+            .next()
+            .and_then(|field| field.parse::<u64>().ok())
+            .ok_or_else(|| format!("an event must start with its {}", what))
+    };
+    let seq = number("number")?;
+    let at = from_unix_secs(number("time")?);
+    let actor = match fields.next() {
+        Some("-") => None,
+        Some(actor) => Some(UserID::new(actor)),
+        None => return Err(String::from("an event must say who made it")),
+    };
+    let operation = fields.next().unwrap_or_default().parse()?;
+    Ok(LoggedEvent {
+        seq,
+        event: Event {
+            at,
+            actor,
+            operation,
+        },
+        changes: Vec::new(),
+    })
+}
+
+/// Storage that writes each change to a queue as an event in a journal, and only saves every entry
+/// (to another [`Storage`](../storage/trait.Storage.html), the snapshot) once every so often. See
+/// the [module documentation](index.html).
+///
+/// Metadata and history are kept by the snapshot storage.
+#[derive(Debug)]
+pub struct JournalStorage {
+    /// Where the entries are saved whenever the journal is compacted
+    snapshot: Box<dyn Storage>,
+    /// Where the journal is
+    path: PathBuf,
+    /// The journal itself, which is only ever appended to (until it is compacted)
+    journal: File,
+    /// The entries as of the last event, which the next event's changes are relative to
+    entries: Vec<Entry>,
+    /// The number of the last event written
+    seq: u64,
+    /// How many events are in the journal
+    events: usize,
+    /// How many events are written before the journal is compacted
+    snapshot_every: usize,
+    /// Whether the journal has events in it that cannot be read, which are archived as soon as
+    /// the next event is written
+    damaged: bool,
+}
+
+impl JournalStorage {
+    /// Journal the changes to the queue saved in `snapshot` in the file at `path`, creating it if
+    /// it does not exist yet. The archive of compacted events is kept next to it, with the extension
+    /// [`ARCHIVE_EXTENSION`](constant.ARCHIVE_EXTENSION.html).
+    pub fn open<P: AsRef<Path>>(snapshot: Box<dyn Storage>, path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let journal = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Self {
+            snapshot,
+            path,
+            journal,
+            entries: Vec::new(),
+            seq: 0,
+            events: 0,
+            snapshot_every: SNAPSHOT_EVERY,
+            damaged: false,
+        })
+    }
+
+    /// Compact the journal after every `events` events, instead of after every
+    /// [`SNAPSHOT_EVERY`](constant.SNAPSHOT_EVERY.html).
+    pub fn set_snapshot_every(&mut self, events: usize) {
+        self.snapshot_every = events.max(1);
+    }
+
+    /// Where the compacted events are kept.
+    fn archive_path(&self) -> PathBuf {
+        self.path.with_extension(ARCHIVE_EXTENSION)
+    }
+
+    /// Every event that was ever written, compacted or not, in order. Events that cannot be read
+    /// back (e.g. one that was cut off by a crash) are left out.
+    pub fn events(&self) -> io::Result<Vec<LoggedEvent>> {
+        let mut events = parse_events(&read_if_exists(&self.archive_path())?).events;
+        events.extend(parse_events(&read_if_exists(&self.path)?).events);
+        // A crash in the middle of compacting can leave the same events in the archive twice
+        let mut last = 0;
+        events.retain(|logged| {
+            let new = logged.seq > last;
+            last = last.max(logged.seq);
+            new
+        });
+        Ok(events)
+    }
+
+    /// The entries of the queue as they were right after the last event at or before `time`, found
+    /// by replaying every event up to then.
+    pub fn state_at(&self, time: SystemTime) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for logged in self
+            .events()?
+            .iter()
+            .take_while(|logged| logged.event.at <= time)
+        {
+            replay(&mut entries, &logged.changes);
+        }
+        Ok(entries.into_iter().flatten().collect())
+    }
+
+    /// Append `logged` to the journal, making sure it is on disk before returning. If it cannot be
+    /// written in full, whatever part of it was written is taken back out.
+    fn append(&mut self, logged: &LoggedEvent) -> io::Result<()> {
+        let len = self.journal.metadata()?.len();
+        let written = self
+            .journal
+            .write_all(format_event(logged).as_bytes())
+            .and_then(|()| self.journal.sync_data());
+        if written.is_err() {
+            let _ = self.journal.set_len(len);
+        }
+        written
+    }
+
+    /// Save the entries as a snapshot, then move the events in the journal to the archive. A crash
+    /// at any point leaves something that loads the same entries, since replaying the journal on top
+    /// of a newer snapshot changes nothing.
+    fn compact(&mut self) -> io::Result<()> {
+        self.snapshot
+            .save(&self.entries.iter().cloned().collect())?;
+        let events = read_if_exists(&self.path)?;
+        let mut archive = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.archive_path())?;
+        archive.write_all(events.as_bytes())?;
+        archive.sync_data()?;
+        self.journal.set_len(0)?;
+        self.events = 0;
+        Ok(())
+    }
+}
+
+impl Storage for JournalStorage {
+    /// Load the snapshot and replay the journal on top of it. Events that cannot be read are
+    /// reported as problems with the journal, and the events after them are still replayed.
+    fn load(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        let mut entries = Vec::new();
+        let mut problems = Vec::new();
+        for record in self.snapshot.load()? {
+            match record {
+                Ok(record) => entries.push(Some(record.entry)),
+                Err(problem) => problems.push(Err(problem)),
+            }
+        }
+
+        let Parsed {
+            events,
+            problems: unreadable,
+            torn,
+        } = parse_events(&read_if_exists(&self.path)?);
+        for logged in &events {
+            replay(&mut entries, &logged.changes);
+        }
+        if let Some(torn) = torn {
+            // Make room for the next event
+            self.journal.set_len(torn as u64)?;
+        }
+        problems.extend(unreadable.into_iter().map(|reason| {
+            Err(LoadError::Journal {
+                reason: format!("{}: {}", self.path.display(), reason),
+            })
+        }));
+        self.events = events.len();
+        self.seq = match events.last() {
+            Some(last) => last.seq,
+            None => parse_events(&read_if_exists(&self.archive_path())?)
+                .events
+                .iter()
+                .map(|logged| logged.seq)
+                .max()
+                .unwrap_or(0),
+        };
+
+        let mut records = Vec::with_capacity(entries.len());
+        for (i, entry) in entries.into_iter().enumerate() {
+            match entry {
+                Some(entry) => records.push(Ok(Record { line: i + 1, entry })),
+                None => problems.push(Err(LoadError::Journal {
+                    reason: format!(
+                        "{}: nothing is known about position {}",
+                        self.path.display(),
+                        i
+                    ),
+                })),
+            }
+        }
+        self.entries = records
+            .iter()
+            .filter_map(|record| record.as_ref().ok().map(|record| record.entry.clone()))
+            .collect();
+        // A journal that has never been written to starts with whoever was already in line
+        if self.seq == 0 && !self.entries.is_empty() {
+            let entries = std::mem::take(&mut self.entries);
+            let opened = Event {
+                at: SystemTime::now(),
+                actor: None,
+                operation: Operation::Open,
+            };
+            self.save_event(&opened, &entries.into_iter().collect())?;
+        }
+        self.damaged = !problems.is_empty();
+        records.extend(problems);
+        Ok(records)
+    }
+
+    /// Save `entries` as a snapshot right away, compacting the journal.
+    fn save(&mut self, entries: &VecDeque<Entry>) -> io::Result<()> {
+        self.entries = entries.iter().cloned().collect();
+        self.compact()
+    }
+
+    fn save_event(&mut self, event: &Event, entries: &VecDeque<Entry>) -> io::Result<()> {
+        let logged = LoggedEvent {
+            seq: self.seq + 1,
+            event: event.clone(),
+            changes: diff(&self.entries, entries),
+        };
+        self.append(&logged)?;
+        self.seq = logged.seq;
+        self.entries = entries.iter().cloned().collect();
+        self.events += 1;
+        if self.events >= self.snapshot_every || self.damaged {
+            self.damaged = false;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.snapshot.metadata(key)
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.snapshot.set_metadata(key, value)
+    }
+
+    /// Set the snapshot aside, and archive the journal, since its events led up to what was set
+    /// aside.
+    fn set_aside(&mut self) -> io::Result<String> {
+        let aside = self.snapshot.set_aside()?;
+        self.entries.clear();
+        self.compact()?;
+        Ok(aside)
+    }
+
+    fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        self.snapshot.record(entry)
+    }
+
+    fn history(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        self.snapshot.history()
+    }
+
+    /// Restore the snapshot from a backup if it was damaged. The backup is older than the snapshot
+    /// was, so if the archive still has every event since the journal was started, the snapshot is
+    /// rebuilt from them instead.
+    fn restore(&mut self) -> io::Result<Option<String>> {
+        let restored = match self.snapshot.restore()? {
+            Some(restored) => restored,
+            None => return Ok(None),
+        };
+        let events = self.events()?;
+        let complete = events
+            .iter()
+            .zip(1..)
+            .all(|(logged, seq)| logged.seq == seq);
+        let mut entries = Vec::new();
+        for logged in &events {
+            replay(&mut entries, &logged.changes);
+        }
+        if !complete || entries.iter().any(Option::is_none) {
+            return Ok(Some(restored));
+        }
+        self.snapshot
+            .save(&entries.into_iter().flatten().collect())?;
+        Ok(Some(format!(
+            "{}, brought up to date with {}",
+            restored,
+            self.archive_path().display()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::queue::Queue;
+    use crate::storage::{MemoryStorage, TextFileStorage};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("queue_journal_{}_{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension(ARCHIVE_EXTENSION));
+        path
+    }
+
+    fn remove(path: &Path) {
+        let _ = fs::remove_file(path.with_extension(ARCHIVE_EXTENSION));
+        let _ = fs::remove_file(path);
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        from_unix_secs(1_600_000_000 + secs)
+    }
+
+    fn entry(user: &str, joined: u64) -> Entry {
+        Entry::new(UserID::new(user), at(joined))
+    }
+
+    /// Write an event that `operation` was done by `UADMIN` at `secs`, leaving `entries` in line.
+    fn write(journal: &mut JournalStorage, secs: u64, operation: Operation, entries: Vec<Entry>) {
+        let event = Event {
+            at: at(secs),
+            actor: Some(UserID::new("UADMIN")),
+            operation,
+        };
+        journal
+            .save_event(&event, &entries.into_iter().collect())
+            .unwrap();
+    }
+
+    fn loaded(journal: &mut JournalStorage) -> Vec<Entry> {
+        journal
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.unwrap().entry)
+            .collect()
+    }
+
+    #[test]
+    fn operations_round_trip() {
+        let user = UserID::new("UA8RXUPSP");
+        let operations = vec![
+            Operation::Add { user: user.clone() },
+            Operation::Remove {
+                user: user.clone(),
+                position: 3,
+            },
+            Operation::Move {
+                user: user.clone(),
+                from: 2,
+                to: 0,
+            },
+            Operation::Swap { a: 0, b: 1 },
+            Operation::Edit { position: 1 },
+            Operation::Clear,
+            Operation::Start { user: user.clone() },
+            Operation::Finish { user: user.clone() },
+            Operation::Fail {
+                user: user.clone(),
+                retry: false,
+            },
+            Operation::Fail {
+                user: user.clone(),
+                retry: true,
+            },
+            Operation::TimedOut { user },
+            Operation::Recover,
+            Operation::Open,
+            Operation::Import { count: 3 },
+        ];
+        for operation in operations {
+            assert_eq!(operation.to_string().parse(), Ok(operation));
+        }
+        assert!("move UA8RXUPSP to 0".parse::<Operation>().is_err());
+    }
+
+    #[test]
+    fn events_are_replayed_on_top_of_the_snapshot() {
+        let path = temp_path("replayed");
+        let snapshot = MemoryStorage::new();
+        let (a, b) = (entry("UA8RXUPSP", 0), entry("UNB2LMZRP", 1));
+        {
+            let mut journal = JournalStorage::open(Box::new(snapshot.clone()), &path).unwrap();
+            assert!(loaded(&mut journal).is_empty());
+            write(
+                &mut journal,
+                0,
+                Operation::Add {
+                    user: a.user.clone(),
+                },
+                vec![a.clone()],
+            );
+            write(
+                &mut journal,
+                1,
+                Operation::Add {
+                    user: b.user.clone(),
+                },
+                vec![a.clone(), b.clone()],
+            );
+            write(
+                &mut journal,
+                2,
+                Operation::Remove {
+                    user: a.user.clone(),
+                    position: 0,
+                },
+                vec![b.clone()],
+            );
+        }
+        // Nothing was compacted, so the snapshot is still empty
+        assert!(snapshot.entries().is_empty());
+
+        let mut journal = JournalStorage::open(Box::new(snapshot), &path).unwrap();
+        assert_eq!(loaded(&mut journal), vec![b.clone()]);
+        let events = journal.events().unwrap();
+        assert_eq!(
+            events.iter().map(|logged| logged.seq).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(events[2].event.actor, Some(UserID::new("UADMIN")));
+        assert_eq!(
+            events[2].event.operation,
+            Operation::Remove {
+                user: a.user,
+                position: 0
+            }
+        );
+        assert_eq!(events[2].changes, [Change::Len(1), Change::Set(0, b)]);
+        remove(&path);
+    }
+
+    #[test]
+    fn compaction_archives_events() {
+        let path = temp_path("compaction");
+        let snapshot = MemoryStorage::new();
+        let (a, b) = (entry("UA8RXUPSP", 0), entry("UNB2LMZRP", 1));
+        let mut journal = JournalStorage::open(Box::new(snapshot.clone()), &path).unwrap();
+        journal.set_snapshot_every(2);
+        loaded(&mut journal);
+        write(
+            &mut journal,
+            0,
+            Operation::Add {
+                user: a.user.clone(),
+            },
+            vec![a.clone()],
+        );
+        write(
+            &mut journal,
+            1,
+            Operation::Add {
+                user: b.user.clone(),
+            },
+            vec![a.clone(), b.clone()],
+        );
+        assert_eq!(snapshot.entries(), [a.clone(), b.clone()]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        write(
+            &mut journal,
+            2,
+            Operation::Swap { a: 0, b: 1 },
+            vec![b.clone(), a.clone()],
+        );
+
+        // Events keep their numbers and can still be read after they are archived
+        let mut reopened = JournalStorage::open(Box::new(snapshot.clone()), &path).unwrap();
+        assert_eq!(loaded(&mut reopened), [b.clone(), a.clone()]);
+        assert_eq!(
+            reopened
+                .events()
+                .unwrap()
+                .iter()
+                .map(|logged| logged.seq)
+                .collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+
+        // A crash right after saving a snapshot leaves the journal as it was, which is harmless
+        snapshot
+            .clone()
+            .save(&vec![b.clone(), a.clone()].into())
+            .unwrap();
+        let mut reopened = JournalStorage::open(Box::new(snapshot), &path).unwrap();
+        assert_eq!(loaded(&mut reopened), [b, a]);
+        remove(&path);
+    }
+
+    #[test]
+    fn torn_events_are_dropped() {
+        let path = temp_path("torn");
+        let snapshot = MemoryStorage::new();
+        let (a, b) = (entry("UA8RXUPSP", 0), entry("UNB2LMZRP", 1));
+        {
+            let mut journal = JournalStorage::open(Box::new(snapshot.clone()), &path).unwrap();
+            loaded(&mut journal);
+            write(
+                &mut journal,
+                0,
+                Operation::Add {
+                    user: a.user.clone(),
+                },
+                vec![a.clone()],
+            );
+        }
+        // A crash in the middle of writing the second event
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"event 2 1600000001 - add UNB2LMZRP\nlen 2\nset 1\tUNB")
+            .unwrap();
+
+        let mut journal = JournalStorage::open(Box::new(snapshot.clone()), &path).unwrap();
+        assert_eq!(loaded(&mut journal), vec![a.clone()]);
+        write(
+            &mut journal,
+            1,
+            Operation::Add {
+                user: b.user.clone(),
+            },
+            vec![a.clone(), b.clone()],
+        );
+
+        let mut journal = JournalStorage::open(Box::new(snapshot), &path).unwrap();
+        assert_eq!(loaded(&mut journal), [a, b]);
+        assert_eq!(journal.events().unwrap().len(), 2);
+        remove(&path);
+    }
+
+    #[test]
+    fn unreadable_events_are_problems() {
+        let path = temp_path("unreadable");
+        fs::write(
+            &path,
+            "event 1 1600000000 - add UA8RXUPSP\nlen 1\nset 0\tUA8RXUPSP\t1600000000\nend 1\n\
+             event 2 1600000001 - dance UA8RXUPSP\nlen 0\nend 2\n",
+        )
+        .unwrap();
+        let mut journal = JournalStorage::open(Box::new(MemoryStorage::new()), &path).unwrap();
+        let records = journal.load().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].as_ref().unwrap().entry.user,
+            UserID::new("UA8RXUPSP")
+        );
+        assert!(matches!(records[1], Err(LoadError::Journal { .. })));
+        remove(&path);
+    }
+
+    #[test]
+    fn queues_write_down_who_did_what() {
+        let path = temp_path("queue");
+        let journal = JournalStorage::open(Box::new(MemoryStorage::new()), &path).unwrap();
+        let mut queue =
+            Queue::from_storage(Box::new(journal), crate::policy::default_policy()).unwrap();
+        let (admin, user) = (UserID::new("UADMIN"), UserID::new("UA8RXUPSP"));
+        queue.set_actor(Some(user.clone()));
+        queue.add_user(user.clone());
+        queue.add_user(UserID::new("UNB2LMZRP"));
+        queue.set_actor(Some(admin.clone()));
+        queue.swap(0, 1);
+
+        let journal = JournalStorage::open(Box::new(MemoryStorage::new()), &path).unwrap();
+        let events = journal
+            .events()
+            .unwrap()
+            .into_iter()
+            .map(|logged| (logged.event.actor, logged.event.operation))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                (Some(user.clone()), Operation::Add { user: user.clone() }),
+                (
+                    Some(user),
+                    Operation::Add {
+                        user: UserID::new("UNB2LMZRP")
+                    }
+                ),
+                (Some(admin), Operation::Swap { a: 0, b: 1 }),
+            ]
+        );
+        remove(&path);
+    }
+
+    #[test]
+    fn damaged_snapshots_are_rebuilt() {
+        let path = temp_path("damaged");
+        let snapshot_path = path.with_extension("txt");
+        let snapshot = || Box::new(TextFileStorage::open(&snapshot_path).unwrap());
+        let (a, b) = (entry("UA8RXUPSP", 0), entry("UNB2LMZRP", 1));
+        {
+            let mut journal = JournalStorage::open(snapshot(), &path).unwrap();
+            journal.set_snapshot_every(1);
+            loaded(&mut journal);
+            write(
+                &mut journal,
+                0,
+                Operation::Add {
+                    user: a.user.clone(),
+                },
+                vec![a.clone()],
+            );
+            write(
+                &mut journal,
+                1,
+                Operation::Add {
+                    user: b.user.clone(),
+                },
+                vec![a.clone(), b.clone()],
+            );
+        }
+        fs::write(&snapshot_path, "    \n    ").unwrap();
+
+        // The newest backup only has `a` in it, but the archive has every event
+        let mut journal = JournalStorage::open(snapshot(), &path).unwrap();
+        let restored = journal.restore().unwrap().unwrap();
+        assert!(restored.ends_with(&format!(
+            "brought up to date with {}",
+            path.with_extension(ARCHIVE_EXTENSION).display()
+        )));
+        assert_eq!(loaded(&mut journal), vec![a, b]);
+        for backup in TextFileStorage::open(&snapshot_path).unwrap().backups() {
+            let _ = fs::remove_file(backup);
+        }
+        let _ = fs::remove_file(&snapshot_path);
+        remove(&path);
+    }
+
+    #[test]
+    fn past_states_are_rebuilt() {
+        let path = temp_path("past");
+        let (a, b) = (entry("UA8RXUPSP", 0), entry("UNB2LMZRP", 10));
+        let mut journal = JournalStorage::open(Box::new(MemoryStorage::new()), &path).unwrap();
+        journal.set_snapshot_every(1);
+        loaded(&mut journal);
+        write(
+            &mut journal,
+            0,
+            Operation::Add {
+                user: a.user.clone(),
+            },
+            vec![a.clone()],
+        );
+        write(
+            &mut journal,
+            10,
+            Operation::Add {
+                user: b.user.clone(),
+            },
+            vec![a.clone(), b.clone()],
+        );
+        write(&mut journal, 20, Operation::Clear, vec![]);
+
+        assert!(journal.state_at(from_unix_secs(0)).unwrap().is_empty());
+        assert_eq!(journal.state_at(at(5)).unwrap(), vec![a.clone()]);
+        assert_eq!(journal.state_at(at(10)).unwrap(), [a, b]);
+        assert!(journal.state_at(at(30)).unwrap().is_empty());
+        remove(&path);
+    }
+}
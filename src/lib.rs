@@ -1,2 +1,23 @@
+#[cfg(any(feature = "slack-rtm", feature = "slack-events"))]
+pub mod command;
+#[cfg(feature = "slack-events")]
+pub mod events;
+pub mod export;
+pub mod fsck;
+pub mod journal;
+pub mod lock;
+pub mod manager;
+#[cfg(all(test, feature = "http-api"))]
+mod mock_slack;
+pub mod notify;
+pub mod policy;
 pub mod queue;
+#[cfg(feature = "slack-rtm")]
+pub mod rtm;
+#[cfg(any(feature = "slack-rtm", feature = "slack-events"))]
+pub mod slack_bot;
+pub mod state_file;
+pub mod storage;
 pub mod user;
+#[cfg(feature = "http-api")]
+pub mod web;
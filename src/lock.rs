@@ -0,0 +1,322 @@
+//! An advisory lock on the place the queues are saved, so that two instances of Queue never save
+//! over each other's changes.
+//!
+//! A lock is a file next to the saved state (see [`Backend::lock_path`](../storage/enum.Backend.html#method.lock_path))
+//! that holds the ID of the process that took it, when it did, and what program it is. On Unix, the
+//! file is also locked with `flock`, so the lock goes away as soon as that process exits, no matter
+//! how. Elsewhere, the file itself is the lock, so one that was left behind by a crash has to be
+//! broken by hand with [`force_unlock`](fn.force_unlock.html).
+//!
+//! The lock is only advisory: it keeps out other instances of Queue, not other programs.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
+
+use crate::storage::{from_unix_secs, read_if_exists, to_unix_secs};
+
+/// The extension of lock files, e.g. `queues.db.lock`.
+pub const LOCK_EXTENSION: &str = "lock";
+
+/// The process holding a lock, as far as its lock file says.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Holder {
+    /// Its process ID
+    pub pid: u32,
+    /// When it took the lock
+    pub since: SystemTime,
+    /// The name of the program it is running, e.g. `slack_main`
+    pub program: String,
+}
+
+impl Holder {
+    /// The current process.
+    fn current() -> Self {
+        let program = std::env::args()
+            .next()
+            .and_then(|program| {
+                Path::new(&program)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| String::from("queue"));
+        Self {
+            pid: process::id(),
+            since: SystemTime::now(),
+            program,
+        }
+    }
+
+    /// Read a lock file, which has a single line of the form `{pid}<tab>{Unix time}<tab>{program}`.
+    /// `None` is returned if it does not have one, e.g. because it is still being written.
+    fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.trim_end().splitn(3, '\t');
+        let pid = fields.next()?.parse().ok()?;
+        let since = from_unix_secs(fields.next()?.parse().ok()?);
+        let program = fields.next().unwrap_or_default().to_string();
+        Some(Self {
+            pid,
+            since,
+            program,
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{}\t{}\t{}\n",
+            self.pid,
+            to_unix_secs(self.since),
+            self.program
+        )
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "process {} ({}), which took it at {} (Unix time)",
+            self.pid,
+            self.program,
+            to_unix_secs(self.since)
+        )
+    }
+}
+
+/// Why a lock could not be taken.
+#[derive(Debug)]
+pub enum LockError {
+    /// Someone else holds the lock at this path. This variant contains who, if the lock file says.
+    Held {
+        path: PathBuf,
+        holder: Option<Holder>,
+    },
+    /// The lock file could not be created, read, or locked.
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held {
+                path,
+                holder: Some(holder),
+            } => write!(f, "{} is locked by {}", path.display(), holder),
+            LockError::Held { path, holder: None } => {
+                write!(f, "{} is locked by another process", path.display())
+            }
+            LockError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+/// A lock that is held until it is dropped.
+#[derive(Debug)]
+pub struct Lock {
+    /// Where the lock file is
+    path: PathBuf,
+    /// The lock file, which is kept open for as long as the lock is held
+    file: File,
+}
+
+impl Lock {
+    /// Take the lock at `path`, or say who holds it.
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<Self, LockError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = match open_exclusive(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let holder = holder(&path)?;
+                return Err(LockError::Held { path, holder });
+            }
+            Err(e) => return Err(e.into()),
+        };
+        file.set_len(0)?;
+        file.write_all(Holder::current().format().as_bytes())?;
+        file.sync_all()?;
+        Ok(Self { path, file })
+    }
+
+    /// Where the lock file is.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Lock {
+    /// Remove the lock file, unless it was broken with [`force_unlock`](fn.force_unlock.html) and
+    /// taken by someone else since.
+    fn drop(&mut self) {
+        if let Ok(true) = same_file(&self.file, &self.path) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Open the lock file at `path` for writing, with no one else holding it. If someone does, an error
+/// of the kind `WouldBlock` is returned.
+#[cfg(unix)]
+fn open_exclusive(path: &Path) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // SAFETY: the file descriptor is open for as long as `file` is
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The holder may have removed the file between us opening and locking it, in which case
+        // we hold a lock on a file no one else will ever look at
+        if same_file(&file, path)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Is `file` still the file at `path`, i.e. has the lock not been broken since it was taken?
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let open = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == open.dev() && current.ino() == open.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Is `file` still the file at `path`, i.e. has the lock not been broken since it was taken? There
+/// is no telling two files apart here, so the lock file is taken to be ours if it names this process.
+#[cfg(not(unix))]
+fn same_file(_file: &File, path: &Path) -> io::Result<bool> {
+    Ok(holder(path)?.map(|holder| holder.pid) == Some(process::id()))
+}
+
+/// Open the lock file at `path` for writing, with no one else holding it. If someone does, an error
+/// of the kind `WouldBlock` is returned.
+#[cfg(not(unix))]
+fn open_exclusive(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::from(io::ErrorKind::WouldBlock),
+            _ => e,
+        })
+}
+
+/// Who the lock file at `path` says holds the lock, if there is a lock file and it says.
+pub fn holder<P: AsRef<Path>>(path: P) -> io::Result<Option<Holder>> {
+    Ok(Holder::parse(&read_if_exists(path.as_ref())?))
+}
+
+/// Break the lock at `path`, whoever holds it, returning who the lock file said held it.
+///
+/// This is meant for locks left behind by an instance of Queue that is no longer running. If it
+/// still is, it keeps running as if it held the lock, and nothing stops the two instances from
+/// saving over each other.
+pub fn force_unlock<P: AsRef<Path>>(path: P) -> io::Result<Option<Holder>> {
+    let holder = holder(&path)?;
+    match fs::remove_file(path) {
+        Ok(()) => Ok(holder),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(holder),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "queue_lock_{}_{}.{}",
+            name,
+            process::id(),
+            LOCK_EXTENSION
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn locks_are_exclusive() {
+        let path = temp_path("exclusive");
+        let lock = Lock::acquire(&path).unwrap();
+
+        let holder = match Lock::acquire(&path) {
+            Err(LockError::Held {
+                holder: Some(holder),
+                ..
+            }) => holder,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(holder.pid, process::id());
+
+        // Dropping the lock releases it
+        drop(lock);
+        assert!(!path.exists());
+        let lock = Lock::acquire(&path).unwrap();
+        assert_eq!(lock.path(), path);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn locks_can_be_broken() {
+        let path = temp_path("broken");
+        let stale = Lock::acquire(&path).unwrap();
+
+        let broken = force_unlock(&path).unwrap().unwrap();
+        assert_eq!(broken.pid, process::id());
+        let lock = Lock::acquire(&path).unwrap();
+
+        // The stale lock does not take the new lock file with it
+        drop(stale);
+        assert!(path.exists());
+        assert_eq!(
+            holder(&path).unwrap().map(|holder| holder.pid),
+            Some(process::id())
+        );
+        drop(lock);
+        assert_eq!(force_unlock(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn lock_files_round_trip() {
+        let holder = Holder {
+            pid: 1234,
+            since: from_unix_secs(1_600_000_000),
+            program: String::from("slack_main"),
+        };
+        assert_eq!(Holder::parse(&holder.format()), Some(holder));
+        assert_eq!(Holder::parse(""), None);
+        assert_eq!(Holder::parse("1234"), None);
+    }
+}
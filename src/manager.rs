@@ -0,0 +1,636 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::notify::{self, Notification};
+use crate::policy;
+use crate::queue::{FrontTimeout, LoadError, LoadReport, Queue, Recovery, TimeoutEvent};
+use crate::storage::{Backend, Storage};
+use crate::user::UserID;
+
+/// The name of the queue that commands without a queue name are applied to, unless another default
+/// is given to [`QueueManager::from_dir`](struct.QueueManager.html#method.from_dir).
+pub const DEFAULT_QUEUE: &str = "printer";
+
+/// The piece of storage metadata that holds the admission policy of a queue, if it was created with
+/// one. See [`policy::from_spec`](../policy/fn.from_spec.html) for its contents.
+pub const POLICY_KEY: &str = "policy";
+
+/// Is `name` usable as the name of a queue?
+///
+/// Since a queue may be persisted in a file named after the queue, names are restricted to lowercase
+/// ASCII letters, digits, dashes, and underscores so that they can never escape the state directory.
+/// The name `all` is reserved for `show all`.
+pub fn is_valid_queue_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "all"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// A type used to represent the result of creating a new queue.
+#[derive(Debug, Eq, PartialEq)]
+pub enum CreateResult {
+    /// The queue was created and is ready to be joined.
+    QueueCreated,
+    /// There already is a queue with the requested name, so nothing was done.
+    QueueAlreadyExists,
+    /// The requested name cannot be used for a queue (see [`is_valid_queue_name`](fn.is_valid_queue_name.html)).
+    InvalidQueueName,
+    /// The requested admission policy could not be understood. This variant contains a description
+    /// of the problem.
+    InvalidPolicy(String),
+    /// The queue was not created because its admission policy could not be saved.
+    PolicyNotSaved(String),
+    /// The queue was not created because there was a problem with the place its state is saved to.
+    /// This variant contains a description of the problem.
+    StorageFailed(String),
+}
+
+/// Something that happened in one of the queues because someone stayed at the front of the line
+/// for too long.
+#[derive(Debug)]
+pub struct TimedOut {
+    /// The name of the queue
+    pub queue: String,
+    /// What happened
+    pub event: TimeoutEvent,
+    /// Whether the change to the queue, if there was one, was saved
+    pub written: io::Result<()>,
+}
+
+/// Keeps track of several named queues at once, e.g. one for each 3D printer and one for the laser
+/// cutter.
+///
+/// Like [`Queue`](../queue/struct.Queue.html), the manager knows nothing about Slack: it only offers
+/// operations on the queues it manages. Commands posted to Slack are parsed and answered by
+/// [`SlackBot`](../slack_bot/struct.SlackBot.html).
+///
+/// Every queue is saved to the same [`Backend`](../storage/enum.Backend.html), e.g. its own file
+/// named `{name}.txt` inside of a single state directory. If a queue was created with its own
+/// admission policy, the policy is saved alongside it (in the metadata of `{name}.txt` for a state
+/// directory).
+#[derive(Debug)]
+pub struct QueueManager {
+    /// The queues being managed, keyed by their names
+    queues: BTreeMap<String, Queue>,
+    /// The name of the queue that commands are applied to when they do not name a queue
+    default_queue: String,
+    /// Where the state of every queue is saved
+    backend: Backend,
+    /// How long someone may stay at the front of any queue, if there is a limit
+    front_timeout: Option<FrontTimeout>,
+    /// The users who may rearrange other people's places in line
+    admins: HashSet<UserID>,
+    /// How many of the first places in line of every queue people are notified about moving into
+    notify_top: usize,
+    /// The notifications that have not been handed out yet
+    notifications: Vec<Notification>,
+}
+
+impl QueueManager {
+    /// Restore every queue whose state file lives in `dir`, creating `dir` if it does not exist yet.
+    /// A queue named `default_queue` is created if one was not found in `dir`.
+    ///
+    /// Files in `dir` that do not end in `.txt` or whose names are not valid queue names are ignored.
+    /// An error is returned if `dir` cannot be read, if a queue's admission policy cannot be read or
+    /// understood, or if a queue cannot be loaded (see [`Queue::from_file`](../queue/struct.Queue.html#method.from_file)).
+    ///
+    /// # Panics
+    /// This function panics if `default_queue` is not a valid queue name.
+    pub fn from_dir<P: AsRef<Path>>(dir: P, default_queue: &str) -> io::Result<Self> {
+        Self::from_backend(
+            Backend::TextFiles(dir.as_ref().to_path_buf()),
+            default_queue,
+        )
+    }
+
+    /// Restore every queue that was saved to `backend`. A queue named `default_queue` is created if
+    /// one was not found in `backend`. Saved queues whose names are not valid queue names are
+    /// ignored.
+    ///
+    /// An error is returned if `backend` cannot be read, if a queue's admission policy cannot be
+    /// read or understood, or if a queue cannot be loaded (see [`Queue::from_storage`](../queue/struct.Queue.html#method.from_storage)).
+    ///
+    /// # Panics
+    /// This function panics if `default_queue` is not a valid queue name.
+    pub fn from_backend(backend: Backend, default_queue: &str) -> io::Result<Self> {
+        let (manager, _) = Self::load(backend, default_queue, Recovery::Strict)?;
+        Ok(manager)
+    }
+
+    /// Like [`QueueManager::from_backend`](#method.from_backend), but problems with the saved state
+    /// of a queue are dealt with according to `recovery` (see [`Queue::load`](../queue/struct.Queue.html#method.load)).
+    /// Along with the manager, the name and load report of every queue that had problems is returned.
+    ///
+    /// # Panics
+    /// This function panics if `default_queue` is not a valid queue name.
+    pub fn load(
+        backend: Backend,
+        default_queue: &str,
+        recovery: Recovery,
+    ) -> io::Result<(Self, Vec<(String, LoadReport)>)> {
+        assert!(
+            is_valid_queue_name(default_queue),
+            "{:?} is not a valid queue name",
+            default_queue
+        );
+        let names = backend.queue_names()?;
+
+        let mut manager = Self {
+            queues: BTreeMap::new(),
+            default_queue: default_queue.to_string(),
+            backend,
+            front_timeout: None,
+            admins: HashSet::new(),
+            notify_top: notify::DEFAULT_NOTIFY_TOP,
+            notifications: Vec::new(),
+        };
+
+        let mut reports = Vec::new();
+        for name in names.iter().filter(|name| is_valid_queue_name(name)) {
+            let (queue, mut report) = load_queue(name, manager.backend.open(name)?, recovery)?;
+            if recovery == Recovery::Strict && !report.is_clean() {
+                return Err(load_failure(name, report.problems.remove(0)));
+            } else if !report.is_clean() || report.restored.is_some() {
+                reports.push((name.to_string(), report));
+            }
+            manager.queues.insert(name.to_string(), queue);
+        }
+
+        if !manager.queues.contains_key(default_queue) {
+            if let CreateResult::StorageFailed(e) = manager.create_queue(default_queue) {
+                return Err(io::Error::other(e));
+            }
+        }
+
+        Ok((manager, reports))
+    }
+
+    /// Manage `queue` under the given `name`, replacing any queue that was already using that name.
+    /// This is useful for queues that are not saved to the manager's backend.
+    pub fn insert(&mut self, name: &str, mut queue: Queue) -> Option<Queue> {
+        queue.set_front_timeout(self.front_timeout);
+        queue.set_notify_top(self.notify_top);
+        self.queues.insert(name.to_string(), queue)
+    }
+
+    /// Create a new, empty queue called `name` whose state is saved to the manager's backend. The
+    /// queue admits people according to the default rules.
+    pub fn create_queue(&mut self, name: &str) -> CreateResult {
+        self.create_queue_with_policy(name, None)
+    }
+
+    /// Create a new, empty queue called `name` whose state is saved to the manager's backend. If `spec`
+    /// is given, the queue admits people according to it (see [`policy::from_spec`](../policy/fn.from_spec.html))
+    /// and the spec is saved alongside the queue. Otherwise, the default rules are used.
+    pub fn create_queue_with_policy(&mut self, name: &str, spec: Option<&str>) -> CreateResult {
+        if !is_valid_queue_name(name) {
+            return CreateResult::InvalidQueueName;
+        } else if self.queues.contains_key(name) {
+            return CreateResult::QueueAlreadyExists;
+        }
+
+        let policy = match spec.map(policy::from_spec) {
+            Some(Ok(policy)) => policy,
+            Some(Err(e)) => return CreateResult::InvalidPolicy(e),
+            None => policy::default_policy(),
+        };
+        let mut storage = match self.backend.open(name) {
+            Ok(storage) => storage,
+            Err(e) => return CreateResult::StorageFailed(e.to_string()),
+        };
+        if let Some(spec) = spec {
+            if let Err(e) = storage.set_metadata(POLICY_KEY, spec) {
+                return CreateResult::PolicyNotSaved(e.to_string());
+            }
+        }
+        let mut queue = match Queue::from_storage(storage, policy) {
+            Ok(queue) => queue,
+            Err(e) => return CreateResult::StorageFailed(e.to_string()),
+        };
+        queue.set_front_timeout(self.front_timeout);
+        queue.set_notify_top(self.notify_top);
+        self.queues.insert(name.to_string(), queue);
+        CreateResult::QueueCreated
+    }
+
+    /// Limit how long someone can stay at the front of every queue, including queues created later,
+    /// or remove the limit with `None`.
+    pub fn set_front_timeout(&mut self, timeout: Option<FrontTimeout>) {
+        self.front_timeout = timeout;
+        for queue in self.queues.values_mut() {
+            queue.set_front_timeout(timeout);
+        }
+    }
+
+    /// Notify people when they move into one of the first `top` places in line of any queue,
+    /// including queues created later. See [`Queue::set_notify_top`](../queue/struct.Queue.html#method.set_notify_top).
+    pub fn set_notify_top(&mut self, top: usize) {
+        self.notify_top = top;
+        for queue in self.queues.values_mut() {
+            queue.set_notify_top(top);
+        }
+    }
+
+    /// Everything that is done to any queue from now on is done by `actor`, or by nobody in
+    /// particular if it is `None`. See [`Queue::set_actor`](../queue/struct.Queue.html#method.set_actor).
+    pub fn set_actor(&mut self, actor: Option<&UserID>) {
+        for queue in self.queues.values_mut() {
+            queue.set_actor(actor.cloned());
+        }
+    }
+
+    /// Note who got to the front of a queue or moved into one of its first few places since this was
+    /// last called, except for `actor`, who made the change and already knows about it.
+    ///
+    /// This is done after every front-of-line timeout, so it only needs to be called after changing
+    /// a queue through [`QueueManager::get_mut`](#method.get_mut), as the Slack bot does after every
+    /// command.
+    pub fn collect_notifications(&mut self, actor: Option<&UserID>) {
+        for (name, queue) in self.queues.iter_mut() {
+            for (user, position) in queue.take_top_changes() {
+                if Some(&user) == actor {
+                    continue;
+                }
+                self.notifications.push(Notification {
+                    delivery: queue.delivery(&user),
+                    user,
+                    queue: name.clone(),
+                    position,
+                });
+            }
+        }
+    }
+
+    /// Hand out the notifications that were collected, leaving none behind.
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Check every queue for someone who has been at the front of the line for too long as of `now`
+    /// (see [`Queue::check_front_timeout`](../queue/struct.Queue.html#method.check_front_timeout)),
+    /// returning what happened in each queue where something did.
+    pub fn check_front_timeouts(&mut self, now: SystemTime) -> Vec<TimedOut> {
+        let mut timed_out = Vec::new();
+        for (name, queue) in self.queues.iter_mut() {
+            if let Some((event, written)) = queue.check_front_timeout(now) {
+                timed_out.push(TimedOut {
+                    queue: name.clone(),
+                    event,
+                    written,
+                });
+            }
+        }
+        self.collect_notifications(None);
+        timed_out
+    }
+
+    /// How long someone may stay at the front of every queue, if there is a limit.
+    pub fn front_timeout(&self) -> Option<FrontTimeout> {
+        self.front_timeout
+    }
+
+    /// Retrieve the queue called `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<&Queue> {
+        self.queues.get(name)
+    }
+
+    /// Retrieve the queue called `name` to change it, if there is one.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Queue> {
+        self.queues.get_mut(name)
+    }
+
+    /// The name of the queue that commands are applied to when they do not name a queue.
+    pub fn default_queue(&self) -> &str {
+        &self.default_queue
+    }
+
+    /// The names of all the queues, in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.queues.keys().map(String::as_str)
+    }
+
+    /// The names of all the queues along with the queues themselves, in alphabetical order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Queue)> {
+        self.queues
+            .iter()
+            .map(|(name, queue)| (name.as_str(), queue))
+    }
+
+    /// Let the users in `admins` rearrange the queues with the `move`, `swap`, `kick`, and `clear`
+    /// commands.
+    pub fn set_admins<I: IntoIterator<Item = UserID>>(&mut self, admins: I) {
+        self.admins = admins.into_iter().collect();
+    }
+
+    /// Whether `user` may rearrange other people's places in line.
+    pub fn is_admin(&self, user: &UserID) -> bool {
+        self.admins.contains(user)
+    }
+}
+
+/// Load the queue called `name` from its `storage`, which admits people according to the policy
+/// it was created with, dealing with problems in its saved state according to `recovery` (see
+/// [`Queue::load`](../queue/struct.Queue.html#method.load)).
+///
+/// This is how every queue of a [`QueueManager`](struct.QueueManager.html) is loaded, and is useful
+/// for loading a single queue the same way, e.g. to inspect it while the bot is not running.
+pub fn load_queue(
+    name: &str,
+    mut storage: Box<dyn Storage>,
+    recovery: Recovery,
+) -> io::Result<(Queue, LoadReport)> {
+    let policy = load_policy(name, storage.as_mut())?;
+    Queue::load(storage, policy, recovery).map_err(|e| load_failure(name, e))
+}
+
+/// Read the admission policy of the queue called `name` from its `storage`, falling back to the
+/// default rules if the queue was not created with one.
+pub fn load_policy(
+    name: &str,
+    storage: &mut dyn Storage,
+) -> io::Result<Box<dyn policy::AdmissionPolicy>> {
+    match storage.metadata(POLICY_KEY)? {
+        Some(spec) => policy::from_spec(&spec).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("policy of queue {}: {}", name, e),
+            )
+        }),
+        None => Ok(policy::default_policy()),
+    }
+}
+
+/// An `io::Error` for the queue called `name` not being loadable because of `e`.
+fn load_failure(name: &str, e: LoadError) -> io::Error {
+    let kind = match &e {
+        LoadError::Io(e) => e.kind(),
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, format!("queue {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::notify::Delivery;
+    use crate::queue::{RemoveResult, TimeoutAction};
+
+    /// A fresh state directory for a single test, so that tests running in parallel do not share
+    /// state files.
+    fn state_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("queue_manager_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn queue_names() {
+        assert!(is_valid_queue_name("prusa"));
+        assert!(is_valid_queue_name("laser-cutter"));
+        assert!(is_valid_queue_name("ender_3"));
+        assert!(!is_valid_queue_name(""));
+        assert!(!is_valid_queue_name("all"));
+        assert!(!is_valid_queue_name("Prusa"));
+        assert!(!is_valid_queue_name("../prusa"));
+    }
+
+    #[test]
+    fn default_queue_is_created() {
+        let dir = state_dir("default_queue_is_created");
+        let manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+
+        assert_eq!(manager.names().collect::<Vec<_>>(), [DEFAULT_QUEUE]);
+        assert!(dir.join("printer.txt").exists());
+    }
+
+    #[test]
+    fn create_and_join_named_queues() {
+        let dir = state_dir("create_and_join_named_queues");
+        let mut manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+        let user = UserID::new("UA8RXUPSP");
+
+        assert_eq!(manager.create_queue("prusa"), CreateResult::QueueCreated);
+        assert_eq!(
+            manager.create_queue("prusa"),
+            CreateResult::QueueAlreadyExists
+        );
+        assert_eq!(manager.create_queue("all"), CreateResult::InvalidQueueName);
+        assert!(matches!(
+            manager.create_queue_with_policy("ultimaker", Some("max-per-user")),
+            CreateResult::InvalidPolicy(_)
+        ));
+
+        manager.get_mut("prusa").unwrap().add_user(user.clone());
+        manager
+            .get_mut(DEFAULT_QUEUE)
+            .unwrap()
+            .add_user(user.clone());
+
+        assert_eq!(manager.get("prusa").unwrap().len(), 1);
+        assert_eq!(manager.get(DEFAULT_QUEUE).unwrap().len(), 1);
+
+        manager.get_mut("prusa").unwrap().remove_user(user);
+
+        assert!(manager.get("prusa").unwrap().is_empty());
+        assert_eq!(manager.get(DEFAULT_QUEUE).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn queues_are_restored_from_their_directory() {
+        let dir = state_dir("queues_are_restored_from_their_directory");
+        {
+            let mut manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+            manager.create_queue("laser-cutter");
+            manager
+                .get_mut("laser-cutter")
+                .unwrap()
+                .add_user(UserID::new("UNB2LMZRP"));
+        }
+
+        let manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+
+        assert_eq!(
+            manager.names().collect::<Vec<_>>(),
+            ["laser-cutter", DEFAULT_QUEUE]
+        );
+        assert_eq!(
+            manager.get("laser-cutter").unwrap().front().unwrap().user,
+            UserID::new("UNB2LMZRP")
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn queues_are_restored_from_a_database() {
+        let db = state_dir("queues_are_restored_from_a_database").with_extension("db");
+        let backend = Backend::Sqlite(db.clone());
+        {
+            let mut manager = QueueManager::from_backend(backend.clone(), DEFAULT_QUEUE).unwrap();
+            manager.create_queue_with_policy("prusa", Some("max-total=1"));
+            manager
+                .get_mut("prusa")
+                .unwrap()
+                .add_user(UserID::new("UNB2LMZRP"));
+        }
+
+        let mut manager = QueueManager::from_backend(backend, DEFAULT_QUEUE).unwrap();
+        let (_, result) = manager
+            .get_mut("prusa")
+            .unwrap()
+            .add_user(UserID::new("UA8RXUPSP"));
+
+        assert_eq!(
+            manager.names().collect::<Vec<_>>(),
+            [DEFAULT_QUEUE, "prusa"]
+        );
+        assert!(matches!(result, crate::queue::AddResult::UserNotAdded(_)));
+        assert_eq!(
+            manager.get("prusa").unwrap().front().unwrap().user,
+            UserID::new("UNB2LMZRP")
+        );
+        let _ = fs::remove_file(db);
+    }
+
+    #[test]
+    fn bad_queues_are_reported_by_name() {
+        let dir = state_dir("bad_queues_are_reported_by_name");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prusa.txt"), "0 UA8RXUPSP\n0 UNB2LMZRP\n").unwrap();
+
+        let error = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error.to_string().starts_with("queue prusa: line 2"),
+            "{}",
+            error
+        );
+
+        let backend = Backend::TextFiles(dir);
+        let (manager, reports) =
+            QueueManager::load(backend, DEFAULT_QUEUE, Recovery::SkipInvalid).unwrap();
+        assert_eq!(manager.get("prusa").unwrap().len(), 1);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0, "prusa");
+    }
+
+    #[test]
+    fn each_queue_has_its_own_policy() {
+        let dir = state_dir("each_queue_has_its_own_policy");
+        let user = UserID::new("UA8RXUPSP");
+        {
+            let mut manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+            manager.create_queue_with_policy("prusa", Some("max-total=1"));
+            manager.get_mut("prusa").unwrap().add_user(user.clone());
+        }
+
+        let mut manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+
+        assert!(!manager
+            .get("prusa")
+            .unwrap()
+            .can_add(&UserID::new("UNB2LMZRP")));
+        // The default queue still uses the default rules
+        let printer = manager.get_mut(DEFAULT_QUEUE).unwrap();
+        printer.add_user(user.clone());
+        printer.add_user(user);
+        assert_eq!(printer.len(), 2);
+    }
+
+    #[test]
+    fn front_timeouts_name_the_queue() {
+        let dir = state_dir("front_timeouts_name_the_queue");
+        let mut manager = QueueManager::from_dir(&dir, DEFAULT_QUEUE).unwrap();
+        manager.create_queue("prusa");
+        manager.set_front_timeout(Some(FrontTimeout {
+            limit: Duration::from_secs(60),
+            grace: Duration::from_secs(60),
+            action: TimeoutAction::Remove,
+        }));
+        let (a, b) = (UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP"));
+        manager.get_mut("prusa").unwrap().add_user(a.clone());
+        manager.get_mut("prusa").unwrap().add_user(b.clone());
+        manager.collect_notifications(None);
+        manager.take_notifications();
+        let now = SystemTime::now();
+
+        let warnings = manager.check_front_timeouts(now + Duration::from_secs(90));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].queue, "prusa");
+        assert!(matches!(&warnings[0].event, TimeoutEvent::Warned(user, _) if *user == a));
+
+        let removals = manager.check_front_timeouts(now + Duration::from_secs(180));
+        assert_eq!(removals.len(), 1);
+        assert!(matches!(&removals[0].event, TimeoutEvent::Removed(user) if *user == a));
+        assert!(removals[0].written.is_ok());
+        assert_eq!(manager.get("prusa").unwrap().len(), 1);
+        // The new head of the line is notified
+        assert_eq!(manager.take_notifications()[0].user, b);
+    }
+
+    #[test]
+    fn admins() {
+        let mut manager = QueueManager::from_backend(Backend::Memory, DEFAULT_QUEUE).unwrap();
+        let admin = UserID::new("UADMIN000");
+        assert!(!manager.is_admin(&admin));
+
+        manager.set_admins(vec![admin.clone()]);
+
+        assert!(manager.is_admin(&admin));
+        assert!(!manager.is_admin(&UserID::new("UA8RXUPSP")));
+    }
+
+    #[test]
+    fn people_near_the_front_are_notified() {
+        let mut manager = QueueManager::from_backend(Backend::Memory, DEFAULT_QUEUE).unwrap();
+        manager.set_notify_top(2);
+        let (a, b, c) = (
+            UserID::new("UA8RXUPSP"),
+            UserID::new("UNB2LMZRP"),
+            UserID::new("UN480W9ND"),
+        );
+        let notified = |manager: &mut QueueManager| {
+            manager
+                .take_notifications()
+                .into_iter()
+                .map(|n| (n.user.0, n.position, n.delivery))
+                .collect::<Vec<_>>()
+        };
+
+        // Nobody is told about a change they made themselves
+        for user in &[&a, &b, &c] {
+            let queue = manager.get_mut(DEFAULT_QUEUE).unwrap();
+            queue.add_user((*user).clone());
+            manager.collect_notifications(Some(user));
+        }
+        assert!(notified(&mut manager).is_empty());
+
+        let queue = manager.get_mut(DEFAULT_QUEUE).unwrap();
+        queue
+            .set_delivery(b.clone(), Delivery::DirectMessage)
+            .unwrap();
+        assert_eq!(
+            queue.metadata("notify").unwrap(),
+            Some(String::from("UNB2LMZRP dm\n"))
+        );
+
+        let (_, result) = queue.remove_user(a.clone());
+        assert!(matches!(result, RemoveResult::UserSuccessfullyRemoved(0)));
+        manager.collect_notifications(Some(&a));
+        assert_eq!(
+            notified(&mut manager),
+            [
+                (b.0.clone(), 0, Delivery::DirectMessage),
+                (c.0.clone(), 1, Delivery::Channel)
+            ]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! A stand-in for the Slack Web API, for testing the code that calls it without a network.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    thread,
+};
+
+/// What the mock Slack answers one request with.
+pub struct Response {
+    /// The status, e.g. `200 OK`
+    pub status: &'static str,
+    /// Any headers besides the ones every response has, each ending in `\r\n`
+    pub headers: &'static str,
+    /// The JSON body
+    pub body: String,
+}
+
+impl Response {
+    /// A `200 OK` response with the JSON `body`.
+    pub fn ok(body: &str) -> Self {
+        Self {
+            status: "200 OK",
+            headers: "",
+            body: body.to_string(),
+        }
+    }
+}
+
+/// A request the mock Slack received.
+#[derive(Debug)]
+pub struct Request {
+    /// The request line, e.g. `POST /api/chat.postMessage HTTP/1.1`
+    pub line: String,
+    /// The body of the request
+    pub body: String,
+}
+
+/// Serve one request per response in `responses` on a local port, in order. Returns the URL the
+/// mock Web API lives at, and a handle that yields the requests that were received once they have
+/// all been answered.
+pub fn serve(responses: Vec<Response>) -> (String, thread::JoinHandle<Vec<Request>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\
+				Connection: close\r\n\r\n{}",
+                response.status,
+                response.body.len(),
+                response.headers,
+                response.body
+            )
+            .unwrap();
+            requests.push(Request {
+                line: line.trim().to_string(),
+                body: String::from_utf8(body).unwrap(),
+            });
+        }
+        requests
+    });
+    (url, handle)
+}
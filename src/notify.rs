@@ -0,0 +1,224 @@
+//! Letting people know that their turn is coming up.
+//!
+//! Whenever a queue changes, whoever became the new head of the line, and whoever moved into one of
+//! the first few places, gets a [`Notification`](struct.Notification.html). How it reaches them is
+//! up to them: each person picks a [`Delivery`](enum.Delivery.html) for each queue with the `notify`
+//! command, and that choice is saved alongside the queue.
+//!
+//! With [`Delivery::Channel`](enum.Delivery.html#variant.Channel), which is what everyone starts
+//! with, the new head of the line is pinged in Queue's channel, as Queue has always done. Direct
+//! messages are sent by a [`Notifier`](trait.Notifier.html), e.g. the Slack Web API client in the
+//! [`web`](../web/index.html) module.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::user::UserID;
+
+/// The piece of storage metadata that holds how everyone who changed it wants to be notified about
+/// a queue: one line per person, with their Slack ID and then their [`Delivery`](enum.Delivery.html).
+pub const DELIVERY_KEY: &str = "notify";
+
+/// How many of the first places in line are notified when someone moves into them, unless another
+/// number is given to [`QueueManager::set_notify_top`](../manager/struct.QueueManager.html#method.set_notify_top).
+pub const DEFAULT_NOTIFY_TOP: usize = 3;
+
+/// How someone wants to be told that their turn is coming up.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Delivery {
+    /// With a ping in Queue's channel when they get to the front of the line.
+    #[default]
+    Channel,
+    /// With a direct message when they get to the front of the line or move into one of the first
+    /// few places, and no ping in the channel.
+    DirectMessage,
+    /// Both of the above.
+    Both,
+}
+
+impl Delivery {
+    /// Whether to ping the person in Queue's channel.
+    pub fn in_channel(self) -> bool {
+        self != Delivery::DirectMessage
+    }
+
+    /// Whether to send the person a direct message.
+    pub fn direct_message(self) -> bool {
+        self != Delivery::Channel
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Delivery::Channel => "channel",
+            Delivery::DirectMessage => "dm",
+            Delivery::Both => "both",
+        })
+    }
+}
+
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(Delivery::Channel),
+            "dm" => Ok(Delivery::DirectMessage),
+            "both" => Ok(Delivery::Both),
+            other => Err(format!(
+                "\"{}\" is not one of `channel`, `dm`, or `both`",
+                other
+            )),
+        }
+    }
+}
+
+/// Parse the [`DELIVERY_KEY`](constant.DELIVERY_KEY.html) metadata of a queue. Lines that cannot be
+/// understood are left out, so that a damaged preference never keeps a queue from loading; the
+/// people they belong to are notified in the channel.
+pub fn parse_deliveries(text: &str) -> HashMap<UserID, Delivery> {
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next().map(str::parse), words.next()) {
+                (Some(user), Some(Ok(delivery)), None) => Some((UserID::new(user), delivery)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The inverse of [`parse_deliveries`](fn.parse_deliveries.html). People are sorted by their Slack
+/// ID so that the metadata does not change from one save to the next.
+pub fn format_deliveries(deliveries: &HashMap<UserID, Delivery>) -> String {
+    let mut lines = deliveries
+        .iter()
+        .map(|(user, delivery)| format!("{} {}\n", user, delivery))
+        .collect::<Vec<_>>();
+    lines.sort();
+    lines.concat()
+}
+
+/// Someone got to the front of a queue, or moved into one of its first few places.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Notification {
+    /// Who to notify
+    pub user: UserID,
+    /// The name of the queue
+    pub queue: String,
+    /// Their new place in line, where 0 is the front
+    pub position: usize,
+    /// How they want to be notified
+    pub delivery: Delivery,
+}
+
+impl Notification {
+    /// What to tell them.
+    pub fn text(&self) -> String {
+        if self.position == 0 {
+            format!(
+                "Hey <@{}>! You're next in line for the *{}* queue!",
+                self.user, self.queue
+            )
+        } else {
+            format!(
+                "Hey <@{}>, you are now in position {} of the *{}* queue, so your turn is coming up.",
+                self.user, self.position, self.queue
+            )
+        }
+    }
+}
+
+/// Something that can send direct messages to people in the Slack workspace.
+pub trait Notifier: fmt::Debug + Send {
+    /// Send `text` to `user` in a direct message. The error describes why that failed.
+    fn direct_message(&self, user: &UserID, text: &str) -> Result<(), String>;
+}
+
+/// Send a direct message for each of `notifications` whose person wants one, reporting the ones
+/// that could not be sent on standard error.
+pub fn deliver(notifier: &dyn Notifier, notifications: &[Notification]) {
+    for notification in notifications {
+        if !notification.delivery.direct_message() {
+            continue;
+        }
+        if let Err(e) = notifier.direct_message(&notification.user, &notification.text()) {
+            eprintln!(
+                "Could not send a direct message to {}: {}",
+                notification.user, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<(UserID, String)>>);
+
+    impl Notifier for Recorder {
+        fn direct_message(&self, user: &UserID, text: &str) -> Result<(), String> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((user.clone(), text.to_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn deliveries_round_trip() {
+        let text = "UNB2LMZRP dm\nUA8RXUPSP both\nUN480W9ND channel\n";
+        let deliveries = parse_deliveries(text);
+
+        assert_eq!(deliveries.len(), 3);
+        assert_eq!(
+            deliveries[&UserID::new("UNB2LMZRP")],
+            Delivery::DirectMessage
+        );
+        assert_eq!(
+            format_deliveries(&deliveries),
+            "UA8RXUPSP both\nUN480W9ND channel\nUNB2LMZRP dm\n"
+        );
+
+        // Lines that cannot be understood are left out
+        let deliveries = parse_deliveries("UA8RXUPSP carrier-pigeon\nUNB2LMZRP dm extra\n\n");
+        assert!(deliveries.is_empty());
+    }
+
+    #[test]
+    fn only_direct_messages_are_delivered() {
+        let recorder = Recorder::default();
+        let notification = |user: &str, delivery| Notification {
+            user: UserID::new(user),
+            queue: String::from("prusa"),
+            position: 0,
+            delivery,
+        };
+
+        deliver(
+            &recorder,
+            &[
+                notification("UA8RXUPSP", Delivery::Channel),
+                notification("UNB2LMZRP", Delivery::DirectMessage),
+                notification("UN480W9ND", Delivery::Both),
+            ],
+        );
+
+        let sent = recorder.0.into_inner().unwrap();
+        assert_eq!(
+            sent.iter()
+                .map(|(user, _)| user.0.as_str())
+                .collect::<Vec<_>>(),
+            ["UNB2LMZRP", "UN480W9ND"]
+        );
+        assert_eq!(
+            sent[0].1,
+            "Hey <@UNB2LMZRP>! You're next in line for the *prusa* queue!"
+        );
+    }
+}
@@ -0,0 +1,410 @@
+//! Rules that decide whether someone may join a queue.
+//!
+//! Every [`Queue`](../queue/struct.Queue.html) holds an [`AdmissionPolicy`](trait.AdmissionPolicy.html)
+//! that is consulted each time someone tries to add themselves. This module provides a handful of
+//! built-in policies that can be combined with [`AllOf`](struct.AllOf.html), as well as a small
+//! textual format (see [`from_spec`](fn.from_spec.html)) so that each queue can be configured from Slack.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use crate::queue::Entry;
+use crate::user::UserID;
+
+/// The spec of the policy used by queues that were not given one: the rules Queue has always had.
+pub const DEFAULT_SPEC: &str = "no-back-to-back=3";
+
+/// Why someone was not allowed to join a queue. The reason is meant to be shown to the person who
+/// was turned away.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rejection(pub String);
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A rule (or set of rules) that decides who may join a queue.
+pub trait AdmissionPolicy: fmt::Debug + Send {
+    /// May `user` be added to the back of `line` at time `now`? If not, the returned `Rejection`
+    /// explains why.
+    fn admit(
+        &self,
+        line: &VecDeque<Entry>,
+        user: &UserID,
+        now: SystemTime,
+    ) -> Result<(), Rejection>;
+
+    /// Called whenever `user` leaves the queue at time `now`. Policies that need to remember who left
+    /// and when (such as [`Cooldown`](struct.Cooldown.html)) can override this; by default it does
+    /// nothing.
+    fn left(&mut self, _user: &UserID, _now: SystemTime) {}
+}
+
+/// You cannot have two back-to-back instances of yourself in line, unless there are fewer than
+/// `grace` people in line.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NoBackToBack {
+    /// While fewer than this many people are in line, back-to-back entries are allowed.
+    pub grace: usize,
+}
+
+impl NoBackToBack {
+    /// Never allow back-to-back entries.
+    pub fn new() -> Self {
+        Self { grace: 0 }
+    }
+
+    /// Allow back-to-back entries as long as there are fewer than `grace` people in line.
+    pub fn unless_shorter_than(grace: usize) -> Self {
+        Self { grace }
+    }
+}
+
+impl Default for NoBackToBack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdmissionPolicy for NoBackToBack {
+    fn admit(
+        &self,
+        line: &VecDeque<Entry>,
+        user: &UserID,
+        _now: SystemTime,
+    ) -> Result<(), Rejection> {
+        if line.len() < self.grace || line.back().map(|entry| &entry.user) != Some(user) {
+            Ok(())
+        } else {
+            Err(Rejection(String::from(
+                "you are already the last person in line. Please let others get a chance to wait \
+				in line before you go again.",
+            )))
+        }
+    }
+}
+
+/// Each person may be in line at most this many times.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MaxPerUser(pub usize);
+
+impl AdmissionPolicy for MaxPerUser {
+    fn admit(
+        &self,
+        line: &VecDeque<Entry>,
+        user: &UserID,
+        _now: SystemTime,
+    ) -> Result<(), Rejection> {
+        if line.iter().filter(|entry| entry.user == *user).count() < self.0 {
+            Ok(())
+        } else {
+            Err(Rejection(format!(
+                "you are already in line {} time{}, which is the most this queue allows.",
+                self.0,
+                if self.0 == 1 { "" } else { "s" }
+            )))
+        }
+    }
+}
+
+/// At most this many people may be in line at once.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MaxTotal(pub usize);
+
+impl AdmissionPolicy for MaxTotal {
+    fn admit(
+        &self,
+        line: &VecDeque<Entry>,
+        _user: &UserID,
+        _now: SystemTime,
+    ) -> Result<(), Rejection> {
+        if line.len() < self.0 {
+            Ok(())
+        } else {
+            Err(Rejection(format!(
+                "the queue is full ({} people are already in line).",
+                self.0
+            )))
+        }
+    }
+}
+
+/// After leaving the queue, you have to wait this long before joining it again.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Cooldown {
+    /// How long someone has to wait after leaving
+    period: Duration,
+    /// When each person last left the queue
+    last_left: HashMap<UserID, SystemTime>,
+}
+
+impl Cooldown {
+    /// Make people wait for `period` after leaving the queue before they can join again.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            last_left: HashMap::new(),
+        }
+    }
+}
+
+impl AdmissionPolicy for Cooldown {
+    fn admit(
+        &self,
+        _line: &VecDeque<Entry>,
+        user: &UserID,
+        now: SystemTime,
+    ) -> Result<(), Rejection> {
+        let elapsed = match self.last_left.get(user) {
+            // If the clock went backwards, err on the side of letting people in
+            Some(left) => now.duration_since(*left).unwrap_or(self.period),
+            None => return Ok(()),
+        };
+        if elapsed >= self.period {
+            Ok(())
+        } else {
+            Err(Rejection(format!(
+                "you left the queue recently. You can join again in {}.",
+                format_duration(self.period - elapsed)
+            )))
+        }
+    }
+
+    fn left(&mut self, user: &UserID, now: SystemTime) {
+        self.last_left.insert(user.clone(), now);
+    }
+}
+
+/// Everyone has to satisfy every one of the contained policies. An empty `AllOf` lets anyone in.
+#[derive(Debug, Default)]
+pub struct AllOf(pub Vec<Box<dyn AdmissionPolicy>>);
+
+impl AllOf {
+    /// Also require `policy` to be satisfied.
+    pub fn and<P: AdmissionPolicy + 'static>(mut self, policy: P) -> Self {
+        self.0.push(Box::new(policy));
+        self
+    }
+}
+
+impl AdmissionPolicy for AllOf {
+    fn admit(
+        &self,
+        line: &VecDeque<Entry>,
+        user: &UserID,
+        now: SystemTime,
+    ) -> Result<(), Rejection> {
+        self.0
+            .iter()
+            .try_for_each(|policy| policy.admit(line, user, now))
+    }
+
+    fn left(&mut self, user: &UserID, now: SystemTime) {
+        for policy in self.0.iter_mut() {
+            policy.left(user, now);
+        }
+    }
+}
+
+/// The policy used by queues that were not given one: the rules Queue has always had.
+///
+/// 1. If the queue is *not empty*, then a user can only be added to the queue if the person in
+///    front of them is __not themselves__.
+/// 2. If the queue *is empty*, then a user can be added up to three times.
+pub fn default_policy() -> Box<dyn AdmissionPolicy> {
+    Box::new(NoBackToBack::unless_shorter_than(3))
+}
+
+/// Build a policy from its textual description, e.g. `"no-back-to-back,max-per-user=2,cooldown=30m"`.
+///
+/// The description is a comma-separated list of the following rules, all of which must be satisfied:
+/// * `no-back-to-back` or `no-back-to-back=N`: see [`NoBackToBack`](struct.NoBackToBack.html); `N` is
+///   the grace length, which defaults to 0.
+/// * `max-per-user=N`: see [`MaxPerUser`](struct.MaxPerUser.html).
+/// * `max-total=N`: see [`MaxTotal`](struct.MaxTotal.html).
+/// * `cooldown=DURATION`: see [`Cooldown`](struct.Cooldown.html). `DURATION` is understood by
+///   [`parse_duration`](fn.parse_duration.html).
+/// * `none`: no rules at all.
+///
+/// If the description cannot be understood, a message describing the problem is returned.
+pub fn from_spec(spec: &str) -> Result<Box<dyn AdmissionPolicy>, String> {
+    let mut policies = AllOf::default();
+    for rule in spec.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (name, arg) = match rule.find('=') {
+            Some(idx) => (&rule[..idx], Some(&rule[idx + 1..])),
+            None => (rule, None),
+        };
+        let count = |arg: Option<&str>| {
+            arg.and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| format!("`{}` needs a whole number, e.g. `{}=2`", name, name))
+        };
+        match name {
+            "none" => (),
+            "no-back-to-back" => match arg {
+                None => policies = policies.and(NoBackToBack::new()),
+                Some(_) => policies = policies.and(NoBackToBack::unless_shorter_than(count(arg)?)),
+            },
+            "max-per-user" => policies = policies.and(MaxPerUser(count(arg)?)),
+            "max-total" => policies = policies.and(MaxTotal(count(arg)?)),
+            "cooldown" => {
+                let period = arg.and_then(parse_duration).ok_or_else(|| {
+                    String::from("`cooldown` needs a duration, e.g. `cooldown=30m`")
+                })?;
+                policies = policies.and(Cooldown::new(period))
+            }
+            _ => return Err(format!("unknown rule `{}`", name)),
+        }
+    }
+    Ok(Box::new(policies))
+}
+
+/// Parse a duration such as `90s`, `30m`, `2h`, `1d`, or `1h30m`. Returns `None` if `text` is not a
+/// sequence of whole numbers, each followed by one of the units `s`, `m`, `h`, or `d`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(number.parse::<u64>().ok()?.checked_mul(unit)?)?;
+        number.clear();
+    }
+    if number.is_empty() && total > 0 {
+        Some(Duration::from_secs(total))
+    } else {
+        None
+    }
+}
+
+/// Describe `duration` for humans, e.g. `2h 10m`. Durations are rounded down to the nearest
+/// second, and durations of a minute or more are rounded down to the nearest minute.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60);
+    let parts = [(days, "d"), (hours, "h"), (mins, "m")]
+        .iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{}{}", amount, unit))
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        format!("{}s", secs)
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(users: &[&str]) -> VecDeque<Entry> {
+        users
+            .iter()
+            .map(|u| Entry::new(UserID::new(u), SystemTime::now()))
+            .collect()
+    }
+
+    #[test]
+    fn no_back_to_back() {
+        let policy = NoBackToBack::new();
+        let now = SystemTime::now();
+        let user = UserID::new("UA8RXUPSP");
+
+        assert!(policy.admit(&line(&[]), &user, now).is_ok());
+        assert!(policy.admit(&line(&["UNB2LMZRP"]), &user, now).is_ok());
+        assert!(policy.admit(&line(&["UA8RXUPSP"]), &user, now).is_err());
+        assert!(NoBackToBack::unless_shorter_than(2)
+            .admit(&line(&["UA8RXUPSP"]), &user, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn max_per_user_and_total() {
+        let now = SystemTime::now();
+        let user = UserID::new("UA8RXUPSP");
+        let queue = line(&["UA8RXUPSP", "UNB2LMZRP", "UA8RXUPSP"]);
+
+        assert!(MaxPerUser(2).admit(&queue, &user, now).is_err());
+        assert!(MaxPerUser(3).admit(&queue, &user, now).is_ok());
+        assert!(MaxTotal(3).admit(&queue, &user, now).is_err());
+        assert!(MaxTotal(4).admit(&queue, &user, now).is_ok());
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut policy = Cooldown::new(Duration::from_secs(600));
+        let left = SystemTime::now();
+        let user = UserID::new("UA8RXUPSP");
+
+        assert!(policy.admit(&line(&[]), &user, left).is_ok());
+        policy.left(&user, left);
+
+        let rejection = policy
+            .admit(&line(&[]), &user, left + Duration::from_secs(60))
+            .unwrap_err();
+        assert_eq!(
+            rejection.0,
+            "you left the queue recently. You can join again in 9m."
+        );
+        assert!(policy
+            .admit(&line(&[]), &user, left + Duration::from_secs(600))
+            .is_ok());
+        assert!(policy
+            .admit(&line(&[]), &UserID::new("UNB2LMZRP"), left)
+            .is_ok());
+    }
+
+    #[test]
+    fn policies_combine() {
+        let policy = AllOf::default().and(NoBackToBack::new()).and(MaxTotal(2));
+        let now = SystemTime::now();
+        let user = UserID::new("UA8RXUPSP");
+
+        assert!(policy.admit(&line(&["UNB2LMZRP"]), &user, now).is_ok());
+        assert!(policy.admit(&line(&["UA8RXUPSP"]), &user, now).is_err());
+        assert!(policy
+            .admit(&line(&["UNB2LMZRP", "UN480W9ND"]), &user, now)
+            .is_err());
+    }
+
+    #[test]
+    fn specs() {
+        let now = SystemTime::now();
+        let user = UserID::new("UA8RXUPSP");
+        let policy = from_spec("max-per-user=1, cooldown=1h30m").unwrap();
+
+        assert!(policy.admit(&line(&["UA8RXUPSP"]), &user, now).is_err());
+        assert!(policy.admit(&line(&["UNB2LMZRP"]), &user, now).is_ok());
+        assert!(from_spec("none").is_ok());
+        assert!(from_spec(DEFAULT_SPEC).is_ok());
+        assert!(from_spec("max-per-user").is_err());
+        assert!(from_spec("cooldown=soon").is_err());
+        assert!(from_spec("first-come-first-served").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5_400)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(172_800)));
+        assert_eq!(parse_duration("30"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(format_duration(Duration::from_secs(7_800)), "2h 10m");
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(90_000)), "1d 1h");
+    }
+}
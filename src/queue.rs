@@ -1,68 +1,335 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
-    fmt,
-    fs::{File, OpenOptions},
-    io::{self, BufWriter, Seek, SeekFrom},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, io,
     ops::Deref,
     path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime},
 };
 
-use slack::RtmClient;
-
+use crate::journal::{Event, Operation};
+use crate::notify::{self, Delivery};
+use crate::policy::{self, AdmissionPolicy, Rejection};
 use crate::queue::{AddResult::*, RemoveResult::*};
-use crate::user::{SlackMap, UserID};
-
-/// The User ID (a string of the form UXXXXXXX) for the Queue app
-pub const QUEUE_UID: &str = "<@U01A844Q2US>";
-
-/// Sometimes we need these.
-pub const INSPIRATIONAL_QUOTE: &str =
-	"_Waiting in line is a great opportunity to meet people, daydream, or play._\n\t\u{2014}Patch Adams";
-
-/// Which Slack channel Queue is running in.
-const CHANNEL: &str = "3d-printer-queue";
-
-/// A help message to display when the `help` command is invoked
-const USAGE: &str = "*Queue* is a :slack: bot that keeps track of who is waiting in line to use the \
-3D printers. You interact with it by @mentioning it and then typing a command (e.g. `@Queue help`).\
-Here are the different commands *Queue* currently recognizes:\n\n\
-• *add*: Add yourself to the queue. You can add yourself multiples times, in case there are multiple \
-things you want to 3D print. However, you cannot have two back-to-back instances of yourself in the \
-queue so that you let others get a chance. However, if the queue is relatively empty (and by relatively \
-empty I mean less than 3 people in line), then you _can_ have back-to-back instances of yourself, since \
-not as many people are being negatively affected by having back-to-back instances of yourself in the queue \
-as they would be if there were more than 3 people in line.\n\
-• *done*: Leave the queue. If there are multiple instances of you in the queue, the _first_ instance \
-(i.e. the one closest to the front) is removed. If you were in 0th place when you were removed, the \
-person is 1st place is notified of this change.\n\
-• *show*: See who is in the queue and in what place.\n\
-• *help*: Display this message.";
-
-/// Given the body of a post to Slack, determine someone mentioned the Queue app
-fn is_app_mention(text: &str) -> bool {
-    text.contains(QUEUE_UID)
-}
-
-/// The main data structure for keeping track of Slack users for an event.
+use crate::storage::{self, to_unix_secs, Record, Storage};
+use crate::user::UserID;
+
+/// The piece of metadata that the lengths of the last few turns at the front of the line are saved
+/// as, one number of seconds per line, oldest first.
+pub const TURNS_KEY: &str = "turns";
+
+/// The piece of metadata that lists the places in line that are let in when the queue is loaded even
+/// though they break its admission policy, e.g. because they were in line before the rules they
+/// break existed (see the [`fsck`](../fsck/index.html) module). Each place is a line of the form
+/// `{user ID}<tab>{join time}`.
+pub const EXEMPT_KEY: &str = "exempt";
+
+/// How many of the last turns at the front of the line are remembered for estimating how long the
+/// next ones will take.
+pub const TURN_HISTORY: usize = 20;
+
+/// What someone is waiting in line to do, as far as they said. Every part of it is optional.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Job {
+    /// What the job is called, e.g. `benchy`
+    pub name: Option<String>,
+    /// What it is made of, e.g. `PLA`
+    pub material: Option<String>,
+    /// The color of the material
+    pub color: Option<String>,
+    /// How long they said their turn would take
+    pub time: Option<Duration>,
+    /// The printer the job has to be done on
+    pub printer: Option<String>,
+}
+
+impl Job {
+    /// Whether nothing was said about the job.
+    pub fn is_empty(&self) -> bool {
+        *self == Job::default()
+    }
+}
+
+/// Where a place in line is in its life, from joining the queue to leaving it.
+///
+/// A place starts out [`Waiting`](#variant.Waiting). Whoever gets to the front of the line is
+/// [`Called`](#variant.Called), and goes on to [`Printing`](#variant.Printing) once they start.
+/// A print ends up [`Finished`](#variant.Finished) or [`Failed`](#variant.Failed), and a place
+/// that is given up on at any point before that is [`Abandoned`](#variant.Abandoned). Someone who
+/// is called or printing can also be sent back to waiting, e.g. when an admin moves them back in
+/// line. The last three states are final: a place in one of them has left the queue.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum State {
+    /// In line, but not at the front
+    Waiting,
+    /// At the front of the line, but not printing yet
+    Called,
+    /// At the front of the line and printing
+    Printing,
+    /// The print is done
+    Finished,
+    /// The print did not work out
+    Failed,
+    /// The place in line was given up on (or taken away) before the print was done
+    Abandoned,
+}
+
+impl State {
+    /// Every state, in the order a place in line usually goes through them.
+    pub const ALL: [State; 6] = [
+        State::Waiting,
+        State::Called,
+        State::Printing,
+        State::Finished,
+        State::Failed,
+        State::Abandoned,
+    ];
+
+    /// The word the state is saved and shown as, e.g. `printing`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Waiting => "waiting",
+            State::Called => "called",
+            State::Printing => "printing",
+            State::Finished => "finished",
+            State::Failed => "failed",
+            State::Abandoned => "abandoned",
+        }
+    }
+
+    /// Whether a place in this state has left the queue for good.
+    pub fn is_final(self) -> bool {
+        matches!(self, State::Finished | State::Failed | State::Abandoned)
+    }
+
+    /// Whether a place in this state can go on to `next`.
+    pub fn can_become(self, next: State) -> bool {
+        match (self, next) {
+            (State::Waiting, State::Called)
+            | (State::Called, State::Printing)
+            | (State::Called, State::Waiting)
+            | (State::Printing, State::Waiting)
+            | (State::Printing, State::Finished)
+            | (State::Printing, State::Failed) => true,
+            (from, State::Abandoned) => !from.is_final(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for State {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        State::ALL
+            .iter()
+            .copied()
+            .find(|state| state.as_str() == s)
+            .ok_or_else(|| format!("{:?} is not the state of a place in line", s))
+    }
+}
+
+/// A place in line going into `state` at time `at`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Transition {
+    /// The state the place went into
+    pub state: State,
+    /// When it did
+    pub at: SystemTime,
+}
+
+/// Someone waiting in line, along with when they got in line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    /// Who is waiting
+    pub user: UserID,
+    /// When they joined the queue
+    pub joined: SystemTime,
+    /// When they got to the front of the line. This is `None` for everyone but the person at the
+    /// front of the line.
+    pub front_since: Option<SystemTime>,
+    /// What they are waiting to do
+    pub job: Job,
+    /// Every state their place in line has been in and when it went into it, oldest first. See
+    /// [`State`](enum.State.html).
+    pub transitions: Vec<Transition>,
+}
+
+impl Entry {
+    /// Someone who joined the queue at time `joined`, and has been waiting since.
+    pub fn new(user: UserID, joined: SystemTime) -> Self {
+        Self {
+            user,
+            joined,
+            front_since: None,
+            job: Job::default(),
+            transitions: vec![Transition {
+                state: State::Waiting,
+                at: joined,
+            }],
+        }
+    }
+
+    /// The state their place in line is in now.
+    pub fn state(&self) -> State {
+        self.transitions
+            .last()
+            .map_or(State::Waiting, |transition| transition.state)
+    }
+
+    /// When their place in line last went into `state`, if it ever did.
+    pub fn entered(&self, state: State) -> Option<SystemTime> {
+        self.transitions
+            .iter()
+            .rev()
+            .find(|transition| transition.state == state)
+            .map(|transition| transition.at)
+    }
+
+    /// Move their place in line into `state` at time `at`, if it can go there from the state it is
+    /// in. Returns whether it did.
+    fn transition(&mut self, state: State, at: SystemTime) -> bool {
+        let allowed = self.state().can_become(state);
+        if allowed {
+            self.transitions.push(Transition { state, at });
+        }
+        allowed
+    }
+
+    /// How long they have been waiting as of `now`.
+    pub fn waited(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.joined).unwrap_or_default()
+    }
+}
+
+impl PartialEq<UserID> for Entry {
+    fn eq(&self, other: &UserID) -> bool {
+        self.user == *other
+    }
+}
+
+/// What to do with someone who has stayed at the front of the line for too long.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TimeoutAction {
+    /// Swap them with the person behind them, so that person gets a turn.
+    MoveBack,
+    /// Take them out of the queue altogether.
+    Remove,
+}
+
+/// How long someone can stay at the front of the line without starting their print before something
+/// is done about it.
+///
+/// Once someone has been at the front of the line for `limit`, they are warned. If they are still
+/// there `grace` after that, `action` is taken.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FrontTimeout {
+    /// How long someone can be at the front of the line before they are warned
+    pub limit: Duration,
+    /// How long after the warning someone has before `action` is taken
+    pub grace: Duration,
+    /// What happens to someone who stays at the front of the line past the grace period
+    pub action: TimeoutAction,
+}
+
+impl FrontTimeout {
+    /// How long the grace period is unless told otherwise.
+    pub const DEFAULT_GRACE: Duration = Duration::from_secs(60 * 60);
+
+    /// Build a time limit out of the `--front-limit`, `--front-grace`, and `--on-timeout` options
+    /// of the bots, where durations are written like `2h30m` (see [`policy::parse_duration`](../policy/fn.parse_duration.html))
+    /// and the action is `move-back` (the default) or `remove`. There is no limit unless `limit` is
+    /// given.
+    pub fn from_options(
+        limit: Option<&str>,
+        grace: Option<&str>,
+        action: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let duration = |text: &str, opt: &str| {
+            policy::parse_duration(text)
+                .ok_or_else(|| format!("\'{}\' is not a valid duration for --{}", text, opt))
+        };
+        let limit = match limit {
+            Some(limit) => duration(limit, "front-limit")?,
+            None => return Ok(None),
+        };
+        let grace = match grace {
+            Some(grace) => duration(grace, "front-grace")?,
+            None => Self::DEFAULT_GRACE,
+        };
+        let action = match action {
+            None | Some("move-back") => TimeoutAction::MoveBack,
+            Some("remove") => TimeoutAction::Remove,
+            Some(other) => return Err(format!("\'{}\' is not a valid --on-timeout action", other)),
+        };
+        Ok(Some(Self {
+            limit,
+            grace,
+            action,
+        }))
+    }
+}
+
+/// Something that happened because the person at the front of the line stayed there too long. See
+/// [`Queue::check_front_timeout`](struct.Queue.html#method.check_front_timeout).
 #[derive(Debug)]
-pub struct Queue<'a> {
-    /// A queue of references to UserIDs in the `uid_username_mapping`
-    queue: VecDeque<UserID>,
-    /// All the possible members of a Slack workspace that can join a queue
-    uid_username_mapping: &'a SlackMap,
-    /// All the different channels in the workspace Queue is installed in, mapping channel names to
-    /// channel IDs
-    chan_name_id_mapping: BTreeMap<String, String>,
-    /// The file that `self` will write to to preserve its state (may be a database connection in the future)
-    db_conn: BufWriter<File>,
+pub enum TimeoutEvent {
+    /// The person at the front of the line has been there for the time limit, and was told that
+    /// they will be skipped in `grace` unless they finish up.
+    Warned(UserID, Duration),
+    /// The person at the front of the line was moved behind the next person in line.
+    MovedBack(UserID),
+    /// The person at the front of the line would have been moved back, but nobody is waiting behind
+    /// them, so their time at the front was started over instead.
+    Kept(UserID),
+    /// The person at the front of the line was taken out of the queue.
+    Removed(UserID),
+}
+
+/// The main data structure for keeping track of the people waiting in line for an event.
+///
+/// A queue knows who is in line and in what order, but nothing about Slack: the people in it are
+/// only known by their IDs, and every operation reports what happened with a typed result rather
+/// than a message. Turning those into Slack messages is up to the [`slack_bot`](../slack_bot/index.html)
+/// module.
+#[derive(Debug)]
+pub struct Queue {
+    /// The people in line, along with when they joined
+    queue: VecDeque<Entry>,
+    /// Where `self` saves its state so that it survives a restart
+    storage: Box<dyn Storage>,
+    /// The rules that decide who may join this queue
+    policy: Box<dyn AdmissionPolicy>,
+    /// How long someone may stay at the front of the line, if there is a limit
+    front_timeout: Option<FrontTimeout>,
+    /// Whether the person at the front of the line has been warned that their time is up
+    front_warned: bool,
+    /// How the people who chose one want to be notified about this queue
+    deliveries: HashMap<UserID, Delivery>,
+    /// How many of the first places in line people are notified about moving into
+    notify_top: usize,
+    /// Who was in those places the last time notifications were handed out
+    notified: Vec<UserID>,
+    /// How long the last few turns at the front of the line took, oldest first
+    turns: VecDeque<Duration>,
+    /// Who is making changes to the queue right now, if anyone (see
+    /// [`Queue::set_actor`](#method.set_actor))
+    actor: Option<UserID>,
 }
 
 /// A type used to represent the result of adding a user to the queue.
 #[derive(Debug)]
 pub enum AddResult {
-    /// The specified user was *not* added to the queue because they need to let someone else take a
-    /// turn before they add themselves again.
-    UserNotAdded,
+    /// The specified user was *not* added to the queue because the queue's admission policy turned
+    /// them away, e.g. because they need to let someone else take a turn before they add themselves
+    /// again. This variant contains the reason why.
+    UserNotAdded(Rejection),
     /// The specified user was successfully added.
     UserSuccessfullyAdded,
     /// The specified user was added to the queue, but there was an I/O error while writing to a file
@@ -85,26 +352,184 @@ pub enum RemoveResult {
     UserUnsuccessfullyRemoved(io::Error),
 }
 
-impl<'a> Queue<'a> {
-    /// Create an empty queue with no previous state. `uids_to_users` is a `std::collections::HashMap`
-    /// whose keys are Slack IDs and whose values are usernames associated with the given Slack ID.
-    ///
-    /// This function will also create an empty file that, over the course of the lifetime of this
-    /// queue, will be written to representing the users in the queue so that, if the app were to
-    /// crash, the queue isn't lost. By default, this file's name is `queue_state.txt`. If you want
-    /// to use a different name, use [`Queue::from_file`](#method.from_file)
-    ///
-    /// # Panics
-    /// This function will panic if the aforementioned `queue_state.txt` fails to be created.
-    pub fn new(uids_to_users: &'a SlackMap) -> Self {
+/// A type used to represent the result of an admin rearranging the queue, e.g. by moving or swapping
+/// people.
+#[derive(Debug)]
+pub enum RearrangeResult {
+    /// The queue was rearranged as requested.
+    Rearranged,
+    /// The user to be moved is not in the queue, so nothing was done.
+    UserNotInQueue,
+    /// The given position is past the end of the queue, so nothing was done. This variant contains
+    /// the offending position.
+    PositionOutOfRange(usize),
+    /// The queue was rearranged, but there was an I/O error while writing to a file that keeps the
+    /// queue persistent, so the backup file and the true state of the queue are now out of sync.
+    UnsuccessfullyRearranged(io::Error),
+}
+
+/// A type used to represent the result of changing the job of someone in line.
+#[derive(Debug)]
+pub enum EditResult {
+    /// The job was changed as requested.
+    Edited,
+    /// The given position is past the end of the queue, so nothing was done. This variant contains
+    /// the offending position.
+    PositionOutOfRange(usize),
+    /// The job was changed, but there was an I/O error while writing to a file that keeps the queue
+    /// persistent, so the backup file and the true state of the queue are now out of sync.
+    UnsuccessfullyEdited(io::Error),
+}
+
+/// A type used to represent the result of bringing places in line into the queue from somewhere
+/// else.
+#[derive(Debug)]
+pub enum ImportResult {
+    /// Every place was added to the back of the line.
+    Imported,
+    /// The queue's admission policy turned away one of the places, so nothing was added. This
+    /// variant contains where that place was among the ones being imported (counting from 0), whose
+    /// it was, and why it was turned away.
+    NotImported {
+        index: usize,
+        user: UserID,
+        rejection: Rejection,
+    },
+    /// Every place was added, but there was an I/O error while writing to a file that keeps the
+    /// queue persistent, so the backup file and the true state of the queue are now out of sync.
+    UnsuccessfullyImported(io::Error),
+}
+
+/// A type used to represent the result of moving someone's place at the front of the line into a
+/// new [`State`](enum.State.html), e.g. when they start printing.
+#[derive(Debug)]
+pub enum TransitionResult {
+    /// Their place in line is now in the new state.
+    Transitioned,
+    /// The user is not in the queue, so nothing was done.
+    UserNotInQueue,
+    /// The user is in the queue, but not at the front of the line, so nothing was done. This
+    /// variant contains the position they are in.
+    NotAtFront(usize),
+    /// Their place in line cannot go into the new state from the state it is in, so nothing was
+    /// done. This variant contains the state it is in.
+    NotAllowed(State),
+    /// Their place in line is in the new state, but there was an I/O error while writing to a file
+    /// that keeps the queue persistent, so the backup file and the true state of the queue are now
+    /// out of sync.
+    UnsuccessfullyTransitioned(io::Error),
+}
+
+/// A type used to represent why the saved state of a queue could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The saved state could not be read at all, e.g. because the file could not be opened.
+    Io(io::Error),
+    /// A line is not in the format that Queue writes. This variant contains the line number
+    /// (counting from 1) and a description of the problem.
+    InvalidLine { line: usize, reason: String },
+    /// A line puts someone in a position that an earlier line already put someone else in.
+    DuplicatePosition { line: usize, position: usize },
+    /// A line adds someone to the queue in a way its admission policy does not allow (e.g. the same
+    /// person five times in a row). This variant contains why they were turned away.
+    BreaksRules {
+        line: usize,
+        user: UserID,
+        rejection: Rejection,
+    },
+    /// Part of the [journal](../journal/index.html) of changes to the queue cannot be replayed,
+    /// for the given reason.
+    Journal { reason: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read the saved queue: {}", e),
+            LoadError::InvalidLine { line, reason } => {
+                write!(f, "line {}: invalid format: {}", line, reason)
+            }
+            LoadError::DuplicatePosition { line, position } => write!(
+                f,
+                "line {}: position {} is taken by someone on an earlier line",
+                line, position
+            ),
+            LoadError::BreaksRules {
+                line,
+                user,
+                rejection,
+            } => write!(
+                f,
+                "line {}: user {} breaks the addition rules ({})",
+                line, user, rejection
+            ),
+            LoadError::Journal { reason } => write!(f, "journal: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/// What to do when the saved state of a queue has problems in it. In every case, a queue whose
+/// saved state cannot be read at all (a [`LoadError::Io`](enum.LoadError.html#variant.Io)) is not
+/// loaded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Recovery {
+    /// Refuse to load the queue.
+    Strict,
+    /// Load everyone else, leaving out the lines with problems.
+    SkipInvalid,
+    /// Start the queue over empty, after moving the saved state aside so it can be fixed by hand.
+    StartEmpty,
+}
+
+/// What had to be done to load a queue despite problems with its saved state. See
+/// [`Queue::load`](struct.Queue.html#method.load).
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// The problems found in the saved state
+    pub problems: Vec<LoadError>,
+    /// Where the saved state was moved to, if the queue was started over empty
+    pub set_aside: Option<String>,
+    /// The backup the saved state was restored from, if it was damaged (see
+    /// [`Storage::restore`](../storage/trait.Storage.html#method.restore))
+    pub restored: Option<String>,
+}
+
+impl LoadReport {
+    /// Was the saved state loaded without any problems?
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Queue {
+    /// An empty queue that saves its state to `storage`. Nothing is read from `storage`.
+    fn empty(storage: Box<dyn Storage>, policy: Box<dyn AdmissionPolicy>) -> Self {
         Self {
             queue: VecDeque::new(),
-            uid_username_mapping: uids_to_users,
-            chan_name_id_mapping: BTreeMap::new(),
-            db_conn: BufWriter::new(
-                File::create("queue_state.txt")
-                    .expect("Could not create a backup file for the queue"),
-            ),
+            storage,
+            policy,
+            front_timeout: None,
+            front_warned: false,
+            deliveries: HashMap::new(),
+            notify_top: notify::DEFAULT_NOTIFY_TOP,
+            notified: Vec::new(),
+            turns: VecDeque::new(),
+            actor: None,
         }
     }
 
@@ -112,8 +537,17 @@ impl<'a> Queue<'a> {
     /// it from a previous state. The file should be one that was previously created by running this
     /// app. Or, if the file does not exist, it will be created.
     ///
+    /// The file is a JSON document in the format described in the
+    /// [`state_file`](../state_file/index.html) module, which has room for everything about each
+    /// place in line (e.g. when they joined and the [`State`](enum.State.html)s their place has been
+    /// in) so that front-of-line timeouts and wait estimates survive a restart. Each change to the
+    /// queue is journaled next to the file (e.g. in `queue_state.txt.journal`), just like the changes
+    /// to a queue in a [`Backend`](../storage/enum.Backend.html); see
+    /// [`storage::open_state_file`](../storage/fn.open_state_file.html).
+    ///
     /// # Examples
-    /// Say the input file contains the following content.
+    /// Files written before that format existed are upgraded as soon as they are loaded. Say the
+    /// input file contains the following content.
     /// ```text
     /// 0 UA8RXUPSP
     /// 1 UNB2LMZRP
@@ -121,132 +555,352 @@ impl<'a> Queue<'a> {
     /// ```
     /// Then invoking this function with the above file will create a new `Queue` where the first
     /// person in line has an ID of `UA8RXUPSP`, the second person in line has an ID of `UNB2LMZRP`,
-    /// and the third person in line has an ID of `UN480W9ND`.
+    /// and the third person in line has an ID of `UN480W9ND`, and rewrite the file in the current
+    /// format. Each line may also contain, after the user ID, the time (in seconds since the Unix
+    /// epoch) the person joined the queue, followed by the time they got to the front of the line,
+    /// and so on (see [`TextFileStorage`](../storage/struct.TextFileStorage.html)). If the
+    /// timestamps are missing, the time the file is loaded is used instead.
     ///
-    /// # Panics
-    /// For better or worse, there are many ways this function can panic.
-    /// * If the file breaks the "rules" (see the documentation for
-    /// [`Queue::add_user`](#method.add_user)) of adding people to the queue (e.g. the file contains
-    /// the same person five times in a row, which shouldn't be allowed under any circumstance), then
-    /// the file will be rejected and this function will panic.
-    /// * If the file at `path` fails to open for any reason (e.g. permissions).
-    /// * If the file at `path` fails to be read for any reason.
-    /// * If the file is _not_ in the valid format expected by queue: each line is a positive integer,
-    /// followed by a tab, followed by a Slack user ID.
-    pub fn from_file<P: AsRef<Path>>(uids_to_users: &'a SlackMap, path: P) -> Self {
-        use std::io::Read; // needed for the invocation of read_to_string()
-
-        let mut people = BTreeMap::new();
-        let mut backup_file_contents = String::new();
-        let backup_file_name = path.as_ref().as_os_str().to_owned();
-        let mut backup_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(path)
-            .expect("Could not open backup file for the queue");
-
-        if let Err(e) = backup_file.read_to_string(&mut backup_file_contents) {
-            panic!("could not read {:?}: {}", backup_file_name, e);
-        }
-
-        // Iterate only over the lines that have content (i.e. are not all whitespace)
-        for line in backup_file_contents
-            .lines()
-            .filter(|s| !s.trim().is_empty())
-        {
-            let [pos, uid] = {
-                let err_msg = "Invalid file format: each line must contain a parse-able \
-				positive integer followed by some amount of whitespace, followed by a Slack user-id";
-                let mut iter = line.split_whitespace();
-                [iter.next().expect(err_msg), iter.next().expect(err_msg)]
-            };
-            let pos = pos.parse::<usize>().expect(
-                "Invalid file format: each line in the file must start with a parse-\
-				able positive integer",
-            );
-            if let Some(_) = people.insert(pos, uid) {
-                panic!("Invalid file format: only one person per position (index) in line");
-            }
+    /// # Errors
+    /// A [`LoadError`](enum.LoadError.html) describing the first problem is returned if
+    /// * the file breaks the "rules" (see the documentation for [`Queue::add_user`](#method.add_user))
+    ///   of adding people to the queue (e.g. the file contains the same person five times in a row,
+    ///   which shouldn't be allowed under any circumstance);
+    /// * the file at `path` fails to be opened or read for any reason (e.g. permissions), or was
+    ///   written by a newer version of Queue in a format this one does not understand;
+    /// * the file is _not_ in the valid format expected by queue (see above);
+    /// * two entries (or, in a legacy file, lines) put people in the same position.
+    ///
+    /// If the file is damaged (e.g. a line cannot be read), the newest of its backups that is not
+    /// is loaded instead; see [`TextFileStorage`](../storage/struct.TextFileStorage.html). Use
+    /// [`Queue::load`](#method.load) to load the rest of a damaged file anyway.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        Self::from_file_with_policy(path, policy::default_policy())
+    }
+
+    /// Like [`Queue::from_file`](#method.from_file), but the queue admits people according to
+    /// `policy` instead of the default rules. The contents of the file have to satisfy `policy` too.
+    ///
+    /// # Errors
+    /// This function fails under the same circumstances as [`Queue::from_file`](#method.from_file).
+    pub fn from_file_with_policy<P: AsRef<Path>>(
+        path: P,
+        policy: Box<dyn AdmissionPolicy>,
+    ) -> Result<Self, LoadError> {
+        let storage = storage::open_state_file(path)?;
+        Self::from_storage(Box::new(storage), policy)
+    }
+
+    /// Create a queue whose state is whatever was last saved to `storage`, admitting people according
+    /// to `policy`. From then on, the queue saves its state to `storage` after every change. See the
+    /// [`storage`](../storage/index.html) module for the kinds of storage there are.
+    ///
+    /// # Errors
+    /// This function fails if `storage` cannot be read, or under the same circumstances as
+    /// [`Queue::from_file`](#method.from_file) if the saved state has problems in it.
+    pub fn from_storage(
+        storage: Box<dyn Storage>,
+        policy: Box<dyn AdmissionPolicy>,
+    ) -> Result<Self, LoadError> {
+        match Self::load(storage, policy, Recovery::Strict)? {
+            (queue, report) if report.is_clean() => Ok(queue),
+            (_, mut report) => Err(report.problems.remove(0)),
         }
+    }
 
-        let mut queue = Self {
-            queue: VecDeque::with_capacity(people.len()),
-            uid_username_mapping: uids_to_users,
-            chan_name_id_mapping: BTreeMap::new(),
-            db_conn: BufWriter::new(backup_file),
+    /// Like [`Queue::from_storage`](#method.from_storage), but problems with the saved state are
+    /// dealt with according to `recovery` instead of always being fatal. Along with the queue, a
+    /// report of what had to be done to load it is returned.
+    ///
+    /// With [`Recovery::Strict`](enum.Recovery.html#variant.Strict), the queue is still returned
+    /// if there are problems, but it is empty and nothing was done to `storage`; callers should check
+    /// the report before using it.
+    ///
+    /// # Errors
+    /// This function fails if `storage` cannot be read, or if the saved state cannot be moved aside.
+    pub fn load(
+        mut storage: Box<dyn Storage>,
+        policy: Box<dyn AdmissionPolicy>,
+        recovery: Recovery,
+    ) -> Result<(Self, LoadReport), LoadError> {
+        let now = SystemTime::now();
+        let restored = storage.restore()?;
+        let records = storage.load()?;
+        let deliveries = storage.metadata(notify::DELIVERY_KEY)?;
+        let turns = storage.metadata(TURNS_KEY)?;
+        let exempt = storage.metadata(EXEMPT_KEY)?;
+        let exempt = exempt.as_deref().map(parse_exempt).unwrap_or_default();
+
+        let mut queue = Self::empty(storage, policy);
+        queue.deliveries = deliveries
+            .as_deref()
+            .map(notify::parse_deliveries)
+            .unwrap_or_default();
+        queue.turns = turns.as_deref().map(parse_turns).unwrap_or_default();
+        let mut report = LoadReport {
+            restored,
+            ..LoadReport::default()
         };
+        queue.queue.reserve(records.len());
+        for record in records {
+            let Record { line, entry } = match record {
+                Ok(record) => record,
+                Err(problem) => {
+                    report.problems.push(problem);
+                    continue;
+                }
+            };
+            let user = entry.user.clone();
+            let mut entry = entry;
+            // Places saved before they had states have been waiting since they joined
+            if entry.transitions.is_empty() {
+                entry.transitions.push(Transition {
+                    state: State::Waiting,
+                    at: entry.joined,
+                });
+            }
+            if exempt.contains(&(user.clone(), to_unix_secs(entry.joined))) {
+                queue.queue.push_back(entry);
+            } else if let Err(rejection) = queue.add_entry_no_write(entry) {
+                report.problems.push(LoadError::BreaksRules {
+                    line,
+                    user,
+                    rejection,
+                });
+            }
+        }
 
-        for (pos, person) in people {
-            if !queue.add_user_no_write(UserID::new(person)) {
-                panic!(
-                    "user {} in position {} \"breaks the addition rules\": see the Queue document\
-				ation for more",
-                    person, pos
-                );
+        if !report.is_clean() {
+            match recovery {
+                Recovery::Strict => queue.queue.clear(),
+                Recovery::SkipInvalid => (),
+                Recovery::StartEmpty => {
+                    queue.queue.clear();
+                    report.set_aside = Some(queue.storage.set_aside()?);
+                }
             }
         }
+        // Only the person at the front of the line should have a front-of-line time
+        for entry in queue.queue.iter_mut().skip(1) {
+            send_back(entry, now);
+        }
+        queue.update_front(now);
+        // Whoever was already near the front before a restart has been told about it
+        queue.notified = queue.top();
+        if recovery == Recovery::SkipInvalid && !report.is_clean() {
+            // Write out what was kept so the problems are gone the next time the queue is loaded
+            queue.write_state(Operation::Recover)?;
+        }
 
-        queue
+        Ok((queue, report))
     }
 
-    /// Writes the current state of `self` to `self.db_conn` so that this particular state can be
-    /// reloaded later.
-    fn write_state(&mut self) -> io::Result<()> {
-        use std::io::Write; // needed for the invocation of std::io::Write::flush
+    /// Writes the current state of `self` to `self.storage` so that this particular state can be
+    /// reloaded later, along with the `operation` that led to it and who did it.
+    fn write_state(&mut self, operation: Operation) -> io::Result<()> {
+        let event = Event {
+            at: SystemTime::now(),
+            actor: self.actor.clone(),
+            operation,
+        };
+        self.storage.save_event(&event, &self.queue)
+    }
 
-        // We write the state to a temporary buffer before writing the entirety of the buffer to the
-        // file.
-        let mut output = Vec::new();
-        // For each user in the queue, write the line
-        // {user position}<tab>{user ID}
-        // returning early if any line fails.
-        // Otherwise, flush the BufWriter to the file and hope it works :P
-        for (pos, uid) in self.queue.iter().enumerate() {
-            write!(output, "{}\t{}\n", pos, uid)?;
-        }
+    /// Everything that is done to the queue from now on is done by `actor`, or by nobody in
+    /// particular if it is `None`. This is only used to write down who did what (see the
+    /// [`journal`](../journal/index.html) module); it does not affect what anyone is allowed to do.
+    pub fn set_actor(&mut self, actor: Option<UserID>) {
+        self.actor = actor;
+    }
 
-        // Get the number of bytes in the file currently
-        let num_bytes = self.db_conn.seek(SeekFrom::End(0))?;
-        // Now go back to the start of the file
-        self.db_conn.seek(SeekFrom::Start(0))?;
-        // And now create a bunch of blanks to erase the file
-        let blanks = vec![b' '; num_bytes as usize];
-        self.db_conn.write_all(&*blanks)?;
-        // This is getting tiring...go back to the start of the file
-        self.db_conn.seek(SeekFrom::Start(0))?;
-        // Write the new state
-        self.db_conn.write_all(output.as_slice())?;
-        self.db_conn.flush()
+    /// The piece of metadata called `key` that was saved alongside this queue, if any.
+    pub fn metadata(&mut self, key: &str) -> io::Result<Option<String>> {
+        self.storage.metadata(key)
     }
 
-    /// Can `user` be added to `self` based on the following rules?
-    ///
-    /// 1. If the queue is *not empty*, then a user can only be added to the queue if the person in
-    /// front of them is __not themselves__.
-    /// 2. If the queue *is empty*, then a user can be added up to three times.
-    fn can_add(&self, user: &UserID) -> bool {
-        self.len() < 3 || self.back() != Some(user)
+    /// Save `value` alongside this queue as the piece of metadata called `key`.
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.storage.set_metadata(key, value)
+    }
+
+    /// How `user` wants to be notified about this queue.
+    pub fn delivery(&self, user: &UserID) -> Delivery {
+        self.deliveries.get(user).copied().unwrap_or_default()
+    }
+
+    /// Notify `user` about this queue according to `delivery` from now on. The choice is saved
+    /// alongside the queue.
+    pub fn set_delivery(&mut self, user: UserID, delivery: Delivery) -> io::Result<()> {
+        self.deliveries.insert(user, delivery);
+        let text = notify::format_deliveries(&self.deliveries);
+        self.storage.set_metadata(notify::DELIVERY_KEY, &text)
+    }
+
+    /// Notify people when they move into one of the first `top` places in line (or to the front,
+    /// whatever `top` is, unless it is 0).
+    pub fn set_notify_top(&mut self, top: usize) {
+        self.notify_top = top;
+        self.notified.truncate(top);
+    }
+
+    /// The people in the places that are notified about, front first.
+    fn top(&self) -> Vec<UserID> {
+        self.queue
+            .iter()
+            .take(self.notify_top)
+            .map(|entry| entry.user.clone())
+            .collect()
+    }
+
+    /// Who got to the front of the line or moved into one of the first few places since the last
+    /// time this was called, along with their new place in line. See
+    /// [`Queue::set_notify_top`](#method.set_notify_top).
+    pub fn take_top_changes(&mut self) -> Vec<(UserID, usize)> {
+        let top = self.top();
+        let changes = top
+            .iter()
+            .enumerate()
+            .filter(|&(position, user)| match position {
+                0 => self.notified.first() != Some(user),
+                _ => !self.notified.contains(user),
+            })
+            .map(|(position, user)| (user.clone(), position))
+            .collect();
+        self.notified = top;
+        changes
+    }
+
+    /// Replace the rules that decide who may join this queue. People already in line are not
+    /// affected.
+    pub fn set_policy(&mut self, policy: Box<dyn AdmissionPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Can `user` be added to `self` according to its admission policy? If not, the returned
+    /// `Rejection` says why.
+    pub fn admit(&self, user: &UserID) -> Result<(), Rejection> {
+        self.policy.admit(&self.queue, user, SystemTime::now())
+    }
+
+    /// Can `user` be added to `self` according to its admission policy?
+    pub fn can_add(&self, user: &UserID) -> bool {
+        self.admit(user).is_ok()
+    }
+
+    /// Add someone to the back of the queue _without_ writing to the backup file, returning `Ok(())`
+    /// if they could be added per the rules, and the reason they could not be otherwise. See
+    /// [`Queue::add_user`](#method.add_user) for more.
+    fn add_entry_no_write(&mut self, entry: Entry) -> Result<(), Rejection> {
+        self.admit(&entry.user)?;
+        self.queue.push_back(entry);
+        Ok(())
     }
 
-    /// Add a user to the back of the queue _without_ writing to the backup file, returning true if
-    /// the user could be added per the rules, and false otherwise. See
+    /// Add a user to the back of the queue _without_ writing to the backup file, returning `Ok(())`
+    /// if the user could be added per the rules, and the reason they could not be otherwise. See
     /// [`Queue::add_user`](#method.add_user) for more.
-    fn add_user_no_write(&mut self, user: UserID) -> bool {
-        if self.can_add(&user) {
-            self.queue.push_back(user);
-            true
+    fn add_user_no_write(&mut self, user: UserID, job: Job) -> Result<(), Rejection> {
+        let now = SystemTime::now();
+        self.add_entry_no_write(Entry {
+            job,
+            ..Entry::new(user, now)
+        })?;
+        self.update_front(now);
+        Ok(())
+    }
+
+    /// Make sure the person at the front of the line knows when they got there, and that they have
+    /// been called. This should be called after any change to the order of the queue.
+    fn update_front(&mut self, now: SystemTime) {
+        if let Some(front) = self.queue.front_mut() {
+            if front.front_since.is_none() {
+                front.front_since = Some(now);
+                self.front_warned = false;
+            }
+            if front.state() == State::Waiting {
+                let since = front.front_since.unwrap_or(now);
+                front.transition(State::Called, since);
+            }
+        }
+    }
+
+    /// Limit how long someone can stay at the front of the line, or remove the limit with `None`.
+    pub fn set_front_timeout(&mut self, timeout: Option<FrontTimeout>) {
+        self.front_timeout = timeout;
+    }
+
+    /// Check whether the person at the front of the line has been there too long as of `now`, and
+    /// warn, move back, or remove them according to the queue's [`FrontTimeout`](struct.FrontTimeout.html).
+    /// Returns what happened, if anything.
+    ///
+    /// Someone is warned once they have been at the front of the line for the time limit. If they
+    /// are still there when the grace period after that is over, they are moved back or removed.
+    /// Only people who have been called count: once someone has started printing, their print takes
+    /// as long as it takes. This function writes to the backup file if the queue changes; a failure
+    /// to do so is returned alongside the event.
+    pub fn check_front_timeout(
+        &mut self,
+        now: SystemTime,
+    ) -> Option<(TimeoutEvent, io::Result<()>)> {
+        let timeout = self.front_timeout?;
+        let front = self
+            .queue
+            .front()
+            .filter(|front| front.state() == State::Called)?;
+        let waited = now
+            .duration_since(front.front_since?)
+            .unwrap_or(Duration::from_secs(0));
+
+        if waited >= timeout.limit + timeout.grace {
+            let user = front.user.clone();
+            let front_user = user.clone();
+            let mut recorded = Ok(());
+            let event = match timeout.action {
+                TimeoutAction::MoveBack if self.queue.len() > 1 => {
+                    self.queue.swap(0, 1);
+                    send_back(&mut self.queue[1], now);
+                    TimeoutEvent::MovedBack(user)
+                }
+                // Nobody is waiting behind them, so start their time over
+                TimeoutAction::MoveBack => {
+                    self.queue[0].front_since = None;
+                    TimeoutEvent::Kept(user)
+                }
+                TimeoutAction::Remove => {
+                    if let Some(removed) = self.queue.pop_front() {
+                        self.turn_ended(&removed, now);
+                        recorded = self.retire(removed, State::Abandoned, now);
+                    }
+                    self.policy.left(&user, now);
+                    TimeoutEvent::Removed(user)
+                }
+            };
+            self.update_front(now);
+            // update_front only resets the warning if there is someone new at the front
+            self.front_warned = false;
+            // Nobody in particular moved them
+            let actor = self.actor.take();
+            let written = self.write_state(Operation::TimedOut { user: front_user });
+            self.actor = actor;
+            Some((event, written.and(recorded)))
+        } else if waited >= timeout.limit && !self.front_warned {
+            self.front_warned = true;
+            Some((
+                TimeoutEvent::Warned(front.user.clone(), timeout.limit + timeout.grace - waited),
+                Ok(()),
+            ))
         } else {
-            false
+            None
         }
     }
 
     /// Add a User to the back of the queue.
     ///
-    /// People are allowed to be in the queue multiple times. The rules are as follows:
+    /// People are allowed to be in the queue multiple times, as long as the queue's admission policy
+    /// (see [`Queue::set_policy`](#method.set_policy)) lets them in. Unless told otherwise, the
+    /// rules are as follows:
     /// 1. If the queue is *not empty*, then a user can only be added to the queue if the person in front
-    /// them is __not themselves__.
+    ///    them is __not themselves__.
     /// 2. If the queue *is empty*, then a user can be added up to three times.
     ///
     /// This function will write to the backup file that persists the state of the queue. If that
@@ -254,77 +908,24 @@ impl<'a> Queue<'a> {
     /// *a reference to* the user that was just added to the queue but __not__ to the backup file,
     /// and `e` is a `std::io::Error` describing what went wrong. If the user couldn't be added to the
     /// queue in the first place (because the addition would have violated the rules stated above),
-    /// then __no file I/O occurs__ and a `(u, AddResult::UserNotAddded)` is returned, where `u` is
-    /// *a reference to* the user that was *going to be* added. Otherwise, a
+    /// then __no file I/O occurs__ and a `(u, AddResult::UserNotAddded(r))` is returned, where `u` is
+    /// *a reference to* the user that was *going to be* added and `r` says why. Otherwise, a
     /// `(u, AddResult::UserSuccessfullyAdded)` is returned, where `u` is a *a reference to* the user
     /// that was just added to the queue.
     pub fn add_user(&mut self, user: UserID) -> (UserID, AddResult) {
-        if self.add_user_no_write(user.clone()) {
-            match self.write_state() {
-                Ok(()) => (user, UserSuccessfullyAdded),
-                Err(e) => (user, UserUnsuccessfullyAdded(e)),
-            }
-        } else {
-            (user, UserNotAdded)
-        }
-    }
-
-    /// Handle the add command. Returns a message to post in the Slack channel depending on whether
-    /// or not the user was actually added.
-    fn add(&mut self, user: UserID) -> String {
-        match self.add_user(user) {
-            (user, UserSuccessfullyAdded) => {
-                format!("Okay <@{}>, I have added you to the queue.", user)
-            }
-            (user, UserNotAdded) => format!(
-                "<@{}>, you cannot be added to the queue at \
-			this time. Please let others get a chance to wait in line before you go again.",
-                user
-            ),
-            (user, UserUnsuccessfullyAdded(e)) => format!(
-                "Hi <@{}>. You have been \
-			added to the queue, but this change has not been reflected in the backup file that stores \
-			the state of the queue. If it helps, the reason why is: {}",
-                user, e
-            ),
-        }
+        self.add_job(user, Job::default())
     }
 
-    /// Handle the done command. Returns a message to post in the Slack channel depending on whether
-    /// or not the user was removed.
-    fn done(&mut self, user: UserID) -> String {
-        match self.remove_user(user) {
-            (user, UserSuccessfullyRemoved(idx)) => {
-                let mut response = format!(
-                    "Okay <@{}>, you have been removed from{}the queue.",
-                    user.0,
-                    if idx == 0 { " the front of " } else { " " }
-                );
-                // If the person just removed was at the front, then notify the next person in line
-                // (if there is one)
-                if idx == 0 {
-                    match self.peek_first_user_in_line() {
-                        Some(next) => {
-                            response.push_str("\nHey <@");
-                            response.push_str(&*next.0);
-                            response.push_str(">! You\'re next in line!");
-                        }
-                        None => response.push_str("\nNobody is next in line!"),
-                    }
-                }
-                response
-            }
-            (user, NonExistentUser) => format!(
-                "<@{}>, you cannot be removed; you are not \
-			in the queue.",
-                user
-            ),
-            (user, UserUnsuccessfullyRemoved(e)) => format!(
-                "Hi <@{}>. You were removed \
-			from the queue, but this change has not been reflected in the backup file that stores \
-			the state of the queue. If it helps, the reason why is: {}",
-                user, e
-            ),
+    /// Like [`Queue::add_user`](#method.add_user), but `user` says what they are waiting to do. How
+    /// long they said the `job` will take is used to estimate how long the people behind them will
+    /// have to wait (see [`Queue::estimated_waits`](#method.estimated_waits)).
+    pub fn add_job(&mut self, user: UserID, job: Job) -> (UserID, AddResult) {
+        match self.add_user_no_write(user.clone(), job) {
+            Ok(()) => match self.write_state(Operation::Add { user: user.clone() }) {
+                Ok(()) => (user, UserSuccessfullyAdded),
+                Err(e) => (user, UserUnsuccessfullyAdded(e)),
+            },
+            Err(rejection) => (user, UserNotAdded(rejection)),
         }
     }
 
@@ -333,8 +934,8 @@ impl<'a> Queue<'a> {
     ///
     /// Returns `None` if the queue is empty. Else returns `Some(user)` where `user` is the user at
     /// the front of the line.
-    pub(in crate) fn peek_first_user_in_line(&self) -> Option<&UserID> {
-        self.queue.get(0)
+    pub fn peek_first_user_in_line(&self) -> Option<&UserID> {
+        self.queue.front().map(|entry| &entry.user)
     }
 
     /// Remove the particular user in the queue, e.g. if they no longer want to wait in line.
@@ -352,7 +953,7 @@ impl<'a> Queue<'a> {
     /// position `u` was in _before_ they were removed from the queue (0 is the first position in the
     /// queue). In all other cases, `Ok(None)` is returned.
     pub fn remove_user(&mut self, user: UserID) -> (UserID, RemoveResult) {
-        match self.queue.iter().position(|u| *u == user) {
+        match self.queue.iter().position(|entry| entry.user == user) {
             Some(idx) => {
                 // If we attempt to remove a non-existent user, Iter::position will return None, so
                 // *in theory* idx should refer to a valid index in the queue.
@@ -360,158 +961,341 @@ impl<'a> Queue<'a> {
                     .queue
                     .remove(idx)
                     .expect("Attempted to remove a non-existent user");
-                match self.write_state() {
-                    Ok(()) => (removed, UserSuccessfullyRemoved(idx)),
-                    Err(e) => (removed, UserUnsuccessfullyRemoved(e)),
+                let now = SystemTime::now();
+                if idx == 0 {
+                    self.turn_ended(&removed, now);
+                }
+                let recorded = self.retire(removed, State::Abandoned, now);
+                self.policy.left(&user, now);
+                self.update_front(now);
+                let written = self.write_state(Operation::Remove {
+                    user: user.clone(),
+                    position: idx,
+                });
+                match written.and(recorded) {
+                    Ok(()) => (user, UserSuccessfullyRemoved(idx)),
+                    Err(e) => (user, UserUnsuccessfullyRemoved(e)),
                 }
             }
             None => (user, NonExistentUser),
         }
     }
 
-    /// Given a Slack ID, return the real name-maybe username pair associated with that ID, if there is one.
-    fn get_username_by_id(&self, id: &UserID) -> Option<&(Option<String>, Option<String>)> {
-        self.uid_username_mapping.get(id)
+    /// Move the _first_ instance of `user` in the queue to position `to`, shifting everyone in
+    /// between. Unlike [`Queue::add_user`](#method.add_user), this ignores the queue's admission
+    /// policy, since it is meant for admins fixing up the queue by hand.
+    pub fn move_user(&mut self, user: &UserID, to: usize) -> RearrangeResult {
+        let from = match self.queue.iter().position(|entry| entry.user == *user) {
+            Some(from) => from,
+            None => return RearrangeResult::UserNotInQueue,
+        };
+        if to >= self.queue.len() {
+            return RearrangeResult::PositionOutOfRange(to);
+        }
+        let entry = self
+            .queue
+            .remove(from)
+            .expect("Attempted to move a non-existent user");
+        self.queue.insert(to, entry);
+        self.rearranged(Operation::Move {
+            user: user.clone(),
+            from,
+            to,
+        })
     }
 
-    /// Given the `body` of what `user` posted when mentioning Queue, determine what to say back.
-    ///
-    /// Currently, this function takes a **mutable reference** to `self` and has the side-effect of
-    /// mutating `self`. In the future, it might return another value indicating how to mutate queue
-    /// after invocation of this method.
-    pub fn determine_response(&mut self, user: UserID, body: &str) -> String {
-        /*
-            Commands are only activated when the body has an @Queue. But we need to strip the command
-            of its @Queue mention before seeing what the user wants Queue to do.
-        */
-        // TODO: handle cases where the mention is not at the beginning of the string
-        let lowercase_queue_id = QUEUE_UID.to_lowercase();
-        let body = body.to_lowercase();
-        let body = body.trim_start_matches(lowercase_queue_id.as_str());
-
-        match body.trim() {
-            "add" => self.add(user),
-            // "cancel" => self.cancel(user),
-            "done" => self.done(user),
-            "show" => format!("{}", self),
-            "help" => String::from(USAGE),
-            s => format!("Unrecognized command {}. Try `@Queue help`.", s),
-        }
-    }
-}
-
-impl slack::EventHandler for Queue<'_> {
-    fn on_event(&mut self, cli: &RtmClient, event: slack::Event) {
-        if cfg!(debug_assertions) {
-            println!("Got event: {:?}", event);
-        }
-        match event {
-            slack::Event::Message(message) => {
-                if let slack::Message::Standard(ms) = *message {
-                    // The channel the message was posted in
-                    let chan = ms.channel.expect("Channel does not exist");
-                    /*
-                        Unfortunately, this Slack bot uses the (deprecated) Slack "real time messaging" (RTM)
-                        API because the slack crate only works with that API (not that I need the slack crate
-                        but the only other option is using Rust's advanced, concurrency-first HTTP libraries
-                        to build my own web API for this Slack bot, and I found working with the slack crate
-                        easier, even if it means using a deprecated API.
-
-                        I can see why Slack is encouraging you to use the new API ("granular scopes"). As the
-                        name suggests, that API allows you to be a lot more granular with the permissions you
-                        give the bot. I suspect that API allows you to only run the bot in certain channels,
-                        unlike the RTM API where the bot receives any event that happens in any channel where it
-                        has been "invited" to. So, to simulate some granularity, I am adding a check to see if
-                        the bot should respond, or if t was invoked in a channel it should not be in (see the
-                        CHANNEL variable).
-                    */
-                    if Some(&chan) == self.chan_name_id_mapping.get(CHANNEL) {
-                        // The content of the message
-                        let text = ms.text.unwrap_or_default();
-                        if is_app_mention(&*text) {
-                            // Who posted the message
-                            let user = ms.user.expect("User does not exist");
-                            // What to send back to Slack
-                            let response = self.determine_response(UserID(user), text.as_str());
-                            // Send 'em back!
-                            let _ = cli.sender().send_message(&*chan, &*response);
-                        }
-                    } else {
-                        let response = match self.chan_name_id_mapping.get(CHANNEL) {
-                            Some(chan_id) => {
-                                format!("Try invoking that same command in <#{}>!", chan_id)
-                            }
-                            None => format!("Try invoking that same command in #{}!", CHANNEL),
-                        };
-                        let _ = cli.sender().send_message(&*chan, &*response);
-                    }
-                }
+    /// Swap the people in positions `a` and `b`. Like [`Queue::move_user`](#method.move_user), this
+    /// ignores the queue's admission policy.
+    pub fn swap(&mut self, a: usize, b: usize) -> RearrangeResult {
+        if let Some(&pos) = [a, b].iter().find(|pos| **pos >= self.queue.len()) {
+            return RearrangeResult::PositionOutOfRange(pos);
+        }
+        self.queue.swap(a, b);
+        self.rearranged(Operation::Swap { a, b })
+    }
+
+    /// Replace the job of whoever is in position `position` with `job`. Who may change which job is
+    /// up to the caller.
+    pub fn set_job(&mut self, position: usize, job: Job) -> EditResult {
+        match self.queue.get_mut(position) {
+            Some(entry) => entry.job = job,
+            None => return EditResult::PositionOutOfRange(position),
+        }
+        match self.write_state(Operation::Edit { position }) {
+            Ok(()) => EditResult::Edited,
+            Err(e) => EditResult::UnsuccessfullyEdited(e),
+        }
+    }
+
+    /// Add `entries` to the back of the queue in order, e.g. to bring in a queue that was kept
+    /// somewhere else. Each place is checked against the queue's admission policy as if the ones
+    /// before it had just been added, and if any of them is turned away, none of them are added.
+    /// Places keep when they joined and their job, but start out waiting, whatever state they were
+    /// in before.
+    pub fn import(&mut self, entries: Vec<Entry>) -> ImportResult {
+        let now = SystemTime::now();
+        let (len, count) = (self.queue.len(), entries.len());
+        for (index, entry) in entries.into_iter().enumerate() {
+            let user = entry.user.clone();
+            let entry = Entry {
+                job: entry.job,
+                ..Entry::new(entry.user, entry.joined)
+            };
+            if let Err(rejection) = self.add_entry_no_write(entry) {
+                self.queue.truncate(len);
+                return ImportResult::NotImported {
+                    index,
+                    user,
+                    rejection,
+                };
             }
-            _ => (),
+        }
+        self.update_front(now);
+        match self.write_state(Operation::Import { count }) {
+            Ok(()) => ImportResult::Imported,
+            Err(e) => ImportResult::UnsuccessfullyImported(e),
+        }
+    }
+
+    /// Remove everyone from the queue, returning who was in line (in order) and the result of writing
+    /// the now-empty queue to the backup file.
+    pub fn clear(&mut self) -> (Vec<UserID>, io::Result<()>) {
+        let now = SystemTime::now();
+        let mut removed = Vec::with_capacity(self.queue.len());
+        let mut recorded = Ok(());
+        for entry in std::mem::take(&mut self.queue) {
+            self.policy.left(&entry.user, now);
+            removed.push(entry.user.clone());
+            recorded = recorded.and(self.retire(entry, State::Abandoned, now));
+        }
+        (removed, self.write_state(Operation::Clear).and(recorded))
+    }
+
+    /// The person at the front of the line, `user`, has started printing.
+    ///
+    /// Only someone who has been called to the front of the line can start; see
+    /// [`State`](enum.State.html). This function writes to the backup file that persists the state
+    /// of the queue.
+    pub fn start(&mut self, user: &UserID) -> TransitionResult {
+        if let Err(result) = self.check_front(user) {
+            return result;
+        }
+        let front = &mut self.queue[0];
+        if !front.transition(State::Printing, SystemTime::now()) {
+            return TransitionResult::NotAllowed(front.state());
+        }
+        match self.write_state(Operation::Start { user: user.clone() }) {
+            Ok(()) => TransitionResult::Transitioned,
+            Err(e) => TransitionResult::UnsuccessfullyTransitioned(e),
+        }
+    }
+
+    /// The print of the person at the front of the line, `user`, is done, so they leave the queue
+    /// and the next person is called. Like [`Queue::start`](#method.start), this writes to the
+    /// backup file.
+    pub fn finish(&mut self, user: &UserID) -> TransitionResult {
+        self.end_print(user, State::Finished, false)
+    }
+
+    /// The print of the person at the front of the line, `user`, did not work out. They leave the
+    /// queue, unless they want to `retry`, in which case they get a new place at the front of the
+    /// line (for the same job) and are called again. Like [`Queue::start`](#method.start), this
+    /// writes to the backup file.
+    pub fn fail(&mut self, user: &UserID, retry: bool) -> TransitionResult {
+        self.end_print(user, State::Failed, retry)
+    }
+
+    /// Check that the _first_ instance of `user` in the queue is at the front of the line, or say
+    /// why not.
+    fn check_front(&self, user: &UserID) -> Result<(), TransitionResult> {
+        match self.queue.iter().position(|entry| entry.user == *user) {
+            Some(0) => Ok(()),
+            Some(position) => Err(TransitionResult::NotAtFront(position)),
+            None => Err(TransitionResult::UserNotInQueue),
+        }
+    }
+
+    /// End the print of `user`, who is at the front of the line, in `state`. See
+    /// [`Queue::fail`](#method.fail) for what `retry` does.
+    fn end_print(&mut self, user: &UserID, state: State, retry: bool) -> TransitionResult {
+        if let Err(result) = self.check_front(user) {
+            return result;
+        }
+        let current = self.queue[0].state();
+        if !current.can_become(state) {
+            return TransitionResult::NotAllowed(current);
+        }
+        let now = SystemTime::now();
+        let ended = self
+            .queue
+            .pop_front()
+            .expect("Attempted to end the print of an empty queue");
+        self.turn_ended(&ended, now);
+        if retry {
+            self.queue.push_front(Entry {
+                job: ended.job.clone(),
+                ..Entry::new(user.clone(), now)
+            });
+        } else {
+            self.policy.left(user, now);
+        }
+        let recorded = self.retire(ended, state, now);
+        self.update_front(now);
+        let user = user.clone();
+        let operation = match state {
+            State::Failed => Operation::Fail { user, retry },
+            _ => Operation::Finish { user },
         };
+        match self.write_state(operation).and(recorded) {
+            Ok(()) => TransitionResult::Transitioned,
+            Err(e) => TransitionResult::UnsuccessfullyTransitioned(e),
+        }
     }
 
-    fn on_close(&mut self, _cli: &RtmClient) {
-        println!("on_close");
+    /// Put `entry`, which just left the queue, into the final `state` as of `now`, and add it to
+    /// the history of the queue. Returns the result of saving the history.
+    fn retire(&mut self, mut entry: Entry, state: State, now: SystemTime) -> io::Result<()> {
+        entry.transition(state, now);
+        self.storage.record(&entry)
     }
 
-    fn on_connect(&mut self, cli: &RtmClient) {
-        println!("{}", INSPIRATIONAL_QUOTE);
-        match cli.start_response().channels.as_ref() {
-            Some(channels) => {
-                for channel in channels {
-                    if let (Some(name), Some(id)) = (channel.name.as_ref(), channel.id.as_ref()) {
-                        assert_eq!(
-                            self.chan_name_id_mapping.insert(name.clone(), id.clone()),
-                            None
-                        );
-                    }
-                }
+    /// Everyone who has left the queue (see [`Storage::record`](../storage/trait.Storage.html#tymethod.record)),
+    /// oldest first. Places that cannot be read back are left out, since the history is only
+    /// used for reports.
+    pub fn history(&mut self) -> io::Result<Vec<Entry>> {
+        Ok(self
+            .storage
+            .history()?
+            .into_iter()
+            .filter_map(|record| record.ok().map(|record| record.entry))
+            .collect())
+    }
+
+    /// Remember how long the turn of `entry`, who just left the front of the line, took. Turns of
+    /// people who were rearranged by an admin are not counted, since they did not end on their own.
+    /// The history is saved alongside the queue; if that fails, it is only kept in memory.
+    fn turn_ended(&mut self, entry: &Entry, now: SystemTime) {
+        // Turns are saved in whole seconds, so they are remembered that way from the start
+        let turn = match entry.front_since {
+            Some(since) => {
+                Duration::from_secs(now.duration_since(since).unwrap_or_default().as_secs())
             }
-            None => panic!("Could not find any channels in this Slack workspace."),
+            None => return,
         };
+        if self.turns.len() == TURN_HISTORY {
+            self.turns.pop_front();
+        }
+        self.turns.push_back(turn);
+        let _ = self
+            .storage
+            .set_metadata(TURNS_KEY, &format_turns(&self.turns));
+    }
 
-        let chan_id = self
-            .chan_name_id_mapping
-            .get(CHANNEL)
-            .expect(&*format!("Channel {} not found", CHANNEL));
+    /// How long a turn at the front of the line usually takes: the average of the last few turns
+    /// (see [`TURN_HISTORY`](constant.TURN_HISTORY.html)), or `None` if nobody has had a turn yet.
+    pub fn typical_turn(&self) -> Option<Duration> {
+        use std::convert::TryFrom;
 
-        let _ = cli.sender().send_message(chan_id, INSPIRATIONAL_QUOTE);
+        let total = self.turns.iter().sum::<Duration>();
+        u32::try_from(self.turns.len())
+            .ok()
+            .filter(|count| *count > 0)
+            .map(|count| total / count)
     }
-}
 
-impl fmt::Display for Queue<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.len() == 0 {
-            f.write_str("Nobody is in line!")
-        } else {
-            write!(
-                f,
-                "Here are the people currently in line:\n{}",
-                self.queue
-                    .iter()
-                    .enumerate()
-                    .map(|(idx, u)| {
-                        let (maybe_real_name, maybe_username) = self.get_username_by_id(u).expect(
-                            format!("For some reason user {} did not have an ID", u).as_str(),
-                        );
-                        let u = &u.to_string();
-                        let real_name = maybe_real_name.as_ref().unwrap_or(u);
-                        match maybe_username {
-                            Some(uname) if !uname.is_empty() => {
-                                format!("{}. {} ({})\n", idx, real_name, uname)
-                            }
-                            _ => format!("{}. {}\n", idx, real_name),
-                        }
-                    })
-                    .fold(String::default(), |acc, line| acc.to_owned() + &line)
-            )
+    /// Estimate, as of `now`, how long each person in line will wait until it is their turn, front
+    /// of the line first. The person at the front is not waiting at all.
+    ///
+    /// Each turn is expected to take as long as the person whose turn it is said it would (see
+    /// [`Queue::add_job`](#method.add_job)), or else as long as a turn usually
+    /// takes (see [`Queue::typical_turn`](#method.typical_turn)), minus however long the person at the
+    /// front has already been there. Once a turn cannot be estimated because nobody has had a turn
+    /// yet, neither can the wait of anyone behind it.
+    pub fn estimated_waits(&self, now: SystemTime) -> Vec<Option<Duration>> {
+        let typical = self.typical_turn();
+        let mut wait = Some(Duration::from_secs(0));
+        let mut waits = Vec::with_capacity(self.queue.len());
+        for (position, entry) in self.queue.iter().enumerate() {
+            waits.push(wait);
+            let mut turn = entry.job.time.or(typical);
+            if position == 0 {
+                let taken = entry.front_since.map_or(Duration::from_secs(0), |since| {
+                    now.duration_since(since).unwrap_or_default()
+                });
+                turn = turn.map(|turn| turn.saturating_sub(taken));
+            }
+            wait = wait.and_then(|wait| Some(wait + turn?));
+        }
+        waits
+    }
+
+    /// Bookkeeping after the order of the queue was changed by hand: restart the front-of-line timer
+    /// if someone new is at the front, and write the new order to the backup file along with the
+    /// `operation` that rearranged it.
+    fn rearranged(&mut self, operation: Operation) -> RearrangeResult {
+        let now = SystemTime::now();
+        for entry in self.queue.iter_mut().skip(1) {
+            send_back(entry, now);
+        }
+        self.update_front(now);
+        match self.write_state(operation) {
+            Ok(()) => RearrangeResult::Rearranged,
+            Err(e) => RearrangeResult::UnsuccessfullyRearranged(e),
         }
     }
 }
 
-impl Deref for Queue<'_> {
-    type Target = VecDeque<UserID>;
+/// Send `entry`, which is no longer at the front of the line, back to waiting as of `now`.
+fn send_back(entry: &mut Entry, now: SystemTime) {
+    entry.front_since = None;
+    entry.transition(State::Waiting, now);
+}
+
+/// Parse the [`TURNS_KEY`](constant.TURNS_KEY.html) metadata of a queue. Lines that cannot be
+/// understood are left out, since the history is only used for estimates.
+fn parse_turns(text: &str) -> VecDeque<Duration> {
+    let turns = text
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .map(Duration::from_secs)
+        .collect::<Vec<_>>();
+    let skipped = turns.len().saturating_sub(TURN_HISTORY);
+    turns.into_iter().skip(skipped).collect()
+}
+
+/// Parse the [`EXEMPT_KEY`](constant.EXEMPT_KEY.html) metadata of a queue into who each place
+/// belongs to and when they joined. Lines that cannot be understood are left out.
+pub(crate) fn parse_exempt(text: &str) -> HashSet<(UserID, u64)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let user = UserID::new(fields.next()?);
+            Some((user, fields.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The inverse of [`parse_exempt`](fn.parse_exempt.html). Places are sorted so that the metadata
+/// does not change from one save to the next.
+pub(crate) fn format_exempt(exempt: &HashSet<(UserID, u64)>) -> String {
+    let mut places = exempt
+        .iter()
+        .map(|(user, joined)| format!("{}\t{}\n", user, joined))
+        .collect::<Vec<_>>();
+    places.sort();
+    places.concat()
+}
+
+/// The inverse of [`parse_turns`](fn.parse_turns.html).
+fn format_turns(turns: &VecDeque<Duration>) -> String {
+    turns
+        .iter()
+        .map(|turn| format!("{}\n", turn.as_secs()))
+        .collect()
+}
+
+impl Deref for Queue {
+    type Target = VecDeque<Entry>;
 
     fn deref(&self) -> &Self::Target {
         &self.queue
@@ -520,43 +1304,68 @@ impl Deref for Queue<'_> {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, path::PathBuf};
 
     use super::*;
+    use crate::storage::{to_unix_secs, TextFileStorage};
+
+    /// A directory of a test's own, which is removed when the test is over.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// An empty queue that saves to a [`TempDir`](struct.TempDir.html) of its own, so that tests
+    /// running in parallel do not share state and nothing is left behind in the working directory.
+    fn temp_queue(test: &str) -> (Queue, TempDir) {
+        let dir = std::env::temp_dir().join(format!("queue_{}_{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let queue = Queue::from_file(dir.join("queue_state.txt")).unwrap();
+        (queue, TempDir(dir))
+    }
+
+    /// The queue saved in the state file at `path`, without a journal, so that every change is
+    /// saved to the file itself (and the file it replaces is kept as a backup).
+    fn from_text_file(path: &Path) -> Result<Queue, LoadError> {
+        let storage = TextFileStorage::open(path)?;
+        Queue::from_storage(Box::new(storage), policy::default_policy())
+    }
 
     #[test]
     fn create_queue() -> Result<(), String> {
-        let hash_map = HashMap::new();
-        let test_file = match File::create("queue_state_2.txt") {
+        let (queue_a, dir) = temp_queue("create_queue");
+        let test_file = match TextFileStorage::create(dir.0.join("queue_state_2.txt")) {
             Ok(f) => f,
             Err(e) => return Err(format!("{}", e)),
         };
 
-        let queue_a = Queue::new(&hash_map);
         let queue_b = Queue {
             queue: VecDeque::new(),
-            uid_username_mapping: &hash_map,
-            chan_name_id_mapping: BTreeMap::new(),
-            db_conn: BufWriter::new(test_file),
+            storage: Box::new(test_file),
+            policy: policy::default_policy(),
+            front_timeout: None,
+            front_warned: false,
+            deliveries: HashMap::new(),
+            notify_top: notify::DEFAULT_NOTIFY_TOP,
+            notified: Vec::new(),
+            turns: VecDeque::new(),
+            actor: None,
         };
 
         if !queue_a.is_empty() {
-            return Err(String::from("Queue::new does not return an empty queue"));
+            return Err(String::from("a new queue is not empty"));
         }
 
-        if queue_a.queue != queue_b.queue {
-            return Err(format!(
-                "queue_a.queue ({:?}) != queue_b.queue ({:?})",
-                queue_a.queue, queue_b.queue
-            ));
-        }
-
-        if queue_a.uid_username_mapping == queue_b.uid_username_mapping {
+        if queue_a.queue == queue_b.queue {
             Ok(())
         } else {
             Err(format!(
-                "queue_a.uid_username_mapping ({:?}) != queue_b.uid_username_mapping ({:?})",
-                queue_a.uid_username_mapping, queue_b.uid_username_mapping
+                "queue_a.queue ({:?}) != queue_b.queue ({:?})",
+                queue_a.queue, queue_b.queue
             ))
         }
     }
@@ -578,8 +1387,7 @@ mod tests {
 
     #[test]
     fn add_users() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("add_users");
 
         add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
         add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
@@ -600,8 +1408,7 @@ mod tests {
     /// in line.
     #[test]
     fn add_duplicate_users_to_nonempty_queue() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("add_duplicate_users_to_nonempty_queue");
         let test_user = UserID::new("UA8RXUPSP");
 
         add_users_helper(&mut queue, test_user.clone());
@@ -616,7 +1423,7 @@ mod tests {
 
         let (new_user, result) = queue.add_user(test_user);
         match result {
-            UserNotAdded => (), // This is the intended behavior
+            UserNotAdded(_) => (), // This is the intended behavior
             res => panic!("{} was erroneously added to the queue: {:?}", new_user, res),
         }
 
@@ -634,8 +1441,7 @@ mod tests {
     /// up to three times to the queue if it is initially empty.
     #[test]
     fn add_duplicate_users_to_empty_queue() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("add_duplicate_users_to_empty_queue");
 
         // This should work, because the queue is empty so UA8RXUPSP can add themselves up to 3 times
         for _ in 0..3 {
@@ -649,7 +1455,7 @@ mod tests {
         assert!(!queue.can_add(&test_user));
         let (new_user, result) = queue.add_user(test_user);
         match result {
-            UserNotAdded => (), // This is the intended behavior
+            UserNotAdded(_) => (), // This is the intended behavior
             res => panic!(
                 "{} was added to an empty queue for the fourth time: {:?}",
                 new_user, res
@@ -659,8 +1465,7 @@ mod tests {
 
     #[test]
     fn remove_front_users() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("remove_front_users");
 
         add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
         add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
@@ -691,8 +1496,7 @@ mod tests {
 
     #[test]
     fn peek_front_users() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("peek_front_users");
 
         add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
         add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
@@ -718,8 +1522,7 @@ mod tests {
 
     #[test]
     fn remove_arbitrary_users() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("remove_arbitrary_users");
 
         add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
         add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
@@ -743,8 +1546,7 @@ mod tests {
 
     #[test]
     fn remove_non_existent_users() {
-        let hash_map = HashMap::new();
-        let mut queue = Queue::new(&hash_map);
+        let (mut queue, _dir) = temp_queue("remove_non_existent_users");
 
         match queue.remove_user(UserID::new("UNB2LMZRP")) {
             (_, NonExistentUser) => (), // This is the behavior that is expected
@@ -767,4 +1569,475 @@ mod tests {
             ),
         }
     }
+
+    #[test]
+    fn front_timeout_moves_people_back() {
+        let (mut queue, _dir) = temp_queue("front_timeout_moves_people_back");
+        let hour = Duration::from_secs(60 * 60);
+        queue.set_front_timeout(Some(FrontTimeout {
+            limit: 24 * hour,
+            grace: hour,
+            action: TimeoutAction::MoveBack,
+        }));
+
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
+        let front_since = queue[0].front_since.expect("front of the line has no time");
+        assert_eq!(queue[1].front_since, None);
+
+        assert!(queue.check_front_timeout(front_since + hour).is_none());
+        match queue.check_front_timeout(front_since + 24 * hour) {
+            Some((TimeoutEvent::Warned(user, remaining), _)) => {
+                assert_eq!(user, UserID::new("UA8RXUPSP"));
+                assert_eq!(remaining, hour);
+            }
+            res => panic!("Queue::check_front_timeout did not warn: {:?}", res),
+        }
+        // Only warn once
+        assert!(queue
+            .check_front_timeout(front_since + 24 * hour + Duration::from_secs(60))
+            .is_none());
+
+        match queue.check_front_timeout(front_since + 25 * hour) {
+            Some((TimeoutEvent::MovedBack(user), Ok(()))) => {
+                assert_eq!(user, UserID::new("UA8RXUPSP"))
+            }
+            res => panic!("Queue::check_front_timeout did not move back: {:?}", res),
+        }
+        assert_eq!(
+            queue.queue,
+            [UserID::new("UNB2LMZRP"), UserID::new("UA8RXUPSP")]
+        );
+        assert_eq!(queue[0].front_since, Some(front_since + 25 * hour));
+        assert_eq!(queue[1].front_since, None);
+        assert_eq!(
+            queue.peek_first_user_in_line(),
+            Some(&UserID::new("UNB2LMZRP"))
+        );
+    }
+
+    #[test]
+    fn front_timeouts_are_read_from_options() {
+        assert_eq!(FrontTimeout::from_options(None, Some("1h"), None), Ok(None));
+        assert_eq!(
+            FrontTimeout::from_options(Some("2h30m"), None, Some("remove")),
+            Ok(Some(FrontTimeout {
+                limit: Duration::from_secs(150 * 60),
+                grace: FrontTimeout::DEFAULT_GRACE,
+                action: TimeoutAction::Remove,
+            }))
+        );
+        assert!(FrontTimeout::from_options(Some("soon"), None, None).is_err());
+        assert!(FrontTimeout::from_options(Some("1h"), None, Some("kick")).is_err());
+    }
+
+    #[test]
+    fn front_timeout_removes_people() {
+        let (mut queue, _dir) = temp_queue("front_timeout_removes_people");
+        queue.set_front_timeout(Some(FrontTimeout {
+            limit: Duration::from_secs(60),
+            grace: Duration::from_secs(0),
+            action: TimeoutAction::Remove,
+        }));
+
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        let front_since = queue[0].front_since.unwrap();
+
+        // With no grace period, there is no time to warn them before they are removed
+        let later = front_since + Duration::from_secs(60);
+        assert!(matches!(
+            queue.check_front_timeout(later),
+            Some((TimeoutEvent::Removed(_), Ok(())))
+        ));
+        assert!(queue.is_empty());
+        assert!(queue.check_front_timeout(later).is_none());
+    }
+
+    #[test]
+    fn timestamps_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "queue_timestamps_survive_a_restart_{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let entries = {
+            let mut queue = Queue::from_file(&path).unwrap();
+            add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+            add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
+            queue.queue.clone()
+        };
+
+        let queue = Queue::from_file(&path).unwrap();
+
+        assert_eq!(queue.len(), 2);
+        for (restored, entry) in queue.iter().zip(entries.iter()) {
+            assert_eq!(restored.user, entry.user);
+            assert_eq!(to_unix_secs(restored.joined), to_unix_secs(entry.joined));
+            assert_eq!(
+                restored.front_since.map(to_unix_secs),
+                entry.front_since.map(to_unix_secs)
+            );
+        }
+        assert!(queue[0].front_since.is_some());
+        for backup in TextFileStorage::open(&path).unwrap().backups() {
+            let _ = std::fs::remove_file(backup);
+        }
+        let _ = std::fs::remove_file(storage::state_file_journal(&path));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn damaged_files_fall_back_to_a_backup() {
+        let path = std::env::temp_dir().join(format!(
+            "queue_damaged_files_fall_back_to_a_backup_{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let backups = {
+            let mut queue = from_text_file(&path).unwrap();
+            add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+            add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
+            TextFileStorage::open(&path).unwrap().backups()
+        };
+
+        // What an interrupted save used to leave behind
+        std::fs::write(&path, " ".repeat(80)).unwrap();
+        let queue = from_text_file(&path).unwrap();
+
+        // The newest backup is from before UNB2LMZRP joined
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].user, UserID::new("UA8RXUPSP"));
+        for backup in backups {
+            let _ = std::fs::remove_file(backup);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn bad_backups_can_be_recovered() {
+        let path = std::env::temp_dir().join(format!(
+            "queue_bad_backups_can_be_recovered_{}.txt",
+            std::process::id()
+        ));
+        let bad =
+            "0 UA8RXUPSP\n1 UA8RXUPSP\n2 UA8RXUPSP\n3 UA8RXUPSP\nfour UNB2LMZRP\n5 UNB2LMZRP\n";
+        let load = |recovery| {
+            let storage = TextFileStorage::open(&path).unwrap();
+            Queue::load(Box::new(storage), policy::default_policy(), recovery).unwrap()
+        };
+        let remove_backups = || {
+            for backup in TextFileStorage::open(&path).unwrap().backups() {
+                let _ = std::fs::remove_file(backup);
+            }
+        };
+        remove_backups();
+
+        std::fs::write(&path, bad).unwrap();
+        assert!(matches!(
+            from_text_file(&path),
+            Err(LoadError::BreaksRules { line: 4, .. })
+        ));
+        let (queue, report) = load(Recovery::Strict);
+        assert!(queue.is_empty());
+        assert_eq!(report.problems.len(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), bad);
+
+        let (queue, report) = load(Recovery::SkipInvalid);
+        assert_eq!(queue.len(), 4);
+        assert!(matches!(
+            report.problems[..],
+            [
+                LoadError::BreaksRules { line: 4, .. },
+                LoadError::InvalidLine { line: 5, .. }
+            ]
+        ));
+        drop(queue);
+        // The lines that were kept were written back
+        assert_eq!(from_text_file(&path).unwrap().len(), 4);
+
+        // Without a good backup to fall back to
+        remove_backups();
+        std::fs::write(&path, bad).unwrap();
+        let (queue, report) = load(Recovery::StartEmpty);
+        assert!(queue.is_empty());
+        let aside = report.set_aside.unwrap();
+        assert_eq!(std::fs::read_to_string(&aside).unwrap(), bad);
+        assert!(from_text_file(&path).unwrap().is_empty());
+        remove_backups();
+        let _ = std::fs::remove_file(aside);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn notification_settings_survive_a_restart() {
+        let storage = crate::storage::MemoryStorage::new();
+        let load =
+            || Queue::from_storage(Box::new(storage.clone()), policy::default_policy()).unwrap();
+        let (a, b) = (UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP"));
+
+        let mut queue = load();
+        add_users_helper(&mut queue, a.clone());
+        add_users_helper(&mut queue, b.clone());
+        queue.set_delivery(b.clone(), Delivery::Both).unwrap();
+        assert_eq!(queue.take_top_changes(), [(a, 0), (b.clone(), 1)]);
+
+        let mut queue = load();
+        assert_eq!(queue.delivery(&b), Delivery::Both);
+        assert_eq!(queue.delivery(&UserID::new("UN480W9ND")), Delivery::Channel);
+        // The people near the front were told before the restart
+        assert!(queue.take_top_changes().is_empty());
+    }
+
+    #[test]
+    fn waits_are_estimated() {
+        let storage = crate::storage::MemoryStorage::new();
+        let load =
+            || Queue::from_storage(Box::new(storage.clone()), policy::default_policy()).unwrap();
+        let hour = Duration::from_secs(60 * 60);
+        let mut queue = load();
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        queue.add_job(
+            UserID::new("UNB2LMZRP"),
+            Job {
+                time: Some(2 * hour),
+                ..Job::default()
+            },
+        );
+        add_users_helper(&mut queue, UserID::new("UN480W9ND"));
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        let now = queue[0].front_since.unwrap();
+
+        // Nobody has had a turn yet, so only the turns people declared can be estimated
+        assert_eq!(queue.typical_turn(), None);
+        assert_eq!(
+            queue.estimated_waits(now),
+            [Some(Duration::from_secs(0)), None, None, None]
+        );
+
+        // The first person takes an hour, and the second person was right about their two hours
+        queue.queue[0].front_since = Some(now - hour);
+        queue.remove_user(UserID::new("UA8RXUPSP"));
+        queue.queue[0].front_since = Some(now - 2 * hour);
+        queue.remove_user(UserID::new("UNB2LMZRP"));
+        assert_eq!(queue.typical_turn(), Some(hour + hour / 2));
+
+        // The person at the front has been there for half an hour
+        let now = queue[0].front_since.unwrap() + hour / 2;
+        assert_eq!(
+            queue.estimated_waits(now),
+            [Some(Duration::from_secs(0)), Some(hour)]
+        );
+        assert_eq!(queue[1].waited(queue[1].joined + hour), hour);
+
+        // The history survives a restart
+        let queue = load();
+        assert_eq!(queue.typical_turn(), Some(hour + hour / 2));
+        assert_eq!(queue[0].job, Job::default());
+        assert_eq!(
+            parse_turns("60\nnope\n120\n"),
+            [Duration::from_secs(60), Duration::from_secs(120)]
+        );
+    }
+
+    #[test]
+    fn move_and_swap_users() {
+        let (mut queue, _dir) = temp_queue("move_and_swap_users");
+
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
+        add_users_helper(&mut queue, UserID::new("UN480W9ND"));
+
+        assert!(matches!(
+            queue.move_user(&UserID::new("UN480W9ND"), 0),
+            RearrangeResult::Rearranged
+        ));
+        assert_eq!(
+            queue.queue,
+            [
+                UserID::new("UN480W9ND"),
+                UserID::new("UA8RXUPSP"),
+                UserID::new("UNB2LMZRP"),
+            ]
+        );
+        // The new person at the front of the line starts their time at the front
+        assert!(queue[0].front_since.is_some());
+        assert_eq!(queue[1].front_since, None);
+
+        assert!(matches!(queue.swap(0, 2), RearrangeResult::Rearranged));
+        assert_eq!(
+            queue.queue,
+            [
+                UserID::new("UNB2LMZRP"),
+                UserID::new("UA8RXUPSP"),
+                UserID::new("UN480W9ND"),
+            ]
+        );
+
+        assert!(matches!(
+            queue.move_user(&UserID::new("UA8RXUPSP"), 3),
+            RearrangeResult::PositionOutOfRange(3)
+        ));
+        assert!(matches!(
+            queue.move_user(&UserID::new("U0000000"), 0),
+            RearrangeResult::UserNotInQueue
+        ));
+        assert!(matches!(
+            queue.swap(1, 5),
+            RearrangeResult::PositionOutOfRange(5)
+        ));
+    }
+
+    #[test]
+    fn jobs_can_be_edited() {
+        let storage = crate::storage::MemoryStorage::new();
+        let mut queue =
+            Queue::from_storage(Box::new(storage.clone()), policy::default_policy()).unwrap();
+        let job = Job {
+            name: Some(String::from("benchy")),
+            material: Some(String::from("PLA")),
+            ..Job::default()
+        };
+        queue.add_job(UserID::new("UA8RXUPSP"), job.clone());
+        assert_eq!(queue[0].job, job);
+
+        let edited = Job {
+            color: Some(String::from("red")),
+            ..job
+        };
+        assert!(matches!(
+            queue.set_job(0, edited.clone()),
+            EditResult::Edited
+        ));
+        assert!(matches!(
+            queue.set_job(1, Job::default()),
+            EditResult::PositionOutOfRange(1)
+        ));
+        assert_eq!(storage.entries()[0].job, edited);
+    }
+
+    #[test]
+    fn prints_go_through_their_states() {
+        let storage = crate::storage::MemoryStorage::new();
+        let mut queue =
+            Queue::from_storage(Box::new(storage.clone()), policy::default_policy()).unwrap();
+        let (a, b) = (UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP"));
+        queue.add_user(a.clone());
+        queue.add_user(b.clone());
+        assert_eq!(queue[0].state(), State::Called);
+        assert_eq!(queue[1].state(), State::Waiting);
+
+        assert!(matches!(queue.start(&b), TransitionResult::NotAtFront(1)));
+        assert!(matches!(
+            queue.start(&UserID::new("UN480W9ND")),
+            TransitionResult::UserNotInQueue
+        ));
+        assert!(matches!(
+            queue.finish(&a),
+            TransitionResult::NotAllowed(State::Called)
+        ));
+        assert!(matches!(queue.start(&a), TransitionResult::Transitioned));
+        assert!(matches!(
+            queue.start(&a),
+            TransitionResult::NotAllowed(State::Printing)
+        ));
+        // States survive a restart
+        let mut queue =
+            Queue::from_storage(Box::new(storage.clone()), policy::default_policy()).unwrap();
+        assert_eq!(queue[0].state(), State::Printing);
+
+        // A failed print can be tried again from the front of the line
+        assert!(matches!(
+            queue.fail(&a, true),
+            TransitionResult::Transitioned
+        ));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue[0], a);
+        assert_eq!(queue[0].state(), State::Called);
+        queue.start(&a);
+        assert!(matches!(queue.finish(&a), TransitionResult::Transitioned));
+        assert_eq!(queue[0], b);
+        assert_eq!(queue[0].state(), State::Called);
+        queue.remove_user(b);
+
+        let history = queue.history().unwrap();
+        let states = history
+            .iter()
+            .map(|entry| {
+                entry
+                    .transitions
+                    .iter()
+                    .map(|transition| transition.state)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        use State::*;
+        assert_eq!(
+            states,
+            [
+                vec![Waiting, Called, Printing, Failed],
+                vec![Waiting, Called, Printing, Finished],
+                vec![Waiting, Called, Abandoned],
+            ]
+        );
+        assert!(!Finished.can_become(Waiting));
+        assert!(!Waiting.can_become(Printing));
+        assert_eq!("printing".parse::<State>(), Ok(Printing));
+    }
+
+    #[test]
+    fn clear_queue() {
+        let (mut queue, _dir) = temp_queue("clear_queue");
+
+        add_users_helper(&mut queue, UserID::new("UA8RXUPSP"));
+        add_users_helper(&mut queue, UserID::new("UNB2LMZRP"));
+
+        let (removed, written) = queue.clear();
+        written.unwrap();
+
+        assert_eq!(
+            removed,
+            [UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP")]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn import_places() {
+        let (mut queue, _dir) = temp_queue("import_places");
+        let joined = crate::storage::from_unix_secs(1_600_000_000);
+        let mut printing = Entry::new(UserID::new("UA8RXUPSP"), joined);
+        printing.transition(State::Called, joined);
+        printing.transition(State::Printing, joined);
+
+        let entries = vec![
+            printing,
+            Entry::new(UserID::new("UNB2LMZRP"), joined),
+            Entry::new(UserID::new("UA8RXUPSP"), joined),
+        ];
+        assert!(matches!(queue.import(entries), ImportResult::Imported));
+        assert_eq!(
+            queue.queue,
+            [
+                UserID::new("UA8RXUPSP"),
+                UserID::new("UNB2LMZRP"),
+                UserID::new("UA8RXUPSP"),
+            ]
+        );
+        // Everyone keeps when they joined, but starts over at the front of the line
+        assert!(queue.iter().all(|entry| entry.joined == joined));
+        assert_eq!(queue.queue[0].state(), State::Called);
+
+        // Someone back to back with themselves turns the whole import away
+        let entries = vec![
+            Entry::new(UserID::new("UN480W9ND"), joined),
+            Entry::new(UserID::new("UN480W9ND"), joined),
+        ];
+        match queue.import(entries) {
+            ImportResult::NotImported { index: 1, user, .. } => {
+                assert_eq!(user, UserID::new("UN480W9ND"))
+            }
+            res => panic!("Queue::import returned an unexpected result: {:?}", res),
+        }
+        assert_eq!(queue.len(), 3);
+    }
 }
@@ -0,0 +1,151 @@
+//! Running Queue as a bot on Slack's (deprecated) real time messaging API, which is what the
+//! `slack_main` binary does. The [`slack`](https://docs.rs/slack) crate keeps the connection open and
+//! hands every event to a [`SlackBot`](../slack_bot/struct.SlackBot.html).
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use slack::RtmClient;
+
+use crate::slack_bot::{is_app_mention, SlackBot, CHANNEL, INSPIRATIONAL_QUOTE};
+use crate::user::UserID;
+
+impl slack::EventHandler for SlackBot<'_> {
+    fn on_event(&mut self, cli: &RtmClient, event: slack::Event) {
+        if cfg!(debug_assertions) {
+            println!("Got event: {:?}", event);
+        }
+        if let slack::Event::Message(message) = event {
+            if let slack::Message::Standard(ms) = *message {
+                // The channel the message was posted in
+                let chan = ms.channel.expect("Channel does not exist");
+                /*
+                    Unfortunately, this Slack bot uses the (deprecated) Slack "real time messaging" (RTM)
+                    API because the slack crate only works with that API (not that I need the slack crate
+                    but the only other option is using Rust's advanced, concurrency-first HTTP libraries
+                    to build my own web API for this Slack bot, and I found working with the slack crate
+                    easier, even if it means using a deprecated API.
+
+                    I can see why Slack is encouraging you to use the new API ("granular scopes"). As the
+                    name suggests, that API allows you to be a lot more granular with the permissions you
+                    give the bot. I suspect that API allows you to only run the bot in certain channels,
+                    unlike the RTM API where the bot receives any event that happens in any channel where it
+                    has been "invited" to. So, to simulate some granularity, I am adding a check to see if
+                    the bot should respond, or if t was invoked in a channel it should not be in (see the
+                    CHANNEL variable).
+                */
+                if Some(chan.as_str()) == self.channel_id() {
+                    // The content of the message
+                    let text = ms.text.unwrap_or_default();
+                    if is_app_mention(&text) {
+                        // Who posted the message
+                        let user = ms.user.expect("User does not exist");
+                        // What to send back to Slack
+                        let response = self.determine_response(UserID(user), text.as_str());
+                        // Send 'em back!
+                        let _ = cli.sender().send_message(&chan, &response);
+                        self.send_notifications();
+                    }
+                } else {
+                    let response = match self.channel_id() {
+                        Some(chan_id) => {
+                            format!("Try invoking that same command in <#{}>!", chan_id)
+                        }
+                        None => format!("Try invoking that same command in #{}!", CHANNEL),
+                    };
+                    let _ = cli.sender().send_message(&chan, &response);
+                }
+            }
+        }
+    }
+
+    fn on_close(&mut self, _cli: &RtmClient) {
+        println!("on_close");
+    }
+
+    fn on_connect(&mut self, cli: &RtmClient) {
+        println!("{}", INSPIRATIONAL_QUOTE);
+        match cli.start_response().channels.as_ref() {
+            Some(channels) => self.set_channels(
+                channels
+                    .iter()
+                    .filter_map(|channel| Some((channel.name.clone()?, channel.id.clone()?))),
+            ),
+            None => panic!("Could not find any channels in this Slack workspace."),
+        };
+
+        let chan_id = self
+            .channel_id()
+            .unwrap_or_else(|| panic!("Channel {} not found", CHANNEL))
+            .to_string();
+
+        let _ = cli.sender().send_message(&chan_id, INSPIRATIONAL_QUOTE);
+    }
+}
+
+/// A [`SlackBot`](struct.SlackBot.html) that is shared with other threads, e.g. the one running
+/// [`watch_front_timeouts`](fn.watch_front_timeouts.html). Slack events are handled by locking the
+/// bot and handing the event to it.
+pub struct SharedSlackBot<'m, 'a>(pub &'m Mutex<SlackBot<'a>>);
+
+impl SharedSlackBot<'_, '_> {
+    /// Run `f` on the shared bot. A panic on another thread while it held the lock does not make
+    /// the queues unusable, since every change to them is written out as soon as it is made.
+    fn with<T>(&self, f: impl FnOnce(&mut SlackBot) -> T) -> T {
+        f(&mut self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl slack::EventHandler for SharedSlackBot<'_, '_> {
+    fn on_event(&mut self, cli: &RtmClient, event: slack::Event) {
+        self.with(|bot| bot.on_event(cli, event))
+    }
+
+    fn on_close(&mut self, cli: &RtmClient) {
+        self.with(|bot| bot.on_close(cli))
+    }
+
+    fn on_connect(&mut self, cli: &RtmClient) {
+        self.with(|bot| bot.on_connect(cli))
+    }
+}
+
+/// Every `every`, check for people who have been at the front of a queue for too long and post what
+/// was done about it to the channel Queue is running in, until `stop` is set.
+///
+/// This is meant to run on its own thread alongside the [`RtmClient`], which is why the bot is
+/// behind a `Mutex`.
+pub fn watch_front_timeouts(
+    bot: &Mutex<SlackBot>,
+    sender: &slack::Sender,
+    every: Duration,
+    stop: &AtomicBool,
+) {
+    loop {
+        // Sleep in small steps so that we notice when we are told to stop
+        let started = Instant::now();
+        while started.elapsed() < every {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            thread::sleep(Duration::from_secs(1).min(every));
+        }
+
+        let mut bot = bot.lock().unwrap_or_else(PoisonError::into_inner);
+        // Nobody would see the notices before Queue has found its channel, so wait until then
+        let chan = match bot.channel_id() {
+            Some(chan) => chan.to_string(),
+            None => continue,
+        };
+        for notice in bot.check_front_timeouts(SystemTime::now()) {
+            let _ = sender.send_message(&chan, &notice);
+        }
+        bot.send_notifications();
+    }
+}
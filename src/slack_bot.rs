@@ -0,0 +1,1241 @@
+//! The part of Queue that talks to Slack.
+//!
+//! [`Queue`](../queue/struct.Queue.html) and [`QueueManager`](../manager/struct.QueueManager.html)
+//! only know how to keep people in line. A [`SlackBot`](struct.SlackBot.html) wraps the manager and
+//! does everything that has to do with Slack: it parses the commands people post when they mention
+//! Queue, applies them to the right queue, and words the replies, showing people by the names they
+//! have in the Slack workspace. The [`rtm`](../rtm/index.html) module hands it the messages posted
+//! while Queue is connected to the real time messaging API, and the [`events`](../events/index.html)
+//! module hands it the requests Slack makes to the Events API server.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    time::{Duration, SystemTime},
+};
+
+use crate::command::{self, Command, JobChanges};
+use crate::manager::{CreateResult, QueueManager, TimedOut};
+use crate::notify::{self, Delivery, Notifier};
+use crate::policy;
+use crate::queue::{
+    AddResult, EditResult, Entry, FrontTimeout, Job, Queue, RearrangeResult, RemoveResult, State,
+    TimeoutAction, TimeoutEvent, TransitionResult,
+};
+use crate::user::{SlackMap, UserID};
+
+/// The User ID (a string of the form UXXXXXXX) for the Queue app
+pub const QUEUE_UID: &str = "<@U01A844Q2US>";
+
+/// Sometimes we need these.
+pub const INSPIRATIONAL_QUOTE: &str =
+	"_Waiting in line is a great opportunity to meet people, daydream, or play._\n\t\u{2014}Patch Adams";
+
+/// Which Slack channel Queue is running in.
+pub(crate) const CHANNEL: &str = "3d-printer-queue";
+
+/// The start of the [help message](fn.usage.html), before the list of commands.
+const USAGE_INTRO: &str = "*Queue* is a :slack: bot that keeps track of who is waiting in line to use the \
+3D printers. You interact with it by @mentioning it and then typing a command (e.g. `@Queue help`).\n\
+There is one queue per printer (or event), and each queue has a name. Commands that take a queue name \
+(e.g. `@Queue add prusa`) use the default queue if you leave the name out.\n\
+Here are the different commands *Queue* currently recognizes:\n";
+
+/// A help message to display when the `help` command is invoked, which lists every one of the
+/// [`COMMANDS`](../command/constant.COMMANDS.html) with its arguments.
+pub(crate) fn usage() -> String {
+    let mut usage = String::from(USAGE_INTRO);
+    for spec in command::COMMANDS {
+        usage.push_str(&format!("\n• *{}*", spec.usage));
+        if !spec.aliases.is_empty() {
+            let aliases = spec
+                .aliases
+                .iter()
+                .map(|alias| format!("*{}*", alias))
+                .collect::<Vec<_>>();
+            usage.push_str(&format!(" (or {})", aliases.join(" or ")));
+        }
+        usage.push_str(&format!(": {}", spec.description));
+    }
+    usage
+}
+
+/// Given the body of a post to Slack, determine someone mentioned the Queue app
+pub(crate) fn is_app_mention(text: &str) -> bool {
+    text.contains(QUEUE_UID)
+}
+
+/// Answers the commands posted to Slack by applying them to a [`QueueManager`](../manager/struct.QueueManager.html).
+#[derive(Debug)]
+pub struct SlackBot<'a> {
+    /// The queues commands are applied to
+    queues: QueueManager,
+    /// All the possible members of a Slack workspace that can join a queue
+    uid_username_mapping: &'a SlackMap,
+    /// All the different channels in the workspace Queue is installed in, mapping channel names to
+    /// channel IDs
+    chan_name_id_mapping: BTreeMap<String, String>,
+    /// Sends the notifications that are direct messages, if Queue can send them
+    notifier: Option<Box<dyn Notifier>>,
+}
+
+impl<'a> SlackBot<'a> {
+    /// A bot that applies commands to `queues` and shows the people in line by their names in
+    /// `uids_to_users`.
+    pub fn new(queues: QueueManager, uids_to_users: &'a SlackMap) -> Self {
+        Self {
+            queues,
+            uid_username_mapping: uids_to_users,
+            chan_name_id_mapping: BTreeMap::new(),
+            notifier: None,
+        }
+    }
+
+    /// The queues commands are applied to.
+    pub fn queues(&self) -> &QueueManager {
+        &self.queues
+    }
+
+    /// The queues commands are applied to, e.g. to change them directly.
+    pub fn queues_mut(&mut self) -> &mut QueueManager {
+        &mut self.queues
+    }
+
+    /// All the possible members of the Slack workspace, e.g. for [`display_name`](fn.display_name.html).
+    pub fn users(&self) -> &'a SlackMap {
+        self.uid_username_mapping
+    }
+
+    /// The ID of the channel Queue is running in, if Queue has connected to Slack and found it.
+    pub fn channel_id(&self) -> Option<&str> {
+        self.chan_name_id_mapping.get(CHANNEL).map(String::as_str)
+    }
+
+    /// Remember the names and IDs of the channels in the workspace, e.g. to find Queue's own channel
+    /// when Queue is not connected to the real time messaging API.
+    pub fn set_channels<I: IntoIterator<Item = (String, String)>>(&mut self, channels: I) {
+        self.chan_name_id_mapping = channels.into_iter().collect();
+    }
+
+    /// Send the notifications that people want as direct messages with `notifier`. Without one,
+    /// [`SlackBot::send_notifications`](#method.send_notifications) does nothing.
+    pub fn set_notifier(&mut self, notifier: Box<dyn Notifier>) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Send the notifications the queues have collected as direct messages with the bot's notifier
+    /// (see [`notify::deliver`](../notify/fn.deliver.html)), if it has one. Pings in the channel are
+    /// already part of the responses to commands, so only the direct messages still have to be sent.
+    pub fn send_notifications(&mut self) {
+        let notifications = self.queues.take_notifications();
+        if let Some(notifier) = &self.notifier {
+            notify::deliver(notifier.as_ref(), &notifications);
+        }
+    }
+
+    /// Check every queue for someone who has been at the front of the line for too long as of `now`
+    /// (see [`QueueManager::check_front_timeouts`](../manager/struct.QueueManager.html#method.check_front_timeouts)),
+    /// returning the messages that should be posted to Slack about it.
+    pub fn check_front_timeouts(&mut self, now: SystemTime) -> Vec<String> {
+        let action = match self.queues.front_timeout() {
+            Some(FrontTimeout {
+                action: TimeoutAction::MoveBack,
+                ..
+            }) => "moved behind the next person in line",
+            _ => "removed from the queue",
+        };
+        let timed_out = self.queues.check_front_timeouts(now);
+        let next_in_line = |name: &str| {
+            self.queues
+                .get(name)
+                .map(next_in_line_message)
+                .unwrap_or_default()
+        };
+        timed_out
+            .into_iter()
+            .map(
+                |TimedOut {
+                     queue: name,
+                     event,
+                     written,
+                 }| {
+                    let mut notice = match event {
+                        TimeoutEvent::Warned(user, remaining) => format!(
+                            "Hey <@{}>, you have been at the front of the *{}* queue for a while. \
+							If you are ready to print, please say `@Queue start {}`, or `@Queue done {}` if \
+							you no longer need your turn. Otherwise, you will be {} in {}.",
+                            user,
+                            name,
+                            name,
+                            name,
+                            action,
+                            policy::format_duration(remaining)
+                        ),
+                        TimeoutEvent::MovedBack(user) => format!(
+                            "<@{}>, you were at the front of the *{}* queue for too long, so I have \
+							moved you behind the next person in line.\n{}",
+                            user,
+                            name,
+                            next_in_line(&name)
+                        ),
+                        TimeoutEvent::Kept(user) => format!(
+                            "<@{}>, you have been at the front of the *{}* queue for a long time, \
+							but nobody is waiting behind you, so you can keep your spot.",
+                            user, name
+                        ),
+                        TimeoutEvent::Removed(user) => format!(
+                            "<@{}>, you were at the front of the *{}* queue for too long, so I have \
+							removed you from the queue.\n{}",
+                            user,
+                            name,
+                            next_in_line(&name)
+                        ),
+                    };
+                    if let Err(e) = written {
+                        notice.push_str(&backup_failure(&e));
+                    }
+                    notice
+                },
+            )
+            .collect()
+    }
+
+    /// Handle the create command. Returns a message to post in the Slack channel depending on
+    /// whether or not the queue was created.
+    fn create(&mut self, name: &str, spec: Option<&str>) -> String {
+        match self.queues.create_queue_with_policy(name, spec) {
+            CreateResult::QueueCreated => format!(
+                "Okay, I have created the *{}* queue. Join it with `@Queue add {}`.",
+                name, name
+            ),
+            CreateResult::InvalidPolicy(e) => format!(
+                "I could not create the *{}* queue because I did not understand its rules: {}.",
+                name, e
+            ),
+            CreateResult::PolicyNotSaved(e) => format!(
+                "I could not create the *{}* queue because I could not save its rules. If it \
+				helps, the reason why is: {}",
+                name, e
+            ),
+            CreateResult::StorageFailed(e) => format!(
+                "I could not create the *{}* queue because I could not set up a place to save it. \
+				If it helps, the reason why is: {}",
+                name, e
+            ),
+            CreateResult::QueueAlreadyExists => {
+                format!("There already is a queue called *{}*.", name)
+            }
+            CreateResult::InvalidQueueName => format!(
+                "\"{}\" cannot be used as the name of a queue. Queue names may only contain lowercase \
+				letters, digits, dashes, and underscores.",
+                name
+            ),
+        }
+    }
+
+    /// Handle the notify command, which sets how `user` wants to be notified about the queue called
+    /// `name` (or the default queue).
+    fn notify(&mut self, user: UserID, delivery: Delivery, name: Option<&str>) -> String {
+        let (name, queue) = match self.queue_mut(name) {
+            Ok(found) => found,
+            Err(e) => return e,
+        };
+        let how = match delivery {
+            Delivery::Channel => "with a ping in this channel",
+            Delivery::DirectMessage => "with a direct message",
+            Delivery::Both => "with a ping in this channel and a direct message",
+        };
+        match queue.set_delivery(user.clone(), delivery) {
+            Ok(()) => format!(
+                "Okay <@{}>, I will let you know {} when your turn in the *{}* queue is coming up.",
+                user, how, name
+            ),
+            Err(e) => format!(
+                "Okay <@{}>, I will let you know {} when your turn in the *{}* queue is coming up, \
+				but I could not save that, so I will forget it if I restart. If it helps, the reason \
+				why is: {}",
+                user, how, name, e
+            ),
+        }
+    }
+
+    /// Handle the edit command, which makes `changes` to the job in position `position` of the queue
+    /// called `name` (or the default queue). People can edit their own jobs, and admins can edit
+    /// anyone's.
+    fn edit(
+        &mut self,
+        user: UserID,
+        position: usize,
+        name: Option<&str>,
+        changes: &JobChanges,
+    ) -> String {
+        let is_admin = self.queues.is_admin(&user);
+        let (name, queue) = match self.queue_mut(name) {
+            Ok(found) => found,
+            Err(e) => return e,
+        };
+        let entry = match queue.get(position) {
+            Some(entry) => entry,
+            None => return out_of_range(queue, &name, position),
+        };
+        if entry.user != user && !is_admin {
+            return format!(
+                "Sorry <@{}>, only <@{}> or a Queue admin can change the job in position {} of the \
+				*{}* queue.",
+                user, entry.user, position, name
+            );
+        }
+        let owner = entry.user.clone();
+        let mut job = entry.job.clone();
+        changes.apply(&mut job);
+        let described = format_job(&job).unwrap_or_else(|| String::from("nothing in particular"));
+        let mut response = if owner == user {
+            format!(
+                "Okay <@{}>, your job in position {} of the *{}* queue is now {}.",
+                user, position, name, described
+            )
+        } else {
+            format!(
+                "<@{}>, <@{}> has changed your job in position {} of the *{}* queue to {}.",
+                owner, user, position, name, described
+            )
+        };
+        match queue.set_job(position, job) {
+            EditResult::PositionOutOfRange(position) => {
+                return out_of_range(queue, &name, position)
+            }
+            EditResult::UnsuccessfullyEdited(e) => response.push_str(&backup_failure(&e)),
+            EditResult::Edited => (),
+        }
+        response
+    }
+
+    /// Handle `show all`, listing the people waiting in every queue.
+    fn show_all(&self) -> String {
+        let shown = self
+            .queues
+            .iter()
+            .map(|(name, queue)| format!("*{}*: {}", name, format_queue(self.users(), queue)))
+            .collect::<Vec<_>>();
+        if shown.is_empty() {
+            String::from("There are no queues!")
+        } else {
+            shown.join("\n\n")
+        }
+    }
+
+    /// Given the `body` of what `user` posted when mentioning Queue, determine what to say back.
+    ///
+    /// The body is parsed with [`command::parse`](../command/fn.parse.html). Most commands may be
+    /// followed by the name of the queue they should be applied to. If no name is given, the default
+    /// queue is used.
+    ///
+    /// Notifications for whoever the command moved near the front of a queue are collected along
+    /// the way (see [`QueueManager::take_notifications`](../manager/struct.QueueManager.html#method.take_notifications)).
+    pub fn determine_response(&mut self, user: UserID, body: &str) -> String {
+        self.queues.set_actor(Some(&user));
+        let response = self.respond(user.clone(), body);
+        self.queues.set_actor(None);
+        self.queues.collect_notifications(Some(&user));
+        response
+    }
+
+    /// Apply a command, returning what to say back. See [`SlackBot::determine_response`](#method.determine_response).
+    fn respond(&mut self, user: UserID, body: &str) -> String {
+        let command = match command::parse(body) {
+            Ok(command) => command,
+            Err(e) => return format!("Sorry <@{}>, {}", user, e),
+        };
+        if command.is_admin_only() && !self.queues.is_admin(&user) {
+            return format!(
+                "Sorry <@{}>, only Queue admins can use the {} command.",
+                user,
+                command.name()
+            );
+        }
+        let users = self.users();
+
+        let response = match command {
+            Command::Add { queue, count, job } => self
+                .queue_mut(queue.as_deref())
+                .map(|(_, queue)| add(queue, user, count, job)),
+            Command::Edit {
+                position,
+                queue,
+                changes,
+            } => Ok(self.edit(user, position, queue.as_deref(), &changes)),
+            Command::Done { queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(_, queue)| done(queue, user)),
+            Command::Start { queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| start(queue, &name, user)),
+            Command::Finish { queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| finish(queue, &name, user)),
+            Command::Failed { queue, retry } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| failed(queue, &name, user, retry)),
+            Command::Show { queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| format!("*{}*: {}", name, format_queue(users, queue))),
+            Command::ShowAll => Ok(self.show_all()),
+            Command::Create { queue, rules } => Ok(self.create(&queue, rules.as_deref())),
+            Command::Notify { delivery, queue } => {
+                Ok(self.notify(user, delivery, queue.as_deref()))
+            }
+            Command::Help { command: None } => Ok(usage()),
+            Command::Help {
+                command: Some(name),
+            } => Ok(command::help(name).unwrap_or_else(usage)),
+            Command::Move {
+                user: moved,
+                to,
+                queue,
+            } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| move_user(&user, queue, &name, &moved, to)),
+            Command::Swap { a, b, queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| swap(&user, queue, &name, a, b)),
+            Command::Kick {
+                user: kicked,
+                queue,
+            } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| kick(&user, queue, &name, kicked)),
+            Command::Clear { queue } => self
+                .queue_mut(queue.as_deref())
+                .map(|(name, queue)| clear(&user, queue, &name)),
+        };
+        response.unwrap_or_else(|e| e)
+    }
+
+    /// If the `body` of what someone posted when mentioning Queue is a `show` command for one queue
+    /// (as opposed to `show all`), the name of that queue and the queue itself.
+    pub fn shown_queue(&self, body: &str) -> Option<(&str, &Queue)> {
+        let name = match command::parse(body) {
+            Ok(Command::Show { queue }) => queue,
+            _ => return None,
+        };
+        let name = name.as_deref().unwrap_or(self.queues.default_queue());
+        self.queues.iter().find(|(queue, _)| *queue == name)
+    }
+
+    /// Retrieve the queue called `name`, or the default queue if no name is given. If there is no
+    /// such queue, a message saying so is returned instead.
+    fn queue_mut(&mut self, name: Option<&str>) -> Result<(String, &mut Queue), String> {
+        let name = name.unwrap_or(self.queues.default_queue()).to_string();
+        match self.queues.get_mut(&name) {
+            Some(queue) => Ok((name, queue)),
+            None => Err(format!(
+                "There is no queue called *{}*. You can create it with `@Queue create {}`.",
+                name, name
+            )),
+        }
+    }
+}
+
+/// How to show `user` in a list of the people in line: their real name in `users` followed by their
+/// username in parentheses.
+pub fn display_name(users: &SlackMap, user: &UserID) -> String {
+    // Someone who joined Slack after Queue started is not in the mapping, so they are shown by
+    // their ID just like someone without a real name
+    let (maybe_real_name, maybe_username) = users.get(user).unwrap_or(&(None, None));
+    let id = user.to_string();
+    let real_name = maybe_real_name.as_ref().unwrap_or(&id);
+    match maybe_username {
+        Some(uname) if !uname.is_empty() => format!("{} ({})", real_name, uname),
+        _ => real_name.to_string(),
+    }
+}
+
+/// The people waiting in `queue` and their places in line, shown by their names in `users`, along
+/// with their jobs (see [`format_job`](fn.format_job.html)), how long they have waited and when their turn should come (see [`wait_status`](fn.wait_status.html)).
+pub fn format_queue(users: &SlackMap, queue: &Queue) -> String {
+    if queue.is_empty() {
+        return String::from("Nobody is in line!");
+    }
+    let now = SystemTime::now();
+    let typical = queue
+        .typical_turn()
+        .map(|turn| {
+            format!(
+                " (a turn usually takes about {})",
+                policy::format_duration(turn)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "Here are the people currently in line{}:\n{}",
+        typical,
+        queue
+            .iter()
+            .zip(queue.estimated_waits(now))
+            .enumerate()
+            .map(|(idx, (entry, estimate))| format!(
+                "{}. {}{} \u{2014} {}\n",
+                idx,
+                display_name(users, &entry.user),
+                format_job(&entry.job)
+                    .map(|job| format!(": {}", job))
+                    .unwrap_or_default(),
+                wait_status(entry, estimate, now)
+            ))
+            .fold(String::default(), |acc, line| acc.to_owned() + &line)
+    )
+}
+
+/// How long the person in `entry` has been printing or at the front of the line as of `now`, or if
+/// they are not there yet, how long they have waited and (if it can be `estimate`d) how long until
+/// their turn, e.g. `waiting 2h 10m, up in about 1h`.
+pub fn wait_status(entry: &Entry, estimate: Option<Duration>, now: SystemTime) -> String {
+    if let Some(since) = entry
+        .entered(State::Printing)
+        .filter(|_| entry.state() == State::Printing)
+    {
+        let printing = now.duration_since(since).unwrap_or_default();
+        return format!("printing for {}", policy::format_duration(printing));
+    }
+    if let Some(since) = entry.front_since {
+        let turn = now.duration_since(since).unwrap_or_default();
+        return format!("at the front for {}", policy::format_duration(turn));
+    }
+    let mut status = format!("waiting {}", policy::format_duration(entry.waited(now)));
+    if let Some(estimate) = estimate {
+        status.push_str(&format!(
+            ", up in about {}",
+            policy::format_duration(estimate)
+        ));
+    }
+    status
+}
+
+/// A message letting the person at the front of the line know that it is their turn, or that
+/// nobody is in line.
+fn next_in_line_message(queue: &Queue) -> String {
+    match queue.peek_first_user_in_line() {
+        Some(next) if queue.delivery(next).in_channel() => {
+            format!("Hey <@{}>! You\'re next in line!", next)
+        }
+        // They asked not to be pinged here, and get a direct message instead
+        Some(_) => String::from("I have let the next person in line know."),
+        None => String::from("Nobody is next in line!"),
+    }
+}
+
+/// Handle the add command, which adds `user` to `queue` `count` times in a row (or as many times
+/// as the queue's rules allow), each time for `job`. Returns a message to post in the Slack channel
+/// depending on whether or not the user was actually added.
+pub(crate) fn add(queue: &mut Queue, user: UserID, count: usize, job: Job) -> String {
+    let described = format_job(&job);
+    let mut user = user;
+    let (mut added, mut rejected, mut failure) = (0, None, None);
+    for _ in 0..count {
+        let (returned, result) = queue.add_job(user, job.clone());
+        user = returned;
+        match result {
+            AddResult::UserSuccessfullyAdded => added += 1,
+            AddResult::UserNotAdded(rejection) => {
+                rejected = Some(rejection);
+                break;
+            }
+            AddResult::UserUnsuccessfullyAdded(e) => {
+                added += 1;
+                failure = Some(e);
+            }
+        }
+    }
+
+    let mut response = match (added, rejected) {
+        (0, Some(rejection)) => {
+            return format!(
+                "<@{}>, you cannot be added to the queue at this time: {}",
+                user, rejection
+            )
+        }
+        (_, Some(rejection)) => format!(
+            "Okay <@{}>, I could only add you to the queue {} of the {} times you asked for: {}",
+            user, added, count, rejection
+        ),
+        (_, None) => format!(
+            "Okay <@{}>, I have added you to the queue{}{}.",
+            user,
+            if count == 1 {
+                String::new()
+            } else {
+                format!(" {} times", count)
+            },
+            described
+                .map(|job| format!(" for {}", job))
+                .unwrap_or_default()
+        ),
+    };
+    if let Some(e) = failure {
+        response.push_str(&backup_failure(&e));
+    }
+    response
+}
+
+/// Describe what someone is waiting to do, e.g. `"benchy" (PLA, red, 2h, on the prusa)`, or `None`
+/// if they did not say.
+pub fn format_job(job: &Job) -> Option<String> {
+    let mut details = Vec::new();
+    details.extend(job.material.clone());
+    details.extend(job.color.clone());
+    details.extend(job.time.map(policy::format_duration));
+    details.extend(
+        job.printer
+            .as_ref()
+            .map(|printer| format!("on the {}", printer)),
+    );
+    let details = Some(details.join(", ")).filter(|details| !details.is_empty());
+    match (&job.name, details) {
+        (Some(name), Some(details)) => Some(format!("\"{}\" ({})", name, details)),
+        (Some(name), None) => Some(format!("\"{}\"", name)),
+        (None, details) => details,
+    }
+}
+
+/// Handle the done command. Returns a message to post in the Slack channel depending on whether
+/// or not the user was removed from `queue`.
+pub(crate) fn done(queue: &mut Queue, user: UserID) -> String {
+    match queue.remove_user(user) {
+        (user, RemoveResult::UserSuccessfullyRemoved(idx)) => {
+            let mut response = format!(
+                "Okay <@{}>, you have been removed from{}the queue.",
+                user.0,
+                if idx == 0 { " the front of " } else { " " }
+            );
+            // If the person just removed was at the front, then notify the next person in line
+            // (if there is one)
+            if idx == 0 {
+                response.push('\n');
+                response.push_str(&next_in_line_message(queue));
+            }
+            response
+        }
+        (user, RemoveResult::NonExistentUser) => format!(
+            "<@{}>, you cannot be removed; you are not \
+		in the queue.",
+            user
+        ),
+        (user, RemoveResult::UserUnsuccessfullyRemoved(e)) => format!(
+            "Hi <@{}>. You were removed \
+		from the queue, but this change has not been reflected in the backup file that stores \
+		the state of the queue. If it helps, the reason why is: {}",
+            user, e
+        ),
+    }
+}
+
+/// Handle the start command for the queue called `name`, which says that `user` has started
+/// printing at the front of the line.
+fn start(queue: &mut Queue, name: &str, user: UserID) -> String {
+    let result = queue.start(&user);
+    transition_response(queue, name, &user, result, |_| {
+        format!(
+            "Okay <@{}>, happy printing! Say `@Queue finish {}` when your print is done, or \
+			`@Queue failed {}` if it does not work out.",
+            user, name, name
+        )
+    })
+}
+
+/// Handle the finish command for the queue called `name`, which says that the print of `user` at
+/// the front of the line is done.
+fn finish(queue: &mut Queue, name: &str, user: UserID) -> String {
+    let result = queue.finish(&user);
+    transition_response(queue, name, &user, result, |queue| {
+        format!(
+            "Okay <@{}>, your print is finished and you have left the *{}* queue.\n{}",
+            user,
+            name,
+            next_in_line_message(queue)
+        )
+    })
+}
+
+/// Handle the failed and retry commands for the queue called `name`, which say that the print of
+/// `user` at the front of the line did not work out.
+fn failed(queue: &mut Queue, name: &str, user: UserID, retry: bool) -> String {
+    let result = queue.fail(&user, retry);
+    transition_response(queue, name, &user, result, |queue| {
+        if retry {
+            format!(
+                "Sorry to hear that, <@{}>. Your print is marked as failed, and you are still at \
+				the front of the *{}* queue to try again. Say `@Queue start {}` when you do.",
+                user, name, name
+            )
+        } else {
+            format!(
+                "Sorry to hear that, <@{}>. Your print is marked as failed and you have left the \
+				*{}* queue.\n{}",
+                user,
+                name,
+                next_in_line_message(queue)
+            )
+        }
+    })
+}
+
+/// What to say about a command of `user` that moves their place in the queue called `name` into a
+/// new state, given its `result`. If the command worked, `done` words the response.
+fn transition_response<F: FnOnce(&Queue) -> String>(
+    queue: &Queue,
+    name: &str,
+    user: &UserID,
+    result: TransitionResult,
+    done: F,
+) -> String {
+    match result {
+        TransitionResult::Transitioned => done(queue),
+        TransitionResult::UnsuccessfullyTransitioned(e) => done(queue) + &backup_failure(&e),
+        TransitionResult::UserNotInQueue => {
+            format!("<@{}>, you are not in the *{}* queue.", user, name)
+        }
+        TransitionResult::NotAtFront(position) => format!(
+            "<@{}>, it is not your turn yet; you are in position {} of the *{}* queue.",
+            user, position, name
+        ),
+        TransitionResult::NotAllowed(State::Printing) => format!(
+            "<@{}>, you have already started printing. Say `@Queue finish {}` when your print is \
+			done.",
+            user, name
+        ),
+        // Whoever is at the front of the line is either called or printing
+        TransitionResult::NotAllowed(_) => format!(
+            "<@{}>, you have not started printing in the *{}* queue yet. Say `@Queue start {}` \
+			once you do.",
+            user, name, name
+        ),
+    }
+}
+
+/// Parse a Slack mention of a user, e.g. `<@UA8RXUPSP>` or `<@UA8RXUPSP|joey>`, into the ID of the
+/// mentioned user.
+pub fn parse_mention(text: &str) -> Option<UserID> {
+    let id = text.strip_prefix("<@")?.strip_suffix('>')?;
+    let id = id.split('|').next()?;
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(UserID::new(id))
+    } else {
+        None
+    }
+}
+
+/// The sentence added to a response when a change to a queue could not be written to its backup
+/// file.
+fn backup_failure(e: &io::Error) -> String {
+    format!(
+        "\nThis change has not been reflected in the backup file that stores the state of the \
+		queue. If it helps, the reason why is: {}",
+        e
+    )
+}
+
+/// Handle the move command for the queue called `name`.
+fn move_user(admin: &UserID, queue: &mut Queue, name: &str, user: &UserID, to: usize) -> String {
+    let from = match queue.iter().position(|entry| entry.user == *user) {
+        Some(from) => from,
+        None => return format!("<@{}> is not in the *{}* queue.", user, name),
+    };
+    let mut response = format!(
+        "<@{}>, <@{}> has moved you from position {} to position {} in the *{}* queue.",
+        user, admin, from, to, name
+    );
+    match queue.move_user(user, to) {
+        RearrangeResult::PositionOutOfRange(to) => return out_of_range(queue, name, to),
+        RearrangeResult::UnsuccessfullyRearranged(e) => response.push_str(&backup_failure(&e)),
+        _ => (),
+    }
+    if from == 0 || to == 0 {
+        response.push('\n');
+        response.push_str(&next_in_line_message(queue));
+    }
+    response
+}
+
+/// Handle the swap command for the queue called `name`.
+fn swap(admin: &UserID, queue: &mut Queue, name: &str, a: usize, b: usize) -> String {
+    let (user_a, user_b) = match (queue.get(a), queue.get(b)) {
+        (Some(entry_a), Some(entry_b)) => (entry_a.user.clone(), entry_b.user.clone()),
+        (None, _) => return out_of_range(queue, name, a),
+        (_, None) => return out_of_range(queue, name, b),
+    };
+    let mut response = format!(
+        "<@{}> has swapped <@{}> (now in position {}) and <@{}> (now in position {}) in the *{}* \
+		queue.",
+        admin, user_a, b, user_b, a, name
+    );
+    if let RearrangeResult::UnsuccessfullyRearranged(e) = queue.swap(a, b) {
+        response.push_str(&backup_failure(&e));
+    }
+    if a == 0 || b == 0 {
+        response.push('\n');
+        response.push_str(&next_in_line_message(queue));
+    }
+    response
+}
+
+/// The response to an admin command that names a position past the end of the queue called `name`.
+fn out_of_range(queue: &Queue, name: &str, pos: usize) -> String {
+    format!(
+        "There is no position {} in the *{}* queue; it only has {} people in it.",
+        pos,
+        name,
+        queue.len()
+    )
+}
+
+/// Handle the kick command for the queue called `name`.
+fn kick(admin: &UserID, queue: &mut Queue, name: &str, user: UserID) -> String {
+    let (user, result) = queue.remove_user(user);
+    let mut response = format!(
+        "<@{}>, <@{}> has removed you from the *{}* queue.",
+        user, admin, name
+    );
+    match result {
+        RemoveResult::NonExistentUser => {
+            return format!("<@{}> is not in the *{}* queue.", user, name)
+        }
+        RemoveResult::UserSuccessfullyRemoved(0) => {
+            response.push('\n');
+            response.push_str(&next_in_line_message(queue));
+        }
+        RemoveResult::UserSuccessfullyRemoved(_) => (),
+        RemoveResult::UserUnsuccessfullyRemoved(e) => response.push_str(&backup_failure(&e)),
+    }
+    response
+}
+
+/// Handle the clear command for the queue called `name`.
+fn clear(admin: &UserID, queue: &mut Queue, name: &str) -> String {
+    let (removed, written) = queue.clear();
+    let mut mentioned = Vec::new();
+    for user in removed {
+        if !mentioned.contains(&user) {
+            mentioned.push(user);
+        }
+    }
+    let mut response = format!("<@{}> has cleared the *{}* queue.", admin, name);
+    if !mentioned.is_empty() {
+        let mentions = mentioned
+            .iter()
+            .map(|user| format!("<@{}>", user))
+            .collect::<Vec<_>>();
+        response.push_str(&format!(
+            " {}, you are no longer in line.",
+            mentions.join(", ")
+        ));
+    }
+    if let Err(e) = written {
+        response.push_str(&backup_failure(&e));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::*;
+    use crate::manager::DEFAULT_QUEUE;
+    use crate::storage::Backend;
+
+    /// A bot for queues that are only kept in memory.
+    fn bot(users: &SlackMap) -> SlackBot<'_> {
+        let queues = QueueManager::from_backend(Backend::Memory, DEFAULT_QUEUE).unwrap();
+        SlackBot::new(queues, users)
+    }
+
+    #[test]
+    fn create_and_join_named_queues() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let user = UserID::new("UA8RXUPSP");
+
+        let response = bot.determine_response(user.clone(), "<@U01A844Q2US> create prusa");
+        assert!(response.starts_with("Okay, I have created the *prusa* queue"));
+        let response = bot.determine_response(user.clone(), "create prusa");
+        assert_eq!(response, "There already is a queue called *prusa*.");
+
+        bot.determine_response(user.clone(), "<@U01A844Q2US> add prusa");
+        bot.determine_response(user.clone(), "<@U01A844Q2US> add");
+
+        assert_eq!(bot.queues().get("prusa").unwrap().len(), 1);
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+
+        let response = bot.determine_response(user, "<@U01A844Q2US> done prusa");
+
+        assert_eq!(
+            response,
+            "Okay <@UA8RXUPSP>, you have been removed from the front of the queue.\nNobody is next \
+			in line!"
+        );
+        assert!(bot.queues().get("prusa").unwrap().is_empty());
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn each_queue_has_its_own_policy() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let user = UserID::new("UA8RXUPSP");
+        bot.determine_response(user.clone(), "create prusa max-total=1");
+        bot.determine_response(user.clone(), "add prusa");
+
+        let response = bot.determine_response(UserID::new("UNB2LMZRP"), "add prusa");
+
+        assert!(response.contains("the queue is full"), "{}", response);
+        assert_eq!(bot.queues().get("prusa").unwrap().len(), 1);
+        let response = bot.determine_response(user, "create ultimaker max-per-user");
+        assert!(
+            response.contains("did not understand its rules"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn queues_are_shown_by_name() {
+        let mut hash_map = HashMap::new();
+        hash_map.insert(
+            UserID::new("UA8RXUPSP"),
+            (Some(String::from("Joey")), Some(String::from("joey"))),
+        );
+        let mut bot = bot(&hash_map);
+        bot.determine_response(UserID::new("UA8RXUPSP"), "add");
+        bot.determine_response(UserID::new("UNB2LMZRP"), "add");
+
+        // Someone who is not in the mapping is shown by their ID
+        let shown = bot.determine_response(UserID::new("UA8RXUPSP"), "show");
+        let lines = shown.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "*printer*: Here are the people currently in line:"
+        );
+        assert!(lines[1].starts_with("0. Joey (joey) \u{2014} at the front for"));
+        assert!(lines[2].starts_with("1. UNB2LMZRP \u{2014} waiting"));
+        bot.determine_response(UserID::new("UA8RXUPSP"), "create prusa");
+        assert!(bot
+            .determine_response(UserID::new("UA8RXUPSP"), "show all")
+            .ends_with("*prusa*: Nobody is in line!"));
+    }
+
+    #[test]
+    fn wait_statuses() {
+        let hour = Duration::from_secs(60 * 60);
+        let joined = SystemTime::now();
+        let now = joined + 2 * hour + hour / 6;
+        let mut entry = Entry::new(UserID::new("UA8RXUPSP"), joined);
+
+        assert_eq!(wait_status(&entry, None, now), "waiting 2h 10m");
+        assert_eq!(
+            wait_status(&entry, Some(hour), now),
+            "waiting 2h 10m, up in about 1h"
+        );
+        entry.front_since = Some(joined + 2 * hour);
+        assert_eq!(
+            wait_status(&entry, Some(Duration::from_secs(0)), now),
+            "at the front for 10m"
+        );
+    }
+
+    #[test]
+    fn front_timeout_notices_name_the_queue() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        bot.queues_mut().create_queue("prusa");
+        bot.queues_mut().set_front_timeout(Some(FrontTimeout {
+            limit: Duration::from_secs(60),
+            grace: Duration::from_secs(60),
+            action: TimeoutAction::Remove,
+        }));
+        bot.determine_response(UserID::new("UA8RXUPSP"), "add prusa");
+        bot.determine_response(UserID::new("UNB2LMZRP"), "add prusa");
+        let now = SystemTime::now();
+
+        let warnings = bot.check_front_timeouts(now + Duration::from_secs(90));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .starts_with("Hey <@UA8RXUPSP>, you have been at the front of the *prusa* queue"));
+
+        let removals = bot.check_front_timeouts(now + Duration::from_secs(180));
+        assert_eq!(removals.len(), 1);
+        assert!(removals[0].ends_with("Hey <@UNB2LMZRP>! You're next in line!"));
+        assert_eq!(bot.queues().get("prusa").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn mentions() {
+        assert_eq!(
+            parse_mention("<@UA8RXUPSP>"),
+            Some(UserID::new("UA8RXUPSP"))
+        );
+        assert_eq!(
+            parse_mention("<@UA8RXUPSP|joey>"),
+            Some(UserID::new("UA8RXUPSP"))
+        );
+        assert_eq!(parse_mention("UA8RXUPSP"), None);
+        assert_eq!(parse_mention("<#C01A844Q2US>"), None);
+        assert_eq!(parse_mention("<@>"), None);
+    }
+
+    #[test]
+    fn only_admins_rearrange_queues() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let admin = UserID::new("UADMIN000");
+        bot.queues_mut().set_admins(vec![admin.clone()]);
+        for user in &["UA8RXUPSP", "UNB2LMZRP", "UN480W9ND"] {
+            bot.determine_response(UserID::new(user), "add");
+        }
+        let line = |bot: &SlackBot| {
+            bot.queues()
+                .get(DEFAULT_QUEUE)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.user.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let response = bot.determine_response(UserID::new("UA8RXUPSP"), "clear");
+        assert!(response.starts_with("Sorry <@UA8RXUPSP>, only Queue admins"));
+        assert_eq!(line(&bot).len(), 3);
+
+        let response = bot.determine_response(admin.clone(), "<@U01A844Q2US> MOVE <@UN480W9ND> 0");
+        assert!(response
+            .starts_with("<@UN480W9ND>, <@UADMIN000> has moved you from position 2 to position 0"));
+        assert!(response.ends_with("Hey <@UN480W9ND>! You're next in line!"));
+        assert_eq!(line(&bot), ["UN480W9ND", "UA8RXUPSP", "UNB2LMZRP"]);
+
+        bot.determine_response(admin.clone(), "swap 1 2 printer");
+        assert_eq!(line(&bot), ["UN480W9ND", "UNB2LMZRP", "UA8RXUPSP"]);
+
+        let response = bot.determine_response(admin.clone(), "kick <@UNB2LMZRP>");
+        assert!(response.starts_with("<@UNB2LMZRP>, <@UADMIN000> has removed you"));
+        assert_eq!(line(&bot), ["UN480W9ND", "UA8RXUPSP"]);
+
+        let response = bot.determine_response(admin.clone(), "move <@UA8RXUPSP> 7");
+        assert!(response.starts_with("There is no position 7"));
+
+        let response = bot.determine_response(admin, "clear");
+        assert!(response.contains("<@UN480W9ND>, <@UA8RXUPSP>, you are no longer in line."));
+        assert!(line(&bot).is_empty());
+    }
+
+    #[test]
+    fn people_join_several_times_with_a_note() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let user = UserID::new("UA8RXUPSP");
+
+        let response = bot.determine_response(
+            user.clone(),
+            "Hey <@U01A844Q2US>, join 2 --note \"PLA benchy\"",
+        );
+        assert_eq!(
+            response,
+            "Okay <@UA8RXUPSP>, I have added you to the queue 2 times for \"PLA benchy\"."
+        );
+        // The default rules only allow three back-to-back places in line
+        let response = bot.determine_response(user.clone(), "add 3");
+        assert!(
+            response.starts_with("Okay <@UA8RXUPSP>, I could only add you to the queue 1 of the 3"),
+            "{}",
+            response
+        );
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 3);
+
+        bot.determine_response(user.clone(), "leave");
+        assert_eq!(bot.queues().get(DEFAULT_QUEUE).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn jobs_are_shown_and_edited() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let (a, b, admin) = (
+            UserID::new("UA8RXUPSP"),
+            UserID::new("UNB2LMZRP"),
+            UserID::new("UADMIN000"),
+        );
+        bot.queues_mut().set_admins(vec![admin.clone()]);
+
+        let response = bot.determine_response(
+            a.clone(),
+            "add --name benchy --material PLA --color red --time 2h --printer prusa",
+        );
+        assert_eq!(
+            response,
+            "Okay <@UA8RXUPSP>, I have added you to the queue for \"benchy\" (PLA, red, 2h, on the \
+			prusa)."
+        );
+        bot.determine_response(b.clone(), "add --material PETG");
+        let shown = bot.determine_response(a.clone(), "show");
+        assert!(
+            shown.contains("\n0. UA8RXUPSP: \"benchy\" (PLA, red, 2h, on the prusa) \u{2014} at")
+        );
+        assert!(shown.contains("\n1. UNB2LMZRP: PETG \u{2014} waiting"));
+
+        let response = bot.determine_response(b.clone(), "edit 0 --color blue");
+        assert!(response.starts_with("Sorry <@UNB2LMZRP>, only <@UA8RXUPSP> or a Queue admin"));
+        let response = bot.determine_response(a.clone(), "edit 0 --color blue --time \"\"");
+        assert_eq!(
+            response,
+            "Okay <@UA8RXUPSP>, your job in position 0 of the *printer* queue is now \"benchy\" \
+			(PLA, blue, on the prusa)."
+        );
+        let response = bot.determine_response(admin, "edit 1 --material \"\"");
+        assert!(response.starts_with("<@UNB2LMZRP>, <@UADMIN000> has changed your job"));
+        assert!(response.ends_with("to nothing in particular."));
+        assert!(bot
+            .determine_response(b, "edit 5 --name vase")
+            .starts_with("There is no position 5"));
+    }
+
+    #[test]
+    fn prints_are_started_and_finished() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let (a, b) = (UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP"));
+        bot.determine_response(a.clone(), "add");
+        bot.determine_response(b.clone(), "add");
+
+        assert_eq!(
+            bot.determine_response(b.clone(), "start"),
+            "<@UNB2LMZRP>, it is not your turn yet; you are in position 1 of the *printer* queue."
+        );
+        assert!(bot.determine_response(a.clone(), "finish").starts_with(
+            "<@UA8RXUPSP>, you have not started printing in the *printer* queue yet."
+        ));
+        assert!(bot
+            .determine_response(a.clone(), "start")
+            .starts_with("Okay <@UA8RXUPSP>, happy printing!"));
+        assert!(bot
+            .determine_response(a.clone(), "show")
+            .contains("\n0. UA8RXUPSP \u{2014} printing for"));
+
+        let response = bot.determine_response(a.clone(), "retry");
+        assert!(response.contains("you are still at the front of the *printer* queue"));
+        bot.determine_response(a.clone(), "start");
+        assert_eq!(
+            bot.determine_response(a, "finished"),
+            "Okay <@UA8RXUPSP>, your print is finished and you have left the *printer* queue.\n\
+			Hey <@UNB2LMZRP>! You're next in line!"
+        );
+        bot.determine_response(b.clone(), "start");
+        assert!(bot
+            .determine_response(b, "failed")
+            .ends_with("Nobody is next in line!"));
+    }
+
+    #[test]
+    fn help_and_parse_errors() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let user = UserID::new("UA8RXUPSP");
+
+        let help = bot.determine_response(user.clone(), "help");
+        assert_eq!(help, usage());
+        for spec in command::COMMANDS {
+            assert!(
+                help.contains(spec.usage),
+                "{} is not in {}",
+                spec.name,
+                help
+            );
+        }
+        assert!(bot
+            .determine_response(user.clone(), "help leave")
+            .starts_with("*done [queue]*"));
+        assert_eq!(
+            bot.determine_response(user.clone(), "<@U01A844Q2US> shwo"),
+            "Sorry <@UA8RXUPSP>, I do not know the command `shwo`. Did you mean `show`? Try \
+			`@Queue help` to see every command."
+        );
+        // Admin commands are parsed before anyone is told that they are only for admins
+        assert!(bot
+            .determine_response(user, "move 2")
+            .contains("`2` does not work there"));
+    }
+
+    #[test]
+    fn unknown_queues_are_reported() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+
+        let response = bot.determine_response(UserID::new("UA8RXUPSP"), "add ultimaker");
+
+        assert!(response.contains("There is no queue called *ultimaker*"));
+        assert!(bot.queues().get("ultimaker").is_none());
+    }
+
+    #[test]
+    fn shown_queues() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        bot.queues_mut().create_queue("prusa");
+
+        let shown = |body| bot.shown_queue(body).map(|(name, _)| name);
+        assert_eq!(shown("<@U01A844Q2US> show"), Some(DEFAULT_QUEUE));
+        assert_eq!(shown("<@U01A844Q2US> SHOW Prusa"), Some("prusa"));
+        assert_eq!(shown("show all"), None);
+        assert_eq!(shown("show ultimaker"), None);
+        assert_eq!(shown("add prusa"), None);
+    }
+
+    #[test]
+    fn people_choose_how_they_are_notified() {
+        let hash_map = HashMap::new();
+        let mut bot = bot(&hash_map);
+        let (a, b) = (UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP"));
+        bot.determine_response(a.clone(), "add");
+        bot.determine_response(b.clone(), "add");
+
+        let response = bot.determine_response(b.clone(), "<@U01A844Q2US> notify DM");
+        assert!(response.contains("direct message"), "{}", response);
+        assert_eq!(
+            bot.queues().get(DEFAULT_QUEUE).unwrap().delivery(&b),
+            Delivery::DirectMessage
+        );
+
+        // B is not pinged in the channel, since they asked for a direct message instead
+        let response = bot.determine_response(a, "done");
+        assert!(!response.contains("<@UNB2LMZRP>"), "{}", response);
+        assert!(response.ends_with("I have let the next person in line know."));
+        let notifications = bot.queues_mut().take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].user, b);
+
+        let response = bot.determine_response(b, "notify carrier-pigeon");
+        assert!(response.contains("is not one of"), "{}", response);
+    }
+
+    #[test]
+    fn notify_says_when_it_could_not_save() {
+        let dir =
+            std::env::temp_dir().join(format!("queue_slack_bot_notify_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let queues =
+            QueueManager::from_backend(Backend::TextFiles(dir.clone()), DEFAULT_QUEUE).unwrap();
+        let hash_map = HashMap::new();
+        let mut bot = SlackBot::new(queues, &hash_map);
+        let user = UserID::new("UA8RXUPSP");
+
+        // Nothing can be saved once the state directory is gone
+        std::fs::remove_dir_all(&dir).unwrap();
+        let response = bot.determine_response(user, "notify DM");
+        assert!(
+            response.starts_with(
+                "Okay <@UA8RXUPSP>, I will let you know with a direct message when your turn in \
+                 the *printer* queue is coming up, but I could not save that, so I will forget it \
+                 if I restart. If it helps, the reason why is: "
+            ),
+            "{}",
+            response
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
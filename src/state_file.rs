@@ -0,0 +1,477 @@
+//! The versioned JSON format that a [`TextFileStorage`](../storage/struct.TextFileStorage.html)
+//! saves a queue in.
+//!
+//! A state file is a single JSON object that says which version of the format it was written in,
+//! along with the entries of the queue and its metadata (e.g. the spec of its admission policy):
+//! ```json
+//! {
+//!   "version": 1,
+//!   "entries": [
+//!     {
+//!       "position": 0,
+//!       "user": "UA8RXUPSP",
+//!       "joined": 1600000000,
+//!       "front_since": 1600000060,
+//!       "job": { "material": "PLA", "time": 3600 },
+//!       "states": [
+//!         { "state": "waiting", "at": 1600000000 },
+//!         { "state": "called", "at": 1600000060 }
+//!       ]
+//!     }
+//!   ],
+//!   "metadata": { "policy": "max-total=3" }
+//! }
+//! ```
+//! Times are in seconds since the Unix epoch, and durations are in seconds. Everything but the
+//! version, the position and user of each entry, and the time they joined may be left out.
+//!
+//! Files written in an older version are read and upgraded the next time they are saved, and files
+//! written in a newer version than [`VERSION`](constant.VERSION.html) are not read at all, since
+//! there is no telling what would be lost by saving over them. Before there were versions, queues
+//! were saved as lines of tab-separated fields; see [`is_legacy`](fn.is_legacy.html).
+
+use std::{
+    collections::{btree_map, BTreeMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::queue::{Entry, Job, LoadError, State, Transition};
+use crate::storage::{from_unix_secs, to_unix_secs, Record};
+use crate::user::UserID;
+
+/// The version of the format that is written, and the newest version that can be read.
+pub const VERSION: u64 = 1;
+
+/// Why a state file could not be read at all.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FormatError {
+    /// The file was written in a newer version of the format than this one, which is given.
+    Newer(u64),
+    /// The file is not a state file in any version of the format, for the given reason, which was
+    /// found on the given line.
+    Invalid { line: usize, reason: String },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Newer(version) => write!(
+                f,
+                "the file was written in version {} of the state format, but this version of Queue \
+				 only understands up to version {}",
+                version, VERSION
+            ),
+            FormatError::Invalid { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+/// A state file that was read back.
+#[derive(Debug, Default)]
+pub struct Document {
+    /// The version of the format it was written in
+    pub version: u64,
+    /// The entries in it, in order from the front of the line to the back, followed by the
+    /// entries that do not make sense
+    pub records: Vec<Result<Record, LoadError>>,
+    /// The metadata saved alongside the entries
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A state file that was read back without putting its entries in order. See
+/// [`scan`](fn.scan.html).
+#[derive(Debug, Default)]
+pub struct Scanned {
+    /// The version of the format it was written in
+    pub version: u64,
+    /// The entries in it, in the order they were written in
+    pub slots: Vec<Slot>,
+    /// The metadata saved alongside the entries
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// An entry of a state file as it was written, before it was put in its place in line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Slot {
+    /// The line (in a legacy file) or the number of the entry (in a JSON file) it was read from,
+    /// counting from 1
+    pub line: usize,
+    /// The position it was written at and the entry itself, or what does not make sense about it
+    pub entry: Result<(usize, Entry), String>,
+}
+
+/// How an entry is written in a state file.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedEntry {
+    position: usize,
+    user: String,
+    joined: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    front_since: Option<u64>,
+    #[serde(default, skip_serializing_if = "SavedJob::is_empty")]
+    job: SavedJob,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    states: Vec<SavedTransition>,
+}
+
+/// How a [`Job`](../queue/struct.Job.html) is written in a state file.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct SavedJob {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    material: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    printer: Option<String>,
+}
+
+impl SavedJob {
+    fn is_empty(&self) -> bool {
+        *self == SavedJob::default()
+    }
+}
+
+/// How a [`Transition`](../queue/struct.Transition.html) is written in a state file.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedTransition {
+    state: String,
+    at: u64,
+}
+
+impl SavedEntry {
+    fn new(position: usize, entry: &Entry) -> Self {
+        let job = &entry.job;
+        Self {
+            position,
+            user: entry.user.to_string(),
+            joined: to_unix_secs(entry.joined),
+            front_since: entry.front_since.map(to_unix_secs),
+            job: SavedJob {
+                name: job.name.clone(),
+                material: job.material.clone(),
+                color: job.color.clone(),
+                time: job.time.map(|time| time.as_secs()),
+                printer: job.printer.clone(),
+            },
+            states: entry
+                .transitions
+                .iter()
+                .map(|transition| SavedTransition {
+                    state: transition.state.to_string(),
+                    at: to_unix_secs(transition.at),
+                })
+                .collect(),
+        }
+    }
+
+    /// The position and entry this describes, or what does not make sense about it.
+    fn into_entry(self) -> Result<(usize, Entry), String> {
+        let transitions = self
+            .states
+            .into_iter()
+            .map(|saved| {
+                Ok(Transition {
+                    state: saved.state.parse::<State>()?,
+                    at: from_unix_secs(saved.at),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let entry = Entry {
+            user: UserID(self.user),
+            joined: from_unix_secs(self.joined),
+            front_since: self.front_since.map(from_unix_secs),
+            job: Job {
+                name: self.job.name,
+                material: self.job.material,
+                color: self.job.color,
+                time: self.job.time.map(Duration::from_secs),
+                printer: self.job.printer,
+            },
+            transitions,
+        };
+        Ok((self.position, entry))
+    }
+}
+
+/// Does `contents` look like it was written before state files had versions, i.e. is it anything
+/// but a JSON object? Empty files are not legacy files; they are just empty.
+pub fn is_legacy(contents: &str) -> bool {
+    let contents = contents.trim_start();
+    !contents.is_empty() && !contents.starts_with('{')
+}
+
+/// Read a state file. Entries are sorted by their position; an entry that does not make sense, or
+/// that has the same position as an entry before it, is returned as an error in its place (with
+/// the number of the entry, counting from 1, as its line).
+///
+/// An empty file is an empty queue.
+pub fn parse(contents: &str) -> Result<Document, FormatError> {
+    let Scanned {
+        version,
+        slots,
+        metadata,
+    } = scan(contents)?;
+    Ok(Document {
+        version,
+        records: place(slots),
+        metadata,
+    })
+}
+
+/// Read the version, entries, and metadata of a state file without putting the entries in order
+/// or checking their positions, e.g. to find out everything that is wrong with it.
+pub fn scan(contents: &str) -> Result<Scanned, FormatError> {
+    if contents.trim().is_empty() {
+        return Ok(Scanned {
+            version: VERSION,
+            ..Scanned::default()
+        });
+    }
+    let invalid = |line: usize, reason: String| FormatError::Invalid { line, reason };
+    let document = serde_json::from_str::<Value>(contents)
+        .map_err(|e| invalid(e.line(), format!("the file is not valid JSON ({})", e)))?;
+    let version = match document.get("version") {
+        Some(Value::Number(version)) => version.as_u64(),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        invalid(
+            1,
+            String::from("the file does not say which version it is in"),
+        )
+    })?;
+    if version > VERSION {
+        return Err(FormatError::Newer(version));
+    }
+
+    let metadata = match document.get("metadata") {
+        None | Some(Value::Null) => BTreeMap::new(),
+        Some(metadata) => serde_json::from_value(metadata.clone()).map_err(|e| {
+            invalid(
+                1,
+                format!("the metadata must be an object of strings ({})", e),
+            )
+        })?,
+    };
+    let entries = match document.get("entries") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(entries)) => entries.clone(),
+        Some(_) => return Err(invalid(1, String::from("the entries must be a list"))),
+    };
+
+    let slots = entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| Slot {
+            line: i + 1,
+            entry: serde_json::from_value::<SavedEntry>(entry)
+                .map_err(|e| e.to_string())
+                .and_then(SavedEntry::into_entry),
+        })
+        .collect();
+    Ok(Scanned {
+        version,
+        slots,
+        metadata,
+    })
+}
+
+/// Put the entries of `slots` in order of their positions, followed by the ones that do not make
+/// sense and the ones whose position was taken by an entry before them, as errors.
+pub(crate) fn place(slots: Vec<Slot>) -> Vec<Result<Record, LoadError>> {
+    let mut people = BTreeMap::new();
+    let mut problems = Vec::new();
+    for Slot { line, entry } in slots {
+        match entry {
+            Ok((position, entry)) => match people.entry(position) {
+                btree_map::Entry::Vacant(vacant) => {
+                    vacant.insert(Record { line, entry });
+                }
+                btree_map::Entry::Occupied(_) => {
+                    problems.push(Err(LoadError::DuplicatePosition { line, position }))
+                }
+            },
+            Err(reason) => problems.push(Err(LoadError::InvalidLine { line, reason })),
+        }
+    }
+    people.into_values().map(Ok).chain(problems).collect()
+}
+
+/// Write `entries` and `metadata` as a state file in the current version of the format.
+pub fn format<'a, I: IntoIterator<Item = &'a Entry>>(
+    entries: I,
+    metadata: &BTreeMap<String, String>,
+) -> String {
+    let entries = entries
+        .into_iter()
+        .enumerate()
+        .map(|(position, entry)| SavedEntry::new(position, entry))
+        .collect::<Vec<_>>();
+    let document = json!({
+        "version": VERSION,
+        "entries": entries,
+        "metadata": metadata,
+    });
+    let mut text = serde_json::to_string_pretty(&document).expect("Could not write a state file");
+    text.push('\n');
+    text
+}
+
+/// Set the piece of metadata called `key` in the state file `contents` to `value`, leaving
+/// everything else in it (including entries that do not make sense) as it is.
+pub fn set_metadata(contents: &str, key: &str, value: &str) -> Result<String, FormatError> {
+    if contents.trim().is_empty() {
+        let mut metadata = BTreeMap::new();
+        metadata.insert(key.to_string(), value.to_string());
+        return Ok(format(&VecDeque::new(), &metadata));
+    }
+    // Make sure the file is one that can be written to
+    parse(contents)?;
+    let mut document =
+        serde_json::from_str::<Value>(contents).map_err(|e| FormatError::Invalid {
+            line: e.line(),
+            reason: e.to_string(),
+        })?;
+    let object = document
+        .as_object_mut()
+        .expect("A state file that was just parsed is not an object");
+    let metadata = object.entry("metadata").or_insert_with(|| json!({}));
+    if !metadata.is_object() {
+        *metadata = json!({});
+    }
+    metadata[key] = Value::String(value.to_string());
+    let mut text = serde_json::to_string_pretty(&document).expect("Could not write a state file");
+    text.push('\n');
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> VecDeque<Entry> {
+        let joined = from_unix_secs(1_600_000_000);
+        let mut entries = VecDeque::new();
+        entries.push_back(Entry::new(UserID::new("UA8RXUPSP"), joined));
+        entries.push_back(Entry {
+            front_since: Some(joined + Duration::from_secs(60)),
+            job: Job {
+                name: Some(String::from("a \"benchy\"")),
+                material: Some(String::from("PLA")),
+                color: None,
+                time: Some(Duration::from_secs(3600)),
+                printer: None,
+            },
+            ..Entry::new(UserID::new("UNB2LMZRP"), joined + Duration::from_secs(30))
+        });
+        entries
+    }
+
+    fn loaded(document: &Document) -> Vec<Entry> {
+        document
+            .records
+            .iter()
+            .map(|record| record.as_ref().unwrap().entry.clone())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut metadata = BTreeMap::new();
+        metadata.insert(String::from("policy"), String::from("max-total=3"));
+        let text = format(&entries(), &metadata);
+
+        let document = parse(&text).unwrap();
+        assert_eq!(document.version, VERSION);
+        assert_eq!(loaded(&document), Vec::from(entries()));
+        assert_eq!(document.metadata, metadata);
+        assert!(!is_legacy(&text));
+        assert!(is_legacy("0\tUA8RXUPSP\n"));
+        assert!(!is_legacy("  \n"));
+    }
+
+    #[test]
+    fn entries_are_sorted_and_checked() {
+        let text = r#"{
+            "version": 1,
+            "entries": [
+                { "position": 1, "user": "UNB2LMZRP", "joined": 1600000030 },
+                { "position": 0, "user": "UA8RXUPSP", "joined": 1600000000,
+                  "states": [{ "state": "waiting", "at": 1600000000 }] },
+                { "position": 1, "user": "UN480W9ND", "joined": 1600000060 },
+                { "position": 2, "user": "UN480W9ND" },
+                { "position": 3, "user": "UN480W9ND", "joined": 0,
+                  "states": [{ "state": "dancing", "at": 0 }] }
+            ],
+            "future": "fields that are not known yet are ignored"
+        }"#;
+        let document = parse(text).unwrap();
+
+        assert_eq!(
+            document
+                .records
+                .iter()
+                .filter_map(|record| record.as_ref().ok())
+                .map(|record| (record.line, record.entry.user.to_string()))
+                .collect::<Vec<_>>(),
+            [
+                (2, String::from("UA8RXUPSP")),
+                (1, String::from("UNB2LMZRP"))
+            ]
+        );
+        assert!(matches!(
+            document.records[2..],
+            [
+                Err(LoadError::DuplicatePosition {
+                    line: 3,
+                    position: 1
+                }),
+                Err(LoadError::InvalidLine { line: 4, .. }),
+                Err(LoadError::InvalidLine { line: 5, .. }),
+            ]
+        ));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        assert_eq!(
+            parse(r#"{ "version": 2, "entries": [] }"#).unwrap_err(),
+            FormatError::Newer(2)
+        );
+        assert!(matches!(
+            parse(r#"{ "entries": [] }"#),
+            Err(FormatError::Invalid { .. })
+        ));
+        assert!(matches!(
+            parse("{\n\"version\": 1,\n"),
+            Err(FormatError::Invalid { line: 3, .. })
+        ));
+        assert!(set_metadata(r#"{ "version": 2 }"#, "policy", "max-total=3").is_err());
+    }
+
+    #[test]
+    fn metadata_is_set_in_place() {
+        let text = set_metadata("", "policy", "max-total=3").unwrap();
+        let text = set_metadata(&text, "turns", "60").unwrap();
+        let text = set_metadata(&text, "policy", "max-total=4").unwrap();
+        let document = parse(&text).unwrap();
+        assert!(document.records.is_empty());
+        assert_eq!(document.metadata.len(), 2);
+        assert_eq!(document.metadata["policy"], "max-total=4");
+
+        // Entries are left alone, even if they do not make sense
+        let text = r#"{ "version": 1, "entries": [{ "position": "first" }] }"#;
+        let text = set_metadata(text, "policy", "max-total=3").unwrap();
+        assert!(text.contains("\"first\""));
+    }
+}
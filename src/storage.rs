@@ -0,0 +1,1834 @@
+//! Places a [`Queue`](../queue/struct.Queue.html) can keep its state so that it survives a restart.
+//!
+//! A queue writes its entries to its [`Storage`](trait.Storage.html) after every change, and reads
+//! them back when it is created. Besides the entries, a storage can hold a few pieces of metadata
+//! about the queue (e.g. the spec of its admission policy) as key-value pairs.
+//!
+//! There are three kinds of storage:
+//! * [`TextFileStorage`](struct.TextFileStorage.html): the state file Queue has always used, which
+//!   is now a versioned JSON document (see the [`state_file`](../state_file/index.html) module).
+//! * [`SqliteStorage`](struct.SqliteStorage.html): a table in an SQLite database, which can hold any
+//!   number of queues and is written to transactionally. Requires the `sqlite` feature.
+//! * [`MemoryStorage`](struct.MemoryStorage.html): nothing is written anywhere, which is handy for
+//!   tests.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::journal::{self, Event, JournalStorage};
+#[cfg(feature = "sqlite")]
+use crate::journal::{LoggedEvent, Operation};
+use crate::lock::{self, Lock, LockError};
+use crate::manager::POLICY_KEY;
+use crate::notify::DELIVERY_KEY;
+use crate::queue::{Entry, Job, LoadError, State, Transition, EXEMPT_KEY, TURNS_KEY};
+use crate::state_file::{self, Document, FormatError, Slot};
+use crate::user::UserID;
+
+/// The extension of the files that [`Backend::TextFiles`](enum.Backend.html#variant.TextFiles)
+/// keeps each queue in.
+const STATE_FILE_EXTENSION: &str = "txt";
+
+/// The extension of the file that a [`TextFileStorage`](struct.TextFileStorage.html) keeps its
+/// history in, next to the text file.
+const HISTORY_FILE_EXTENSION: &str = "history";
+
+/// The pieces of metadata that may be kept in files next to a state file from before metadata was
+/// kept in it, which are moved into the state file the next time it is saved.
+const LEGACY_METADATA_KEYS: &[&str] = &[POLICY_KEY, DELIVERY_KEY, TURNS_KEY, EXEMPT_KEY];
+
+/// How many of the snapshots it saved before the current one a
+/// [`TextFileStorage`](struct.TextFileStorage.html) keeps, unless told otherwise.
+pub const DEFAULT_BACKUPS: usize = 3;
+
+/// Somewhere a queue's entries and metadata can be saved to and loaded from.
+pub trait Storage: fmt::Debug + Send {
+    /// Read back the entries that were last saved, in order from the front of the line to the back.
+    ///
+    /// An error is only returned if the saved state could not be read at all. Each entry that was
+    /// read but does not make sense is returned as an error in its place instead, so that the caller
+    /// can decide whether to skip it or give up.
+    fn load(&mut self) -> io::Result<Vec<Result<Record, LoadError>>>;
+
+    /// Replace whatever was saved before with `entries`.
+    fn save(&mut self, entries: &VecDeque<Entry>) -> io::Result<()>;
+
+    /// Save `entries`, which are what the queue looks like after `event`. Storage that keeps a
+    /// [`journal`](../journal/index.html) writes the event down; other storage just saves the
+    /// entries.
+    fn save_event(&mut self, event: &Event, entries: &VecDeque<Entry>) -> io::Result<()> {
+        let _ = event;
+        self.save(entries)
+    }
+
+    /// Read the piece of metadata called `key`, if it was ever saved.
+    fn metadata(&mut self, key: &str) -> io::Result<Option<String>>;
+
+    /// Save `value` as the piece of metadata called `key`, replacing any previous value.
+    fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()>;
+
+    /// Move the saved entries somewhere they will not be loaded from again, leaving nothing saved,
+    /// and describe where they went. Used to start a queue over without losing state that could not
+    /// be loaded.
+    fn set_aside(&mut self) -> io::Result<String>;
+
+    /// Add `entry`, whose place in line has ended (e.g. because their print finished), to the
+    /// history of the queue. Unlike the entries, the history is only ever added to.
+    fn record(&mut self, entry: &Entry) -> io::Result<()>;
+
+    /// Read back every entry that was [`record`](#tymethod.record)ed, oldest first, in the same
+    /// way as [`Storage::load`](#tymethod.load).
+    fn history(&mut self) -> io::Result<Vec<Result<Record, LoadError>>>;
+
+    /// If the saved entries were damaged (e.g. by a crash in the middle of saving them), replace
+    /// them with a backup that is not, and describe where it came from. Storage that cannot be
+    /// damaged that way, or that keeps no backups, does nothing.
+    fn restore(&mut self) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// An entry read back from [`Storage`](trait.Storage.html), along with where it was found.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    /// The line (or, for storage that is not a text file, the row) the entry was read from,
+    /// counting from 1
+    pub line: usize,
+    /// The entry itself
+    pub entry: Entry,
+}
+
+/// The number of whole seconds between the Unix epoch and `time`, which is how times are saved.
+pub(crate) fn to_unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The inverse of [`to_unix_secs`](fn.to_unix_secs.html).
+pub(crate) fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// The contents of the file at `path`, which is empty if there is no such file.
+pub(crate) fn read_if_exists(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// `path` with `suffix` added to the end of its file name, e.g. `queues/prusa.txt.tmp`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Replace the file at `path` with `contents` in such a way that, no matter when this is
+/// interrupted, the file has either its old contents or its new ones: the contents are written to a
+/// temporary file next to it, which is synced to disk and then renamed over it.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp = with_suffix(path, ".tmp");
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&temp, path)) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+/// Make sure the renames in `dir` are on disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be synced outside of Unix; renames there are as durable as they get.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The storage Queue has always used: a state file holding the entries and metadata of a queue, in
+/// the versioned JSON format described in the [`state_file`](../state_file/index.html) module.
+///
+/// Before that format existed, the file had one line per person in line, of the form
+/// `{position}<tab>{user ID}<tab>{join time}<tab>{front-of-line time or -}<tab>{expected seconds or -}`,
+/// followed by the name, material, color, and printer of their job (each `-` if it was not given)
+/// and the states their place in line has been in (e.g. `waiting@1600000000,called@1600000060`).
+/// The fields of a job are free text, so whitespace and `%` in them are percent-encoded (e.g. a
+/// space is written as `%20`) to keep each line a list of whitespace-separated fields. Files in
+/// that format (including ones written before a field was added, whose missing fields are left
+/// empty) are still read, and are upgraded as soon as they are loaded without problems.
+///
+/// Metadata used to be kept in files next to the text file, named after the text file but with the
+/// key as their extension (e.g. the `policy` of `queues/prusa.txt` was kept in
+/// `queues/prusa.policy`). Those files are still read if the state file does not have the metadata
+/// in it, and are moved into the state file (and removed) the next time it is saved, e.g. when a
+/// legacy file is upgraded. The history is kept as `queues/prusa.history`, in lines like those of the old format but
+/// without the position.
+///
+/// The text file is never changed in place. Instead, every save writes a new file and renames it
+/// over the old one, so a crash never leaves a half-written file behind. The last few files it
+/// replaced are kept as backups (e.g. `queues/prusa.txt.1.bak` is the newest), in case the text file
+/// is damaged some other way.
+#[derive(Debug)]
+pub struct TextFileStorage {
+    /// Where the text file is
+    path: PathBuf,
+    /// How many backups are kept
+    backups: usize,
+}
+
+impl TextFileStorage {
+    /// Use the text file at `path`, creating it if it does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_truncate(path, false)
+    }
+
+    /// Use the text file at `path`, emptying it if it already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::with_truncate(path, true)
+    }
+
+    fn with_truncate<P: AsRef<Path>>(path: P, truncate: bool) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if truncate {
+            write_atomically(&path, b"")?;
+        } else {
+            // The file is created right away so that the queue can be found before it is saved
+            OpenOptions::new().append(true).create(true).open(&path)?;
+        }
+        Ok(Self {
+            path,
+            backups: DEFAULT_BACKUPS,
+        })
+    }
+
+    /// Keep `backups` of the files that were replaced, instead of
+    /// [`DEFAULT_BACKUPS`](constant.DEFAULT_BACKUPS.html). Backups beyond the new number are
+    /// left alone, but are no longer used.
+    pub fn set_backups(&mut self, backups: usize) {
+        self.backups = backups;
+    }
+
+    /// The backups that exist, newest first.
+    pub fn backups(&self) -> Vec<PathBuf> {
+        (1..=self.backups)
+            .map(|n| self.backup(n))
+            .filter(|backup| backup.exists())
+            .collect()
+    }
+
+    /// Where the `n`th newest backup is kept, counting from 1.
+    fn backup(&self, n: usize) -> PathBuf {
+        with_suffix(&self.path, &format!(".{}.bak", n))
+    }
+
+    /// Make the text file the newest backup, shifting the older backups back by one and dropping
+    /// the oldest. The text file is linked (or, where that is not possible, copied) rather than
+    /// moved, so that there is always a text file to load.
+    fn rotate(&self) -> io::Result<()> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for n in (1..self.backups).rev() {
+            let older = self.backup(n);
+            if older.exists() {
+                fs::rename(&older, self.backup(n + 1))?;
+            }
+        }
+        let newest = self.backup(1);
+        let _ = fs::remove_file(&newest);
+        fs::hard_link(&self.path, &newest).or_else(|_| fs::copy(&self.path, &newest).map(drop))
+    }
+
+    /// The file that the piece of metadata called `key` was kept in before it was kept in the
+    /// state file, unless that is the state file itself.
+    fn metadata_file(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path.with_extension(key)).filter(|file| *file != self.path)
+    }
+
+    /// The piece of metadata called `key` that is kept next to the state file, if there is one.
+    fn legacy_metadata(&self, key: &str) -> io::Result<Option<String>> {
+        let file = match self.metadata_file(key) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        match fs::read_to_string(file) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The metadata to keep when the state file is rewritten: whatever is in it now, or, if it
+    /// cannot be read, whatever is in the newest backup that can be, along with whatever is kept
+    /// next to it (see [`metadata_file`](#method.metadata_file)) that is not in it.
+    fn saved_metadata(&self) -> io::Result<BTreeMap<String, String>> {
+        let mut metadata = self.document_metadata()?;
+        for key in LEGACY_METADATA_KEYS {
+            if !metadata.contains_key(*key) {
+                if let Some(value) = self.legacy_metadata(key)? {
+                    metadata.insert(key.to_string(), value);
+                }
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// The metadata in the state file, or, if it cannot be read, in the newest backup that can be.
+    fn document_metadata(&self) -> io::Result<BTreeMap<String, String>> {
+        let contents = read_if_exists(&self.path)?;
+        if state_file::is_legacy(&contents) {
+            return Ok(BTreeMap::new());
+        }
+        let damaged = match state_file::parse(&contents) {
+            Ok(document) => return Ok(document.metadata),
+            Err(e @ FormatError::Newer(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e @ FormatError::Invalid { .. }) => e,
+        };
+        for backup in self.backups() {
+            let contents = read_if_exists(&backup)?;
+            if contents.trim().is_empty() || state_file::is_legacy(&contents) {
+                continue;
+            }
+            if let Ok(document) = state_file::parse(&contents) {
+                return Ok(document.metadata);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} cannot be read ({}), and neither can its backups, so it was left as it is \
+				 rather than losing the metadata in it",
+                self.path.display(),
+                damaged
+            ),
+        ))
+    }
+
+    /// The state file, unless it is a legacy file or cannot be read.
+    fn document(&self) -> io::Result<Option<Document>> {
+        let contents = read_if_exists(&self.path)?;
+        if state_file::is_legacy(&contents) {
+            return Ok(None);
+        }
+        match state_file::parse(&contents) {
+            Ok(document) => Ok(Some(document)),
+            Err(e @ FormatError::Newer(_)) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(FormatError::Invalid { .. }) => Ok(None),
+        }
+    }
+}
+
+/// Read the entries of the state file at `path` (see [`TextFileStorage`](struct.TextFileStorage.html))
+/// without changing it in any way, e.g. to compare it with another one. Unlike
+/// [`Storage::load`](trait.Storage.html#tymethod.load), a legacy file is not upgraded and a damaged
+/// one is not restored from a backup.
+///
+/// # Errors
+/// An error is returned if the file cannot be read, or was written by a newer version of Queue.
+pub fn read_state_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Result<Record, LoadError>>> {
+    parse_state(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The journal kept next to a state file that is used on its own rather than as part of a
+/// [`Backend`](enum.Backend.html) (e.g. with `--file`): the name of the state file with `.journal`
+/// after it, e.g. `queue_state.txt.journal`.
+pub fn state_file_journal<P: AsRef<Path>>(path: P) -> PathBuf {
+    with_suffix(path.as_ref(), &format!(".{}", journal::JOURNAL_EXTENSION))
+}
+
+/// The [lock](../lock/index.html) taken on a state file that is used on its own (e.g. with
+/// `--file`), so that only one instance of Queue uses it at a time: the name of the state file with
+/// `.lock` after it, e.g. `queue_state.txt.lock`.
+pub fn state_file_lock_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    with_suffix(path.as_ref(), &format!(".{}", lock::LOCK_EXTENSION))
+}
+
+/// Use the state file at `path` on its own, creating it if it does not exist yet. Its changes are
+/// journaled (see [`state_file_journal`](fn.state_file_journal.html)) just like those of a queue in a
+/// [`Backend`](enum.Backend.html).
+pub fn open_state_file<P: AsRef<Path>>(path: P) -> io::Result<JournalStorage> {
+    let snapshot = TextFileStorage::open(&path)?;
+    JournalStorage::open(Box::new(snapshot), state_file_journal(path))
+}
+
+/// Read the entries of a state file in whichever format it is in, without putting them in order or
+/// checking their positions (see [`state_file::scan`](../state_file/fn.scan.html)).
+///
+/// # Errors
+/// An error is returned if the file is a JSON file that cannot be read at all, or was written by a
+/// newer version of Queue.
+pub fn scan_state(contents: &str) -> Result<Vec<Slot>, FormatError> {
+    if state_file::is_legacy(contents) {
+        return Ok(scan_text_file(contents));
+    }
+    state_file::scan(contents).map(|scanned| scanned.slots)
+}
+
+/// Read the entries of a state file in whichever format it is in. A JSON file that cannot be
+/// read at all is a single problem on the line where that was noticed, so that it can be set aside
+/// like any other problem, unless it is from a newer version of the format.
+fn parse_state(contents: &str) -> Result<Vec<Result<Record, LoadError>>, FormatError> {
+    if state_file::is_legacy(contents) {
+        return Ok(parse_text_file(contents));
+    }
+    match state_file::parse(contents) {
+        Ok(document) => Ok(document.records),
+        Err(FormatError::Invalid { line, reason }) => {
+            Ok(vec![Err(LoadError::InvalidLine { line, reason })])
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the contents of a legacy [`TextFileStorage`](struct.TextFileStorage.html) file. Lines are
+/// sorted by their position, and lines that are all whitespace are skipped. Missing timestamps are
+/// filled in with the current time.
+fn parse_text_file(contents: &str) -> Vec<Result<Record, LoadError>> {
+    state_file::place(scan_text_file(contents))
+}
+
+/// Read the lines of a legacy [`TextFileStorage`](struct.TextFileStorage.html) file that have
+/// content (i.e. are not all whitespace), in the order they are in.
+fn scan_text_file(contents: &str) -> Vec<Slot> {
+    let now = SystemTime::now();
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, text)| (i + 1, text))
+        .filter(|(_, s)| !s.trim().is_empty())
+        .map(|(line, text)| Slot {
+            line,
+            entry: parse_line(text, now),
+        })
+        .collect()
+}
+
+impl Storage for TextFileStorage {
+    /// Read the state file. A legacy file that is read without problems is upgraded right away,
+    /// which leaves the legacy file as the newest backup. A file from a newer version of the
+    /// format is not read at all.
+    fn load(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        let contents = read_if_exists(&self.path)?;
+        let records =
+            parse_state(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if state_file::is_legacy(&contents) && records.iter().all(Result::is_ok) {
+            let entries = records
+                .iter()
+                .filter_map(|record| record.as_ref().ok().map(|record| record.entry.clone()))
+                .collect();
+            self.save(&entries)?;
+        }
+        Ok(records)
+    }
+
+    /// Write a new state file, keeping the metadata in the old one, and swap it in for the old
+    /// one, which becomes the newest backup. If the old one cannot be read at all, the metadata is
+    /// taken from the newest backup that can be, and if there is none, nothing is saved rather than
+    /// losing the metadata.
+    fn save(&mut self, entries: &VecDeque<Entry>) -> io::Result<()> {
+        let metadata = self.saved_metadata()?;
+        let contents = state_file::format(entries, &metadata);
+        self.rotate()?;
+        write_atomically(&self.path, contents.as_bytes())?;
+        // The metadata that was kept next to the state file is in it now
+        for file in LEGACY_METADATA_KEYS
+            .iter()
+            .filter_map(|key| self.metadata_file(key))
+        {
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, key: &str) -> io::Result<Option<String>> {
+        if let Some(value) = self
+            .document()?
+            .and_then(|mut document| document.metadata.remove(key))
+        {
+            return Ok(Some(value));
+        }
+        self.legacy_metadata(key)
+    }
+
+    /// Save the metadata in the state file, without changing the entries in it. Legacy files
+    /// have their metadata saved next to them, as they always have, until they are upgraded.
+    fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()> {
+        let contents = read_if_exists(&self.path)?;
+        if state_file::is_legacy(&contents) {
+            if let Some(file) = self.metadata_file(key) {
+                return write_atomically(&file, value.as_bytes());
+            }
+        }
+        let contents = state_file::set_metadata(&contents, key, value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomically(&self.path, contents.as_bytes())
+    }
+
+    /// Rename the text file to `{name}.bad-{current Unix time}` and start a new, empty one.
+    fn set_aside(&mut self) -> io::Result<String> {
+        let aside = with_suffix(
+            &self.path,
+            &format!(".bad-{}", to_unix_secs(SystemTime::now())),
+        );
+        fs::rename(&self.path, &aside)?;
+        write_atomically(&self.path, b"")?;
+        Ok(aside.display().to_string())
+    }
+
+    fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(self.path.with_extension(HISTORY_FILE_EXTENSION))?;
+        writeln!(file, "{}", format_entry(entry))
+    }
+
+    fn history(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        let now = SystemTime::now();
+        let contents = read_if_exists(&self.path.with_extension(HISTORY_FILE_EXTENSION))?;
+        Ok(contents
+            .lines()
+            .enumerate()
+            .map(|(i, text)| (i + 1, text))
+            .filter(|(_, s)| !s.trim().is_empty())
+            .map(|(line, text)| {
+                parse_entry(&mut text.split_whitespace(), now)
+                    .map(|entry| Record { line, entry })
+                    .map_err(|reason| LoadError::InvalidLine { line, reason })
+            })
+            .collect())
+    }
+
+    /// Replace the text file with the newest backup that can be read in full if the text file
+    /// cannot be, or if it is nothing but whitespace, which is what an interrupted save used to
+    /// leave behind.
+    fn restore(&mut self) -> io::Result<Option<String>> {
+        let readable = |contents: &str| matches!(parse_state(contents), Ok(records) if records.iter().all(Result::is_ok));
+        let contents = read_if_exists(&self.path)?;
+        let blanked = !contents.is_empty() && contents.trim().is_empty();
+        match parse_state(&contents) {
+            // Files from a newer version of the format are refused when they are loaded
+            Err(_) => return Ok(None),
+            Ok(records) if !blanked && records.iter().all(Result::is_ok) => return Ok(None),
+            Ok(_) => (),
+        }
+        for backup in self.backups() {
+            let contents = fs::read_to_string(&backup)?;
+            if readable(&contents) {
+                write_atomically(&self.path, contents.as_bytes())?;
+                return Ok(Some(backup.display().to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Parse one line of a [`TextFileStorage`](struct.TextFileStorage.html) into the position and entry
+/// it describes, or describe what is wrong with it.
+fn parse_line(line: &str, now: SystemTime) -> Result<(usize, Entry), String> {
+    let mut iter = line.split_whitespace();
+    let (pos, uid) =
+        match (iter.next(), iter.next()) {
+            (Some(pos), Some(uid)) => (pos, uid),
+            _ => return Err(String::from(
+                "each line must contain a parse-able positive integer followed by some amount of \
+				whitespace, followed by a Slack user-id",
+            )),
+        };
+    let pos = pos.parse::<usize>().map_err(|_| {
+        format!(
+            "each line must start with a parse-able positive integer, not {:?}",
+            pos
+        )
+    })?;
+    let mut iter = iter::once(uid).chain(iter);
+    Ok((pos, parse_entry(&mut iter, now)?))
+}
+
+/// Write everything about `entry` as the whitespace-separated fields of a line of a
+/// [`TextFileStorage`](struct.TextFileStorage.html), after the position:
+/// `{user ID}<tab>{join time}<tab>{front-of-line time or -}<tab>{expected seconds or -}<tab>{job
+/// name}<tab>{material}<tab>{color}<tab>{printer}<tab>{states}`.
+pub(crate) fn format_entry(entry: &Entry) -> String {
+    let front_since = match entry.front_since {
+        Some(time) => to_unix_secs(time).to_string(),
+        None => String::from("-"),
+    };
+    let job = &entry.job;
+    let expected = match job.time {
+        Some(expected) => expected.as_secs().to_string(),
+        None => String::from("-"),
+    };
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        entry.user,
+        to_unix_secs(entry.joined),
+        front_since,
+        expected,
+        encode_field(job.name.as_deref()),
+        encode_field(job.material.as_deref()),
+        encode_field(job.color.as_deref()),
+        encode_field(job.printer.as_deref()),
+        format_transitions(&entry.transitions)
+    )
+}
+
+/// The inverse of [`format_entry`](fn.format_entry.html). Missing timestamps are filled in with
+/// `now`.
+pub(crate) fn parse_entry<'a, I: Iterator<Item = &'a str>>(
+    iter: &mut I,
+    now: SystemTime,
+) -> Result<Entry, String> {
+    let uid = iter
+        .next()
+        .ok_or_else(|| String::from("each line must contain a Slack user-id"))?;
+    let number = |field: Option<&str>| match field.filter(|t| *t != "-") {
+        Some(t) => t.parse::<u64>().map(Some).map_err(|_| {
+            format!(
+                "timestamps and durations must be a parse-able positive integer, not {:?}",
+                t
+            )
+        }),
+        None => Ok(None),
+    };
+    let user = UserID::new(uid);
+    let joined = number(iter.next())?.map_or(now, from_unix_secs);
+    let front_since = number(iter.next())?.map(from_unix_secs);
+    let time = number(iter.next())?.map(Duration::from_secs);
+    let mut text = || iter.next().map_or(Ok(None), decode_field);
+    let job = Job {
+        name: text()?,
+        material: text()?,
+        color: text()?,
+        time,
+        printer: text()?,
+    };
+    let transitions = iter.next().map_or(Ok(Vec::new()), parse_transitions)?;
+    Ok(Entry {
+        user,
+        joined,
+        front_since,
+        job,
+        transitions,
+    })
+}
+
+/// Write the states a place in line has been in as a list of `{state}@{Unix time}`, separated by
+/// commas, or `-` if there are none.
+fn format_transitions(transitions: &[Transition]) -> String {
+    if transitions.is_empty() {
+        return String::from("-");
+    }
+    transitions
+        .iter()
+        .map(|transition| format!("{}@{}", transition.state, to_unix_secs(transition.at)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The inverse of [`format_transitions`](fn.format_transitions.html).
+fn parse_transitions(text: &str) -> Result<Vec<Transition>, String> {
+    if text == "-" {
+        return Ok(Vec::new());
+    }
+    text.split(',')
+        .map(|transition| {
+            let (state, at) = transition.split_once('@').ok_or_else(|| {
+                format!(
+                    "states must look like `waiting@1600000000`, not {:?}",
+                    transition
+                )
+            })?;
+            let at = at.parse::<u64>().map_err(|_| {
+                format!(
+                    "timestamps must be a parse-able positive integer, not {:?}",
+                    at
+                )
+            })?;
+            Ok(Transition {
+                state: state.parse::<State>()?,
+                at: from_unix_secs(at),
+            })
+        })
+        .collect()
+}
+
+/// Write a free-text field of a [`TextFileStorage`](struct.TextFileStorage.html) line, which is
+/// `-` if there is nothing in it.
+fn encode_field(field: Option<&str>) -> String {
+    match field {
+        None => String::from("-"),
+        Some("-") => String::from("%2D"),
+        Some(text) => {
+            let mut encoded = String::with_capacity(text.len());
+            for c in text.chars() {
+                if c == '%' || c.is_whitespace() {
+                    let mut bytes = [0; 4];
+                    for byte in c.encode_utf8(&mut bytes).bytes() {
+                        encoded.push_str(&format!("%{:02X}", byte));
+                    }
+                } else {
+                    encoded.push(c);
+                }
+            }
+            encoded
+        }
+    }
+}
+
+/// The inverse of [`encode_field`](fn.encode_field.html).
+fn decode_field(field: &str) -> Result<Option<String>, String> {
+    if field == "-" {
+        return Ok(None);
+    }
+    let invalid = || format!("{:?} is not a percent-encoded piece of text", field);
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = after.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).map(Some).map_err(|_| invalid())
+}
+
+/// What a [`MemoryStorage`](struct.MemoryStorage.html) holds.
+#[derive(Debug, Default)]
+struct Saved {
+    entries: Vec<Entry>,
+    metadata: BTreeMap<String, String>,
+    set_aside: Vec<Vec<Entry>>,
+    history: Vec<Entry>,
+}
+
+/// Storage that keeps everything in memory, so nothing survives the process. Clones share the same
+/// saved state, so a test can hand a clone to a queue and inspect what the queue saved.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage(Arc<Mutex<Saved>>);
+
+impl MemoryStorage {
+    /// Storage that holds nothing yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Storage that already holds `entries`, as if a queue had saved them.
+    pub fn with_entries(entries: Vec<Entry>) -> Self {
+        let storage = Self::default();
+        storage.saved().entries = entries;
+        storage
+    }
+
+    /// The entries that were last saved.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.saved().entries.clone()
+    }
+
+    fn saved(&self) -> std::sync::MutexGuard<'_, Saved> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        Ok(records(self.entries()))
+    }
+
+    fn save(&mut self, entries: &VecDeque<Entry>) -> io::Result<()> {
+        self.saved().entries = entries.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn metadata(&mut self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.saved().metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.saved()
+            .metadata
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn set_aside(&mut self) -> io::Result<String> {
+        let mut saved = self.saved();
+        let entries = std::mem::take(&mut saved.entries);
+        saved.set_aside.push(entries);
+        Ok(String::from("memory"))
+    }
+
+    fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        self.saved().history.push(entry.clone());
+        Ok(())
+    }
+
+    fn history(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        Ok(records(self.saved().history.clone()))
+    }
+}
+
+/// Number `entries` by their position in line, as storage that does not have lines does.
+fn records(entries: Vec<Entry>) -> Vec<Result<Record, LoadError>> {
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| Ok(Record { line: i + 1, entry }))
+        .collect()
+}
+
+/// Storage for one queue in an SQLite database. Any number of queues can share a database; each
+/// one's rows are told apart by the queue's name.
+///
+/// Every save replaces the queue's entries inside of a single transaction, so a crash in the middle
+/// of a save leaves the previous entries in place. Each change to the queue is
+/// [journal](../journal/index.html)ed as a row of the events table, which is written in the same
+/// transaction as the entries it led to, so the two never disagree.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteStorage {
+    /// The connection to the database
+    conn: rusqlite::Connection,
+    /// The name of the queue whose rows this storage reads and writes
+    queue: String,
+    /// The entries as of the last save, which the next event's changes are relative to
+    entries: Vec<Entry>,
+}
+
+/// Turn an SQLite error into an `io::Error`, so that every kind of storage fails the same way.
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    /// The tables every Queue database has.
+    const SCHEMA: &'static str = "
+        CREATE TABLE IF NOT EXISTS queues (
+            name TEXT PRIMARY KEY NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS entries (
+            queue TEXT NOT NULL REFERENCES queues (name),
+            position INTEGER NOT NULL,
+            user TEXT NOT NULL,
+            joined INTEGER NOT NULL,
+            front_since INTEGER,
+            expected INTEGER,
+            job_name TEXT,
+            material TEXT,
+            color TEXT,
+            printer TEXT,
+            states TEXT,
+            PRIMARY KEY (queue, position)
+        );
+        CREATE TABLE IF NOT EXISTS history (
+            queue TEXT NOT NULL REFERENCES queues (name),
+            user TEXT NOT NULL,
+            joined INTEGER NOT NULL,
+            front_since INTEGER,
+            expected INTEGER,
+            job_name TEXT,
+            material TEXT,
+            color TEXT,
+            printer TEXT,
+            states TEXT
+        );
+        CREATE TABLE IF NOT EXISTS metadata (
+            queue TEXT NOT NULL REFERENCES queues (name),
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (queue, key)
+        );
+        CREATE TABLE IF NOT EXISTS events (
+            queue TEXT NOT NULL REFERENCES queues (name),
+            seq INTEGER NOT NULL,
+            at INTEGER NOT NULL,
+            actor TEXT,
+            operation TEXT NOT NULL,
+            changes TEXT NOT NULL,
+            PRIMARY KEY (queue, seq)
+        );";
+
+    /// The columns of the entries table that were added after it was first created, along with
+    /// their types.
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("expected", "INTEGER"),
+        ("job_name", "TEXT"),
+        ("material", "TEXT"),
+        ("color", "TEXT"),
+        ("printer", "TEXT"),
+        ("states", "TEXT"),
+    ];
+
+    /// Use the rows of the queue called `queue` in the database at `path`, creating the database,
+    /// its tables, and the queue if they do not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P, queue: &str) -> io::Result<Self> {
+        let conn = Self::connect(path)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO queues (name) VALUES (?1)",
+            rusqlite::params![queue],
+        )
+        .map_err(sqlite_error)?;
+        Ok(Self {
+            conn,
+            queue: queue.to_string(),
+            entries: Vec::new(),
+        })
+    }
+
+    /// The names of every queue in the database at `path`, in alphabetical order.
+    pub fn queue_names<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+        let conn = Self::connect(path)?;
+        let mut statement = conn
+            .prepare("SELECT name FROM queues ORDER BY name")
+            .map_err(sqlite_error)?;
+        let names = statement
+            .query_map([], |row| row.get(0))
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(sqlite_error);
+        names
+    }
+
+    /// Open the database at `path` and make sure it has Queue's tables.
+    fn connect<P: AsRef<Path>>(path: P) -> io::Result<rusqlite::Connection> {
+        let conn = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        conn.execute_batch(Self::SCHEMA).map_err(sqlite_error)?;
+        // Databases created before a column was added to the entries are missing it
+        for (column, kind) in Self::ADDED_COLUMNS {
+            let exists = conn
+                .prepare("SELECT name FROM pragma_table_info('entries') WHERE name = ?1")
+                .and_then(|mut statement| statement.exists([column]))
+                .map_err(sqlite_error)?;
+            if !exists {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE entries ADD COLUMN {} {}",
+                    column, kind
+                ))
+                .map_err(sqlite_error)?;
+            }
+        }
+        Ok(conn)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    /// Read the entries of this queue in `table`, which is either the entries or the history, in
+    /// the order given by the column `order`.
+    fn read_entries(&self, table: &str, order: &str) -> io::Result<Vec<Result<Record, LoadError>>> {
+        use std::convert::TryFrom;
+
+        let mut statement = self
+            .conn
+            .prepare(&format!(
+                "SELECT user, joined, front_since, expected, job_name, material, color, printer, \
+				states FROM {} WHERE queue = ?1 ORDER BY {}",
+                table, order
+            ))
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(rusqlite::params![self.queue], |row| {
+                let job = (
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                    row.get::<_, Option<String>>(7)?,
+                );
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                    job,
+                    row.get::<_, Option<String>>(8)?,
+                ))
+            })
+            .map_err(sqlite_error)?;
+
+        let mut records = Vec::new();
+        for (i, row) in rows.enumerate() {
+            let (user, joined, front_since, expected, (name, material, color, printer), states) =
+                row.map_err(sqlite_error)?;
+            let line = i + 1;
+            let time = |secs: i64| {
+                u64::try_from(secs)
+                    .map(from_unix_secs)
+                    .map_err(|_| LoadError::InvalidLine {
+                        line,
+                        reason: format!("timestamps must be positive, not {}", secs),
+                    })
+            };
+            records.push(time(joined).and_then(|joined| {
+                Ok(Record {
+                    line,
+                    entry: Entry {
+                        user: UserID::new(&user),
+                        joined,
+                        front_since: front_since.map(time).transpose()?,
+                        job: Job {
+                            name,
+                            material,
+                            color,
+                            time: expected
+                                .map(|secs| {
+                                    u64::try_from(secs).map(Duration::from_secs).map_err(|_| {
+                                        LoadError::InvalidLine {
+                                            line,
+                                            reason: format!(
+                                                "durations must be positive, not {}",
+                                                secs
+                                            ),
+                                        }
+                                    })
+                                })
+                                .transpose()?,
+                            printer,
+                        },
+                        transitions: states
+                            .as_deref()
+                            .map_or(Ok(Vec::new()), parse_transitions)
+                            .map_err(|reason| LoadError::InvalidLine { line, reason })?,
+                    },
+                })
+            }));
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    /// Every event that was journaled for this queue, in order. Events that cannot be read back
+    /// are left out.
+    pub fn events(&self) -> io::Result<Vec<LoggedEvent>> {
+        let mut statement = self
+            .conn
+            .prepare(
+                "SELECT seq, at, actor, operation, changes FROM events WHERE queue = ?1 \
+				ORDER BY seq",
+            )
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(rusqlite::params![self.queue], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })
+            .map_err(sqlite_error)?;
+        let mut events = Vec::new();
+        for row in rows {
+            let (seq, at, actor, operation, changes) = row.map_err(sqlite_error)?;
+            let (operation, changes) = match (operation.parse(), journal::parse_changes(&changes)) {
+                (Ok(operation), Ok(changes)) => (operation, changes),
+                _ => continue,
+            };
+            events.push(LoggedEvent {
+                seq: seq as u64,
+                event: Event {
+                    at: from_unix_secs(at as u64),
+                    actor: actor.as_deref().map(UserID::new),
+                    operation,
+                },
+                changes,
+            });
+        }
+        Ok(events)
+    }
+}
+
+/// The number the next event journaled for `queue` gets.
+#[cfg(feature = "sqlite")]
+fn next_seq(conn: &rusqlite::Connection, queue: &str) -> io::Result<u64> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(seq), 0) + 1 FROM events WHERE queue = ?1",
+        rusqlite::params![queue],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|seq| seq as u64)
+    .map_err(sqlite_error)
+}
+
+/// Journal `logged` as an event of `queue`. An event that is already there is left alone.
+#[cfg(feature = "sqlite")]
+fn insert_event(conn: &rusqlite::Connection, queue: &str, logged: &LoggedEvent) -> io::Result<()> {
+    let LoggedEvent {
+        seq,
+        event,
+        changes,
+    } = logged;
+    conn.execute(
+        "INSERT OR IGNORE INTO events (queue, seq, at, actor, operation, changes) \
+		VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            queue,
+            *seq as i64,
+            to_unix_secs(event.at) as i64,
+            event.actor.as_ref().map(UserID::to_string),
+            event.operation.to_string(),
+            journal::format_changes(changes),
+        ],
+    )
+    .map(drop)
+    .map_err(sqlite_error)
+}
+
+/// Replace the entries of `queue` with `entries`.
+#[cfg(feature = "sqlite")]
+fn write_entries(
+    conn: &rusqlite::Connection,
+    queue: &str,
+    entries: &VecDeque<Entry>,
+) -> io::Result<()> {
+    conn.execute(
+        "DELETE FROM entries WHERE queue = ?1",
+        rusqlite::params![queue],
+    )
+    .map_err(sqlite_error)?;
+    for (pos, entry) in entries.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO entries (queue, position, user, joined, front_since, expected, \
+					job_name, material, color, printer, states) \
+					VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            rusqlite::params![
+                queue,
+                pos as i64,
+                entry.user.to_string(),
+                to_unix_secs(entry.joined) as i64,
+                entry.front_since.map(|t| to_unix_secs(t) as i64),
+                entry.job.time.map(|d| d.as_secs() as i64),
+                entry.job.name,
+                entry.job.material,
+                entry.job.color,
+                entry.job.printer,
+                format_transitions(&entry.transitions),
+            ],
+        )
+        .map_err(sqlite_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    /// Load the entries. A queue that has never had an event journaled starts its journal with
+    /// whoever was already in line, like a [`JournalStorage`](../journal/struct.JournalStorage.html).
+    fn load(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        let records = self.read_entries("entries", "position")?;
+        self.entries = records
+            .iter()
+            .filter_map(|record| record.as_ref().ok().map(|record| record.entry.clone()))
+            .collect();
+        if !self.entries.is_empty() && next_seq(&self.conn, &self.queue)? == 1 {
+            let opened = LoggedEvent {
+                seq: 1,
+                event: Event {
+                    at: SystemTime::now(),
+                    actor: None,
+                    operation: Operation::Open,
+                },
+                changes: journal::diff(&[], &self.entries.iter().cloned().collect()),
+            };
+            insert_event(&self.conn, &self.queue, &opened)?;
+        }
+        Ok(records)
+    }
+
+    fn save(&mut self, entries: &VecDeque<Entry>) -> io::Result<()> {
+        let transaction = self.conn.transaction().map_err(sqlite_error)?;
+        write_entries(&transaction, &self.queue, entries)?;
+        transaction.commit().map_err(sqlite_error)?;
+        self.entries = entries.iter().cloned().collect();
+        Ok(())
+    }
+
+    /// Save `entries` and journal `event` in the same transaction.
+    fn save_event(&mut self, event: &Event, entries: &VecDeque<Entry>) -> io::Result<()> {
+        let transaction = self.conn.transaction().map_err(sqlite_error)?;
+        write_entries(&transaction, &self.queue, entries)?;
+        let logged = LoggedEvent {
+            seq: next_seq(&transaction, &self.queue)?,
+            event: event.clone(),
+            changes: journal::diff(&self.entries, entries),
+        };
+        insert_event(&transaction, &self.queue, &logged)?;
+        transaction.commit().map_err(sqlite_error)?;
+        self.entries = entries.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn metadata(&mut self, key: &str) -> io::Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+
+        self.conn
+            .query_row(
+                "SELECT value FROM metadata WHERE queue = ?1 AND key = ?2",
+                rusqlite::params![self.queue, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO metadata (queue, key, value) VALUES (?1, ?2, ?3)",
+                rusqlite::params![self.queue, key, value],
+            )
+            .map(|_| ())
+            .map_err(sqlite_error)
+    }
+
+    /// Move the entries to a queue called `{name}.bad-{current Unix time}`, which is not a valid
+    /// queue name and so is never loaded.
+    fn set_aside(&mut self) -> io::Result<String> {
+        let aside = format!("{}.bad-{}", self.queue, to_unix_secs(SystemTime::now()));
+        let transaction = self.conn.transaction().map_err(sqlite_error)?;
+        transaction
+            .execute(
+                "INSERT OR IGNORE INTO queues (name) VALUES (?1)",
+                rusqlite::params![aside],
+            )
+            .map_err(sqlite_error)?;
+        transaction
+            .execute(
+                "UPDATE entries SET queue = ?1 WHERE queue = ?2",
+                rusqlite::params![aside, self.queue],
+            )
+            .map_err(sqlite_error)?;
+        transaction.commit().map_err(sqlite_error)?;
+        self.entries.clear();
+        Ok(format!("queue {:?} of the database", aside))
+    }
+
+    fn record(&mut self, entry: &Entry) -> io::Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO history (queue, user, joined, front_since, expected, job_name, \
+				material, color, printer, states) \
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                rusqlite::params![
+                    self.queue,
+                    entry.user.to_string(),
+                    to_unix_secs(entry.joined) as i64,
+                    entry.front_since.map(|t| to_unix_secs(t) as i64),
+                    entry.job.time.map(|d| d.as_secs() as i64),
+                    entry.job.name,
+                    entry.job.material,
+                    entry.job.color,
+                    entry.job.printer,
+                    format_transitions(&entry.transitions),
+                ],
+            )
+            .map(|_| ())
+            .map_err(sqlite_error)
+    }
+
+    fn history(&mut self) -> io::Result<Vec<Result<Record, LoadError>>> {
+        self.read_entries("history", "rowid")
+    }
+}
+
+/// Where a [`QueueManager`](../manager/struct.QueueManager.html) keeps the state of all of its
+/// queues.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Backend {
+    /// One text file per queue, named `{name}.txt`, inside of this directory. Changes to the queue
+    /// are [journal](../journal/index.html)ed in `{name}.journal`, and the text file is a snapshot
+    /// that is only rewritten once in a while.
+    TextFiles(PathBuf),
+    /// One SQLite database at this path that holds every queue. Changes to each queue are
+    /// journaled in the database's events table.
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// Nowhere; every queue starts out empty and nothing is saved.
+    Memory,
+}
+
+impl Backend {
+    /// Get the backend ready for use (e.g. by creating its directory) and return the names of the
+    /// queues that were saved in it before.
+    pub fn queue_names(&self) -> io::Result<Vec<String>> {
+        match self {
+            Backend::TextFiles(dir) => {
+                fs::create_dir_all(dir)?;
+                let mut names = Vec::new();
+                for entry in fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|ext| ext.to_str()) != Some(STATE_FILE_EXTENSION) {
+                        continue;
+                    }
+                    if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                        names.push(name.to_string());
+                    }
+                }
+                names.sort();
+                Ok(names)
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(path) => SqliteStorage::queue_names(path),
+            Backend::Memory => Ok(Vec::new()),
+        }
+    }
+
+    /// Open the storage for the queue called `name`, creating it if it does not exist yet.
+    pub fn open(&self, name: &str) -> io::Result<Box<dyn Storage>> {
+        Ok(match self {
+            Backend::TextFiles(dir) => {
+                let path = dir.join(name);
+                let snapshot = TextFileStorage::open(path.with_extension(STATE_FILE_EXTENSION))?;
+                Box::new(JournalStorage::open(
+                    Box::new(snapshot),
+                    path.with_extension(journal::JOURNAL_EXTENSION),
+                )?)
+            }
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(path) => Box::new(SqliteStorage::open(path, name)?),
+            Backend::Memory => Box::new(MemoryStorage::new()),
+        })
+    }
+
+    /// The state file the queue called `name` is kept in, if it is kept in one.
+    pub fn state_file(&self, name: &str) -> Option<PathBuf> {
+        match self {
+            Backend::TextFiles(dir) => Some(dir.join(name).with_extension(STATE_FILE_EXTENSION)),
+            _ => None,
+        }
+    }
+
+    /// The file the changes to the queue called `name` are journaled in, if they are journaled in
+    /// a file of their own.
+    pub fn journal(&self, name: &str) -> Option<PathBuf> {
+        match self {
+            Backend::TextFiles(dir) => {
+                Some(dir.join(name).with_extension(journal::JOURNAL_EXTENSION))
+            }
+            _ => None,
+        }
+    }
+
+    /// Where the [lock](../lock/index.html) on this backend is kept: `queue.lock` inside of the
+    /// directory of text files, or next to the SQLite database, e.g. `queues.db.lock`. Nothing is
+    /// saved in memory, so there is nothing to lock.
+    pub fn lock_path(&self) -> Option<PathBuf> {
+        match self {
+            Backend::TextFiles(dir) => Some(dir.join("queue").with_extension(lock::LOCK_EXTENSION)),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite(path) => Some(with_suffix(path, &format!(".{}", lock::LOCK_EXTENSION))),
+            Backend::Memory => None,
+        }
+    }
+
+    /// Take the lock on this backend, so that no other instance of Queue saves to it for as long as
+    /// the lock is held.
+    pub fn lock(&self) -> Result<Option<Lock>, LockError> {
+        if let Backend::TextFiles(dir) = self {
+            fs::create_dir_all(dir)?;
+        }
+        self.lock_path().map(Lock::acquire).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> VecDeque<Entry> {
+        let joined = from_unix_secs(1_600_000_000);
+        let mut entries = VecDeque::new();
+        let called = joined + Duration::from_secs(60);
+        entries.push_back(Entry {
+            front_since: Some(called),
+            transitions: vec![
+                Transition {
+                    state: State::Waiting,
+                    at: joined,
+                },
+                Transition {
+                    state: State::Called,
+                    at: called,
+                },
+            ],
+            ..Entry::new(UserID::new("UA8RXUPSP"), joined)
+        });
+        entries.push_back(Entry {
+            job: Job {
+                name: Some(String::from("benchy 100% - big")),
+                material: Some(String::from("PLA")),
+                color: Some(String::from("-")),
+                time: Some(Duration::from_secs(2 * 60 * 60)),
+                printer: None,
+            },
+            ..Entry::new(UserID::new("UNB2LMZRP"), joined + Duration::from_secs(30))
+        });
+        entries
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("queue_storage_{}_{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// The entries in `storage`, which must all make sense.
+    fn loaded(storage: &mut dyn Storage) -> Vec<Entry> {
+        storage
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.unwrap().entry)
+            .collect()
+    }
+
+    /// Saving and then loading should give back what was saved, and metadata should stick.
+    fn round_trip(storage: &mut dyn Storage) {
+        assert!(storage.load().unwrap().is_empty());
+        storage.save(&entries()).unwrap();
+        assert_eq!(loaded(storage), Vec::from(entries()));
+
+        // Saving fewer entries should not leave the old ones behind
+        let mut fewer = entries();
+        fewer.pop_front();
+        storage.save(&fewer).unwrap();
+        assert_eq!(loaded(storage), Vec::from(fewer));
+
+        assert_eq!(storage.metadata("policy").unwrap(), None);
+        storage.set_metadata("policy", "max-total=3").unwrap();
+        storage.set_metadata("policy", "max-total=4").unwrap();
+        assert_eq!(
+            storage.metadata("policy").unwrap(),
+            Some(String::from("max-total=4"))
+        );
+
+        // The history is only added to
+        assert!(storage.history().unwrap().is_empty());
+        for entry in entries() {
+            storage.record(&entry).unwrap();
+        }
+        let history = storage
+            .history()
+            .unwrap()
+            .into_iter()
+            .map(|record| record.unwrap().entry)
+            .collect::<Vec<_>>();
+        assert_eq!(history, Vec::from(entries()));
+    }
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new();
+        round_trip(&mut storage.clone());
+        assert_eq!(storage.entries().len(), 1);
+    }
+
+    #[test]
+    fn text_file_storage() {
+        let path = temp_path("text_file_storage.txt");
+        round_trip(&mut TextFileStorage::open(&path).unwrap());
+        // The file is still readable by a fresh storage
+        assert_eq!(
+            TextFileStorage::open(&path).unwrap().load().unwrap().len(),
+            1
+        );
+        let _ = fs::remove_file(path.with_extension("policy"));
+        let _ = fs::remove_file(path.with_extension(HISTORY_FILE_EXTENSION));
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn legacy_text_files_load() {
+        let path = temp_path("legacy_text_files_load.txt");
+        fs::write(&path, "1 UNB2LMZRP\n\n0\tUA8RXUPSP\n   \n").unwrap();
+
+        let entries = loaded(&mut TextFileStorage::open(&path).unwrap());
+
+        assert_eq!(
+            entries,
+            [UserID::new("UA8RXUPSP"), UserID::new("UNB2LMZRP")]
+        );
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn legacy_text_files_are_upgraded() {
+        let path = temp_path("legacy_text_files_are_upgraded.txt");
+        remove_with_backups(&path);
+        let legacy = "0\tUA8RXUPSP\t1600000000\t-\t-\tbenchy\tPLA\t-\t-\twaiting@1600000000\n";
+        fs::write(&path, legacy).unwrap();
+        fs::write(path.with_extension("policy"), "max-total=3").unwrap();
+        fs::write(path.with_extension(DELIVERY_KEY), "UA8RXUPSP dm").unwrap();
+        let mut storage = TextFileStorage::open(&path).unwrap();
+
+        // Only loading the file upgrades it
+        let read = read_state_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), legacy);
+        let entries = loaded(&mut storage);
+        assert_eq!(
+            read.into_iter()
+                .map(|record| record.unwrap().entry)
+                .collect::<Vec<_>>(),
+            entries
+        );
+
+        // The file was upgraded, and the legacy file kept as a backup
+        let upgraded = fs::read_to_string(&path).unwrap();
+        assert!(!state_file::is_legacy(&upgraded));
+        assert_eq!(fs::read_to_string(storage.backup(1)).unwrap(), legacy);
+        assert_eq!(loaded(&mut storage), entries);
+        assert_eq!(entries[0].job.material.as_deref(), Some("PLA"));
+        // Metadata saved next to legacy files is moved into the upgraded file
+        let upgraded = state_file::parse(&upgraded).unwrap();
+        assert_eq!(upgraded.metadata["policy"], "max-total=3");
+        assert_eq!(upgraded.metadata[DELIVERY_KEY], "UA8RXUPSP dm");
+        assert!(!path.with_extension("policy").exists());
+        assert!(!path.with_extension(DELIVERY_KEY).exists());
+        assert_eq!(
+            storage.metadata("policy").unwrap().as_deref(),
+            Some("max-total=3")
+        );
+        storage.set_metadata("policy", "max-total=4").unwrap();
+        assert_eq!(
+            storage.metadata("policy").unwrap().as_deref(),
+            Some("max-total=4")
+        );
+        assert_eq!(
+            state_file::parse(&fs::read_to_string(&path).unwrap())
+                .unwrap()
+                .metadata["policy"],
+            "max-total=4"
+        );
+        // Saving entries keeps the metadata
+        storage.save(&VecDeque::new()).unwrap();
+        assert_eq!(
+            storage.metadata("policy").unwrap().as_deref(),
+            Some("max-total=4")
+        );
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn newer_state_files_are_refused() {
+        let path = temp_path("newer_state_files_are_refused.txt");
+        let newer = "{ \"version\": 99, \"entries\": [] }";
+        fs::write(&path, newer).unwrap();
+        let mut storage = TextFileStorage::open(&path).unwrap();
+
+        assert_eq!(storage.restore().unwrap(), None);
+        let error = storage.load().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("version 99"), "{}", error);
+        assert!(storage.set_metadata("policy", "max-total=3").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn bad_lines_are_numbered() {
+        let path = temp_path("bad_lines_are_numbered.txt");
+        fs::write(
+            &path,
+            "0 UA8RXUPSP\n\nnope\n0 UNB2LMZRP\n1 UNB2LMZRP x\n2 UN480W9ND\n",
+        )
+        .unwrap();
+
+        let records = TextFileStorage::open(&path).unwrap().load().unwrap();
+
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].as_ref().unwrap().line, 1);
+        assert_eq!(records[1].as_ref().unwrap().line, 6);
+        assert!(matches!(
+            records[2],
+            Err(LoadError::InvalidLine { line: 3, .. })
+        ));
+        assert!(matches!(
+            records[3],
+            Err(LoadError::DuplicatePosition {
+                line: 4,
+                position: 0
+            })
+        ));
+        assert!(matches!(
+            records[4],
+            Err(LoadError::InvalidLine { line: 5, .. })
+        ));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn text_files_are_set_aside() {
+        let path = temp_path("text_files_are_set_aside.txt");
+        let mut storage = TextFileStorage::open(&path).unwrap();
+        storage.save(&entries()).unwrap();
+
+        let aside = PathBuf::from(storage.set_aside().unwrap());
+
+        assert!(storage.load().unwrap().is_empty());
+        assert_eq!(loaded(&mut TextFileStorage::open(&aside).unwrap()).len(), 2);
+        // Set-aside files are not mistaken for queues
+        assert_eq!(
+            aside
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.starts_with("bad-")),
+            Some(true)
+        );
+        let _ = fs::remove_file(aside);
+        remove_with_backups(&path);
+    }
+
+    /// Remove the text file at `path` along with its backups.
+    fn remove_with_backups(path: &Path) {
+        for n in 1..=DEFAULT_BACKUPS {
+            let _ = fs::remove_file(with_suffix(path, &format!(".{}.bak", n)));
+        }
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn text_files_keep_backups() {
+        let path = temp_path("text_files_keep_backups.txt");
+        remove_with_backups(&path);
+        let mut storage = TextFileStorage::open(&path).unwrap();
+        let mut saved = VecDeque::new();
+        for entry in entries().iter().cycle().take(DEFAULT_BACKUPS + 2) {
+            saved.push_back(entry.clone());
+            storage.save(&saved).unwrap();
+        }
+
+        // The newest backup is what was saved before the last save, and so on
+        let backups = storage.backups();
+        assert_eq!(backups.len(), DEFAULT_BACKUPS);
+        for (n, backup) in backups.iter().enumerate() {
+            let records = TextFileStorage::open(backup).unwrap().load().unwrap();
+            assert_eq!(records.len(), DEFAULT_BACKUPS + 1 - n);
+        }
+        assert!(!with_suffix(&path, ".tmp").exists());
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn damaged_text_files_are_restored() {
+        let path = temp_path("damaged_text_files_are_restored.txt");
+        remove_with_backups(&path);
+        let mut storage = TextFileStorage::open(&path).unwrap();
+        storage.save(&entries()).unwrap();
+        storage.save(&VecDeque::new()).unwrap();
+        // Nothing is wrong yet
+        assert_eq!(storage.restore().unwrap(), None);
+
+        // A file that is nothing but whitespace is what an interrupted save used to leave behind
+        fs::write(&path, "          \n   ").unwrap();
+        let restored = storage.restore().unwrap();
+        assert_eq!(restored, Some(storage.backup(1).display().to_string()));
+        assert_eq!(loaded(&mut storage), Vec::from(entries()));
+
+        // Backups that are damaged too are skipped
+        fs::write(storage.backup(1), "0 UA8RXUPSP nope\n").unwrap();
+        fs::write(&path, "garbage\n").unwrap();
+        assert_eq!(
+            storage.restore().unwrap(),
+            Some(storage.backup(2).display().to_string())
+        );
+        assert!(storage.load().unwrap().iter().all(Result::is_ok));
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn damaged_state_files_keep_their_metadata() {
+        let path = temp_path("damaged_state_files_keep_their_metadata.txt");
+        remove_with_backups(&path);
+        let mut storage = TextFileStorage::open(&path).unwrap();
+        storage.set_metadata("policy", "max-total=3").unwrap();
+        storage.save(&entries()).unwrap();
+
+        // The metadata comes from the newest backup that can be read
+        fs::write(&path, "{\"version\": 1, \"entries\": ").unwrap();
+        storage.save(&entries()).unwrap();
+        assert_eq!(
+            storage.metadata("policy").unwrap(),
+            Some(String::from("max-total=3"))
+        );
+
+        // Without one, the file is left alone
+        for backup in storage.backups() {
+            fs::write(backup, "{").unwrap();
+        }
+        fs::write(&path, "{").unwrap();
+        let error = storage.save(&entries()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{");
+        remove_with_backups(&path);
+    }
+
+    #[test]
+    fn state_files_on_their_own_are_journaled() {
+        let path = temp_path("state_files_on_their_own_are_journaled.txt");
+        let journal_path = state_file_journal(&path);
+        let _ = fs::remove_file(&journal_path);
+        let event = Event {
+            at: SystemTime::now(),
+            actor: None,
+            operation: journal::Operation::Add {
+                user: UserID::new("UA8RXUPSP"),
+            },
+        };
+        let mut storage = open_state_file(&path).unwrap();
+        assert!(storage.load().unwrap().is_empty());
+        storage.save_event(&event, &entries()).unwrap();
+        assert_eq!(storage.events().unwrap().len(), 1);
+        assert_eq!(
+            loaded(&mut open_state_file(&path).unwrap()),
+            Vec::from(entries())
+        );
+        let _ = fs::remove_file(journal_path);
+        remove_with_backups(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_storage() {
+        let path = temp_path("sqlite_storage.db");
+        round_trip(&mut SqliteStorage::open(&path, "prusa").unwrap());
+
+        // Queues in the same database do not see each other's entries
+        let mut other = SqliteStorage::open(&path, "laser-cutter").unwrap();
+        assert!(other.load().unwrap().is_empty());
+        assert_eq!(other.metadata("policy").unwrap(), None);
+        assert_eq!(
+            Backend::Sqlite(path.clone()).queue_names().unwrap(),
+            ["laser-cutter", "prusa"]
+        );
+
+        let mut prusa = SqliteStorage::open(&path, "prusa").unwrap();
+        let aside = prusa.set_aside().unwrap();
+        assert!(prusa.load().unwrap().is_empty());
+        assert!(aside.contains("prusa.bad-"), "{}", aside);
+        let _ = fs::remove_file(path);
+    }
+
+    /// An event of someone joining the back of the line.
+    #[cfg(feature = "sqlite")]
+    fn added(user: &str) -> Event {
+        Event {
+            at: SystemTime::now(),
+            actor: Some(UserID::new(user)),
+            operation: Operation::Add {
+                user: UserID::new(user),
+            },
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_events_are_journaled_in_the_database() {
+        let path = temp_path("sqlite_events_are_journaled_in_the_database.db");
+        let mut storage = SqliteStorage::open(&path, "prusa").unwrap();
+        assert!(storage.load().unwrap().is_empty());
+        let mut saved = VecDeque::new();
+        for entry in entries() {
+            saved.push_back(entry.clone());
+            storage
+                .save_event(&added(&entry.user.to_string()), &saved)
+                .unwrap();
+        }
+
+        let events = SqliteStorage::open(&path, "prusa")
+            .unwrap()
+            .events()
+            .unwrap();
+        assert_eq!(
+            events.iter().map(|logged| logged.seq).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(events[1].event.operation, added("UNB2LMZRP").operation);
+        let mut replayed = Vec::new();
+        for logged in &events {
+            journal::replay(&mut replayed, &logged.changes);
+        }
+        assert_eq!(
+            replayed.into_iter().flatten().collect::<Vec<_>>(),
+            Vec::from(entries())
+        );
+        assert!(!path
+            .with_extension(format!("prusa.{}", journal::JOURNAL_EXTENSION))
+            .exists());
+
+        // A queue that was saved without its events starts its journal with whoever is in line
+        let mut other = SqliteStorage::open(&path, "laser-cutter").unwrap();
+        other.save(&entries()).unwrap();
+        assert_eq!(loaded(&mut other), Vec::from(entries()));
+        // Loading it again does not start the journal over
+        loaded(&mut other);
+        let events = other.events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.operation, Operation::Open);
+        let _ = fs::remove_file(path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn old_databases_are_upgraded() {
+        let path = temp_path("old_databases_are_upgraded.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE queues (name TEXT PRIMARY KEY NOT NULL);
+            CREATE TABLE entries (
+                queue TEXT NOT NULL REFERENCES queues (name),
+                position INTEGER NOT NULL,
+                user TEXT NOT NULL,
+                joined INTEGER NOT NULL,
+                front_since INTEGER,
+                PRIMARY KEY (queue, position)
+            );
+            INSERT INTO queues (name) VALUES ('prusa');
+            INSERT INTO entries VALUES ('prusa', 0, 'UA8RXUPSP', 1600000000, NULL);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut storage = SqliteStorage::open(&path, "prusa").unwrap();
+
+        assert_eq!(loaded(&mut storage), [UserID::new("UA8RXUPSP")]);
+        storage.save(&entries()).unwrap();
+        assert_eq!(loaded(&mut storage), Vec::from(entries()));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,7 +1,21 @@
 use std::{collections::HashMap, fmt};
+#[cfg(feature = "http-api")]
+use std::{thread, time::Duration};
 
+#[cfg(feature = "http-api")]
 use serde::Deserialize;
 
+#[cfg(feature = "http-api")]
+use crate::web::SLACK_API;
+
+/// How many users to ask Slack for at a time. Slack recommends no more than 200.
+#[cfg(feature = "http-api")]
+const PAGE_SIZE: &str = "200";
+
+/// How many times in a row to wait out Slack's rate limit before giving up.
+#[cfg(feature = "http-api")]
+const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+
 /// A user of Slack, i.e. someone who will wait in line for an event.
 ///
 /// This type simply wraps a string of the format UXXXXXXXX which represents the ID of a Slack user.
@@ -27,20 +41,85 @@ impl fmt::Display for UserID {
 }
 
 /// The shape of the JSON returned by the Slack users.list method.
+#[cfg(feature = "http-api")]
 #[derive(Debug, Deserialize)]
 struct UsersList {
-    cache_ts: u32,
-    members: Vec<slack::User>,
+    #[serde(default)]
+    members: Vec<Member>,
     ok: bool,
-    response_metadata: ResponseMetadata,
+    error: Option<String>,
+    response_metadata: Option<ResponseMetadata>,
 }
 
 /// The JSON object part of the JSON returned by the Slack users.list method.
+#[cfg(feature = "http-api")]
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
 struct ResponseMetadata {
+    /// Where the next page of users starts, or empty if this was the last page
+    #[serde(default)]
     next_cursor: String,
 }
 
+/// A member of a Slack workspace, as returned by the Slack users.list method.
+#[cfg(feature = "http-api")]
+#[derive(Debug, Deserialize)]
+struct Member {
+    id: Option<String>,
+    profile: Option<Profile>,
+}
+
+/// The names a [`Member`](struct.Member.html) goes by.
+#[cfg(feature = "http-api")]
+#[derive(Debug, Deserialize)]
+struct Profile {
+    real_name: Option<String>,
+    display_name: Option<String>,
+}
+
+/// Why the members of a Slack workspace could not be listed.
+#[cfg(feature = "http-api")]
+#[derive(Debug)]
+pub enum DirectoryError {
+    /// Slack could not be reached, or answered with something that is not JSON.
+    Http(reqwest::Error),
+    /// Slack answered, but refused to list the members. This variant contains the error code Slack
+    /// gave, e.g. `invalid_auth`.
+    Slack(String),
+    /// Slack kept saying that too many requests were being made, even after waiting as long as it
+    /// asked to.
+    RateLimited,
+}
+
+#[cfg(feature = "http-api")]
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirectoryError::Http(e) => write!(f, "could not list the users in Slack: {}", e),
+            DirectoryError::Slack(e) => write!(f, "Slack would not list its users: {}", e),
+            DirectoryError::RateLimited => {
+                f.write_str("Slack kept rate limiting the requests to list its users")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http-api")]
+impl std::error::Error for DirectoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DirectoryError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "http-api")]
+impl From<reqwest::Error> for DirectoryError {
+    fn from(e: reqwest::Error) -> Self {
+        DirectoryError::Http(e)
+    }
+}
+
 /// A mapping from Slack user IDs to real name-maybe username pairs.
 // TODO: Create struct so that we don't have to use (Option<String>, Option<String>)
 pub type SlackMap = HashMap<UserID, (Option<String>, Option<String>)>;
@@ -49,33 +128,87 @@ pub type SlackMap = HashMap<UserID, (Option<String>, Option<String>)>;
 /// IDs to a pair. The first item in the pair is the corresponding Slack user's real name, if one is
 /// set in Slack. The second item in the pair is the corresponding Slack user's username, if one is
 /// set in Slack.
-pub fn create_uid_username_mapping(auth_token: &str) -> reqwest::Result<SlackMap> {
+///
+/// Slack lists the members of a workspace a page at a time, so this makes as many calls to the
+/// users.list method as it takes to get every page, waiting out Slack's rate limit if it is hit.
+#[cfg(feature = "http-api")]
+pub fn create_uid_username_mapping(auth_token: &str) -> Result<SlackMap, DirectoryError> {
+    create_uid_username_mapping_from(SLACK_API, auth_token)
+}
+
+/// Like [`create_uid_username_mapping`](fn.create_uid_username_mapping.html), but using the Web API
+/// that lives at `base_url` instead of Slack's, e.g. a mock Slack for testing.
+#[cfg(feature = "http-api")]
+pub fn create_uid_username_mapping_from(
+    base_url: &str,
+    auth_token: &str,
+) -> Result<SlackMap, DirectoryError> {
     // I'M GONNA GET THE REAL NAMES FINALLY!!!!!1
     let client = reqwest::blocking::Client::new();
-    let users = client
-        .get("https://slack.com/api/users.list") // reqwest::RequestBuilder
-        .bearer_auth(auth_token) // reqwest::RequestBuilder
-        .send()? // reqwest::blocking::response::Response
-        .json::<UsersList>()? // UsersList
-        ;
+    let url = format!("{}/users.list", base_url.trim_end_matches('/'));
+    let mut members = Vec::new();
+    let mut cursor = String::new();
+    let mut rate_limited = 0;
+    loop {
+        let mut request = client
+            .get(&url) // reqwest::RequestBuilder
+            .bearer_auth(auth_token) // reqwest::RequestBuilder
+            .query(&[("limit", PAGE_SIZE)]);
+        if !cursor.is_empty() {
+            request = request.query(&[("cursor", &cursor)]);
+        }
+        let response = request.send()?; // reqwest::blocking::response::Response
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            rate_limited += 1;
+            if rate_limited > MAX_RATE_LIMITED_RETRIES {
+                return Err(DirectoryError::RateLimited);
+            }
+            // Slack says how many seconds to wait before trying again
+            let wait = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(1);
+            thread::sleep(Duration::from_secs(wait));
+            continue;
+        }
+        rate_limited = 0;
+
+        let page = response.json::<UsersList>()?; // UsersList
+        if !page.ok {
+            return Err(DirectoryError::Slack(page.error.unwrap_or_default()));
+        }
+        members.extend(page.members);
+        cursor = page
+            .response_metadata
+            .map(|metadata| metadata.next_cursor)
+            .unwrap_or_default();
+        if cursor.is_empty() {
+            break;
+        }
+    }
 
     // Yikes there are about 857 users
-    let mut uid_username_mapping = HashMap::with_capacity(860);
+    let mut uid_username_mapping = HashMap::with_capacity(members.len());
 
     /* Let's just see what a randomly chosen user looks like (and by randomly chosen I mean I
     randomly picked the number 70; I'm not bringing in another dependency (specifically the rand
     crate) just for debugging purposes. See https://xkcd.com/221/) */
     if cfg!(debug_assertions) {
-        println!("{:#?}", users.members[70]);
+        if let Some(user) = members.get(70) {
+            println!("{:#?}", user);
+        }
     }
     /* Extract the information we need from each member (If I ever decide to just go with storing
     queue::Users directly in the queue, (which I don't think can happen, see documentation for
     UserID) couldn't I just do something like
          users.members.iter().collect::<HashSet<_>>()
     ?) */
-    for user in users.members {
-        /* id lives at slack::User.id
-        username lives at slack::User.profile.display_name */
+    for user in members {
+        /* id lives at Member.id
+        username lives at Member.profile.display_name */
         let id = if let Some(id) = user.id {
             id
         } else {
@@ -102,6 +235,8 @@ pub fn create_uid_username_mapping(auth_token: &str) -> reqwest::Result<SlackMap
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "http-api")]
+    use crate::mock_slack::{self, Response};
 
     #[test]
     fn create_user_id() {
@@ -117,4 +252,55 @@ mod tests {
 
         assert_eq!(format!("{}", user), "UA8RXUPSP");
     }
+
+    #[cfg(feature = "http-api")]
+    #[test]
+    fn every_page_of_users_is_fetched() {
+        let (url, slack) = mock_slack::serve(vec![
+            Response::ok(
+                r#"{"ok": true, "members": [{"id": "UA8RXUPSP", "profile": {"real_name": "Joey",
+                "display_name": "joey"}}], "response_metadata": {"next_cursor": "dXNlcjpVMEc5V0ZYTlo="}}"#,
+            ),
+            Response {
+                status: "429 Too Many Requests",
+                headers: "Retry-After: 0\r\n",
+                body: String::from(r#"{"ok": false, "error": "ratelimited"}"#),
+            },
+            Response::ok(
+                r#"{"ok": true, "members": [{"id": "UNB2LMZRP", "profile": {"real_name": "Sam"}}],
+                "response_metadata": {"next_cursor": ""}}"#,
+            ),
+        ]);
+
+        let users = create_uid_username_mapping_from(&url, "xoxb-test").unwrap();
+
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            users[&UserID::new("UA8RXUPSP")],
+            (Some(String::from("Joey")), Some(String::from("joey")))
+        );
+        assert_eq!(
+            users[&UserID::new("UNB2LMZRP")],
+            (Some(String::from("Sam")), None)
+        );
+        let requests = slack.join().unwrap();
+        assert_eq!(requests[0].line, "GET /api/users.list?limit=200 HTTP/1.1");
+        assert_eq!(
+            requests[2].line,
+            "GET /api/users.list?limit=200&cursor=dXNlcjpVMEc5V0ZYTlo%3D HTTP/1.1"
+        );
+    }
+
+    #[cfg(feature = "http-api")]
+    #[test]
+    fn slack_errors_are_reported() {
+        let (url, slack) = mock_slack::serve(vec![Response::ok(
+            r#"{"ok": false, "error": "invalid_auth"}"#,
+        )]);
+
+        let error = create_uid_username_mapping_from(&url, "xoxb-wrong").unwrap_err();
+
+        assert!(matches!(error, DirectoryError::Slack(ref e) if e == "invalid_auth"));
+        slack.join().unwrap();
+    }
 }
@@ -0,0 +1,344 @@
+//! A client for the parts of the Slack Web API that Queue uses: posting messages, sending direct
+//! messages, and listing channels. Both the real time messaging bot and the Events API server post
+//! through it, so it is built with the `http-api` feature that either of them turns on.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::notify::Notifier;
+use crate::user::UserID;
+
+/// Where the Slack Web API lives, unless a [`WebClient`](struct.WebClient.html) is pointed
+/// somewhere else (e.g. a mock Slack for testing).
+pub const SLACK_API: &str = "https://slack.com/api";
+
+/// How many channels to ask for with each call to `conversations.list`. Slack recommends no more
+/// than 200.
+const PAGE_SIZE: &str = "200";
+
+/// A message to post to a Slack channel with `chat.postMessage`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Message {
+    /// The ID of the channel to post in
+    pub channel: String,
+    /// What to post, or what to show in notifications if there are `blocks`
+    pub text: String,
+    /// The Block Kit layout of the message, if it is more than just `text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<Value>>,
+}
+
+/// Why a call to the Slack Web API failed.
+#[derive(Debug)]
+pub enum WebError {
+    /// Slack could not be reached, or answered with something that is not JSON.
+    Http(reqwest::Error),
+    /// Slack answered, but refused to do what was asked. This variant contains the error code
+    /// Slack gave, e.g. `not_in_channel`.
+    Slack(String),
+}
+
+impl fmt::Display for WebError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebError::Http(e) => write!(f, "could not reach Slack: {}", e),
+            WebError::Slack(e) => write!(f, "Slack returned an error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WebError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebError::Http(e) => Some(e),
+            WebError::Slack(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for WebError {
+    fn from(e: reqwest::Error) -> Self {
+        WebError::Http(e)
+    }
+}
+
+/// The parts of a Slack Web API response that every method has.
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    error: Option<String>,
+}
+
+/// The shape of the JSON returned by the Slack conversations.list method.
+#[derive(Debug, Deserialize)]
+struct ConversationsList {
+    ok: bool,
+    error: Option<String>,
+    #[serde(default)]
+    channels: Vec<Channel>,
+    response_metadata: Option<ResponseMetadata>,
+}
+
+/// The part of the JSON returned by the Slack conversations.list method that says whether there are
+/// more channels to list.
+#[derive(Debug, Deserialize)]
+struct ResponseMetadata {
+    /// Where the next page of channels starts, or empty if this was the last page
+    #[serde(default)]
+    next_cursor: String,
+}
+
+/// The shape of the JSON returned by the Slack conversations.open method.
+#[derive(Debug, Deserialize)]
+struct ConversationsOpen {
+    ok: bool,
+    error: Option<String>,
+    channel: Option<OpenedChannel>,
+}
+
+/// The direct message channel opened by the Slack conversations.open method.
+#[derive(Debug, Deserialize)]
+struct OpenedChannel {
+    id: String,
+}
+
+/// A channel, as returned by the Slack conversations.list method.
+#[derive(Debug, Deserialize)]
+struct Channel {
+    id: String,
+    name: String,
+}
+
+/// Calls the methods of the Slack Web API that Queue needs.
+#[derive(Debug, Clone)]
+pub struct WebClient {
+    /// The bot token to authenticate with
+    token: String,
+    /// Where the Web API lives, without a trailing slash
+    base_url: String,
+    /// The HTTP client requests are sent with
+    client: reqwest::blocking::Client,
+}
+
+impl WebClient {
+    /// A client for the real Slack that authenticates with the bot token `token`.
+    pub fn new(token: &str) -> Self {
+        Self::with_base_url(token, SLACK_API)
+    }
+
+    /// A client for a Web API that lives at `base_url` instead of at Slack, e.g. a mock Slack.
+    pub fn with_base_url(token: &str, base_url: &str) -> Self {
+        Self {
+            token: token.to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    /// Post `message` with `chat.postMessage`.
+    pub fn post_message(&self, message: &Message) -> Result<(), WebError> {
+        let response = self
+            .client
+            .post(&format!("{}/chat.postMessage", self.base_url))
+            .bearer_auth(&self.token)
+            .json(message)
+            .send()?
+            .json::<ApiResponse>()?;
+        if response.ok {
+            Ok(())
+        } else {
+            Err(WebError::Slack(response.error.unwrap_or_default()))
+        }
+    }
+
+    /// The ID of the direct message channel between Queue and `user`, opening it with
+    /// `conversations.open` if it is not open yet.
+    pub fn open_direct_message(&self, user: &UserID) -> Result<String, WebError> {
+        let response = self
+            .client
+            .post(&format!("{}/conversations.open", self.base_url))
+            .bearer_auth(&self.token)
+            .json(&json!({ "users": user.0 }))
+            .send()?
+            .json::<ConversationsOpen>()?;
+        match response {
+            ConversationsOpen {
+                ok: true,
+                channel: Some(channel),
+                ..
+            } => Ok(channel.id),
+            ConversationsOpen { error, .. } => Err(WebError::Slack(error.unwrap_or_default())),
+        }
+    }
+
+    /// Send `body` as JSON to a `response_url` Slack gave Queue, e.g. along with an interaction.
+    /// Response URLs do not need the bot token.
+    pub fn respond<T: Serialize>(&self, response_url: &str, body: &T) -> Result<(), WebError> {
+        self.client
+            .post(response_url)
+            .json(body)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// The names and IDs of the public channels in the workspace, from `conversations.list`, which
+    /// is called for one page of channels after another until there are none left.
+    pub fn channels(&self) -> Result<Vec<(String, String)>, WebError> {
+        let mut channels = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let mut request = self
+                .client
+                .get(&format!("{}/conversations.list", self.base_url))
+                .bearer_auth(&self.token)
+                .query(&[("exclude_archived", "true"), ("limit", PAGE_SIZE)]);
+            if !cursor.is_empty() {
+                request = request.query(&[("cursor", &cursor)]);
+            }
+            let page = request.send()?.json::<ConversationsList>()?;
+            if !page.ok {
+                return Err(WebError::Slack(page.error.unwrap_or_default()));
+            }
+            channels.extend(
+                page.channels
+                    .into_iter()
+                    .map(|channel| (channel.name, channel.id)),
+            );
+            cursor = page
+                .response_metadata
+                .map(|metadata| metadata.next_cursor)
+                .unwrap_or_default();
+            if cursor.is_empty() {
+                return Ok(channels);
+            }
+        }
+    }
+}
+
+impl Notifier for WebClient {
+    fn direct_message(&self, user: &UserID, text: &str) -> Result<(), String> {
+        let channel = self.open_direct_message(user).map_err(|e| e.to_string())?;
+        self.post_message(&Message {
+            channel,
+            text: text.to_string(),
+            blocks: None,
+        })
+        .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_slack::{self, Response};
+
+    const QUEUE_CHANNEL: &str = "C0123456789";
+
+    #[test]
+    fn messages_are_posted_to_slack() {
+        let (url, slack) = mock_slack::serve(vec![Response::ok(r#"{"ok": true}"#)]);
+        let client = WebClient::with_base_url("xoxb-test", &url);
+
+        client
+            .post_message(&Message {
+                channel: String::from(QUEUE_CHANNEL),
+                text: String::from("Hello!"),
+                blocks: None,
+            })
+            .unwrap();
+
+        let requests = slack.join().unwrap();
+        assert_eq!(requests[0].line, "POST /api/chat.postMessage HTTP/1.1");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[0].body).unwrap(),
+            serde_json::json!({"channel": QUEUE_CHANNEL, "text": "Hello!"})
+        );
+    }
+
+    #[test]
+    fn slack_errors_are_reported() {
+        let (url, slack) = mock_slack::serve(vec![Response::ok(
+            r#"{"ok": false, "error": "not_in_channel"}"#,
+        )]);
+        let client = WebClient::with_base_url("xoxb-test", &url);
+
+        let error = client
+            .post_message(&Message {
+                channel: String::from(QUEUE_CHANNEL),
+                text: String::from("Hello!"),
+                blocks: None,
+            })
+            .unwrap_err();
+
+        assert!(matches!(error, WebError::Slack(ref e) if e == "not_in_channel"));
+        slack.join().unwrap();
+    }
+
+    #[test]
+    fn every_page_of_channels_is_listed() {
+        let (url, slack) = mock_slack::serve(vec![
+            Response::ok(
+                r#"{"ok": true, "channels": [{"id": "C0123456789", "name": "3d-printer-queue"}],
+                "response_metadata": {"next_cursor": "dGVhbTpDMDYxRkE1UEI="}}"#,
+            ),
+            Response::ok(
+                r#"{"ok": true, "channels": [{"id": "C9876543210", "name": "general"}],
+                "response_metadata": {"next_cursor": ""}}"#,
+            ),
+        ]);
+        let client = WebClient::with_base_url("xoxb-test", &url);
+
+        assert_eq!(
+            client.channels().unwrap(),
+            [
+                (
+                    String::from("3d-printer-queue"),
+                    String::from(QUEUE_CHANNEL)
+                ),
+                (String::from("general"), String::from("C9876543210")),
+            ]
+        );
+        let requests = slack.join().unwrap();
+        assert_eq!(
+            requests[0].line,
+            "GET /api/conversations.list?exclude_archived=true&limit=200 HTTP/1.1"
+        );
+        assert_eq!(
+            requests[1].line,
+            "GET /api/conversations.list?exclude_archived=true&limit=200&cursor=dGVhbTpDMDYxRkE1UEI%3D \
+			HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn direct_messages_are_sent_to_a_new_conversation() {
+        let (url, slack) = mock_slack::serve(vec![
+            Response::ok(r#"{"ok": true, "channel": {"id": "D0123456789"}}"#),
+            Response::ok(r#"{"ok": true}"#),
+            Response::ok(r#"{"ok": false, "error": "user_not_found"}"#),
+        ]);
+        let client = WebClient::with_base_url("xoxb-test", &url);
+
+        client
+            .direct_message(&UserID::new("UA8RXUPSP"), "Your turn!")
+            .unwrap();
+        let error = client
+            .direct_message(&UserID::new("UNOBODY00"), "Your turn!")
+            .unwrap_err();
+
+        assert!(error.contains("user_not_found"), "{}", error);
+        let requests = slack.join().unwrap();
+        assert_eq!(requests[0].line, "POST /api/conversations.open HTTP/1.1");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[0].body).unwrap(),
+            serde_json::json!({"users": "UA8RXUPSP"})
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[1].body).unwrap(),
+            serde_json::json!({"channel": "D0123456789", "text": "Your turn!"})
+        );
+    }
+}